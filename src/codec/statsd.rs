@@ -12,6 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+//! The `statsd` codec
+//!
+//! Besides the classic `name:value|type|@rate` format the `DogStatsD` extensions are supported:
+//!
+//! * tags: `name:value|type|#tag:value,othertag` decode into a `tags` record, tags without a value map to `null`
+//! * container ids (`|c:<id>`) and timestamps (`|T<seconds>`)
+//! * distributions (`d`) and sets with non numeric values
+//! * events: `_e{<title length>,<text length>}:<title>|<text>|d:<timestamp>|h:<hostname>|...` with `"type": "event"`
+//! * service checks: `_sc|<name>|<status>|d:<timestamp>|h:<hostname>|#<tags>|m:<message>` with `"type": "service_check"`
+//!
+//! A datagram containing multiple newline separated metrics decodes into an array of metrics,
+//! and arrays encode into newline separated datagrams. Use the `lines` preprocessor to get one
+//! event per metric instead.
+
 use super::prelude::*;
 use std::{slice::SliceIndex, str};
 
@@ -44,12 +58,31 @@ impl Codec for StatsD {
 
 fn encode(value: &Value) -> Result<Vec<u8>> {
     let mut r = String::new();
+    if let Some(values) = value.as_array() {
+        for (i, v) in values.iter().enumerate() {
+            if i > 0 {
+                r.push('\n');
+            }
+            encode_one(v, &mut r)?;
+        }
+    } else {
+        encode_one(value, &mut r)?;
+    }
+    Ok(r.into_bytes())
+}
+
+fn encode_one(value: &Value, r: &mut String) -> Result<()> {
+    match value.get_str("type") {
+        Some("event") => encode_event(value, r),
+        Some("service_check") => encode_service_check(value, r),
+        _ => encode_metric(value, r),
+    }
+}
+
+fn encode_metric(value: &Value, r: &mut String) -> Result<()> {
     r.push_str(value.get_str("metric").ok_or(ErrorKind::InvalidStatsD)?);
     let t = value.get_str("type").ok_or(ErrorKind::InvalidStatsD)?;
     let val = value.get("value").ok_or(ErrorKind::InvalidStatsD)?;
-    if !val.is_number() {
-        return Err(ErrorKind::InvalidStatsD.into());
-    };
     r.push(':');
     if t == "g" {
         match value.get_str("action") {
@@ -59,7 +92,14 @@ fn encode(value: &Value) -> Result<Vec<u8>> {
         }
     };
 
-    r.push_str(&val.encode());
+    if val.is_number() {
+        r.push_str(&val.encode());
+    } else if let (Some(s), "s") = (val.as_str(), t) {
+        // sets can count arbitrary values
+        r.push_str(s);
+    } else {
+        return Err(ErrorKind::InvalidStatsD.into());
+    };
     r.push('|');
     r.push_str(t);
 
@@ -71,11 +111,116 @@ fn encode(value: &Value) -> Result<Vec<u8>> {
             return Err(ErrorKind::InvalidStatsD.into());
         }
     }
+    encode_tags(value, r)?;
+    if let Some(id) = value.get_str("container_id") {
+        r.push_str("|c:");
+        r.push_str(id);
+    }
+    if let Some(ts) = value.get("timestamp") {
+        r.push_str("|T");
+        r.push_str(&ts.as_u64().ok_or(ErrorKind::InvalidStatsD)?.to_string());
+    }
+    Ok(())
+}
+
+fn encode_event(value: &Value, r: &mut String) -> Result<()> {
+    let title = escape(value.get_str("title").ok_or(ErrorKind::InvalidStatsD)?);
+    let text = escape(value.get_str("text").ok_or(ErrorKind::InvalidStatsD)?);
+    r.push_str("_e{");
+    r.push_str(&title.len().to_string());
+    r.push(',');
+    r.push_str(&text.len().to_string());
+    r.push_str("}:");
+    r.push_str(&title);
+    r.push('|');
+    r.push_str(&text);
+    if let Some(ts) = value.get("timestamp") {
+        r.push_str("|d:");
+        r.push_str(&ts.as_u64().ok_or(ErrorKind::InvalidStatsD)?.to_string());
+    }
+    for (key, prefix) in &[
+        ("hostname", "|h:"),
+        ("aggregation_key", "|k:"),
+        ("priority", "|p:"),
+        ("source_type_name", "|s:"),
+        ("alert_type", "|t:"),
+    ] {
+        if let Some(v) = value.get(*key) {
+            r.push_str(prefix);
+            r.push_str(v.as_str().ok_or(ErrorKind::InvalidStatsD)?);
+        }
+    }
+    encode_tags(value, r)
+}
+
+fn encode_service_check(value: &Value, r: &mut String) -> Result<()> {
+    r.push_str("_sc|");
+    r.push_str(value.get_str("name").ok_or(ErrorKind::InvalidStatsD)?);
+    r.push('|');
+    let status = value.get_u8("status").ok_or(ErrorKind::InvalidStatsD)?;
+    r.push_str(&status.to_string());
+    if let Some(ts) = value.get("timestamp") {
+        r.push_str("|d:");
+        r.push_str(&ts.as_u64().ok_or(ErrorKind::InvalidStatsD)?.to_string());
+    }
+    if let Some(host) = value.get("hostname") {
+        r.push_str("|h:");
+        r.push_str(host.as_str().ok_or(ErrorKind::InvalidStatsD)?);
+    }
+    encode_tags(value, r)?;
+    // the message has to be the last section
+    if let Some(msg) = value.get("message") {
+        r.push_str("|m:");
+        r.push_str(&escape(msg.as_str().ok_or(ErrorKind::InvalidStatsD)?));
+    }
+    Ok(())
+}
 
-    Ok(r.as_bytes().to_vec())
+fn encode_tags(value: &Value, r: &mut String) -> Result<()> {
+    if let Some(tags) = value.get("tags") {
+        let tags = tags.as_object().ok_or(ErrorKind::InvalidStatsD)?;
+        r.push_str("|#");
+        for (i, (k, v)) in tags.iter().enumerate() {
+            if i > 0 {
+                r.push(',');
+            }
+            r.push_str(k);
+            if let Some(s) = v.as_str() {
+                r.push(':');
+                r.push_str(s);
+            } else if !v.is_null() {
+                r.push(':');
+                r.push_str(&v.encode());
+            }
+        }
+    }
+    Ok(())
 }
 
 fn decode(data: &[u8], _ingest_ns: u64) -> Result<Value> {
+    let data = data.strip_suffix(b"\n").unwrap_or(data);
+    if data.contains(&b'\n') {
+        data.split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(decode_one)
+            .collect::<Result<Vec<_>>>()
+            .map(Value::from)
+    } else {
+        decode_one(data)
+    }
+}
+
+fn decode_one(data: &[u8]) -> Result<Value> {
+    if data.starts_with(b"_e{") {
+        decode_event(data)
+    } else if data.starts_with(b"_sc|") {
+        decode_service_check(data)
+    } else {
+        decode_metric(data)
+    }
+}
+
+fn decode_metric(data: &[u8]) -> Result<Value> {
     enum Sign {
         Plus,
         Minus,
@@ -103,28 +248,31 @@ fn decode(data: &[u8], _ingest_ns: u64) -> Result<Value> {
         Some((_, b'-')) => Sign::Minus,
         _ => Sign::None,
     };
-    let mut value: Value;
+    let raw_value: &str;
     loop {
         match d.next() {
             Some((_, b'.')) => is_float = true,
             Some((idx, b'|')) => {
-                let s = substr(data, value_start..idx)?;
-                if is_float {
-                    let v: f64 = s.parse()?;
-                    value = Value::from(v);
-                } else {
-                    let v: i64 = s.parse()?;
-                    value = Value::from(v);
-                };
+                raw_value = substr(data, value_start..idx)?;
                 break;
             }
             Some(_) => (),
             None => return Err(invalid()),
         }
     }
+    let mut value: Value;
     match d.next() {
-        Some((i, b'c' | b'h' | b's')) => m.insert("type".into(), substr(data, i..=i)?.into()),
+        Some((i, b'c' | b'h' | b'd')) => {
+            value = parse_number(raw_value, is_float)?;
+            m.insert("type".into(), substr(data, i..=i)?.into())
+        }
+        Some((i, b's')) => {
+            // sets can count arbitrary values, not only numbers
+            value = parse_number(raw_value, is_float).unwrap_or_else(|_| Value::from(raw_value));
+            m.insert("type".into(), substr(data, i..=i)?.into())
+        }
         Some((i, b'm')) => {
+            value = parse_number(raw_value, is_float)?;
             if let Some((j, b's')) = d.next() {
                 m.insert("type".into(), substr(data, i..=j)?.into())
             } else {
@@ -132,6 +280,7 @@ fn decode(data: &[u8], _ingest_ns: u64) -> Result<Value> {
             }
         }
         Some((i, b'g')) => {
+            value = parse_number(raw_value, is_float)?;
             match sign {
                 Sign::Plus => {
                     m.insert("action".into(), "add".into());
@@ -154,13 +303,21 @@ fn decode(data: &[u8], _ingest_ns: u64) -> Result<Value> {
         _ => return Err(invalid()),
     };
     match d.next() {
-        Some((_, b'|')) => {
-            if let Some((sample_start, b'@')) = d.next() {
-                let s = substr(data, sample_start + 1..)?;
-                let v: f64 = s.parse()?;
-                m.insert("sample_rate".into(), Value::from(v));
-            } else {
-                return Err(invalid());
+        Some((i, b'|')) => {
+            for section in substr(data, i + 1..)?.split('|') {
+                if let Some(rate) = section.strip_prefix('@') {
+                    let v: f64 = rate.parse()?;
+                    m.insert("sample_rate".into(), Value::from(v));
+                } else if let Some(tags) = section.strip_prefix('#') {
+                    m.insert("tags".into(), decode_tags(tags));
+                } else if let Some(id) = section.strip_prefix("c:") {
+                    m.insert("container_id".into(), Value::from(id));
+                } else if let Some(ts) = section.strip_prefix('T') {
+                    let v: u64 = ts.parse()?;
+                    m.insert("timestamp".into(), Value::from(v));
+                } else {
+                    return Err(invalid());
+                }
             }
         }
         Some(_) => return Err(invalid()),
@@ -170,6 +327,134 @@ fn decode(data: &[u8], _ingest_ns: u64) -> Result<Value> {
     Ok(Value::from(m))
 }
 
+/// `_e{<title length>,<text length>}:<title>|<text>|d:<timestamp>|h:<hostname>|p:<priority>|t:<alert type>|#<tags>`
+fn decode_event(data: &[u8]) -> Result<Value> {
+    let header_end = data.iter().position(|b| *b == b'}').ok_or_else(invalid)?;
+    let (title_len, text_len) = substr(data, 3..header_end)?
+        .split_once(',')
+        .ok_or_else(invalid)?;
+    let title_len: usize = title_len.parse()?;
+    let text_len: usize = text_len.parse()?;
+    if data.get(header_end + 1) != Some(&b':') {
+        return Err(invalid());
+    }
+    // the lengths are untrusted, they must not overflow
+    let title_start = header_end + 2;
+    let text_start = title_start
+        .checked_add(title_len)
+        .and_then(|i| i.checked_add(1))
+        .ok_or_else(invalid)?;
+    let text_end = text_start.checked_add(text_len).ok_or_else(invalid)?;
+    if data.get(text_start - 1) != Some(&b'|') {
+        return Err(invalid());
+    }
+    let mut m = Object::with_capacity(4);
+    m.insert("type".into(), Value::from("event"));
+    m.insert(
+        "title".into(),
+        Value::from(unescape(substr(data, title_start..text_start - 1)?)),
+    );
+    m.insert(
+        "text".into(),
+        Value::from(unescape(substr(data, text_start..text_end)?)),
+    );
+    match data.get(text_end) {
+        Some(b'|') => {
+            for section in substr(data, text_end + 1..)?.split('|') {
+                if let Some(tags) = section.strip_prefix('#') {
+                    m.insert("tags".into(), decode_tags(tags));
+                    continue;
+                }
+                let (key, value) = section.split_once(':').ok_or_else(invalid)?;
+                let key = match key {
+                    "d" => {
+                        let v: u64 = value.parse()?;
+                        m.insert("timestamp".into(), Value::from(v));
+                        continue;
+                    }
+                    "h" => "hostname",
+                    "k" => "aggregation_key",
+                    "p" => "priority",
+                    "s" => "source_type_name",
+                    "t" => "alert_type",
+                    _ => return Err(invalid()),
+                };
+                m.insert(key.into(), Value::from(value));
+            }
+        }
+        Some(_) => return Err(invalid()),
+        None => (),
+    }
+    Ok(Value::from(m))
+}
+
+/// `_sc|<name>|<status>|d:<timestamp>|h:<hostname>|#<tags>|m:<message>`
+fn decode_service_check(data: &[u8]) -> Result<Value> {
+    let s = substr(data, 4..)?;
+    let mut sections = s.splitn(3, '|');
+    let name = sections.next().ok_or_else(invalid)?;
+    let status: u8 = sections.next().ok_or_else(invalid)?.parse()?;
+    if name.is_empty() || status > 3 {
+        return Err(invalid());
+    }
+    let mut m = Object::with_capacity(4);
+    m.insert("type".into(), Value::from("service_check"));
+    m.insert("name".into(), Value::from(name));
+    m.insert("status".into(), Value::from(status));
+    let mut rest = sections.next();
+    while let Some(r) = rest {
+        // the message is the last section and may contain `|`
+        if let Some(msg) = r.strip_prefix("m:") {
+            m.insert("message".into(), Value::from(unescape(msg)));
+            break;
+        }
+        let (section, tail) = r.split_once('|').map_or((r, None), |(s, t)| (s, Some(t)));
+        rest = tail;
+        if let Some(tags) = section.strip_prefix('#') {
+            m.insert("tags".into(), decode_tags(tags));
+        } else if let Some(ts) = section.strip_prefix("d:") {
+            let v: u64 = ts.parse()?;
+            m.insert("timestamp".into(), Value::from(v));
+        } else if let Some(host) = section.strip_prefix("h:") {
+            m.insert("hostname".into(), Value::from(host));
+        } else {
+            return Err(invalid());
+        }
+    }
+    Ok(Value::from(m))
+}
+
+fn decode_tags(tags: &str) -> Value {
+    let mut res = Object::with_capacity(4);
+    for tag in tags.split(',').filter(|t| !t.is_empty()) {
+        if let Some((k, v)) = tag.split_once(':') {
+            res.insert(k.into(), Value::from(v));
+        } else {
+            res.insert(tag.into(), Value::null());
+        }
+    }
+    Value::from(res)
+}
+
+/// newlines in event texts and service check messages are sent as `\n`
+fn escape(s: &str) -> String {
+    s.replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("\\n", "\n")
+}
+
+fn parse_number(raw: &str, is_float: bool) -> Result<Value<'static>> {
+    if is_float {
+        let v: f64 = raw.parse()?;
+        Ok(Value::from(v))
+    } else {
+        let v: i64 = raw.parse()?;
+        Ok(Value::from(v))
+    }
+}
+
 fn invalid() -> Error {
     Error::from(ErrorKind::InvalidStatsD)
 }
@@ -340,4 +625,90 @@ mod test {
         let m = decode(data, 0).expect("failed to decode");
         assert_eq!(&data[..], encode(&m).expect("failed to encode"));
     }
+
+    #[test]
+    fn dogstatsd_tags() -> Result<()> {
+        let data = b"page.views:1|c|@0.5|#env:prod|c:83c0a99c|T1656581400";
+        let parsed = decode(data, 0)?;
+        let expected = literal!({
+            "type": "c",
+            "metric": "page.views",
+            "value": 1,
+            "sample_rate": 0.5,
+            "tags": {"env": "prod"},
+            "container_id": "83c0a99c",
+            "timestamp": 1_656_581_400
+
+        });
+        assert_eq!(parsed, expected);
+        assert_eq!(encode(&parsed)?, data);
+
+        let parsed = decode(b"latency:12.5|d|#region:eu,canary", 0)?;
+        assert_eq!(Some("d"), parsed.get_str("type"));
+        assert_eq!(
+            literal!({"region": "eu", "canary": null}),
+            parsed.get("tags").cloned().unwrap_or_default()
+        );
+        assert_eq!(decode(&encode(&parsed)?, 0)?, parsed);
+        Ok(())
+    }
+
+    #[test]
+    fn dogstatsd_set() -> Result<()> {
+        let data = b"users.uniques:badger|s";
+        let parsed = decode(data, 0)?;
+        assert_eq!(Some("badger"), parsed.get_str("value"));
+        assert_eq!(encode(&parsed)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn dogstatsd_event() -> Result<()> {
+        let data = b"_e{5,14}:snot!|badger\\nbadger|d:1656581400|h:host|p:low|t:warning|#env:prod";
+        let parsed = decode(data, 0)?;
+        let expected = literal!({
+            "type": "event",
+            "title": "snot!",
+            "text": "badger\nbadger",
+            "timestamp": 1_656_581_400,
+            "hostname": "host",
+            "priority": "low",
+            "alert_type": "warning",
+            "tags": {"env": "prod"}
+        });
+        assert_eq!(parsed, expected);
+        assert_eq!(encode(&parsed)?, data);
+        assert!(decode(b"_e{5,99}:snot!|badger", 0).is_err());
+        assert!(decode(b"_e{18446744073709551615,0}:", 0).is_err());
+        assert!(decode(b"_e{0,18446744073709551615}:|", 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dogstatsd_service_check() -> Result<()> {
+        let data = b"_sc|db.up|2|d:1656581400|h:host|#env:prod|m:down | really";
+        let parsed = decode(data, 0)?;
+        let expected = literal!({
+            "type": "service_check",
+            "name": "db.up",
+            "status": 2,
+            "timestamp": 1_656_581_400,
+            "hostname": "host",
+            "tags": {"env": "prod"},
+            "message": "down | really"
+        });
+        assert_eq!(parsed, expected);
+        assert_eq!(encode(&parsed)?, data);
+        assert!(decode(b"_sc|db.up|7", 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_metrics() -> Result<()> {
+        let data = b"gorets:1|c\nglork:320|ms|#env:prod\n";
+        let parsed = decode(data, 0)?;
+        assert_eq!(2, parsed.as_array().map_or(0, Vec::len));
+        assert_eq!(encode(&parsed)?, &data[..data.len() - 1]);
+        Ok(())
+    }
 }
@@ -21,3 +21,42 @@ pub mod otel;
 pub mod gcp;
 
 pub(crate) mod pb;
+
+/// Columnar (parquet) output for file based offramps
+pub(crate) mod columnar;
+
+/// Prometheus remote-write protocol
+pub(crate) mod prometheus;
+
+/// Unacknowledged messages of transactional onramps
+pub(crate) mod inflight;
+
+/// Batching for offramps that send many events per request
+pub(crate) mod batch;
+
+/// Exponential backoff for retries, reconnects and restarts
+pub(crate) mod backoff;
+
+/// TLS or plain client connections
+pub(crate) mod tls;
+
+/// Websocket subprotocols and keepalive
+pub(crate) mod ws;
+
+/// MQTT 3.1.1 and 5 client
+pub(crate) mod mqtt;
+
+/// Redis client
+pub(crate) mod redis;
+
+/// S3 client
+pub(crate) mod s3;
+
+/// AMQP header conversions
+pub(crate) mod amqp;
+
+/// NATS JetStream API
+pub(crate) mod jetstream;
+
+/// Child processes for the `exec` onramp and offramp
+pub(crate) mod exec;
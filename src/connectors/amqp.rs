@@ -0,0 +1,116 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversions between AMQP field tables and tremor values, shared by the `amqp` onramp
+//! and offramp for message headers
+
+use lapin::types::{AMQPValue, ByteArray, FieldArray, FieldTable, LongString, ShortString};
+use simd_json::StaticNode;
+use tremor_value::Value;
+
+/// Converts a tremor value into an AMQP field value
+///
+/// Strings become long strings, arrays field arrays, objects nested field tables and
+/// binaries byte arrays.
+// ALLOW: only integers beyond `i64::MAX` are sent as doubles
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn to_amqp(value: &Value) -> AMQPValue {
+    match value {
+        Value::Static(StaticNode::Null) => AMQPValue::Void,
+        Value::Static(StaticNode::Bool(b)) => AMQPValue::Boolean(*b),
+        Value::Static(StaticNode::I64(n)) => AMQPValue::LongLongInt(*n),
+        Value::Static(StaticNode::U64(n)) => {
+            i64::try_from(*n).map_or_else(|_| AMQPValue::Double(*n as f64), AMQPValue::LongLongInt)
+        }
+        Value::Static(StaticNode::F64(n)) => AMQPValue::Double(*n),
+        Value::String(s) => AMQPValue::LongString(LongString::from(s.to_string())),
+        Value::Array(a) => {
+            AMQPValue::FieldArray(FieldArray::from(a.iter().map(to_amqp).collect::<Vec<_>>()))
+        }
+        Value::Object(o) => {
+            let mut table = FieldTable::default();
+            for (k, v) in o.iter() {
+                table.insert(ShortString::from(k.to_string()), to_amqp(v));
+            }
+            AMQPValue::FieldTable(table)
+        }
+        Value::Bytes(b) => AMQPValue::ByteArray(ByteArray::from(b.to_vec())),
+    }
+}
+
+/// Converts an AMQP field value into a tremor value
+pub(crate) fn from_amqp(value: &AMQPValue) -> Value<'static> {
+    match value {
+        AMQPValue::Boolean(b) => Value::from(*b),
+        AMQPValue::ShortShortInt(n) => Value::from(*n),
+        AMQPValue::ShortShortUInt(n) => Value::from(*n),
+        AMQPValue::ShortInt(n) => Value::from(*n),
+        AMQPValue::ShortUInt(n) => Value::from(*n),
+        AMQPValue::LongInt(n) => Value::from(*n),
+        AMQPValue::LongUInt(n) => Value::from(*n),
+        AMQPValue::LongLongInt(n) => Value::from(*n),
+        AMQPValue::Float(n) => Value::from(*n),
+        AMQPValue::Double(n) => Value::from(*n),
+        AMQPValue::Timestamp(n) => Value::from(*n),
+        AMQPValue::DecimalValue(d) => {
+            Value::from(f64::from(d.value) / 10_f64.powi(i32::from(d.scale)))
+        }
+        AMQPValue::ShortString(s) => Value::from(s.as_str().to_string()),
+        AMQPValue::LongString(s) => Value::from(s.as_str().to_string()),
+        AMQPValue::FieldArray(a) => Value::from(
+            a.as_slice()
+                .iter()
+                .map(from_amqp)
+                .collect::<Vec<Value<'static>>>(),
+        ),
+        AMQPValue::FieldTable(t) => from_field_table(t),
+        AMQPValue::ByteArray(b) => Value::Bytes(b.as_slice().to_vec().into()),
+        AMQPValue::Void => Value::null(),
+    }
+}
+
+/// Converts an AMQP field table, e.g. message headers, into a tremor object
+pub(crate) fn from_field_table(table: &FieldTable) -> Value<'static> {
+    let mut obj = Value::object_with_capacity(table.inner().len());
+    for (k, v) in table.inner() {
+        // keys are unique within a field table
+        obj.try_insert(k.as_str().to_string(), from_amqp(v));
+    }
+    obj
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tremor_value::literal;
+
+    #[test]
+    fn roundtrip() {
+        let headers = literal!({
+            "snot": "badger",
+            "count": 42,
+            "ratio": 0.5,
+            "flag": true,
+            "nothing": null,
+            "list": ["a", 1],
+            "nested": {"key": "value"}
+        });
+        let table = if let AMQPValue::FieldTable(table) = to_amqp(&headers) {
+            table
+        } else {
+            panic!("objects need to become field tables")
+        };
+        assert_eq!(headers, from_field_table(&table));
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exponential backoff for retries, reconnects and restarts
+//!
+//! [`Backoff`] is meant to be flattened into the config of an onramp or offramp,
+//! so all of them share the `initial_backoff_ms` and `max_backoff_ms` settings.
+
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff settings
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct Backoff {
+    /// backoff before the first attempt, doubled for every subsequent attempt (default: 100)
+    #[serde(default = "dflt_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    /// upper bound for the backoff (default: 10000)
+    #[serde(default = "dflt_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: dflt_initial_backoff_ms(),
+            max_backoff_ms: dflt_max_backoff_ms(),
+        }
+    }
+}
+
+fn dflt_initial_backoff_ms() -> u64 {
+    100
+}
+
+fn dflt_max_backoff_ms() -> u64 {
+    10_000
+}
+
+impl Backoff {
+    /// upper bound for the backoff, never below the initial backoff
+    pub(crate) fn max(&self) -> Duration {
+        Duration::from_millis(self.max_backoff_ms.max(self.initial_backoff_ms))
+    }
+
+    fn delay_ms(&self, attempt: u32) -> u64 {
+        self.initial_backoff_ms
+            .saturating_mul(2_u64.saturating_pow(attempt))
+            .min(self.max_backoff_ms.max(self.initial_backoff_ms))
+    }
+
+    /// backoff before attempt number `attempt` (starting at 0)
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        Duration::from_millis(self.delay_ms(attempt))
+    }
+
+    /// backoff before attempt number `attempt`, randomized within its upper half to
+    /// avoid clients retrying in lockstep
+    pub(crate) fn jittered(&self, attempt: u32) -> Duration {
+        let ms = self.delay_ms(attempt);
+        if ms > 1 {
+            Duration::from_millis(ms / 2 + rand::thread_rng().gen_range(0..=ms / 2))
+        } else {
+            Duration::from_millis(ms)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delays() {
+        let backoff = Backoff::default();
+        assert_eq!(Duration::from_millis(100), backoff.delay(0));
+        assert_eq!(Duration::from_millis(400), backoff.delay(2));
+        assert_eq!(Duration::from_millis(10_000), backoff.delay(10));
+        assert_eq!(Duration::from_millis(10_000), backoff.delay(u32::MAX));
+
+        let backoff = Backoff {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 10,
+        };
+        assert_eq!(Duration::from_millis(100), backoff.max());
+        assert_eq!(Duration::from_millis(100), backoff.delay(3));
+
+        let backoff = Backoff {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+        };
+        for _ in 0..100 {
+            let jittered = backoff.jittered(2);
+            assert!(jittered >= Duration::from_millis(200));
+            assert!(jittered <= Duration::from_millis(400));
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Batching for offramps that send many events per request
+//!
+//! [`Batch`] keeps track of the events whose data an offramp buffered, so they
+//! can be acknowledged or failed together once the request holding them was sent.
+//! [`Config`] is meant to be flattened into the config of the offramp, so all of
+//! them share the `batch_size` and `flush_interval_ms` settings.
+
+use crate::errors::Result;
+use tremor_pipeline::{Event, EventId, OpMeta};
+
+/// Batch settings
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct Config {
+    /// Maximum number of items (entries, events, series, ...) per request, defaults to 500
+    #[serde(default = "dflt_batch_size")]
+    pub batch_size: usize,
+    /// Send incomplete batches after this many milliseconds, defaults to 1000, 0 to only
+    /// send full batches
+    #[serde(default = "dflt_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            batch_size: dflt_batch_size(),
+            flush_interval_ms: dflt_flush_interval_ms(),
+        }
+    }
+}
+
+fn dflt_batch_size() -> usize {
+    500
+}
+
+fn dflt_flush_interval_ms() -> u64 {
+    1000
+}
+
+/// The events of a batch that was taken to be sent
+#[derive(Debug)]
+pub(crate) struct Events {
+    ids: EventId,
+    /// merged `op_meta` of the events
+    op_meta: OpMeta,
+    /// ingest time of the first event
+    ingest_ns: u64,
+}
+
+impl Events {
+    /// The ack or fail insight for all events of the batch
+    pub(crate) fn insight(self, ack: bool) -> Event {
+        let mut insight = Event::ack_or_fail(ack, self.ingest_ns, self.ids);
+        insight.op_meta = self.op_meta;
+        insight
+    }
+}
+
+/// The events of the batch currently being filled
+#[derive(Debug)]
+pub(crate) struct Batch {
+    config: Config,
+    events: Option<Events>,
+    /// number of items the events added to the batch
+    len: usize,
+}
+
+impl Batch {
+    pub(crate) fn new(config: Config) -> Result<Self> {
+        if config.batch_size == 0 {
+            return Err("`batch_size` must be greater than 0".into());
+        }
+        Ok(Self {
+            config,
+            events: None,
+            len: 0,
+        })
+    }
+
+    /// Adds an event whose data made up `items` items of the batch
+    pub(crate) fn push(&mut self, event: Event, items: usize) {
+        if let Some(events) = &mut self.events {
+            events.ids.track(&event.id);
+            events.op_meta.merge(event.op_meta);
+        } else {
+            self.events = Some(Events {
+                ids: event.id,
+                op_meta: event.op_meta,
+                ingest_ns: event.ingest_ns,
+            });
+        }
+        self.len += items;
+    }
+
+    /// `true` once the batch holds `batch_size` items
+    pub(crate) fn is_full(&self) -> bool {
+        self.len >= self.config.batch_size
+    }
+
+    /// `true` if the first event of the batch is older than `flush_interval_ms`
+    pub(crate) fn is_due(&self, now_ns: u64) -> bool {
+        let ms = self.config.flush_interval_ms;
+        match &self.events {
+            Some(events) if ms > 0 => {
+                now_ns.saturating_sub(events.ingest_ns) >= ms.saturating_mul(1_000_000)
+            }
+            _ => false,
+        }
+    }
+
+    /// Takes the events of the batch to send it, `None` if it is empty
+    pub(crate) fn take(&mut self) -> Option<Events> {
+        self.len = 0;
+        self.events.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use simd_json::OwnedValue;
+    use tremor_pipeline::CbAction;
+
+    fn event(id: u64, ingest_ns: u64) -> Event {
+        let mut event = Event {
+            id: (1, 1, id).into(),
+            ingest_ns,
+            ..Event::default()
+        };
+        event.op_meta.insert(id, OwnedValue::null());
+        event
+    }
+
+    #[test]
+    fn batch() -> Result<()> {
+        assert!(Batch::new(Config {
+            batch_size: 0,
+            flush_interval_ms: 0
+        })
+        .is_err());
+        let mut batch = Batch::new(Config {
+            batch_size: 3,
+            flush_interval_ms: 1,
+        })?;
+        assert!(!batch.is_due(u64::MAX));
+        assert!(batch.take().is_none());
+
+        batch.push(event(1, 1_000_000), 2);
+        assert!(!batch.is_full());
+        assert!(!batch.is_due(1_999_999));
+        assert!(batch.is_due(2_000_000));
+        batch.push(event(2, 5_000_000), 1);
+        assert!(batch.is_full());
+
+        let insight = batch.take().ok_or("empty batch")?.insight(false);
+        assert_eq!(CbAction::Fail, insight.cb);
+        assert_eq!(1_000_000, insight.ingest_ns);
+        assert!(insight.id.is_tracking(&(1, 1, 1).into()));
+        assert!(insight.id.is_tracking(&(1, 1, 2).into()));
+        // the insight carries the op_meta of all events
+        assert!(insight.op_meta.contains_key(1) && insight.op_meta.contains_key(2));
+        assert!(!batch.is_full());
+        assert!(batch.take().is_none());
+        Ok(())
+    }
+}
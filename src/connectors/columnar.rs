@@ -0,0 +1,714 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Columnar (Apache Parquet) batching for file based offramps
+//!
+//! Events are buffered as rows, converted into arrow record batches once a
+//! row group is full and written into an in-memory parquet file. The file
+//! is handed back to the offramp once it is due by size, event count or age,
+//! along with the ids of the transactional events it holds. Those are only
+//! acked or failed by the offramp once the file was written.
+
+use crate::errors::{Error, Result};
+use arrow::array::{
+    ArrayRef, BinaryBuilder, BooleanBuilder, Float64Builder, Int64Builder, StringBuilder,
+    UInt64Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression as ParquetCompression;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::InMemoryWriteableCursor;
+use std::mem;
+use std::sync::Arc;
+use tremor_pipeline::{Event, EventId, OpMeta};
+use tremor_value::prelude::*;
+use tremor_value::Value;
+
+/// Compression codec applied to parquet column chunks
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Compression {
+    None,
+    Snappy,
+    Gzip,
+    Lz4,
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::Snappy
+    }
+}
+
+impl From<Compression> for ParquetCompression {
+    fn from(c: Compression) -> Self {
+        match c {
+            Compression::None => Self::UNCOMPRESSED,
+            Compression::Snappy => Self::SNAPPY,
+            Compression::Gzip => Self::GZIP,
+            Compression::Lz4 => Self::LZ4,
+            Compression::Zstd => Self::ZSTD,
+        }
+    }
+}
+
+/// Column types supported for parquet output
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FieldType {
+    Boolean,
+    Int64,
+    UInt64,
+    Float64,
+    /// utf8 strings, non string values are stored as their json representation
+    String,
+    Binary,
+}
+
+impl From<FieldType> for DataType {
+    fn from(t: FieldType) -> Self {
+        match t {
+            FieldType::Boolean => Self::Boolean,
+            FieldType::Int64 => Self::Int64,
+            FieldType::UInt64 => Self::UInt64,
+            FieldType::Float64 => Self::Float64,
+            FieldType::String => Self::Utf8,
+            FieldType::Binary => Self::Binary,
+        }
+    }
+}
+
+/// A single column of the output schema
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct FieldConfig {
+    /// name of the top level event field stored in this column
+    pub name: String,
+    /// column type
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    /// if the column may contain nulls, missing fields are written as null
+    #[serde(default = "default_true")]
+    pub nullable: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Config {
+    /// Explicit schema, if not provided it is inferred from the first event of each file
+    #[serde(default)]
+    pub schema: Option<Vec<FieldConfig>>,
+    /// Number of rows per parquet row group
+    #[serde(default = "default_row_group_size")]
+    pub row_group_size: usize,
+    /// Flush a file once it holds this many events
+    #[serde(default = "default_max_events")]
+    pub max_events: usize,
+    /// Flush a file once the (estimated) uncompressed size of its events exceeds this many bytes
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+    /// Flush a file once its first event is older than this, checked on every tick,
+    /// defaults to 1000, 0 to only flush by event count or size
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+    /// Compression of column chunks (default: `snappy`)
+    #[serde(default)]
+    pub compression: Compression,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_row_group_size() -> usize {
+    10_000
+}
+
+fn default_max_events() -> usize {
+    1_000_000
+}
+
+fn default_max_bytes() -> usize {
+    128 * 1024 * 1024
+}
+
+fn default_flush_interval_ms() -> u64 {
+    1000
+}
+
+/// The parquet file currently being written
+struct OpenFile {
+    writer: ArrowWriter<InMemoryWriteableCursor>,
+    cursor: InMemoryWriteableCursor,
+    events: usize,
+    bytes: usize,
+    started_ns: u64,
+}
+
+/// The transactional events of a file, acked or failed once it was written
+#[derive(Default)]
+pub(crate) struct Pending {
+    id: Option<EventId>,
+    op_meta: OpMeta,
+    ingest_ns: u64,
+}
+
+impl Pending {
+    fn track(&mut self, event: &Event) {
+        if !event.transactional {
+            return;
+        }
+        if let Some(id) = &mut self.id {
+            id.track(&event.id);
+        } else {
+            self.id = Some(event.id.clone());
+            self.ingest_ns = event.ingest_ns;
+        }
+        self.op_meta.merge(event.op_meta.clone());
+    }
+
+    /// The ack or fail insight for the events, `None` if none of them is transactional
+    pub(crate) fn insight(self, written: bool) -> Option<Event> {
+        let mut insight = Event::ack_or_fail(written, self.ingest_ns, self.id?);
+        insight.op_meta = self.op_meta;
+        Some(insight)
+    }
+}
+
+/// A file that is done
+pub(crate) struct Finished {
+    /// the parquet file or the error encoding it
+    pub data: Result<Vec<u8>>,
+    /// the events in the file
+    pub events: Pending,
+}
+
+/// Buffers events into row groups and produces complete parquet files
+pub(crate) struct ParquetBatcher {
+    config: Config,
+    configured_schema: Option<SchemaRef>,
+    schema: Option<SchemaRef>,
+    rows: Vec<Value<'static>>,
+    file: Option<OpenFile>,
+    pending: Pending,
+}
+
+impl ParquetBatcher {
+    pub(crate) fn new(config: Config) -> Result<Self> {
+        if config.row_group_size == 0 {
+            return Err("`row_group_size` must be greater than 0".into());
+        }
+        let configured_schema = config.schema.as_ref().map(|fields| {
+            Arc::new(Schema::new(
+                fields
+                    .iter()
+                    .map(|f| Field::new(&f.name, f.field_type.into(), f.nullable))
+                    .collect(),
+            ))
+        });
+        Ok(Self {
+            config,
+            configured_schema,
+            schema: None,
+            rows: Vec::new(),
+            file: None,
+            pending: Pending::default(),
+        })
+    }
+
+    /// Adds the values of an event to the current file. Returns the finished file
+    /// if the event made it hit the configured event count or size, the values of
+    /// an event always end up in the same file.
+    ///
+    /// Events with a value that doesn't fit the schema are rejected as a whole,
+    /// without affecting the events buffered so far.
+    pub(crate) fn push(&mut self, event: &Event) -> Result<Option<Finished>> {
+        let schema = self.check(event)?;
+        if self.file.is_none() {
+            self.file = Some(self.open(schema.clone(), event.ingest_ns)?);
+            self.schema = Some(schema);
+        }
+        self.pending.track(event);
+        let mut values = 0;
+        let mut size = 0;
+        for value in event.value_iter() {
+            let row = value.clone_static();
+            values += 1;
+            size += estimate_size(&row);
+            self.rows.push(row);
+            if self.rows.len() >= self.config.row_group_size {
+                if let Err(e) = self.write_row_group() {
+                    return Ok(Some(self.abort(e)));
+                }
+            }
+        }
+        if let Some(file) = &mut self.file {
+            file.events += values;
+            file.bytes += size;
+            if file.events >= self.config.max_events || file.bytes >= self.config.max_bytes {
+                return Ok(self.finish());
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns `true` if the current file is older than `flush_interval_ms`
+    pub(crate) fn is_due(&self, now_ns: u64) -> bool {
+        match (&self.file, self.config.flush_interval_ms) {
+            (Some(_), 0) | (None, _) => false,
+            (Some(file), interval) => {
+                now_ns.saturating_sub(file.started_ns) >= interval.saturating_mul(1_000_000)
+            }
+        }
+    }
+
+    /// Closes the current file and returns it, if there is one
+    pub(crate) fn finish(&mut self) -> Option<Finished> {
+        self.file.as_ref()?;
+        let data = self.write_row_group().and_then(|()| self.close());
+        self.file = None;
+        self.schema = None;
+        Some(Finished {
+            data,
+            events: mem::take(&mut self.pending),
+        })
+    }
+
+    /// Drops the current file, its events are returned to be failed
+    fn abort(&mut self, e: Error) -> Finished {
+        self.rows.clear();
+        self.file = None;
+        self.schema = None;
+        Finished {
+            data: Err(e),
+            events: mem::take(&mut self.pending),
+        }
+    }
+
+    /// Checks all values of an event against the schema of the current file, or the
+    /// one to use for a new file, and returns it
+    fn check(&self, event: &Event) -> Result<SchemaRef> {
+        let mut schema = self
+            .schema
+            .clone()
+            .or_else(|| self.configured_schema.clone());
+        for value in event.value_iter() {
+            if !value.is_object() {
+                return Err("Parquet output requires events to be records".into());
+            }
+            if schema.is_none() {
+                schema = Some(infer_schema(value)?);
+            }
+            if let Some(schema) = &schema {
+                check_row(schema, value)?;
+            }
+        }
+        schema.ok_or_else(|| "Event holds no values".into())
+    }
+
+    fn open(&self, schema: SchemaRef, ingest_ns: u64) -> Result<OpenFile> {
+        let props = WriterProperties::builder()
+            .set_compression(self.config.compression.into())
+            .set_max_row_group_size(self.config.row_group_size)
+            .build();
+        let cursor = InMemoryWriteableCursor::default();
+        let writer = ArrowWriter::try_new(cursor.clone(), schema, Some(props))?;
+        Ok(OpenFile {
+            writer,
+            cursor,
+            events: 0,
+            bytes: 0,
+            started_ns: ingest_ns,
+        })
+    }
+
+    fn close(&mut self) -> Result<Vec<u8>> {
+        let mut file = self.file.take().ok_or("No parquet file open")?;
+        file.writer.close()?;
+        // drop the writer so we hold the only reference to the cursor
+        drop(file.writer);
+        Ok(file.cursor.data())
+    }
+
+    fn write_row_group(&mut self) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let schema = self
+            .schema
+            .clone()
+            .ok_or("Parquet schema not initialized")?;
+        // rows are checked when they are pushed, never keep them around if converting
+        // them fails anyways
+        let batch = to_record_batch(&schema, &self.rows);
+        self.rows.clear();
+        let batch = batch?;
+        if let Some(file) = &mut self.file {
+            file.writer.write(&batch)?;
+        }
+        Ok(())
+    }
+}
+
+/// Infers a schema from the top level fields of the given record. All
+/// columns are nullable; nested values and nulls are mapped to string
+/// columns holding their json representation.
+fn infer_schema(value: &Value) -> Result<SchemaRef> {
+    let obj = value
+        .as_object()
+        .ok_or("Parquet output requires events to be records")?;
+    let fields = obj
+        .iter()
+        .map(|(k, v)| {
+            let data_type = if v.is_bool() {
+                DataType::Boolean
+            } else if v.is_i64() {
+                DataType::Int64
+            } else if v.is_u64() {
+                DataType::UInt64
+            } else if v.is_f64() {
+                DataType::Float64
+            } else if let Value::Bytes(_) = v {
+                DataType::Binary
+            } else {
+                DataType::Utf8
+            };
+            Field::new(k, data_type, true)
+        })
+        .collect();
+    Ok(Arc::new(Schema::new(fields)))
+}
+
+fn estimate_size(value: &Value) -> usize {
+    match value {
+        Value::Static(_) => 8,
+        Value::String(s) => s.len(),
+        Value::Bytes(b) => b.len(),
+        Value::Array(a) => a.iter().map(estimate_size).sum(),
+        Value::Object(o) => o.iter().map(|(k, v)| k.len() + estimate_size(v)).sum(),
+    }
+}
+
+fn null_check(field: &Field) -> Result<()> {
+    if field.is_nullable() {
+        Ok(())
+    } else {
+        Err(Error::from(format!(
+            "Missing value for non nullable column `{}`",
+            field.name()
+        )))
+    }
+}
+
+/// Checks that a record fits the schema, values of non nullable columns must
+/// be present and all values must be representable in their column
+fn check_row(schema: &Schema, row: &Value) -> Result<()> {
+    for field in schema.fields() {
+        let name = field.name().as_str();
+        match row.get(name) {
+            Some(v) if !v.is_null() => {
+                let fits = match field.data_type() {
+                    DataType::Boolean => v.is_bool(),
+                    DataType::Int64 => v.as_i64().is_some(),
+                    DataType::UInt64 => v.as_u64().is_some(),
+                    DataType::Float64 => v.cast_f64().is_some(),
+                    DataType::Binary => matches!(v, Value::Bytes(_) | Value::String(_)),
+                    // other values are stored as their json representation
+                    _ => true,
+                };
+                if !fits {
+                    return Err(format!(
+                        "Value of column `{}` is not of type {:?}",
+                        name,
+                        field.data_type()
+                    )
+                    .into());
+                }
+            }
+            _ => null_check(field)?,
+        }
+    }
+    Ok(())
+}
+
+/// Converts buffered rows into a record batch for the given schema. The rows
+/// need to be checked with `check_row` before.
+fn to_record_batch(schema: &SchemaRef, rows: &[Value<'static>]) -> Result<RecordBatch> {
+    let mut columns: Vec<ArrayRef> = Vec::with_capacity(schema.fields().len());
+    for field in schema.fields() {
+        let name = field.name().as_str();
+        let column: ArrayRef = match field.data_type() {
+            DataType::Boolean => {
+                let mut b = BooleanBuilder::new(rows.len());
+                for row in rows {
+                    if let Some(v) = row.get_bool(name) {
+                        b.append_value(v)?;
+                    } else {
+                        null_check(field)?;
+                        b.append_null()?;
+                    }
+                }
+                Arc::new(b.finish())
+            }
+            DataType::Int64 => {
+                let mut b = Int64Builder::new(rows.len());
+                for row in rows {
+                    if let Some(v) = row.get_i64(name) {
+                        b.append_value(v)?;
+                    } else {
+                        null_check(field)?;
+                        b.append_null()?;
+                    }
+                }
+                Arc::new(b.finish())
+            }
+            DataType::UInt64 => {
+                let mut b = UInt64Builder::new(rows.len());
+                for row in rows {
+                    if let Some(v) = row.get_u64(name) {
+                        b.append_value(v)?;
+                    } else {
+                        null_check(field)?;
+                        b.append_null()?;
+                    }
+                }
+                Arc::new(b.finish())
+            }
+            DataType::Float64 => {
+                let mut b = Float64Builder::new(rows.len());
+                for row in rows {
+                    if let Some(v) = row.get(name).and_then(|v| v.cast_f64()) {
+                        b.append_value(v)?;
+                    } else {
+                        null_check(field)?;
+                        b.append_null()?;
+                    }
+                }
+                Arc::new(b.finish())
+            }
+            DataType::Binary => {
+                let mut b = BinaryBuilder::new(rows.len());
+                for row in rows {
+                    match row.get(name) {
+                        Some(Value::Bytes(v)) => b.append_value(v)?,
+                        Some(Value::String(v)) => b.append_value(v.as_bytes())?,
+                        _ => {
+                            null_check(field)?;
+                            b.append_null()?;
+                        }
+                    }
+                }
+                Arc::new(b.finish())
+            }
+            DataType::Utf8 => {
+                let mut b = StringBuilder::new(rows.len());
+                for row in rows {
+                    match row.get(name) {
+                        Some(Value::String(v)) => b.append_value(v)?,
+                        Some(v) if !v.is_null() => b.append_value(v.encode())?,
+                        _ => {
+                            null_check(field)?;
+                            b.append_null()?;
+                        }
+                    }
+                }
+                Arc::new(b.finish())
+            }
+            other => {
+                return Err(format!("Unsupported parquet column type: {:?}", other).into());
+            }
+        };
+        columns.push(column);
+    }
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::util::cursor::SliceableCursor;
+    use tremor_pipeline::CbAction;
+    use tremor_value::literal;
+
+    fn config() -> Config {
+        Config {
+            schema: None,
+            row_group_size: 2,
+            max_events: 5,
+            max_bytes: default_max_bytes(),
+            flush_interval_ms: 1000,
+            compression: Compression::default(),
+        }
+    }
+
+    fn event(id: u64, ingest_ns: u64, value: Value<'static>) -> Event {
+        Event {
+            id: (1, 1, id).into(),
+            ingest_ns,
+            data: (value, Value::object()).into(),
+            transactional: true,
+            ..Event::default()
+        }
+    }
+
+    fn batch(id: u64, values: Vec<Value<'static>>) -> Event {
+        let values: Vec<Value<'static>> = values
+            .into_iter()
+            .map(|value| literal!({"data": {"value": value, "meta": {}}}))
+            .collect();
+        Event {
+            is_batch: true,
+            ..event(id, 0, Value::from(values))
+        }
+    }
+
+    fn read(finished: Finished) -> Result<SerializedFileReader<SliceableCursor>> {
+        Ok(SerializedFileReader::new(SliceableCursor::new(
+            finished.data?,
+        ))?)
+    }
+
+    #[test]
+    fn flush_on_count() -> Result<()> {
+        let mut batcher = ParquetBatcher::new(config())?;
+        let value = literal!({"snot": "badger", "n": 1, "f": 1.5, "ok": true, "nested": [1, 2]});
+        for id in 1..5 {
+            assert!(batcher.push(&event(id, 0, value.clone()))?.is_none());
+        }
+        let mut finished = batcher
+            .push(&event(5, 0, value))?
+            .expect("file to be flushed");
+        let events = mem::take(&mut finished.events);
+        let reader = read(finished)?;
+        let meta = reader.metadata();
+        assert_eq!(5, meta.file_metadata().num_rows());
+        // row groups of 2, 2 and 1 rows
+        assert_eq!(3, meta.num_row_groups());
+        assert_eq!(5, meta.file_metadata().schema_descr().num_columns());
+        // all events are acked together, once the file is written
+        let insight = events.insight(true).expect("no insight");
+        assert_eq!(CbAction::Ack, insight.cb);
+        for id in 1..=5 {
+            assert!(insight.id.is_tracking(&(1, 1, id).into()));
+        }
+        assert!(batcher.finish().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn flush_on_time() -> Result<()> {
+        let mut batcher = ParquetBatcher::new(config())?;
+        assert!(!batcher.is_due(0));
+        let mut e = event(1, 1_000_000, literal!({"snot": "badger"}));
+        e.transactional = false;
+        batcher.push(&e)?;
+        assert!(!batcher.is_due(2_000_000));
+        assert!(batcher.is_due(1_001_000_000));
+        let mut finished = batcher.finish().expect("file to be flushed");
+        // nothing to ack for non transactional events
+        assert!(mem::take(&mut finished.events).insight(true).is_none());
+        assert_eq!(1, read(finished)?.metadata().file_metadata().num_rows());
+        Ok(())
+    }
+
+    #[test]
+    fn explicit_schema() -> Result<()> {
+        let mut config = config();
+        config.schema = Some(vec![
+            FieldConfig {
+                name: "id".to_string(),
+                field_type: FieldType::Int64,
+                nullable: false,
+            },
+            FieldConfig {
+                name: "msg".to_string(),
+                field_type: FieldType::String,
+                nullable: true,
+            },
+        ]);
+        let mut batcher = ParquetBatcher::new(config)?;
+        batcher.push(&event(1, 0, literal!({"id": 1, "ignored": "field"})))?;
+        batcher.push(&event(2, 0, literal!({"id": 2, "msg": {"nested": true}})))?;
+        let finished = batcher.finish().expect("file to be flushed");
+        let reader = read(finished)?;
+        let meta = reader.metadata();
+        assert_eq!(2, meta.file_metadata().num_rows());
+        assert_eq!(2, meta.file_metadata().schema_descr().num_columns());
+
+        // missing non nullable field
+        assert!(batcher
+            .push(&event(3, 0, literal!({"msg": "snot"})))
+            .is_err());
+        assert!(batcher
+            .push(&event(4, 0, literal!({"id": "snot"})))
+            .is_err());
+        assert!(batcher.finish().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_bad_rows() -> Result<()> {
+        let mut batcher = ParquetBatcher::new(config())?;
+        batcher.push(&event(1, 0, literal!({"n": 1, "s": "snot"})))?;
+        // doesn't fit the schema inferred from the first event
+        assert!(batcher
+            .push(&event(2, 0, literal!({"n": "one", "s": "snot"})))
+            .is_err());
+        // later events are not affected
+        batcher.push(&event(3, 0, literal!({"n": 2, "s": "badger"})))?;
+        batcher.push(&event(4, 0, literal!({"n": 3})))?;
+        let mut finished = batcher.finish().expect("file to be flushed");
+        let insight = mem::take(&mut finished.events)
+            .insight(true)
+            .expect("no insight");
+        assert!(!insight.id.is_tracking(&(1, 1, 2).into()));
+        assert_eq!(3, read(finished)?.metadata().file_metadata().num_rows());
+        Ok(())
+    }
+
+    #[test]
+    fn batches() -> Result<()> {
+        let mut batcher = ParquetBatcher::new(config())?;
+        // the last value doesn't fit, none of the values are buffered
+        let e = batch(
+            1,
+            vec![
+                literal!({"n": 1}),
+                literal!({"n": 2}),
+                literal!({"n": "three"}),
+            ],
+        );
+        assert!(batcher.push(&e).is_err());
+        assert!(batcher.finish().is_none());
+
+        // values of an event never span files
+        let values = (0_u64..4).map(|n| literal!({ "n": n })).collect();
+        assert!(batcher.push(&batch(2, values))?.is_none());
+        let values = (4_u64..8).map(|n| literal!({ "n": n })).collect();
+        let finished = batcher
+            .push(&batch(3, values))?
+            .expect("file to be flushed");
+        assert_eq!(8, read(finished)?.metadata().file_metadata().num_rows());
+        Ok(())
+    }
+
+    #[test]
+    fn records_only() -> Result<()> {
+        let mut batcher = ParquetBatcher::new(config())?;
+        assert!(batcher.push(&event(1, 0, literal!([1, 2, 3]))).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Child processes shared by the `exec` onramp and offramp
+//!
+//! The output of a child is read line by line, the trailing newline is not part of
+//! the data handed to preprocessors and codecs.
+
+use crate::errors::Result;
+use async_std::io::{BufRead, BufReadExt};
+use smol::process::{Child, Command, Stdio};
+use std::collections::BTreeMap;
+use tremor_pipeline::EventOriginUri;
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct CommandConfig {
+    /// the program to run, looked up in `PATH` unless it is a path
+    pub command: String,
+    /// arguments passed to the program
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// additional environment variables for the child, it inherits the ones of tremor
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// working directory of the child, defaults to the one of tremor
+    #[serde(default)]
+    pub cwd: Option<String>,
+}
+
+impl CommandConfig {
+    /// Spawns the command with a piped stderr, stdin and stdout are only piped if asked for.
+    /// The child is killed once it is dropped.
+    pub(crate) fn spawn(&self, stdin: bool, stdout: bool) -> Result<Child> {
+        let mut command = Command::new(&self.command);
+        command
+            .args(&self.args)
+            .envs(&self.env)
+            .stdin(if stdin { Stdio::piped() } else { Stdio::null() })
+            .stdout(if stdout {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        Ok(command.spawn()?)
+    }
+
+    pub(crate) fn origin_uri(&self, uid: u64) -> EventOriginUri {
+        EventOriginUri {
+            uid,
+            scheme: "tremor-exec".to_string(),
+            host: crate::utils::hostname(),
+            port: None,
+            path: vec![self.command.clone()],
+        }
+    }
+}
+
+/// Reads the next line without its line ending, `None` once the child closed the pipe
+pub(crate) async fn read_line<R>(reader: &mut R) -> Result<Option<Vec<u8>>>
+where
+    R: BufRead + Unpin,
+{
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::io::BufReader;
+
+    #[async_std::test]
+    async fn lines() -> Result<()> {
+        let data: &[u8] = b"snot\r\nbadger\n\nlast";
+        let mut reader = BufReader::new(data);
+        assert_eq!(Some(b"snot".to_vec()), read_line(&mut reader).await?);
+        assert_eq!(Some(b"badger".to_vec()), read_line(&mut reader).await?);
+        assert_eq!(Some(vec![]), read_line(&mut reader).await?);
+        assert_eq!(Some(b"last".to_vec()), read_line(&mut reader).await?);
+        assert_eq!(None, read_line(&mut reader).await?);
+        Ok(())
+    }
+}
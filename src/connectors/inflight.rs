@@ -0,0 +1,166 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Unacknowledged messages of transactional onramps
+//!
+//! A message pulled with event id `id` creates the events `id` up to, but not
+//! including, the id the next message is pulled with. [`Inflight`] keeps these
+//! id ranges. Acks are cumulative, the source manager only passes the max id of
+//! an insight, so acking an id settles every message whose events all have an id
+//! up to it. A message is never settled before all of its events are acked, and a
+//! failed message is settled right away, so a later ack doesn't settle it again.
+
+use std::collections::BTreeMap;
+
+/// Unacknowledged messages by the range of event ids created from them
+#[derive(Debug)]
+pub(crate) struct Inflight<T> {
+    /// first event id -> (end of the id range, message)
+    messages: BTreeMap<u64, (u64, T)>,
+    /// all events up to this id were acknowledged
+    acked: Option<u64>,
+}
+
+impl<T> Default for Inflight<T> {
+    fn default() -> Self {
+        Self {
+            messages: BTreeMap::new(),
+            acked: None,
+        }
+    }
+}
+
+impl<T> Inflight<T> {
+    /// Ends the id range of the newest message, to be called with the id of every pull
+    /// as all events before it were created by then. Returns the messages whose events
+    /// are all acknowledged now, oldest first
+    pub(crate) fn close(&mut self, id: u64) -> Vec<T> {
+        if let Some((_, (end, _))) = self.messages.range_mut(..id).next_back() {
+            *end = (*end).min(id);
+        }
+        self.settle()
+    }
+
+    /// Registers the message pulled with event id `id`
+    pub(crate) fn insert(&mut self, id: u64, message: T) {
+        if let Some((_, (end, _))) = self.messages.range_mut(..id).next_back() {
+            *end = (*end).min(id);
+        }
+        self.messages.insert(id, (u64::MAX, message));
+    }
+
+    /// Removes the message pulled with event id `id`, used for messages that
+    /// created no events
+    pub(crate) fn remove(&mut self, id: u64) -> Option<T> {
+        self.messages.remove(&id).map(|(_, message)| message)
+    }
+
+    /// Acknowledges all events up to `id`, returns the messages whose events are all
+    /// acknowledged now, oldest first
+    pub(crate) fn ack(&mut self, id: u64) -> Vec<T> {
+        self.acked = Some(self.acked.map_or(id, |acked| acked.max(id)));
+        self.settle()
+    }
+
+    /// Removes the message event `id` was created from, if it is still unacknowledged
+    pub(crate) fn take(&mut self, id: u64) -> Option<T> {
+        let first_id = self
+            .messages
+            .range(..=id)
+            .next_back()
+            .filter(|(_, (end, _))| id < *end)
+            .map(|(first_id, _)| *first_id)?;
+        self.remove(first_id)
+    }
+
+    /// Forgets all messages, e.g. after a reconnect where the broker redelivers them
+    pub(crate) fn clear(&mut self) {
+        self.messages.clear();
+        self.acked = None;
+    }
+
+    /// Removes the messages whose id range ends at or before the acked id
+    fn settle(&mut self) -> Vec<T> {
+        let end = match self.acked {
+            Some(acked) => acked.saturating_add(1),
+            None => return Vec::new(),
+        };
+        // ranges don't overlap, so the settled messages are the oldest ones
+        let rest = match self.messages.iter().find(|(_, (e, _))| *e > end) {
+            Some((first_id, _)) => {
+                let first_id = *first_id;
+                self.messages.split_off(&first_id)
+            }
+            None => BTreeMap::new(),
+        };
+        std::mem::replace(&mut self.messages, rest)
+            .into_values()
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cumulative_acks() {
+        let mut inflight = Inflight::default();
+        inflight.insert(1, "a");
+        // events 2, 3 and 4 are created from "b"
+        inflight.insert(2, "b");
+        inflight.insert(5, "c");
+        // event 4 of "b" is still in flight
+        assert_eq!(vec!["a"], inflight.ack(3));
+        assert_eq!(vec!["b"], inflight.ack(4));
+        // the range of "c" is open until the next pull
+        assert!(inflight.ack(5).is_empty());
+        assert_eq!(vec!["c"], inflight.close(6));
+        assert!(inflight.is_empty());
+    }
+
+    #[test]
+    fn failed_messages() {
+        let mut inflight = Inflight::default();
+        inflight.insert(1, "a");
+        inflight.insert(2, "b");
+        inflight.insert(5, "c");
+        assert!(inflight.close(7).is_empty());
+        // event 3 was created from "b"
+        assert_eq!(Some("b"), inflight.take(3));
+        assert_eq!(None, inflight.take(4));
+        // a later ack doesn't settle "b" again
+        assert_eq!(vec!["a", "c"], inflight.ack(6));
+        assert_eq!(None, inflight.take(0));
+        assert!(inflight.is_empty());
+    }
+
+    #[test]
+    fn empty_messages() {
+        let mut inflight = Inflight::default();
+        inflight.insert(1, "a");
+        // "b" created no event, "c" is pulled with the same id
+        inflight.insert(2, "b");
+        assert_eq!(Some("b"), inflight.remove(2));
+        inflight.insert(2, "c");
+        assert_eq!(vec!["a"], inflight.ack(2));
+        assert_eq!(vec!["c"], inflight.close(3));
+    }
+}
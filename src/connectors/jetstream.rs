@@ -0,0 +1,446 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NATS `JetStream` support shared by the `nats` onramp and offramp
+//!
+//! `JetStream` is driven entirely through request/reply on core NATS: streams and consumers
+//! are managed via JSON requests to `$JS.API.*` subjects, published messages are
+//! acknowledged by the stream with a `PubAck` reply and consumed messages carry a
+//! `$JS.ACK.*` reply subject to acknowledge them on.
+
+use crate::errors::{Error, Result};
+use async_nats::Connection;
+use async_std::future::timeout;
+use std::time::Duration;
+use tremor_value::prelude::*;
+
+/// timeout for `JetStream` API requests
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Storage {
+    File,
+    Memory,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::File
+    }
+}
+
+impl Storage {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::File => "file",
+            Self::Memory => "memory",
+        }
+    }
+}
+
+/// The stream messages are published to or consumed from
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct StreamConfig {
+    /// name of the stream
+    pub stream: String,
+    /// create the stream, capturing the configured subject, if it doesn't exist,
+    /// defaults to `true`
+    #[serde(default = "default_true")]
+    pub create_stream: bool,
+    /// storage of a created stream, defaults to `file`
+    #[serde(default)]
+    pub storage: Storage,
+    /// maximum age of messages in a created stream in seconds, unlimited if not set
+    #[serde(default)]
+    pub max_age_s: Option<u64>,
+    /// window in seconds in which messages with the same `Nats-Msg-Id` are
+    /// deduplicated, the server default of 2 minutes if not set
+    #[serde(default)]
+    pub duplicate_window_s: Option<u64>,
+    /// number of replicas of a created stream, defaults to 1
+    #[serde(default = "default_replicas")]
+    pub replicas: u64,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_replicas() -> u64 {
+    1
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DeliverPolicy {
+    All,
+    Last,
+    New,
+}
+
+impl Default for DeliverPolicy {
+    fn default() -> Self {
+        Self::All
+    }
+}
+
+impl DeliverPolicy {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Last => "last",
+            Self::New => "new",
+        }
+    }
+}
+
+/// How messages are delivered to a durable consumer
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Delivery {
+    /// messages are fetched in batches of the given size
+    Pull { batch: usize },
+    /// messages are pushed to the given subject, optionally load balanced across a
+    /// queue group
+    Push {
+        deliver_subject: String,
+        #[serde(default)]
+        deliver_group: Option<String>,
+    },
+}
+
+/// A durable consumer with explicit acks
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct ConsumerConfig {
+    /// name of the durable consumer
+    pub durable: String,
+    pub delivery: Delivery,
+    /// where a new consumer starts, defaults to `all`
+    #[serde(default)]
+    pub deliver_policy: DeliverPolicy,
+    /// seconds after which unacknowledged messages are redelivered, defaults to 30
+    #[serde(default = "default_ack_wait_s")]
+    pub ack_wait_s: u64,
+    /// maximum number of unacknowledged messages, defaults to 1000
+    #[serde(default = "default_max_ack_pending")]
+    pub max_ack_pending: u64,
+}
+
+fn default_ack_wait_s() -> u64 {
+    30
+}
+
+fn default_max_ack_pending() -> u64 {
+    1000
+}
+
+fn api_error(response: &Value) -> Option<(u64, String)> {
+    response.get("error").map(|e| {
+        (
+            e.get_u64("code").unwrap_or_default(),
+            e.get_str("description")
+                .unwrap_or("unknown error")
+                .to_string(),
+        )
+    })
+}
+
+/// Sends a `JetStream` API request, returning the (static) response or the API error
+async fn request(nc: &Connection, subject: &str, body: &Value<'_>) -> Result<Value<'static>> {
+    let msg = timeout(API_TIMEOUT, nc.request(subject, body.encode()))
+        .await
+        .map_err(|_| Error::from(format!("JetStream request to {} timed out", subject)))??;
+    let mut data = msg.data;
+    let response = tremor_value::parse_to_value(&mut data)?.into_static();
+    if let Some((code, description)) = api_error(&response) {
+        Err(format!("JetStream error {} on {}: {}", code, subject, description).into())
+    } else {
+        Ok(response)
+    }
+}
+
+/// Returns `Ok(false)` if the requested stream or consumer info does not exist
+async fn exists(nc: &Connection, subject: &str) -> Result<bool> {
+    let msg = timeout(API_TIMEOUT, nc.request(subject, b""))
+        .await
+        .map_err(|_| Error::from(format!("JetStream request to {} timed out", subject)))??;
+    let mut data = msg.data;
+    let response = tremor_value::parse_to_value(&mut data)?;
+    match api_error(&response) {
+        None => Ok(true),
+        Some((404, _)) => Ok(false),
+        Some((code, description)) => {
+            Err(format!("JetStream error {} on {}: {}", code, subject, description).into())
+        }
+    }
+}
+
+fn stream_create_request(config: &StreamConfig, subject: &str) -> Value<'static> {
+    literal!({
+        "name": config.stream.clone(),
+        "subjects": [subject.to_string()],
+        "retention": "limits",
+        "storage": config.storage.as_str(),
+        "max_consumers": -1,
+        "max_msgs": -1,
+        "max_bytes": -1,
+        "max_msg_size": -1,
+        "max_age": config.max_age_s.unwrap_or_default() * NS_PER_SEC,
+        "duplicate_window": config.duplicate_window_s.unwrap_or(120) * NS_PER_SEC,
+        "discard": "old",
+        "num_replicas": config.replicas
+    })
+}
+
+fn consumer_create_request(
+    stream: &str,
+    config: &ConsumerConfig,
+    filter_subject: &str,
+) -> Value<'static> {
+    let mut consumer = literal!({
+        "durable_name": config.durable.clone(),
+        "deliver_policy": config.deliver_policy.as_str(),
+        "ack_policy": "explicit",
+        "ack_wait": config.ack_wait_s * NS_PER_SEC,
+        "max_deliver": -1,
+        "filter_subject": filter_subject.to_string(),
+        "replay_policy": "instant",
+        "max_ack_pending": config.max_ack_pending
+    });
+    if let Delivery::Push {
+        deliver_subject,
+        deliver_group,
+    } = &config.delivery
+    {
+        consumer.try_insert("deliver_subject", deliver_subject.clone());
+        if let Some(group) = deliver_group {
+            consumer.try_insert("deliver_group", group.clone());
+        }
+    }
+    literal!({
+        "stream_name": stream.to_string(),
+        "config": consumer
+    })
+}
+
+/// Ensures the stream exists, creating it for `subject` if configured to
+pub(crate) async fn ensure_stream(
+    nc: &Connection,
+    config: &StreamConfig,
+    subject: &str,
+) -> Result<()> {
+    if exists(nc, &format!("$JS.API.STREAM.INFO.{}", config.stream)).await? {
+        Ok(())
+    } else if config.create_stream {
+        info!("[JetStream] Creating stream {}", config.stream);
+        request(
+            nc,
+            &format!("$JS.API.STREAM.CREATE.{}", config.stream),
+            &stream_create_request(config, subject),
+        )
+        .await
+        .map(|_| ())
+    } else {
+        Err(format!("JetStream stream {} does not exist", config.stream).into())
+    }
+}
+
+/// Ensures the durable consumer exists on the stream, creating it if needed
+pub(crate) async fn ensure_consumer(
+    nc: &Connection,
+    stream: &str,
+    config: &ConsumerConfig,
+    filter_subject: &str,
+) -> Result<()> {
+    let info = format!("$JS.API.CONSUMER.INFO.{}.{}", stream, config.durable);
+    if !exists(nc, &info).await? {
+        info!(
+            "[JetStream] Creating durable consumer {} on stream {}",
+            config.durable, stream
+        );
+        request(
+            nc,
+            &format!(
+                "$JS.API.CONSUMER.DURABLE.CREATE.{}.{}",
+                stream, config.durable
+            ),
+            &consumer_create_request(stream, config, filter_subject),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Requests the next batch of messages of a pull consumer to be delivered to `inbox`,
+/// the request expires after `expires`
+pub(crate) async fn pull(
+    nc: &Connection,
+    stream: &str,
+    durable: &str,
+    inbox: &str,
+    batch: usize,
+    expires: Duration,
+) -> Result<()> {
+    let body = literal!({
+        "batch": batch,
+        "expires": u64::try_from(expires.as_nanos()).unwrap_or(u64::MAX)
+    });
+    nc.publish_request(
+        &format!("$JS.API.CONSUMER.MSG.NEXT.{}.{}", stream, durable),
+        inbox,
+        body.encode(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Metadata of a consumed message, taken from its `$JS.ACK.*` reply subject
+#[derive(Debug, PartialEq)]
+pub(crate) struct AckInfo {
+    pub stream: String,
+    pub consumer: String,
+    pub delivered: u64,
+    pub stream_seq: u64,
+    pub consumer_seq: u64,
+    pub timestamp: u64,
+    pub pending: u64,
+}
+
+impl AckInfo {
+    /// Parses `$JS.ACK.<stream>.<consumer>.<delivered>.<sseq>.<cseq>.<ts>.<pending>`,
+    /// also with the domain and account hash tokens of newer servers
+    pub(crate) fn parse(reply: &str) -> Option<Self> {
+        let tokens: Vec<&str> = reply.split('.').collect();
+        let offset = match tokens.as_slice() {
+            ["$JS", "ACK", rest @ ..] if rest.len() == 7 => 2,
+            ["$JS", "ACK", rest @ ..] if rest.len() >= 9 => 4,
+            _ => return None,
+        };
+        let number = |i: usize| tokens.get(offset + i).and_then(|t| t.parse::<u64>().ok());
+        Some(Self {
+            stream: tokens.get(offset).map(|t| (*t).to_string())?,
+            consumer: tokens.get(offset + 1).map(|t| (*t).to_string())?,
+            delivered: number(2)?,
+            stream_seq: number(3)?,
+            consumer_seq: number(4)?,
+            timestamp: number(5)?,
+            pending: number(6)?,
+        })
+    }
+
+    pub(crate) fn to_value(&self) -> Value<'static> {
+        literal!({
+            "stream": self.stream.clone(),
+            "consumer": self.consumer.clone(),
+            "delivered": self.delivered,
+            "stream_seq": self.stream_seq,
+            "consumer_seq": self.consumer_seq,
+            "timestamp": self.timestamp,
+            "pending": self.pending
+        })
+    }
+}
+
+/// Parses the `PubAck` of a published message, returning the stream sequence and whether
+/// the message was a duplicate
+pub(crate) fn pub_ack(mut data: Vec<u8>) -> Result<(u64, bool)> {
+    let response = tremor_value::parse_to_value(&mut data)?;
+    if let Some((code, description)) = api_error(&response) {
+        Err(format!("JetStream publish failed with {}: {}", code, description).into())
+    } else if let Some(seq) = response.get_u64("seq") {
+        Ok((seq, response.get_bool("duplicate").unwrap_or_default()))
+    } else {
+        Err("Invalid JetStream PubAck".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ack_info() {
+        let info = AckInfo {
+            stream: "ORDERS".to_string(),
+            consumer: "tremor".to_string(),
+            delivered: 1,
+            stream_seq: 42,
+            consumer_seq: 7,
+            timestamp: 1_634_567_890_000_000_000,
+            pending: 3,
+        };
+        assert_eq!(
+            Some(&info),
+            AckInfo::parse("$JS.ACK.ORDERS.tremor.1.42.7.1634567890000000000.3").as_ref()
+        );
+        assert_eq!(
+            Some(info),
+            AckInfo::parse("$JS.ACK.hub.ACCHASH.ORDERS.tremor.1.42.7.1634567890000000000.3.rand")
+        );
+        assert_eq!(None, AckInfo::parse("_INBOX.snot"));
+        assert_eq!(None, AckInfo::parse("$JS.ACK.ORDERS.tremor.one.42.7.0.3"));
+    }
+
+    #[test]
+    fn pub_acks() -> Result<()> {
+        assert_eq!(
+            (5, false),
+            pub_ack(br#"{"stream":"ORDERS","seq":5}"#.to_vec())?
+        );
+        assert_eq!(
+            (5, true),
+            pub_ack(br#"{"stream":"ORDERS","seq":5,"duplicate":true}"#.to_vec())?
+        );
+        assert!(
+            pub_ack(br#"{"error":{"code":503,"description":"no responders"}}"#.to_vec()).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn create_requests() {
+        let stream = StreamConfig {
+            stream: "ORDERS".to_string(),
+            create_stream: true,
+            storage: Storage::Memory,
+            max_age_s: None,
+            duplicate_window_s: Some(60),
+            replicas: 1,
+        };
+        let req = stream_create_request(&stream, "orders.>");
+        assert_eq!(req["subjects"][0], "orders.>");
+        assert_eq!(req["storage"], "memory");
+        assert_eq!(req["duplicate_window"], 60 * NS_PER_SEC);
+
+        let consumer = ConsumerConfig {
+            durable: "tremor".to_string(),
+            delivery: Delivery::Push {
+                deliver_subject: "deliver.orders".to_string(),
+                deliver_group: None,
+            },
+            deliver_policy: DeliverPolicy::New,
+            ack_wait_s: 30,
+            max_ack_pending: 10,
+        };
+        let req = consumer_create_request("ORDERS", &consumer, "orders.>");
+        assert_eq!(req["stream_name"], "ORDERS");
+        assert_eq!(req["config"]["ack_policy"], "explicit");
+        assert_eq!(req["config"]["deliver_policy"], "new");
+        assert_eq!(req["config"]["deliver_subject"], "deliver.orders");
+        assert_eq!(req["config"].get("deliver_group"), None);
+    }
+}
@@ -0,0 +1,578 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! MQTT client shared by the `mqtt` onramp and offramp
+//!
+//! A connection runs two tasks: one writing packets handed to it, sending pings to keep the
+//! connection alive, and one reading and decoding packets from the broker. The parts of the
+//! QoS 2 flows that need no decision on our side (`PUBREL` and `PUBCOMP`) are answered by the
+//! reading task.
+//!
+//! The client is our own as the maintained MQTT crates are built on tokio, while the onramps
+//! and offramps run on async-std and need control over when `PUBACK`s are sent.
+
+mod packet;
+
+pub(crate) use packet::{is_failure, Connect, Packet, Properties, Property, Publish, QoS, Version};
+
+use crate::connectors::tls::{wrap, Io};
+use crate::errors::{Error, Result};
+use crate::sink::tcp::TLSConfig;
+use crate::utils::hostname;
+use async_channel::{bounded, Receiver, Sender, TryRecvError};
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use async_std::task;
+use either::Either;
+use futures::io::{ReadHalf, WriteHalf};
+use futures::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use tremor_common::time::nanotime;
+use tremor_value::prelude::*;
+use tremor_value::{literal, Value};
+
+/// How long to wait for the broker to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct ConnectionConfig {
+    /// Broker host
+    pub host: String,
+    /// Broker port, defaults to 1883
+    #[serde(default = "dflt_port")]
+    pub port: u16,
+    /// Protocol version, `v3` for 3.1.1 (default) or `v5`
+    #[serde(default)]
+    pub version: Version,
+    /// Client identifier, a random one is generated if not set. Needs to be set for persistent
+    /// sessions.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Keep alive interval in seconds, defaults to 60, 0 disables keep alive
+    #[serde(default = "dflt_keep_alive")]
+    pub keep_alive: u16,
+    /// Start a new session on connect (default), otherwise the broker keeps subscriptions and
+    /// unacknowledged messages of a client between connections
+    #[serde(default = "dflt_clean_session")]
+    pub clean_session: bool,
+    /// TLS settings, either `true` to verify the broker against the default CA certificates,
+    /// or a `cafile` and `domain` to verify against
+    #[serde(with = "either::serde_untagged_optional", default = "Default::default")]
+    pub tls: Option<Either<TLSConfig, bool>>,
+}
+
+fn dflt_port() -> u16 {
+    1883
+}
+
+fn dflt_keep_alive() -> u16 {
+    60
+}
+
+fn dflt_clean_session() -> bool {
+    true
+}
+
+impl ConnectionConfig {
+    fn client_id(&self) -> String {
+        self.client_id
+            .clone()
+            .unwrap_or_else(|| format!("tremor-{}-{:08x}", hostname(), rand::random::<u32>()))
+    }
+}
+
+/// Validates a topic filter, `+` matches exactly one level and `#` all remaining levels
+pub(crate) fn validate_filter(filter: &str) -> Result<()> {
+    let levels: Vec<&str> = filter.split('/').collect();
+    let last = levels.len() - 1;
+    for (i, level) in levels.iter().enumerate() {
+        let valid = match *level {
+            "+" => true,
+            "#" => i == last,
+            level => !level.contains('+') && !level.contains('#'),
+        };
+        if !valid || filter.is_empty() {
+            return Err(format!("Invalid MQTT topic filter `{}`", filter).into());
+        }
+    }
+    Ok(())
+}
+
+/// Validates a topic to publish to, wildcards are not allowed
+pub(crate) fn validate_topic(topic: &str) -> Result<()> {
+    if topic.is_empty() || topic.contains('+') || topic.contains('#') {
+        Err(format!("Invalid MQTT topic `{}`", topic).into())
+    } else {
+        Ok(())
+    }
+}
+
+fn property_name(id: u8) -> Option<&'static str> {
+    match id {
+        packet::PAYLOAD_FORMAT_INDICATOR => Some("payload_format_indicator"),
+        packet::MESSAGE_EXPIRY_INTERVAL => Some("message_expiry_interval"),
+        packet::CONTENT_TYPE => Some("content_type"),
+        packet::RESPONSE_TOPIC => Some("response_topic"),
+        packet::CORRELATION_DATA => Some("correlation_data"),
+        packet::SUBSCRIPTION_IDENTIFIER => Some("subscription_identifier"),
+        packet::TOPIC_ALIAS => Some("topic_alias"),
+        _ => None,
+    }
+}
+
+/// Turns the properties of a publish into metadata, user properties are collected in
+/// `user_properties`
+pub(crate) fn properties_to_value(properties: &[(u8, Property)]) -> Result<Value<'static>> {
+    let mut res = Value::object();
+    let mut user = Value::object();
+    let mut has_user = false;
+    for (id, property) in properties {
+        let value: Value<'static> = match property {
+            Property::Pair(k, v) => {
+                user.insert(k.clone(), v.clone())?;
+                has_user = true;
+                continue;
+            }
+            Property::Byte(v) => Value::from(*v),
+            Property::U16(v) => Value::from(*v),
+            Property::U32(v) | Property::VarInt(v) => Value::from(*v),
+            Property::Str(v) => Value::from(v.clone()),
+            Property::Bin(v) => Value::Bytes(v.clone().into()),
+        };
+        if let Some(name) = property_name(*id) {
+            res.insert(name, value)?;
+        }
+    }
+    if has_user {
+        res.insert("user_properties", user)?;
+    }
+    Ok(res)
+}
+
+/// Turns publish properties given as metadata back into properties
+pub(crate) fn value_to_properties(value: &Value) -> Result<Properties> {
+    let mut res = Properties::new();
+    if let Some(v) = value.get_u8("payload_format_indicator") {
+        res.push((packet::PAYLOAD_FORMAT_INDICATOR, Property::Byte(v)));
+    }
+    if let Some(v) = value.get_u32("message_expiry_interval") {
+        res.push((packet::MESSAGE_EXPIRY_INTERVAL, Property::U32(v)));
+    }
+    if let Some(v) = value.get_str("content_type") {
+        res.push((packet::CONTENT_TYPE, Property::Str(v.to_string())));
+    }
+    if let Some(v) = value.get_str("response_topic") {
+        validate_topic(v)?;
+        res.push((packet::RESPONSE_TOPIC, Property::Str(v.to_string())));
+    }
+    match value.get("correlation_data") {
+        Some(Value::Bytes(v)) => {
+            res.push((packet::CORRELATION_DATA, Property::Bin(v.to_vec())));
+        }
+        Some(v) => {
+            if let Some(v) = v.as_str() {
+                res.push((
+                    packet::CORRELATION_DATA,
+                    Property::Bin(v.as_bytes().to_vec()),
+                ));
+            }
+        }
+        None => (),
+    }
+    if let Some(user) = value.get_object("user_properties") {
+        for (k, v) in user {
+            let v = v
+                .as_str()
+                .ok_or_else(|| Error::from(format!("MQTT user property `{}` is no string", k)))?;
+            res.push((
+                packet::USER_PROPERTY,
+                Property::Pair(k.to_string(), v.to_string()),
+            ));
+        }
+    }
+    Ok(res)
+}
+
+/// Metadata of a received publish
+pub(crate) fn publish_meta(publish: &Publish) -> Result<Value<'static>> {
+    let qos = publish.qos as u8;
+    let mut meta = literal!({
+        "topic": publish.topic.clone(),
+        "qos": qos,
+        "retain": publish.retain,
+        "dup": publish.dup,
+    });
+    if !publish.properties.is_empty() {
+        meta.insert("properties", properties_to_value(&publish.properties)?)?;
+    }
+    Ok(meta)
+}
+
+/// A connection to a broker
+pub(crate) struct Client {
+    pub(crate) version: Version,
+    tx: Sender<Packet>,
+    rx: Receiver<Packet>,
+    pkid: u16,
+}
+
+impl Client {
+    /// Connects to the broker and waits for it to accept the connection, returns if the broker
+    /// had a session for us
+    pub(crate) async fn connect(config: &ConnectionConfig) -> Result<(Self, bool)> {
+        let tcp = TcpStream::connect((config.host.as_str(), config.port)).await?;
+        let io = wrap(tcp, &config.host, config.tls.as_ref(), false).await?;
+        let (mut reader, mut writer) = io.split();
+        let version = config.version;
+        let connect = Packet::Connect(Connect {
+            version,
+            client_id: config.client_id(),
+            keep_alive: config.keep_alive,
+            clean_session: config.clean_session,
+            username: config.username.clone(),
+            password: config.password.clone(),
+        });
+        writer.write_all(&connect.encode(version)?).await?;
+
+        let mut buf = Vec::with_capacity(4096);
+        let session_present = timeout(CONNECT_TIMEOUT, async {
+            match read_packet(&mut reader, &mut buf, version).await? {
+                Packet::ConnAck {
+                    code: 0,
+                    session_present,
+                } => Ok(session_present),
+                Packet::ConnAck { code, .. } => Err(Error::from(format!(
+                    "MQTT broker refused the connection with reason code 0x{:02x}",
+                    code
+                ))),
+                other => Err(Error::from(format!(
+                    "Expected CONNACK from MQTT broker, got {:?}",
+                    other
+                ))),
+            }
+        })
+        .await
+        .map_err(|_| Error::from("MQTT broker didn't accept the connection in time"))??;
+
+        let (tx, writer_rx) = bounded(crate::QSIZE);
+        let (reader_tx, rx) = bounded(crate::QSIZE);
+        let keep_alive = Duration::from_secs(u64::from(config.keep_alive));
+        let keep_alive = if keep_alive.as_secs() > 0 {
+            Some(keep_alive)
+        } else {
+            None
+        };
+        task::spawn(write_loop(writer, writer_rx, version, keep_alive));
+        task::spawn(read_loop(
+            reader,
+            buf,
+            reader_tx,
+            tx.clone(),
+            version,
+            // the broker answers pings, so we should hear from it at least once per interval
+            keep_alive.map(|k| k + k / 2),
+        ));
+        Ok((
+            Self {
+                version,
+                tx,
+                rx,
+                pkid: 0,
+            },
+            session_present,
+        ))
+    }
+
+    /// The next packet identifier to use
+    pub(crate) fn next_pkid(&mut self) -> u16 {
+        self.pkid = self.pkid.checked_add(1).unwrap_or(1);
+        self.pkid
+    }
+
+    /// Hands a packet to the connection
+    pub(crate) async fn send(&self, packet: Packet) -> Result<()> {
+        self.tx
+            .send(packet)
+            .await
+            .map_err(|_| Error::from("MQTT connection closed"))
+    }
+
+    /// Subscribes to `filters`, the `SUBACK` is received like any other packet
+    pub(crate) async fn subscribe(&mut self, filters: &[String], qos: QoS) -> Result<u16> {
+        let pkid = self.next_pkid();
+        self.send(Packet::Subscribe {
+            pkid,
+            filters: filters.iter().map(|f| (f.clone(), qos)).collect(),
+        })
+        .await?;
+        Ok(pkid)
+    }
+
+    /// Takes the next packet received from the broker, if any.
+    /// Errors if the connection is closed.
+    pub(crate) fn try_recv(&self) -> Result<Option<Packet>> {
+        match self.rx.try_recv() {
+            Ok(packet) => Ok(Some(packet)),
+            Err(TryRecvError::Empty) if !self.tx.is_closed() => Ok(None),
+            Err(_) => Err("MQTT connection closed".into()),
+        }
+    }
+}
+
+async fn read_packet<R>(reader: &mut R, buf: &mut Vec<u8>, version: Version) -> Result<Packet>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut chunk = [0_u8; 4096];
+    loop {
+        if let Some((packet, len)) = Packet::decode(buf, version)? {
+            buf.drain(..len);
+            return Ok(packet);
+        }
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Err("MQTT connection closed".into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+async fn write_loop(
+    mut writer: WriteHalf<Box<dyn Io>>,
+    rx: Receiver<Packet>,
+    version: Version,
+    keep_alive: Option<Duration>,
+) -> Result<()> {
+    loop {
+        let packet = if let Some(keep_alive) = keep_alive {
+            match timeout(keep_alive, rx.recv()).await {
+                Ok(Ok(packet)) => packet,
+                Ok(Err(_)) => break,
+                Err(_) => Packet::PingReq,
+            }
+        } else if let Ok(packet) = rx.recv().await {
+            packet
+        } else {
+            break;
+        };
+        if let Err(e) = writer.write_all(&packet.encode(version)?).await {
+            error!("[MQTT] Error writing to the broker: {}", e);
+            return Err(e.into());
+        }
+    }
+    // the client is gone, say goodbye
+    writer
+        .write_all(&Packet::Disconnect { code: 0 }.encode(version)?)
+        .await?;
+    writer.close().await?;
+    Ok(())
+}
+
+async fn read_loop(
+    mut reader: ReadHalf<Box<dyn Io>>,
+    mut buf: Vec<u8>,
+    tx: Sender<Packet>,
+    writer_tx: Sender<Packet>,
+    version: Version,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    let mut last_read = nanotime();
+    loop {
+        let packet = if let Some(idle_timeout) = idle_timeout {
+            match timeout(idle_timeout, read_packet(&mut reader, &mut buf, version)).await {
+                Ok(packet) => packet,
+                Err(_) => {
+                    error!(
+                        "[MQTT] Nothing heard from the broker for {}s, closing the connection",
+                        nanotime().saturating_sub(last_read) / 1_000_000_000
+                    );
+                    break;
+                }
+            }
+        } else {
+            read_packet(&mut reader, &mut buf, version).await
+        };
+        let packet = match packet {
+            Ok(packet) => packet,
+            Err(e) => {
+                error!("[MQTT] {}", e);
+                break;
+            }
+        };
+        last_read = nanotime();
+        let reply = match &packet {
+            Packet::PingResp => continue,
+            // the message was delivered to us, finish the QoS 2 flow
+            Packet::PubRel { pkid, .. } => Packet::PubComp {
+                pkid: *pkid,
+                code: 0,
+            },
+            // the broker has the message, release it
+            Packet::PubRec { pkid, code } if !is_failure(*code) => Packet::PubRel {
+                pkid: *pkid,
+                code: 0,
+            },
+            Packet::Disconnect { code } => {
+                warn!(
+                    "[MQTT] Broker closed the connection with reason code 0x{:02x}",
+                    code
+                );
+                break;
+            }
+            _ => {
+                if tx.send(packet).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        if writer_tx.send(reply).await.is_err() {
+            break;
+        }
+    }
+    // closing the channel to the writer tells the client the connection is gone
+    writer_tx.close();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filters() {
+        assert!(validate_filter("a/b/c").is_ok());
+        assert!(validate_filter("a/+/c").is_ok());
+        assert!(validate_filter("+/#").is_ok());
+        assert!(validate_filter("#").is_ok());
+        assert!(validate_filter("").is_err());
+        assert!(validate_filter("a/#/c").is_err());
+        assert!(validate_filter("a/b+/c").is_err());
+        assert!(validate_topic("a/b").is_ok());
+        assert!(validate_topic("a/+").is_err());
+    }
+
+    #[test]
+    fn properties() -> Result<()> {
+        let meta = literal!({
+            "content_type": "application/json",
+            "message_expiry_interval": 60,
+            "correlation_data": "snot",
+            "user_properties": {
+                "device": "42"
+            }
+        });
+        let properties = value_to_properties(&meta)?;
+        assert_eq!(4, properties.len());
+        let value = properties_to_value(&properties)?;
+        assert_eq!(Some("application/json"), value.get_str("content_type"));
+        assert_eq!(Some(60), value.get_u32("message_expiry_interval"));
+        assert_eq!(
+            Some("42"),
+            value
+                .get("user_properties")
+                .and_then(|u| u.get_str("device"))
+        );
+        assert!(matches!(
+            value.get("correlation_data"),
+            Some(Value::Bytes(b)) if &b[..] == b"snot"
+        ));
+        Ok(())
+    }
+
+    /// a tiny broker that accepts one connection and answers a QoS 2 publish
+    #[async_std::test]
+    async fn qos2_flow() -> Result<()> {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let broker = task::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut buf = Vec::new();
+            let version = Version::V5;
+            let connect = read_packet(&mut stream, &mut buf, version).await?;
+            assert!(matches!(
+                connect,
+                Packet::Connect(Connect {
+                    version: Version::V5,
+                    ..
+                })
+            ));
+            let connack = Packet::ConnAck {
+                session_present: false,
+                code: 0,
+            };
+            stream.write_all(&connack.encode(version)?).await?;
+            let publish = read_packet(&mut stream, &mut buf, version).await?;
+            let pkid = if let Packet::Publish(Publish { pkid, .. }) = publish {
+                pkid
+            } else {
+                return Err(Error::from("expected publish"));
+            };
+            stream
+                .write_all(&Packet::PubRec { pkid, code: 0 }.encode(version)?)
+                .await?;
+            let pubrel = read_packet(&mut stream, &mut buf, version).await?;
+            assert_eq!(Packet::PubRel { pkid, code: 0 }, pubrel);
+            stream
+                .write_all(&Packet::PubComp { pkid, code: 0 }.encode(version)?)
+                .await?;
+            // wait for the client to go away
+            let disconnect = read_packet(&mut stream, &mut buf, version).await?;
+            assert_eq!(Packet::Disconnect { code: 0 }, disconnect);
+            Ok::<(), Error>(())
+        });
+
+        let config = ConnectionConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            version: Version::V5,
+            client_id: None,
+            username: None,
+            password: None,
+            keep_alive: 0,
+            clean_session: true,
+            tls: None,
+        };
+        let (mut client, session_present) = Client::connect(&config).await?;
+        assert!(!session_present);
+        let pkid = client.next_pkid();
+        client
+            .send(Packet::Publish(Publish {
+                topic: "snot".to_string(),
+                qos: QoS::ExactlyOnce,
+                retain: false,
+                dup: false,
+                pkid,
+                properties: Vec::new(),
+                payload: b"badger".to_vec(),
+            }))
+            .await?;
+        loop {
+            match client.try_recv()? {
+                Some(Packet::PubRec { .. }) => (),
+                Some(packet) => {
+                    assert_eq!(Packet::PubComp { pkid, code: 0 }, packet);
+                    break;
+                }
+                None => task::sleep(Duration::from_millis(10)).await,
+            }
+        }
+        drop(client);
+        broker.await
+    }
+}
@@ -0,0 +1,664 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! MQTT 3.1.1 and 5 control packets, limited to what a client sends and receives
+
+use crate::errors::{Error, Result};
+
+/// Maximum value of the variable length encoding
+const MAX_VARINT: u32 = 268_435_455;
+
+/// Protocol version
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Version {
+    /// MQTT 3.1.1
+    V3,
+    /// MQTT 5
+    V5,
+}
+
+impl Default for Version {
+    fn default() -> Self {
+        Self::V3
+    }
+}
+
+impl Version {
+    fn level(self) -> u8 {
+        match self {
+            Self::V3 => 4,
+            Self::V5 => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+    ExactlyOnce = 2,
+}
+
+impl QoS {
+    pub(crate) fn from_u8(qos: u8) -> Result<Self> {
+        match qos {
+            0 => Ok(Self::AtMostOnce),
+            1 => Ok(Self::AtLeastOnce),
+            2 => Ok(Self::ExactlyOnce),
+            other => Err(format!("Invalid MQTT QoS {}", other).into()),
+        }
+    }
+}
+
+/// An MQTT 5 property value
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Property {
+    Byte(u8),
+    U16(u16),
+    U32(u32),
+    VarInt(u32),
+    Str(String),
+    Bin(Vec<u8>),
+    Pair(String, String),
+}
+
+/// MQTT 5 properties by their identifier, in wire order
+pub(crate) type Properties = Vec<(u8, Property)>;
+
+pub(crate) const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+pub(crate) const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+pub(crate) const CONTENT_TYPE: u8 = 0x03;
+pub(crate) const RESPONSE_TOPIC: u8 = 0x08;
+pub(crate) const CORRELATION_DATA: u8 = 0x09;
+pub(crate) const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+pub(crate) const TOPIC_ALIAS: u8 = 0x23;
+pub(crate) const USER_PROPERTY: u8 = 0x26;
+
+enum Kind {
+    Byte,
+    U16,
+    U32,
+    VarInt,
+    Str,
+    Bin,
+    Pair,
+}
+
+fn property_kind(id: u8) -> Result<Kind> {
+    Ok(match id {
+        0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2A => Kind::Byte,
+        0x13 | 0x21 | 0x22 | 0x23 => Kind::U16,
+        0x02 | 0x11 | 0x18 | 0x27 => Kind::U32,
+        0x0B => Kind::VarInt,
+        0x03 | 0x08 | 0x12 | 0x15 | 0x1A | 0x1C | 0x1F => Kind::Str,
+        0x09 | 0x16 => Kind::Bin,
+        0x26 => Kind::Pair,
+        other => return Err(format!("Unknown MQTT property 0x{:02x}", other).into()),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Connect {
+    pub version: Version,
+    pub client_id: String,
+    pub keep_alive: u16,
+    pub clean_session: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Publish {
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub dup: bool,
+    /// packet identifier, only used for QoS 1 and 2
+    pub pkid: u16,
+    pub properties: Properties,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Packet {
+    Connect(Connect),
+    ConnAck {
+        session_present: bool,
+        code: u8,
+    },
+    Publish(Publish),
+    PubAck {
+        pkid: u16,
+        code: u8,
+    },
+    PubRec {
+        pkid: u16,
+        code: u8,
+    },
+    PubRel {
+        pkid: u16,
+        code: u8,
+    },
+    PubComp {
+        pkid: u16,
+        code: u8,
+    },
+    Subscribe {
+        pkid: u16,
+        filters: Vec<(String, QoS)>,
+    },
+    SubAck {
+        pkid: u16,
+        codes: Vec<u8>,
+    },
+    PingReq,
+    PingResp,
+    Disconnect {
+        code: u8,
+    },
+}
+
+/// Reason codes from `0x80` on signal failures, in both versions
+pub(crate) fn is_failure(code: u8) -> bool {
+    code >= 0x80
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_bin(buf: &mut Vec<u8>, v: &[u8]) -> Result<()> {
+    let len = u16::try_from(v.len()).map_err(|_| Error::from("MQTT string or binary too long"))?;
+    put_u16(buf, len);
+    buf.extend_from_slice(v);
+    Ok(())
+}
+
+fn put_str(buf: &mut Vec<u8>, v: &str) -> Result<()> {
+    put_bin(buf, v.as_bytes())
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u32) -> Result<()> {
+    if v > MAX_VARINT {
+        return Err("MQTT packet too large".into());
+    }
+    loop {
+        #[allow(clippy::cast_possible_truncation)] // we only take the lower 7 bit
+        let mut byte = (v % 128) as u8;
+        v /= 128;
+        if v > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if v == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn put_properties(buf: &mut Vec<u8>, properties: &[(u8, Property)]) -> Result<()> {
+    let mut props = Vec::new();
+    for (id, property) in properties {
+        props.push(*id);
+        match property {
+            Property::Byte(v) => props.push(*v),
+            Property::U16(v) => put_u16(&mut props, *v),
+            Property::U32(v) => props.extend_from_slice(&v.to_be_bytes()),
+            Property::VarInt(v) => put_varint(&mut props, *v)?,
+            Property::Str(v) => put_str(&mut props, v)?,
+            Property::Bin(v) => put_bin(&mut props, v)?,
+            Property::Pair(k, v) => {
+                put_str(&mut props, k)?;
+                put_str(&mut props, v)?;
+            }
+        }
+    }
+    put_varint(buf, len_u32(props.len())?)?;
+    buf.extend_from_slice(&props);
+    Ok(())
+}
+
+fn len_u32(len: usize) -> Result<u32> {
+    u32::try_from(len).map_err(|_| Error::from("MQTT packet too large"))
+}
+
+/// Reads from the variable header and payload of a packet
+struct Reader<'buf> {
+    buf: &'buf [u8],
+    pos: usize,
+}
+
+impl<'buf> Reader<'buf> {
+    fn take(&mut self, n: usize) -> Result<&'buf [u8]> {
+        let end = self.pos + n;
+        let res = self
+            .buf
+            .get(self.pos..end)
+            .ok_or_else(|| Error::from("Malformed MQTT packet"))?;
+        self.pos = end;
+        Ok(res)
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len().saturating_sub(self.pos)
+    }
+
+    fn rest(&mut self) -> &'buf [u8] {
+        let res = self.buf.get(self.pos..).unwrap_or_default();
+        self.pos = self.buf.len();
+        res
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn varint(&mut self) -> Result<u32> {
+        let mut v = 0_u32;
+        for i in 0..4 {
+            let byte = self.u8()?;
+            v += u32::from(byte & 0x7F) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err("Malformed MQTT variable length integer".into())
+    }
+
+    fn bin(&mut self) -> Result<Vec<u8>> {
+        let len = self.u16()?;
+        Ok(self.take(usize::from(len))?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.bin()?)?)
+    }
+
+    fn properties(&mut self) -> Result<Properties> {
+        let len = self.varint()? as usize;
+        let mut reader = Reader {
+            buf: self.take(len)?,
+            pos: 0,
+        };
+        let mut res = Vec::new();
+        while reader.remaining() > 0 {
+            let id = reader.u8()?;
+            let property = match property_kind(id)? {
+                Kind::Byte => Property::Byte(reader.u8()?),
+                Kind::U16 => Property::U16(reader.u16()?),
+                Kind::U32 => Property::U32(reader.u32()?),
+                Kind::VarInt => Property::VarInt(reader.varint()?),
+                Kind::Str => Property::Str(reader.string()?),
+                Kind::Bin => Property::Bin(reader.bin()?),
+                Kind::Pair => Property::Pair(reader.string()?, reader.string()?),
+            };
+            res.push((id, property));
+        }
+        Ok(res)
+    }
+}
+
+impl Packet {
+    /// Encodes the packet, including the fixed header
+    pub(crate) fn encode(&self, version: Version) -> Result<Vec<u8>> {
+        let v5 = version == Version::V5;
+        let mut body = Vec::new();
+        let header = match self {
+            Self::Connect(c) => {
+                put_str(&mut body, "MQTT")?;
+                body.push(c.version.level());
+                let mut flags = 0;
+                if c.username.is_some() {
+                    flags |= 0x80;
+                }
+                if c.password.is_some() {
+                    flags |= 0x40;
+                }
+                if c.clean_session {
+                    flags |= 0x02;
+                }
+                body.push(flags);
+                put_u16(&mut body, c.keep_alive);
+                if v5 {
+                    put_properties(&mut body, &[])?;
+                }
+                put_str(&mut body, &c.client_id)?;
+                if let Some(username) = &c.username {
+                    put_str(&mut body, username)?;
+                }
+                if let Some(password) = &c.password {
+                    put_str(&mut body, password)?;
+                }
+                0x10
+            }
+            Self::ConnAck {
+                session_present,
+                code,
+            } => {
+                body.push(u8::from(*session_present));
+                body.push(*code);
+                if v5 {
+                    put_properties(&mut body, &[])?;
+                }
+                0x20
+            }
+            Self::Publish(p) => {
+                put_str(&mut body, &p.topic)?;
+                if p.qos != QoS::AtMostOnce {
+                    put_u16(&mut body, p.pkid);
+                }
+                if v5 {
+                    put_properties(&mut body, &p.properties)?;
+                }
+                body.extend_from_slice(&p.payload);
+                0x30 | (u8::from(p.dup) << 3) | ((p.qos as u8) << 1) | u8::from(p.retain)
+            }
+            Self::PubAck { pkid, code } => Self::encode_ack(&mut body, *pkid, *code, v5, 0x40),
+            Self::PubRec { pkid, code } => Self::encode_ack(&mut body, *pkid, *code, v5, 0x50),
+            Self::PubRel { pkid, code } => Self::encode_ack(&mut body, *pkid, *code, v5, 0x62),
+            Self::PubComp { pkid, code } => Self::encode_ack(&mut body, *pkid, *code, v5, 0x70),
+            Self::Subscribe { pkid, filters } => {
+                put_u16(&mut body, *pkid);
+                if v5 {
+                    put_properties(&mut body, &[])?;
+                }
+                for (filter, qos) in filters {
+                    put_str(&mut body, filter)?;
+                    body.push(*qos as u8);
+                }
+                0x82
+            }
+            Self::SubAck { pkid, codes } => {
+                put_u16(&mut body, *pkid);
+                if v5 {
+                    put_properties(&mut body, &[])?;
+                }
+                body.extend_from_slice(codes);
+                0x90
+            }
+            Self::PingReq => 0xC0,
+            Self::PingResp => 0xD0,
+            Self::Disconnect { code } => {
+                if v5 && *code != 0 {
+                    body.push(*code);
+                }
+                0xE0
+            }
+        };
+        let mut res = Vec::with_capacity(body.len() + 5);
+        res.push(header);
+        put_varint(&mut res, len_u32(body.len())?)?;
+        res.append(&mut body);
+        Ok(res)
+    }
+
+    fn encode_ack(body: &mut Vec<u8>, pkid: u16, code: u8, v5: bool, header: u8) -> u8 {
+        put_u16(body, pkid);
+        // the reason code can be left out if it is success
+        if v5 && code != 0 {
+            body.push(code);
+        }
+        header
+    }
+
+    /// Decodes the first packet in `buf`, returns the packet and the number of bytes it used,
+    /// or `None` if `buf` doesn't contain a full packet yet
+    pub(crate) fn decode(buf: &[u8], version: Version) -> Result<Option<(Self, usize)>> {
+        let v5 = version == Version::V5;
+        let header = if let Some(header) = buf.first() {
+            *header
+        } else {
+            return Ok(None);
+        };
+        let mut len = 0_usize;
+        let mut header_len = 1;
+        loop {
+            let byte = if let Some(byte) = buf.get(header_len) {
+                *byte
+            } else {
+                return Ok(None);
+            };
+            len += usize::from(byte & 0x7F) << (7 * (header_len - 1));
+            header_len += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if header_len > 4 {
+                return Err("Malformed MQTT remaining length".into());
+            }
+        }
+        let total = header_len + len;
+        let mut r = if let Some(body) = buf.get(header_len..total) {
+            Reader { buf: body, pos: 0 }
+        } else {
+            return Ok(None);
+        };
+        let packet = match header >> 4 {
+            1 => {
+                if r.string()? != "MQTT" {
+                    return Err("Invalid MQTT protocol name".into());
+                }
+                let version = match r.u8()? {
+                    4 => Version::V3,
+                    5 => Version::V5,
+                    other => return Err(format!("Unsupported MQTT version {}", other).into()),
+                };
+                let flags = r.u8()?;
+                let keep_alive = r.u16()?;
+                if version == Version::V5 {
+                    r.properties()?;
+                }
+                let client_id = r.string()?;
+                let username = if flags & 0x80 == 0 {
+                    None
+                } else {
+                    Some(r.string()?)
+                };
+                let password = if flags & 0x40 == 0 {
+                    None
+                } else {
+                    Some(r.string()?)
+                };
+                Self::Connect(Connect {
+                    version,
+                    client_id,
+                    keep_alive,
+                    clean_session: flags & 0x02 != 0,
+                    username,
+                    password,
+                })
+            }
+            2 => {
+                let session_present = r.u8()? & 0x01 != 0;
+                let code = r.u8()?;
+                if v5 && r.remaining() > 0 {
+                    r.properties()?;
+                }
+                Self::ConnAck {
+                    session_present,
+                    code,
+                }
+            }
+            3 => {
+                let qos = QoS::from_u8((header >> 1) & 0x03)?;
+                let topic = r.string()?;
+                let pkid = if qos == QoS::AtMostOnce { 0 } else { r.u16()? };
+                let properties = if v5 { r.properties()? } else { Vec::new() };
+                Self::Publish(Publish {
+                    topic,
+                    qos,
+                    retain: header & 0x01 != 0,
+                    dup: header & 0x08 != 0,
+                    pkid,
+                    properties,
+                    payload: r.rest().to_vec(),
+                })
+            }
+            kind @ 4..=7 => {
+                let pkid = r.u16()?;
+                let code = if v5 && r.remaining() > 0 { r.u8()? } else { 0 };
+                match kind {
+                    4 => Self::PubAck { pkid, code },
+                    5 => Self::PubRec { pkid, code },
+                    6 => Self::PubRel { pkid, code },
+                    _ => Self::PubComp { pkid, code },
+                }
+            }
+            8 => {
+                let pkid = r.u16()?;
+                if v5 {
+                    r.properties()?;
+                }
+                let mut filters = Vec::new();
+                while r.remaining() > 0 {
+                    let filter = r.string()?;
+                    filters.push((filter, QoS::from_u8(r.u8()? & 0x03)?));
+                }
+                Self::Subscribe { pkid, filters }
+            }
+            9 => {
+                let pkid = r.u16()?;
+                if v5 {
+                    r.properties()?;
+                }
+                Self::SubAck {
+                    pkid,
+                    codes: r.rest().to_vec(),
+                }
+            }
+            12 => Self::PingReq,
+            13 => Self::PingResp,
+            14 => Self::Disconnect {
+                code: if v5 && r.remaining() > 0 { r.u8()? } else { 0 },
+            },
+            other => return Err(format!("Unsupported MQTT packet type {}", other).into()),
+        };
+        Ok(Some((packet, total)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(packet: &Packet, version: Version) -> Result<()> {
+        let mut encoded = packet.encode(version)?;
+        // incomplete packets need more data
+        assert_eq!(
+            None,
+            Packet::decode(&encoded[..encoded.len() - 1], version)?
+        );
+        encoded.extend_from_slice(&[0xC0, 0x00]);
+        let (decoded, len) = Packet::decode(&encoded, version)?.ok_or("no packet")?;
+        assert_eq!(packet, &decoded);
+        assert_eq!(encoded.len() - 2, len);
+        Ok(())
+    }
+
+    #[test]
+    fn packets() -> Result<()> {
+        let publish = Publish {
+            topic: "sensors/1/temperature".to_string(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            dup: false,
+            pkid: 42,
+            properties: vec![
+                (CONTENT_TYPE, Property::Str("application/json".to_string())),
+                (
+                    USER_PROPERTY,
+                    Property::Pair("snot".to_string(), "badger".to_string()),
+                ),
+                (MESSAGE_EXPIRY_INTERVAL, Property::U32(60)),
+            ],
+            payload: vec![b'x'; 300],
+        };
+        roundtrip(&Packet::Publish(publish.clone()), Version::V5)?;
+        roundtrip(
+            &Packet::Publish(Publish {
+                properties: Vec::new(),
+                ..publish
+            }),
+            Version::V3,
+        )?;
+        for version in [Version::V3, Version::V5] {
+            roundtrip(
+                &Packet::Connect(Connect {
+                    version,
+                    client_id: "tremor".to_string(),
+                    keep_alive: 60,
+                    clean_session: true,
+                    username: Some("snot".to_string()),
+                    password: Some("badger".to_string()),
+                }),
+                version,
+            )?;
+            roundtrip(
+                &Packet::ConnAck {
+                    session_present: true,
+                    code: 0,
+                },
+                version,
+            )?;
+            roundtrip(
+                &Packet::Subscribe {
+                    pkid: 1,
+                    filters: vec![
+                        ("a/+/c".to_string(), QoS::ExactlyOnce),
+                        ("d/#".to_string(), QoS::AtMostOnce),
+                    ],
+                },
+                version,
+            )?;
+            roundtrip(
+                &Packet::SubAck {
+                    pkid: 1,
+                    codes: vec![2, 0x80],
+                },
+                version,
+            )?;
+            roundtrip(&Packet::PubAck { pkid: 7, code: 0 }, version)?;
+            roundtrip(&Packet::PubRel { pkid: 7, code: 0 }, version)?;
+            roundtrip(&Packet::PingResp, version)?;
+            roundtrip(&Packet::Disconnect { code: 0 }, version)?;
+        }
+        roundtrip(
+            &Packet::PubRec {
+                pkid: 7,
+                code: 0x97,
+            },
+            Version::V5,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(Packet::decode(&[0xF0, 0x00], Version::V3).is_err());
+        assert!(Packet::decode(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01], Version::V3).is_err());
+        // topic length beyond the packet
+        assert!(Packet::decode(&[0x30, 0x02, 0x00, 0x05], Version::V3).is_err());
+    }
+}
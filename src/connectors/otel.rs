@@ -13,7 +13,9 @@
 // limitations under the License.
 
 pub(crate) mod common;
+pub(crate) mod http;
 pub(crate) mod id;
+pub(crate) mod json;
 pub(crate) mod logs;
 pub(crate) mod metrics;
 pub(crate) mod resource;
@@ -0,0 +1,251 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! OTLP over HTTP
+//!
+//! Export requests are `POST`ed to `/v1/traces`, `/v1/metrics` and `/v1/logs`, encoded as
+//! protocol buffers or OTLP/JSON and optionally gzip compressed.
+
+use super::{json, logs, metrics, trace};
+use crate::errors::{Error, Result};
+use libflate::{finish, gzip};
+use prost::Message;
+use std::io::{Read, Write};
+use tremor_otelapis::all::OpenTelemetryEvents;
+use tremor_otelapis::opentelemetry::proto::collector::{
+    logs::v1::ExportLogsServiceRequest, metrics::v1::ExportMetricsServiceRequest,
+    trace::v1::ExportTraceServiceRequest,
+};
+use tremor_value::{prelude::*, Value};
+
+pub(crate) const LOGS_PATH: &str = "/v1/logs";
+pub(crate) const METRICS_PATH: &str = "/v1/metrics";
+pub(crate) const TRACES_PATH: &str = "/v1/traces";
+
+/// Maximum size of a (decompressed) request body
+pub(crate) const MAX_BODY_LEN: usize = 32 * 1024 * 1024;
+
+const PROTOBUF: &str = "application/x-protobuf";
+const JSON: &str = "application/json";
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// OTLP/gRPC
+    Grpc,
+    /// OTLP/HTTP
+    Http,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Self::Grpc
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Protobuf,
+    Json,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Self::Protobuf
+    }
+}
+
+impl Encoding {
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Self::Protobuf => PROTOBUF,
+            Self::Json => JSON,
+        }
+    }
+
+    /// The encoding for the essence of a content type, i.e. without parameters
+    pub(crate) fn from_content_type(essence: &str) -> Option<Self> {
+        if essence.eq_ignore_ascii_case(PROTOBUF) {
+            Some(Self::Protobuf)
+        } else if essence.eq_ignore_ascii_case(JSON) {
+            Some(Self::Json)
+        } else {
+            None
+        }
+    }
+
+    /// Body of a successful export response, an empty `Export*ServiceResponse`
+    pub(crate) fn empty_response(self) -> &'static [u8] {
+        match self {
+            Self::Protobuf => b"",
+            Self::Json => b"{}",
+        }
+    }
+}
+
+/// The kinds of telemetry, each has its own path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Signal {
+    Logs,
+    Metrics,
+    Trace,
+}
+
+impl Signal {
+    pub(crate) fn path(self) -> &'static str {
+        match self {
+            Self::Logs => LOGS_PATH,
+            Self::Metrics => METRICS_PATH,
+            Self::Trace => TRACES_PATH,
+        }
+    }
+}
+
+pub(crate) fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(data.len() / 4);
+    {
+        let mut writer = finish::AutoFinishUnchecked::new(gzip::Encoder::new(&mut buffer)?);
+        writer.write_all(data)?;
+    }
+    Ok(buffer)
+}
+
+/// Decompresses `data`, `None` if it decompresses to more than `max_len` bytes
+pub(crate) fn gunzip(data: &[u8], max_len: usize) -> Result<Option<Vec<u8>>> {
+    let mut buffer = Vec::with_capacity(data.len().saturating_mul(4).min(max_len));
+    // reading one byte more than allowed tells us if there is more
+    let limit = u64::try_from(max_len).unwrap_or(u64::MAX).saturating_add(1);
+    gzip::Decoder::new(data)?
+        .take(limit)
+        .read_to_end(&mut buffer)?;
+    Ok(if buffer.len() > max_len {
+        None
+    } else {
+        Some(buffer)
+    })
+}
+
+fn decode_pb<M: Message + Default>(body: &[u8]) -> Result<M> {
+    M::decode(body).map_err(|e| Error::from(format!("Invalid OTLP protobuf message: {}", e)))
+}
+
+/// Decodes the (uncompressed) body of an export request for a signal
+pub(crate) fn decode(
+    signal: Signal,
+    encoding: Encoding,
+    body: &mut [u8],
+) -> Result<OpenTelemetryEvents> {
+    Ok(match encoding {
+        Encoding::Protobuf => match signal {
+            Signal::Logs => OpenTelemetryEvents::Logs(decode_pb(body)?),
+            Signal::Metrics => OpenTelemetryEvents::Metrics(decode_pb(body)?),
+            Signal::Trace => OpenTelemetryEvents::Trace(decode_pb(body)?),
+        },
+        Encoding::Json => {
+            let json = json::from_otlp(&tremor_value::parse_to_value(body)?)?;
+            match signal {
+                Signal::Logs => OpenTelemetryEvents::Logs(ExportLogsServiceRequest {
+                    resource_logs: logs::resource_logs_to_pb(&json)?,
+                }),
+                Signal::Metrics => OpenTelemetryEvents::Metrics(ExportMetricsServiceRequest {
+                    resource_metrics: metrics::resource_metrics_to_pb(Some(&json))?,
+                }),
+                Signal::Trace => OpenTelemetryEvents::Trace(ExportTraceServiceRequest {
+                    resource_spans: trace::resource_spans_to_pb(Some(&json))?,
+                }),
+            }
+        }
+    })
+}
+
+/// Encodes an export request, `json` is the tremor representation of `request`
+pub(crate) fn encode<M: Message>(
+    encoding: Encoding,
+    request: &M,
+    json: &Value<'_>,
+) -> Result<Vec<u8>> {
+    Ok(match encoding {
+        Encoding::Protobuf => request.encode_to_vec(),
+        Encoding::Json => json::to_otlp(json)?.encode().into_bytes(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tremor_value::literal;
+
+    #[test]
+    fn content_types() {
+        assert_eq!(
+            Some(Encoding::Protobuf),
+            Encoding::from_content_type("application/x-protobuf")
+        );
+        assert_eq!(
+            Some(Encoding::Json),
+            Encoding::from_content_type("Application/JSON")
+        );
+        assert_eq!(None, Encoding::from_content_type("text/plain"));
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let json = literal!({
+            "logs": [{
+                "instrumentation_library_logs": [{
+                    "logs": [{
+                        "name": "snot",
+                        "time_unix_nano": 42,
+                        "severity_number": 9,
+                        "severity_text": "INFO",
+                        "flags": 128,
+                        "span_id": "051581bf3cb55c13",
+                        "trace_id": "5b8aa5a2d2c872e8321cf37308d69df2",
+                        "attributes": {},
+                        "dropped_attributes_count": 0,
+                        "body": "badger"
+                    }],
+                    "schema_url": ""
+                }],
+                "schema_url": ""
+            }]
+        });
+        let request = ExportLogsServiceRequest {
+            resource_logs: logs::resource_logs_to_pb(&json)?,
+        };
+        for encoding in [Encoding::Protobuf, Encoding::Json] {
+            let body = gzip(&encode(encoding, &request, &json)?)?;
+            let mut body = gunzip(&body, MAX_BODY_LEN)?.ok_or("body too large")?;
+            match decode(Signal::Logs, encoding, &mut body)? {
+                OpenTelemetryEvents::Logs(decoded) => assert_eq!(request, decoded),
+                _ => return Err("expected logs".into()),
+            }
+        }
+        assert!(decode(Signal::Trace, Encoding::Json, &mut b"{}".to_vec()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn gunzip_limit() -> Result<()> {
+        let body = gzip(&vec![0; 1024 * 1024])?;
+        assert!(body.len() < 1024 * 1024);
+        assert!(gunzip(&body, 1024)?.is_none());
+        assert_eq!(
+            Some(1024 * 1024),
+            gunzip(&body, 1024 * 1024)?.map(|b| b.len())
+        );
+        Ok(())
+    }
+}
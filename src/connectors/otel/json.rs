@@ -0,0 +1,514 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mapping between OTLP/JSON and the tremor representation of OpenTelemetry messages
+//!
+//! OTLP/JSON is the protobuf JSON mapping with lowerCamelCase keys, hex encoded ids,
+//! integer enums, 64 bit integers that may be strings and fields with default values
+//! left out. Tremor uses snake_case keys, attribute objects instead of key value lists,
+//! plain values instead of `AnyValue`s and nests metric data under `data`.
+
+use crate::errors::Result;
+use tremor_value::{literal, prelude::*, Object, StaticNode, Value};
+
+/// Top level keys of the tremor representation and their OTLP/JSON counterparts
+const SIGNALS: [(&str, &str); 3] = [
+    ("logs", "resourceLogs"),
+    ("metrics", "resourceMetrics"),
+    ("trace", "resourceSpans"),
+];
+
+/// Kinds of metric data and their OTLP/JSON counterparts
+const METRIC_DATA: [(&str, &str); 7] = [
+    ("int-gauge", "intGauge"),
+    ("double-gauge", "gauge"),
+    ("int-sum", "intSum"),
+    ("double-sum", "sum"),
+    ("int-histogram", "intHistogram"),
+    ("double-histogram", "histogram"),
+    ("double-summary", "summary"),
+];
+
+/// Lists of `KeyValue`s
+const KEY_VALUES: [&str; 2] = ["attributes", "filtered_attributes"];
+
+/// Lists of `StringKeyValue`s
+const STRING_KEY_VALUES: [&str; 2] = ["labels", "filtered_labels"];
+
+/// Floating point fields, OTLP/JSON encodes whole numbers without a fraction
+const DOUBLES: [&str; 3] = ["as_double", "quantile", "explicit_bounds"];
+
+/// 64 bit integer fields, OTLP/JSON may encode them as strings
+const INTEGERS: [&str; 8] = [
+    "time_unix_nano",
+    "start_time_unix_nano",
+    "end_time_unix_nano",
+    "observed_time_unix_nano",
+    "count",
+    "bucket_counts",
+    "as_int",
+    "value",
+];
+
+fn to_snake_case(key: &str) -> String {
+    let mut snake = String::with_capacity(key.len() + 4);
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            snake.push('_');
+            snake.push(c.to_ascii_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+fn to_camel_case(key: &str) -> String {
+    let mut camel = String::with_capacity(key.len());
+    let mut upper = false;
+    for c in key.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            camel.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            camel.push(c);
+        }
+    }
+    camel
+}
+
+/// Fields the tremor representation requires but OTLP/JSON leaves out when they have
+/// their default value, by the key the object is found under
+fn defaults(context: &str) -> Option<Value<'static>> {
+    Some(match context {
+        "resource_logs" => literal!({ "instrumentation_library_logs": [] }),
+        "resource_metrics" => literal!({ "instrumentation_library_metrics": [] }),
+        "resource_spans" => literal!({ "instrumentation_library_spans": [] }),
+        "instrumentation_library_logs" => literal!({ "logs": [] }),
+        "instrumentation_library_metrics" => literal!({ "metrics": [] }),
+        "instrumentation_library_spans" => literal!({ "spans": [] }),
+        "resource" | "links" => literal!({ "attributes": {}, "dropped_attributes_count": 0 }),
+        "logs" => literal!({
+            "time_unix_nano": 0,
+            "severity_number": 0,
+            "flags": 0,
+            "attributes": {},
+            "dropped_attributes_count": 0
+        }),
+        "spans" => literal!({
+            "start_time_unix_nano": 0,
+            "end_time_unix_nano": 0,
+            "kind": 0,
+            "parent_span_id": "",
+            "attributes": {},
+            "dropped_attributes_count": 0,
+            "dropped_events_count": 0,
+            "dropped_links_count": 0
+        }),
+        "events" => literal!({
+            "time_unix_nano": 0,
+            "attributes": {},
+            "dropped_attributes_count": 0
+        }),
+        "status" => literal!({ "code": 0, "deprecated_code": 0 }),
+        "data_points" => literal!({
+            "start_time_unix_nano": 0,
+            "time_unix_nano": 0,
+            "attributes": {},
+            "labels": {},
+            "exemplars": [],
+            "count": 0,
+            "bucket_counts": [],
+            "explicit_bounds": [],
+            "quantile_values": []
+        }),
+        "exemplars" => literal!({
+            "time_unix_nano": 0,
+            "filtered_attributes": {},
+            "filtered_labels": {}
+        }),
+        "quantile_values" => literal!({ "quantile": 0.0, "value": 0.0 }),
+        _ if METRIC_DATA.iter().any(|(kind, _)| *kind == context) => literal!({
+            "data_points": [],
+            "is_monotonic": false,
+            "aggregation_temporality": 0
+        }),
+        _ => return None,
+    })
+}
+
+/// Converts an OTLP/JSON export request into the tremor representation
+pub(crate) fn from_otlp(json: &Value<'_>) -> Result<Value<'static>> {
+    for (tremor, otlp) in SIGNALS {
+        if let Some(resources) = json.get(otlp) {
+            let mut result = Object::with_capacity(1);
+            result.insert(
+                tremor.into(),
+                from_otlp_value(resources, &to_snake_case(otlp)),
+            );
+            return Ok(Value::from(result));
+        }
+    }
+    Err("Invalid OTLP/JSON message, expected one of `resourceLogs`, `resourceMetrics` or `resourceSpans`".into())
+}
+
+fn from_otlp_value(json: &Value<'_>, context: &str) -> Value<'static> {
+    match json {
+        Value::Array(values) => values.iter().map(|v| from_otlp_value(v, context)).collect(),
+        Value::Object(o) => {
+            let mut result = Object::with_capacity(o.len());
+            for (k, v) in o.iter() {
+                let raw: &str = k;
+                let key = to_snake_case(raw);
+                if let Some((kind, _)) = METRIC_DATA.iter().find(|(_, otlp)| *otlp == raw) {
+                    if v.is_object() {
+                        let mut points = from_otlp_value(v, kind);
+                        fix_data_points(kind, &mut points);
+                        let mut data = Object::with_capacity(1);
+                        data.insert((*kind).into(), points);
+                        result.insert("data".into(), Value::from(data));
+                        continue;
+                    }
+                }
+                let value = if KEY_VALUES.contains(&key.as_str())
+                    || STRING_KEY_VALUES.contains(&key.as_str())
+                {
+                    key_values_from_otlp(v)
+                } else if key == "body" {
+                    any_value_from_otlp(v)
+                } else if DOUBLES.contains(&key.as_str())
+                    || (key == "value" && context == "quantile_values")
+                {
+                    double_from_otlp(v)
+                } else if INTEGERS.contains(&key.as_str()) {
+                    integer_from_otlp(v)
+                } else {
+                    from_otlp_value(v, &key)
+                };
+                // number data points and exemplars keep their value under `value`
+                let key = if key == "as_double" || key == "as_int" {
+                    "value".to_string()
+                } else {
+                    key
+                };
+                result.insert(key.into(), value);
+            }
+            if let Some(Value::Object(defaults)) = defaults(context) {
+                for (k, v) in *defaults {
+                    result.entry(k).or_insert(v);
+                }
+            }
+            Value::from(result)
+        }
+        other => other.clone_static(),
+    }
+}
+
+fn integer_from_otlp(json: &Value<'_>) -> Value<'static> {
+    match json {
+        Value::String(s) => s
+            .parse::<u64>()
+            .map(Value::from)
+            .or_else(|_| s.parse::<i64>().map(Value::from))
+            .unwrap_or_else(|_| Value::from(s.to_string())),
+        Value::Array(values) => values.iter().map(integer_from_otlp).collect(),
+        other => other.clone_static(),
+    }
+}
+
+/// Fills in the fields of data points that depend on the kind of metric data
+fn fix_data_points(kind: &str, data: &mut Value<'static>) {
+    if let Some(Value::Array(points)) = data.get_mut("data_points") {
+        for point in points.iter_mut().filter_map(Value::as_object_mut) {
+            match kind {
+                "int-gauge" | "int-sum" => {
+                    point
+                        .entry("value".into())
+                        .or_insert_with(|| Value::from(0));
+                }
+                "int-histogram" => {
+                    point.entry("sum".into()).or_insert_with(|| Value::from(0));
+                }
+                "double-histogram" | "double-summary" => {
+                    let sum = point
+                        .get("sum")
+                        .map_or_else(|| Value::from(0.0), double_from_otlp);
+                    point.insert("sum".into(), sum);
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+fn double_from_otlp(json: &Value<'_>) -> Value<'static> {
+    match json {
+        Value::String(s) => s
+            .parse::<f64>()
+            .map_or_else(|_| Value::from(s.to_string()), Value::from),
+        Value::Array(values) => values.iter().map(double_from_otlp).collect(),
+        other => other
+            .cast_f64()
+            .map_or_else(|| other.clone_static(), Value::from),
+    }
+}
+
+fn key_values_from_otlp(json: &Value<'_>) -> Value<'static> {
+    json.as_array().map_or_else(
+        || from_otlp_value(json, ""),
+        |kvs| {
+            kvs.iter()
+                .filter_map(|kv| {
+                    let key = kv.get_str("key")?.to_string();
+                    let value = match kv.get("value") {
+                        Some(v) if v.is_object() => any_value_from_otlp(v),
+                        Some(v) => v.clone_static(),
+                        None => Value::null(),
+                    };
+                    Some((key, value))
+                })
+                .collect()
+        },
+    )
+}
+
+fn any_value_from_otlp(json: &Value<'_>) -> Value<'static> {
+    if let Some(v) = json.get("stringValue") {
+        v.clone_static()
+    } else if let Some(v) = json.get("boolValue") {
+        v.clone_static()
+    } else if let Some(v) = json.get("intValue") {
+        integer_from_otlp(v)
+    } else if let Some(v) = json.get("doubleValue") {
+        v.clone_static()
+    } else if let Some(v) = json.get("arrayValue") {
+        v.get_array("values")
+            .map(|values| values.iter().map(any_value_from_otlp).collect())
+            .unwrap_or_else(Value::array)
+    } else if let Some(v) = json.get("kvlistValue") {
+        v.get("values")
+            .map_or_else(Value::object, key_values_from_otlp)
+    } else if let Some(v) = json.get_str("bytesValue") {
+        base64::decode(v).map_or_else(
+            |_| Value::from(v.to_string()),
+            |bytes| Value::Bytes(bytes.into()),
+        )
+    } else {
+        Value::null()
+    }
+}
+
+/// Converts the tremor representation of an export request into OTLP/JSON
+pub(crate) fn to_otlp(json: &Value<'_>) -> Result<Value<'static>> {
+    for (tremor, otlp) in SIGNALS {
+        if let Some(resources) = json.get(tremor) {
+            let mut result = Object::with_capacity(1);
+            result.insert(otlp.into(), to_otlp_value(resources, false));
+            return Ok(Value::from(result));
+        }
+    }
+    Err("Invalid otel message, expected one of `logs`, `metrics` or `trace`".into())
+}
+
+/// `numbers` is set within metric data whose data points and exemplars hold a
+/// `NumberDataPoint` or `Exemplar` value, those are `asDouble` or `asInt` in OTLP/JSON
+fn to_otlp_value(json: &Value<'_>, numbers: bool) -> Value<'static> {
+    match json {
+        Value::Array(values) => values.iter().map(|v| to_otlp_value(v, numbers)).collect(),
+        Value::Object(o) => {
+            let mut result = Object::with_capacity(o.len());
+            for (k, v) in o.iter() {
+                let key: &str = k;
+                let (key, value) = if KEY_VALUES.contains(&key) {
+                    (to_camel_case(key), key_values_to_otlp(v, true))
+                } else if STRING_KEY_VALUES.contains(&key) {
+                    (to_camel_case(key), key_values_to_otlp(v, false))
+                } else if key == "body" {
+                    (key.to_string(), any_value_to_otlp(v))
+                } else if key == "value" && numbers {
+                    let key = if v.is_f64() { "asDouble" } else { "asInt" };
+                    (key.to_string(), v.clone_static())
+                } else if key == "data" && v.is_object() {
+                    for (kind, data) in v.as_object().into_iter().flat_map(|o| o.iter()) {
+                        let kind: &str = kind;
+                        if let Some((tremor, otlp)) =
+                            METRIC_DATA.iter().find(|(tremor, _)| *tremor == kind)
+                        {
+                            let numbers = matches!(
+                                *tremor,
+                                "double-gauge" | "double-sum" | "double-histogram"
+                            );
+                            result.insert((*otlp).into(), to_otlp_value(data, numbers));
+                        }
+                    }
+                    continue;
+                } else {
+                    (to_camel_case(key), to_otlp_value(v, numbers))
+                };
+                result.insert(key.into(), value);
+            }
+            Value::from(result)
+        }
+        other => other.clone_static(),
+    }
+}
+
+fn key_values_to_otlp(json: &Value<'_>, any: bool) -> Value<'static> {
+    json.as_object().map_or_else(Value::array, |o| {
+        o.iter()
+            .map(|(k, v)| {
+                let value = if any {
+                    any_value_to_otlp(v)
+                } else {
+                    Value::from(v.as_str().map_or_else(|| v.encode(), ToString::to_string))
+                };
+                literal!({ "key": k.to_string(), "value": value })
+            })
+            .collect()
+    })
+}
+
+fn any_value_to_otlp(json: &Value<'_>) -> Value<'static> {
+    match json {
+        Value::Static(StaticNode::Null) => Value::object(),
+        Value::Static(StaticNode::Bool(b)) => literal!({ "boolValue": *b }),
+        Value::Static(StaticNode::F64(f)) => literal!({ "doubleValue": *f }),
+        Value::Static(_) => literal!({ "intValue": json.clone_static() }),
+        Value::String(s) => literal!({ "stringValue": s.to_string() }),
+        Value::Array(values) => {
+            let values: Value = values.iter().map(any_value_to_otlp).collect();
+            literal!({ "arrayValue": { "values": values } })
+        }
+        Value::Object(_) => {
+            let values = key_values_to_otlp(json, true);
+            literal!({ "kvlistValue": { "values": values } })
+        }
+        Value::Bytes(b) => literal!({ "bytesValue": base64::encode(b) }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connectors::otel::{metrics, trace};
+
+    #[test]
+    fn case() {
+        assert_eq!("start_time_unix_nano", to_snake_case("startTimeUnixNano"));
+        assert_eq!("startTimeUnixNano", to_camel_case("start_time_unix_nano"));
+    }
+
+    #[test]
+    fn otlp_trace() -> Result<()> {
+        let otlp = literal!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{"key": "service.name", "value": {"stringValue": "snot"}}]
+                },
+                "instrumentationLibrarySpans": [{
+                    "spans": [{
+                        "traceId": "5b8aa5a2d2c872e8321cf37308d69df2",
+                        "spanId": "051581bf3cb55c13",
+                        "name": "badger",
+                        "startTimeUnixNano": "1544712660000000000",
+                        "endTimeUnixNano": "1544712661000000000",
+                        "attributes": [
+                            {"key": "http.status_code", "value": {"intValue": "500"}},
+                            {"key": "tags", "value": {"arrayValue": {"values": [{"boolValue": true}]}}}
+                        ],
+                        "status": {"code": 2}
+                    }]
+                }]
+            }]
+        });
+        let json = from_otlp(&otlp)?;
+        let spans = trace::resource_spans_to_pb(Some(&json))?;
+        let span = &spans[0].instrumentation_library_spans[0].spans[0];
+        assert_eq!("badger", span.name);
+        assert_eq!(1_544_712_661_000_000_000, span.end_time_unix_nano);
+        assert_eq!(Some(2), span.status.as_ref().map(|s| s.code));
+        assert!(span.parent_span_id.is_empty());
+        assert_eq!(
+            Some(&literal!({"http.status_code": 500, "tags": [true]})),
+            json.get("trace")
+                .and_then(|t| t.get_idx(0))
+                .and_then(|r| r.get("instrumentation_library_spans"))
+                .and_then(|ils| ils.get_idx(0))
+                .and_then(|il| il.get("spans"))
+                .and_then(|s| s.get_idx(0))
+                .and_then(|s| s.get("attributes"))
+        );
+        assert!(from_otlp(&literal!({"snot": []})).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn otlp_metrics() -> Result<()> {
+        let otlp = literal!({
+            "resourceMetrics": [{
+                "instrumentationLibraryMetrics": [{
+                    "metrics": [{
+                        "name": "requests",
+                        "sum": {
+                            "dataPoints": [{"asDouble": 4.2, "timeUnixNano": 42}],
+                            "aggregationTemporality": 2,
+                            "isMonotonic": true
+                        }
+                    }]
+                }]
+            }]
+        });
+        let json = from_otlp(&otlp)?;
+        let resource_metrics = metrics::resource_metrics_to_pb(Some(&json))?;
+        let json = metrics::resource_metrics_to_json(
+            tremor_otelapis::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest {
+                resource_metrics,
+            },
+        );
+        let back = to_otlp(&json)?;
+        let metric = back
+            .get("resourceMetrics")
+            .and_then(|r| r.get_idx(0))
+            .and_then(|r| r.get("instrumentationLibraryMetrics"))
+            .and_then(|ilm| ilm.get_idx(0))
+            .and_then(|ilm| ilm.get("metrics"))
+            .and_then(|m| m.get_idx(0));
+        let sum = metric.and_then(|m| m.get("sum"));
+        assert_eq!(Some("requests"), metric.and_then(|m| m.get_str("name")));
+        assert_eq!(Some(true), sum.and_then(|s| s.get_bool("isMonotonic")));
+        assert_eq!(
+            Some(4.2),
+            sum.and_then(|s| s.get("dataPoints"))
+                .and_then(|p| p.get_idx(0))
+                .and_then(|p| p.get_f64("asDouble"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn any_values() {
+        let json = literal!({
+            "string": "snot",
+            "int": 42,
+            "double": 4.2,
+            "array": [null, false],
+            "kvlist": {"badger": "snot"}
+        });
+        let otlp = key_values_to_otlp(&json, true);
+        let back = key_values_from_otlp(&otlp);
+        assert_eq!(json, back);
+    }
+}
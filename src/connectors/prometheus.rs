@@ -0,0 +1,242 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prometheus remote-write protocol
+//!
+//! Remote-write requests are snappy (block format, not the framed format of
+//! the `snappy` processors) compressed protocol buffer `WriteRequest`s.
+//!
+//! Each sample maps to an influx style event:
+//!
+//! ```json
+//! {
+//!   "measurement": "<__name__ label>",
+//!   "tags": { "<label>": "<value>", ... },
+//!   "fields": { "value": <sample value> },
+//!   "timestamp": <sample timestamp in nanoseconds>
+//! }
+//! ```
+//!
+//! When encoding, every field of an event becomes its own series, fields
+//! other than `value` are appended to the measurement name: `<measurement>_<field>`.
+
+use crate::errors::{Error, Result};
+use prost::Message;
+use tremor_value::prelude::*;
+use tremor_value::{literal, Value};
+
+/// The label holding the metric name
+const NAME_LABEL: &str = "__name__";
+/// The field name that maps to the plain measurement name
+const VALUE_FIELD: &str = "value";
+/// Upper bound for compressed and decompressed request bodies
+pub(crate) const MAX_BODY_LEN: usize = 32 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// milliseconds since epoch
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+/// Decompresses and decodes a remote-write request body into one event per sample,
+/// `None` if it decompresses to more than `max_len` bytes
+pub(crate) fn decode_write_request(
+    body: &[u8],
+    max_len: usize,
+) -> Result<Option<Vec<Value<'static>>>> {
+    // the decoder allocates whatever length the header claims
+    if snap::raw::decompress_len(body)? > max_len {
+        return Ok(None);
+    }
+    let decompressed = snap::raw::Decoder::new().decompress_vec(body)?;
+    let request = WriteRequest::decode(decompressed.as_slice())
+        .map_err(|e| Error::from(format!("Invalid remote-write request: {}", e)))?;
+    let mut events = Vec::new();
+    for series in request.timeseries {
+        let mut measurement = String::new();
+        let mut tags = Value::object_with_capacity(series.labels.len());
+        for label in series.labels {
+            if label.name == NAME_LABEL {
+                measurement = label.value;
+            } else {
+                tags.insert(label.name, label.value)?;
+            }
+        }
+        for sample in series.samples {
+            #[allow(clippy::cast_sign_loss)] // negative timestamps are clamped to 0
+            let timestamp = (sample.timestamp.max(0) as u64) * 1_000_000;
+            events.push(literal!({
+                "measurement": measurement.clone(),
+                "tags": tags.clone(),
+                "fields": {
+                    "value": sample.value
+                },
+                "timestamp": timestamp
+            }));
+        }
+    }
+    Ok(Some(events))
+}
+
+/// Converts an influx style event into time series, one per field
+pub(crate) fn value_to_timeseries(value: &Value) -> Result<Vec<TimeSeries>> {
+    let measurement = value
+        .get_str("measurement")
+        .ok_or("Invalid metric, expected `measurement` field")?;
+    let fields = value
+        .get_object("fields")
+        .ok_or("Invalid metric, expected `fields` record")?;
+    #[allow(clippy::cast_possible_wrap)]
+    let timestamp = value.get_u64("timestamp").map_or_else(
+        || tremor_common::time::nanotime() / 1_000_000,
+        |ts| ts / 1_000_000,
+    ) as i64;
+
+    let mut labels = Vec::new();
+    if let Some(tags) = value.get_object("tags") {
+        for (name, v) in tags.iter() {
+            let value = v.as_str().map_or_else(|| v.encode(), ToString::to_string);
+            labels.push(Label {
+                name: name.to_string(),
+                value,
+            });
+        }
+    }
+
+    let mut res = Vec::with_capacity(fields.len());
+    for (field, v) in fields.iter() {
+        let field: &str = field;
+        let sample_value = v
+            .cast_f64()
+            .or_else(|| v.as_bool().map(|b| if b { 1.0 } else { 0.0 }))
+            .ok_or_else(|| {
+                Error::from(format!("Invalid metric, field `{}` is not numeric", field))
+            })?;
+        let name = if field == VALUE_FIELD {
+            measurement.to_string()
+        } else {
+            format!("{}_{}", measurement, field)
+        };
+        let mut series_labels = Vec::with_capacity(labels.len() + 1);
+        series_labels.push(Label {
+            name: NAME_LABEL.to_string(),
+            value: name,
+        });
+        series_labels.extend(labels.iter().cloned());
+        // remote-write requires labels to be sorted by name
+        series_labels.sort_by(|a, b| a.name.cmp(&b.name));
+        res.push(TimeSeries {
+            labels: series_labels,
+            samples: vec![Sample {
+                value: sample_value,
+                timestamp,
+            }],
+        });
+    }
+    Ok(res)
+}
+
+/// Encodes and compresses time series into a remote-write request body
+pub(crate) fn encode_write_request(timeseries: Vec<TimeSeries>) -> Result<Vec<u8>> {
+    let request = WriteRequest { timeseries };
+    Ok(snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let event = literal!({
+            "measurement": "http_requests",
+            "tags": {"method": "GET", "code": "200"},
+            "fields": {"value": 42, "errors": 1.5},
+            "timestamp": 1_600_000_000_000_000_000_u64
+        });
+        let series = value_to_timeseries(&event)?;
+        assert_eq!(2, series.len());
+        let names: Vec<&str> = series[0].labels.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(vec![NAME_LABEL, "code", "method"], names);
+
+        let body = encode_write_request(series)?;
+        let mut events = decode_write_request(&body, MAX_BODY_LEN)?.ok_or("body too large")?;
+        assert_eq!(2, events.len());
+        events.sort_by(|a, b| {
+            a.get_str("measurement")
+                .unwrap_or_default()
+                .cmp(b.get_str("measurement").unwrap_or_default())
+        });
+        assert_eq!(
+            literal!({
+                "measurement": "http_requests",
+                "tags": {"method": "GET", "code": "200"},
+                "fields": {"value": 42.0},
+                "timestamp": 1_600_000_000_000_000_000_u64
+            }),
+            events[0]
+        );
+        assert_eq!(
+            Some("http_requests_errors"),
+            events[1].get_str("measurement")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(value_to_timeseries(&literal!({"fields": {"value": 1}})).is_err());
+        assert!(value_to_timeseries(&literal!({"measurement": "snot"})).is_err());
+        assert!(value_to_timeseries(
+            &literal!({"measurement": "snot", "fields": {"value": "badger"}})
+        )
+        .is_err());
+        assert!(decode_write_request(b"snot", MAX_BODY_LEN).is_err());
+    }
+
+    #[test]
+    fn decompressed_limit() -> Result<()> {
+        let body = snap::raw::Encoder::new().compress_vec(&vec![0; 1024 * 1024])?;
+        assert!(body.len() < 1024);
+        assert!(decode_write_request(&body, 1024)?.is_none());
+        // a header claiming ~4 GiB is rejected before anything is allocated
+        assert!(decode_write_request(&[0xff, 0xff, 0xff, 0xff, 0x0f], MAX_BODY_LEN)?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Redis client shared by the `redis` onramp and offramp
+//!
+//! Speaks RESP2, commands are sent as arrays of bulk strings and can be pipelined: all
+//! commands are written before the first reply is read.
+//!
+//! The client is our own as the `redis` crate pulls in its own runtime integration and
+//! connection pooling, while the onramp and offramp only need a single pipelined connection
+//! on async-std with TLS configured the same way as the other connectors.
+
+use crate::connectors::tls::{wrap, Io};
+use crate::errors::{Error, Result};
+use crate::sink::tcp::TLSConfig;
+use async_std::net::TcpStream;
+use either::Either;
+use futures::{AsyncReadExt, AsyncWriteExt};
+use tremor_value::Value;
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct ConnectionConfig {
+    /// Redis host
+    pub host: String,
+    /// Redis port, defaults to 6379
+    #[serde(default = "dflt_port")]
+    pub port: u16,
+    /// Username for ACL authentication (Redis 6+), only the password is sent if not set
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Database to select, defaults to 0
+    #[serde(default)]
+    pub db: Option<u32>,
+    /// TLS settings, either `true` to verify the server against the default CA certificates,
+    /// or a `cafile` and `domain` to verify against
+    #[serde(with = "either::serde_untagged_optional", default = "Default::default")]
+    pub tls: Option<Either<TLSConfig, bool>>,
+}
+
+fn dflt_port() -> u16 {
+    6379
+}
+
+/// A RESP2 reply
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Resp {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Resp>>),
+}
+
+/// Builds a command from its name and arguments
+pub(crate) fn cmd<I, A>(args: I) -> Vec<Vec<u8>>
+where
+    I: IntoIterator<Item = A>,
+    A: AsRef<[u8]>,
+{
+    args.into_iter().map(|a| a.as_ref().to_vec()).collect()
+}
+
+/// Encodes a command as an array of bulk strings
+pub(crate) fn encode(cmd: &[Vec<u8>], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(format!("*{}\r\n", cmd.len()).as_bytes());
+    for arg in cmd {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Reads a line starting at `pos`, returns it and the position after its `\r\n`
+fn line(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let rest = buf.get(pos..)?;
+    let end = rest.windows(2).position(|w| w == b"\r\n")?;
+    Some((&rest[..end], pos + end + 2))
+}
+
+/// Largest bulk string we accept, the default `proto-max-bulk-len` of redis
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+fn int(line: &[u8]) -> Result<i64> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::from("Invalid integer in redis reply"))
+}
+
+impl Resp {
+    /// Decodes a reply from the start of `buf`, returns it and the number of bytes it took,
+    /// or `None` if `buf` doesn't hold a complete reply yet
+    pub(crate) fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>> {
+        Self::decode_at(buf, 0)
+    }
+
+    fn decode_at(buf: &[u8], pos: usize) -> Result<Option<(Self, usize)>> {
+        let (kind, (line, next)) = match (buf.get(pos), line(buf, pos + 1)) {
+            (Some(kind), Some(line)) => (*kind, line),
+            _ => return Ok(None),
+        };
+        Ok(Some(match kind {
+            b'+' => (
+                Resp::Simple(String::from_utf8_lossy(line).to_string()),
+                next,
+            ),
+            b'-' => (Resp::Error(String::from_utf8_lossy(line).to_string()), next),
+            b':' => (Resp::Integer(int(line)?), next),
+            b'$' => {
+                let len = int(line)?;
+                if len < 0 {
+                    (Resp::Bulk(None), next)
+                } else {
+                    let end = usize::try_from(len)
+                        .ok()
+                        .filter(|len| *len <= MAX_BULK_LEN)
+                        .and_then(|len| next.checked_add(len))
+                        .ok_or_else(|| Error::from("Invalid bulk string length in redis reply"))?;
+                    if buf.len() < end + 2 {
+                        return Ok(None);
+                    }
+                    (Resp::Bulk(Some(buf[next..end].to_vec())), end + 2)
+                }
+            }
+            b'*' => {
+                let len = int(line)?;
+                if len < 0 {
+                    (Resp::Array(None), next)
+                } else {
+                    // every element takes at least 3 bytes, don't trust the length for more
+                    let capacity = usize::try_from(len)
+                        .unwrap_or(usize::MAX)
+                        .min(buf.len().saturating_sub(next) / 3);
+                    let mut items = Vec::with_capacity(capacity);
+                    let mut next = next;
+                    for _ in 0..len {
+                        match Self::decode_at(buf, next)? {
+                            Some((item, n)) => {
+                                items.push(item);
+                                next = n;
+                            }
+                            None => return Ok(None),
+                        }
+                    }
+                    (Resp::Array(Some(items)), next)
+                }
+            }
+            other => {
+                let e = format!("Invalid redis reply type `{}`", char::from(other));
+                return Err(e.into());
+            }
+        }))
+    }
+
+    /// Turns error replies into errors
+    pub(crate) fn into_result(self) -> Result<Self> {
+        match self {
+            Resp::Error(e) => Err(Error::from(format!("Redis error: {}", e))),
+            other => Ok(other),
+        }
+    }
+
+    /// The content of a simple or bulk string
+    pub(crate) fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            Resp::Simple(s) => Some(s.into_bytes()),
+            Resp::Bulk(b) => b,
+            _ => None,
+        }
+    }
+
+    /// The elements of an array
+    pub(crate) fn into_array(self) -> Option<Vec<Resp>> {
+        match self {
+            Resp::Array(a) => a,
+            _ => None,
+        }
+    }
+
+    /// The reply as a value, bulk strings become strings if they are valid UTF-8
+    pub(crate) fn into_value(self) -> Value<'static> {
+        match self {
+            Resp::Simple(s) | Resp::Error(s) => Value::from(s),
+            Resp::Integer(i) => Value::from(i),
+            Resp::Bulk(None) | Resp::Array(None) => Value::null(),
+            Resp::Bulk(Some(b)) => match String::from_utf8(b) {
+                Ok(s) => Value::from(s),
+                Err(e) => Value::Bytes(e.into_bytes().into()),
+            },
+            Resp::Array(Some(a)) => Value::from(
+                a.into_iter()
+                    .map(Resp::into_value)
+                    .collect::<Vec<Value<'static>>>(),
+            ),
+        }
+    }
+}
+
+/// A connection to a redis server
+pub(crate) struct Connection {
+    io: Box<dyn Io>,
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    /// Connects, authenticates and selects the configured database
+    pub(crate) async fn connect(config: &ConnectionConfig) -> Result<Self> {
+        let tcp = TcpStream::connect((config.host.as_str(), config.port)).await?;
+        let io = wrap(tcp, &config.host, config.tls.as_ref(), false).await?;
+        let mut conn = Self {
+            io,
+            buf: Vec::with_capacity(4096),
+        };
+        match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                conn.query(cmd(["AUTH", username.as_str(), password.as_str()]))
+                    .await?;
+            }
+            (None, Some(password)) => {
+                conn.query(cmd(["AUTH", password.as_str()])).await?;
+            }
+            (_, None) => (),
+        }
+        if let Some(db) = config.db {
+            conn.query(cmd(["SELECT".to_string(), db.to_string()]))
+                .await?;
+        }
+        Ok(conn)
+    }
+
+    /// Writes commands without waiting for their replies
+    pub(crate) async fn send(&mut self, cmds: &[Vec<Vec<u8>>]) -> Result<()> {
+        let mut out = Vec::new();
+        for cmd in cmds {
+            encode(cmd, &mut out);
+        }
+        self.io.write_all(&out).await?;
+        Ok(())
+    }
+
+    /// Reads the next reply
+    pub(crate) async fn read(&mut self) -> Result<Resp> {
+        let mut chunk = [0_u8; 4096];
+        loop {
+            if let Some((resp, len)) = Resp::decode(&self.buf)? {
+                self.buf.drain(..len);
+                return Ok(resp);
+            }
+            let n = self.io.read(&mut chunk).await?;
+            if n == 0 {
+                return Err("Redis connection closed".into());
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Sends a command and returns its reply, error replies are errors
+    pub(crate) async fn query(&mut self, cmd: Vec<Vec<u8>>) -> Result<Resp> {
+        self.send(&[cmd]).await?;
+        self.read().await?.into_result()
+    }
+
+    /// Sends all commands at once and returns their replies in order, error replies
+    /// are returned as they are
+    pub(crate) async fn pipeline(&mut self, cmds: &[Vec<Vec<u8>>]) -> Result<Vec<Resp>> {
+        self.send(cmds).await?;
+        let mut res = Vec::with_capacity(cmds.len());
+        for _ in cmds {
+            res.push(self.read().await?);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::task;
+
+    #[test]
+    fn resp() -> Result<()> {
+        let mut buf = Vec::new();
+        encode(&cmd(["SET", "snot", "badger"]), &mut buf);
+        assert_eq!(
+            b"*3\r\n$3\r\nSET\r\n$4\r\nsnot\r\n$6\r\nbadger\r\n".to_vec(),
+            buf
+        );
+
+        let reply = b"*4\r\n+OK\r\n:42\r\n$-1\r\n*2\r\n$5\r\nhello\r\n-ERR nope\r\n";
+        let expected = Resp::Array(Some(vec![
+            Resp::Simple("OK".to_string()),
+            Resp::Integer(42),
+            Resp::Bulk(None),
+            Resp::Array(Some(vec![
+                Resp::Bulk(Some(b"hello".to_vec())),
+                Resp::Error("ERR nope".to_string()),
+            ])),
+        ]));
+        assert_eq!(Some((expected.clone(), reply.len())), Resp::decode(reply)?);
+        // incomplete replies need more data
+        for i in 0..reply.len() {
+            assert_eq!(None, Resp::decode(&reply[..i])?);
+        }
+        assert!(Resp::decode(b"?what\r\n").is_err());
+        assert!(Resp::decode(b":nan\r\n").is_err());
+        // lengths of malformed replies are not trusted
+        assert!(Resp::decode(b"$9223372036854775807\r\n").is_err());
+        assert_eq!(None, Resp::decode(b"*9223372036854775807\r\n:1\r\n")?);
+
+        assert!(Resp::Error("ERR".to_string()).into_result().is_err());
+        assert_eq!(
+            Value::from(vec![
+                Value::from("OK"),
+                Value::from(42),
+                Value::null(),
+                Value::from(vec![Value::from("hello"), Value::from("ERR nope")])
+            ]),
+            expected.into_value()
+        );
+        Ok(())
+    }
+
+    async fn server(listener: TcpListener) -> Result<()> {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = vec![0_u8; 1024];
+        let mut received = Vec::new();
+        // AUTH, SELECT and two pipelined commands
+        let expected = {
+            let mut b = Vec::new();
+            encode(&cmd(["AUTH", "secret"]), &mut b);
+            encode(&cmd(["SELECT", "2"]), &mut b);
+            encode(&cmd(["INCR", "snot"]), &mut b);
+            encode(&cmd(["GET", "badger"]), &mut b);
+            b
+        };
+        let mut replies = vec![
+            b"+OK\r\n".to_vec(),
+            b"+OK\r\n".to_vec(),
+            b":1\r\n$3\r\nyay\r\n".to_vec(),
+        ]
+        .into_iter();
+        let mut answered = 0;
+        while received.len() < expected.len() {
+            let n = stream.read(&mut buf).await?;
+            received.extend_from_slice(&buf[..n]);
+            // answer every complete command we didn't answer yet
+            let mut complete = 0;
+            let mut pos = 0;
+            while let Some((_, len)) = Resp::decode(&received[pos..])? {
+                pos += len;
+                complete += 1;
+            }
+            while answered < complete.min(2) {
+                stream
+                    .write_all(&replies.next().unwrap_or_default())
+                    .await?;
+                answered += 1;
+            }
+        }
+        stream
+            .write_all(&replies.next().unwrap_or_default())
+            .await?;
+        assert_eq!(expected, received);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn pipeline() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let handle = task::spawn(server(listener));
+        let config: ConnectionConfig = serde_yaml::from_str(&format!(
+            "{{host: 127.0.0.1, port: {}, password: secret, db: 2}}",
+            port
+        ))?;
+        let mut conn = Connection::connect(&config).await?;
+        let replies = conn
+            .pipeline(&[cmd(["INCR", "snot"]), cmd(["GET", "badger"])])
+            .await?;
+        assert_eq!(
+            vec![Resp::Integer(1), Resp::Bulk(Some(b"yay".to_vec()))],
+            replies
+        );
+        handle.await
+    }
+}
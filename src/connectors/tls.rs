@@ -0,0 +1,51 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client connections that are either TLS or plain, shared by the connectors speaking their
+//! own protocol over tcp
+
+use crate::errors::Result;
+use crate::sink::tcp::{connector, TLSConfig};
+use async_std::net::TcpStream;
+use async_tls::TlsConnector;
+use either::Either;
+use futures::{AsyncRead, AsyncWrite};
+
+/// A connection, TLS or plain
+pub(crate) trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T> Io for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// Wraps `tcp`, connected to `host`, according to the `tls` setting of a connector: a
+/// `cafile` and `domain` to verify against, `true` to verify against the default CA
+/// certificates or `false` for a plain connection. `force` uses TLS even without a setting,
+/// e.g. for a `wss://` url.
+pub(crate) async fn wrap(
+    tcp: TcpStream,
+    host: &str,
+    tls: Option<&Either<TLSConfig, bool>>,
+    force: bool,
+) -> Result<Box<dyn Io>> {
+    Ok(match tls {
+        Some(Either::Left(tls)) => {
+            let c = connector(tls).await?;
+            Box::new(
+                c.connect(tls.domain.as_deref().unwrap_or(host), tcp)
+                    .await?,
+            )
+        }
+        Some(Either::Right(true)) => Box::new(TlsConnector::default().connect(host, tcp).await?),
+        _ if force => Box::new(TlsConnector::default().connect(host, tcp).await?),
+        _ => Box::new(tcp),
+    })
+}
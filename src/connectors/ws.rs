@@ -0,0 +1,136 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Websocket subprotocol negotiation and keepalive, shared by the `ws` onramp and offramp
+
+use crate::errors::{Error, Result};
+use async_std::future::timeout;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures::{AsyncRead, AsyncWrite, SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Header used to negotiate subprotocols
+pub(crate) const PROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub(crate) struct Keepalive {
+    /// Send a ping every this many milliseconds, no pings are sent if not set
+    #[serde(default)]
+    pub ping_interval_ms: Option<u64>,
+    /// Consider the connection dead if no pong arrived this many milliseconds after a ping,
+    /// defaults to 5000
+    #[serde(default = "dflt_pong_timeout_ms")]
+    pub pong_timeout_ms: u64,
+}
+
+fn dflt_pong_timeout_ms() -> u64 {
+    5000
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            ping_interval_ms: None,
+            pong_timeout_ms: dflt_pong_timeout_ms(),
+        }
+    }
+}
+
+impl Keepalive {
+    pub(crate) fn interval(&self) -> Option<Duration> {
+        self.ping_interval_ms.map(Duration::from_millis)
+    }
+
+    pub(crate) fn timeout(&self) -> Duration {
+        Duration::from_millis(self.pong_timeout_ms)
+    }
+}
+
+/// Picks the first of the subprotocols `offered` by a client that is `supported`.
+///
+/// Nothing is negotiated if no protocols are supported, it is an error if the client
+/// offered protocols but none of them is supported.
+pub(crate) fn select_protocol<'offer>(
+    offered: Option<&'offer str>,
+    supported: &[String],
+) -> Result<Option<&'offer str>> {
+    if supported.is_empty() {
+        return Ok(None);
+    }
+    match offered {
+        None => Ok(None),
+        Some(offered) => offered
+            .split(',')
+            .map(str::trim)
+            .find(|p| supported.iter().any(|s| s == p))
+            .map(Some)
+            .ok_or_else(|| {
+                Error::from(format!(
+                    "None of the websocket subprotocols `{}` is supported",
+                    offered
+                ))
+            }),
+    }
+}
+
+/// Sends a ping and waits for the pong, text and binary messages received in the
+/// meantime are added to `received`
+pub(crate) async fn ping<S>(
+    ws: &mut WebSocketStream<S>,
+    keepalive: &Keepalive,
+    received: &mut VecDeque<Message>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    ws.send(Message::Ping(Vec::new())).await?;
+    let pong = async {
+        while let Some(msg) = ws.next().await {
+            match msg? {
+                Message::Pong(_) => return Ok(()),
+                Message::Close(_) => return Err(Error::from("connection closed")),
+                msg @ (Message::Text(_) | Message::Binary(_)) => received.push_back(msg),
+                Message::Ping(_) => (),
+            }
+        }
+        Err(Error::from("connection closed"))
+    };
+    timeout(keepalive.timeout(), pong)
+        .await
+        .map_err(|_| Error::from(format!("no pong within {:?}", keepalive.timeout())))?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn protocols() -> Result<()> {
+        let supported = vec!["v2.tremor".to_string(), "v1.tremor".to_string()];
+        assert_eq!(None, select_protocol(Some("v1.tremor"), &[])?);
+        assert_eq!(None, select_protocol(None, &supported)?);
+        assert_eq!(
+            Some("v1.tremor"),
+            select_protocol(Some("v1.tremor, v2.tremor"), &supported)?
+        );
+        assert_eq!(
+            Some("v2.tremor"),
+            select_protocol(Some("mqtt,v2.tremor"), &supported)?
+        );
+        assert!(select_protocol(Some("mqtt"), &supported).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keys and framing for the encryption and signing pre- and postprocessors.
+//!
+//! Keys are identified by a key id and loaded from the environment:
+//!
+//! * `TREMOR_<KIND>_KEY_ID` - id of the key used for encrypting / signing
+//! * `TREMOR_<KIND>_KEY_<ID>` - base64 encoded key with the id `<ID>`
+//! * `TREMOR_<KIND>_KEY_FILE_<ID>` - path to a file containing the base64 encoded key with the id `<ID>`
+//!
+//! where `<KIND>` is `AES_GCM` or `HMAC`. Every frame carries the id of the key it was
+//! created with, so new keys can be rolled out while frames created with old keys can
+//! still be decrypted / verified as long as the old keys stay configured.
+//!
+//! Frames look like this:
+//!
+//! ```text
+//! aes-gcm: | key id len (u8) | key id | nonce (12 bytes) | ciphertext + tag |
+//! hmac:    | key id len (u8) | key id | hmac-sha256 (32 bytes) | payload |
+//! ```
+
+use crate::errors::{Error, Result};
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hashbrown::HashMap;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::ffi::OsString;
+
+const NONCE_LEN: usize = 12;
+const AES_KEY_LEN: usize = 32;
+const HMAC_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The kind of keys held by a keyring
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum KeyKind {
+    AesGcm,
+    Hmac,
+}
+
+impl KeyKind {
+    fn env_prefix(self) -> &'static str {
+        match self {
+            Self::AesGcm => "TREMOR_AES_GCM_KEY",
+            Self::Hmac => "TREMOR_HMAC_KEY",
+        }
+    }
+}
+
+/// A set of keys, one of which is used for encryption / signing
+pub(crate) struct Keyring {
+    active: String,
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl Keyring {
+    pub(crate) fn new(
+        kind: KeyKind,
+        active: String,
+        keys: HashMap<String, Vec<u8>>,
+    ) -> Result<Self> {
+        if active.is_empty() || active.len() > usize::from(u8::MAX) {
+            return Err(format!("Invalid key id `{}`", active).into());
+        }
+        if !keys.contains_key(&active) {
+            return Err(format!("No key with the id `{}` configured", active).into());
+        }
+        for (id, key) in &keys {
+            match kind {
+                KeyKind::AesGcm if key.len() != AES_KEY_LEN => {
+                    return Err(format!(
+                        "AES-GCM key `{}` must be {} bytes long, but is {}",
+                        id,
+                        AES_KEY_LEN,
+                        key.len()
+                    )
+                    .into());
+                }
+                KeyKind::Hmac if key.is_empty() => {
+                    return Err(format!("HMAC key `{}` is empty", id).into());
+                }
+                _ => (),
+            }
+        }
+        Ok(Self { active, keys })
+    }
+
+    /// Loads the keyring for the given kind from the environment
+    pub(crate) fn from_env(kind: KeyKind) -> Result<Self> {
+        // `vars` panics on any variable that isn't unicode, even unrelated ones
+        Self::from_vars(kind, std::env::vars_os())
+    }
+
+    /// Loads the keyring for the given kind from environment variables
+    fn from_vars(kind: KeyKind, vars: impl Iterator<Item = (OsString, OsString)>) -> Result<Self> {
+        let prefix = kind.env_prefix();
+        let id_var = format!("{}_ID", prefix);
+        let file_prefix = format!("{}_FILE_", prefix);
+        let key_prefix = format!("{}_", prefix);
+        let not_unicode =
+            |name: &str| Error::from(format!("Environment variable `{}` is not unicode", name));
+        let mut active = None;
+        let mut keys = HashMap::new();
+        for (name, value) in vars {
+            let name = match name.into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if name == id_var {
+                active = Some(value.into_string().map_err(|_| not_unicode(&name))?);
+            } else if let Some(id) = name.strip_prefix(&file_prefix) {
+                let encoded = std::fs::read_to_string(&value)?;
+                keys.insert(id.to_string(), base64::decode(encoded.trim())?);
+            } else if let Some(id) = name.strip_prefix(&key_prefix) {
+                let value = value.into_string().map_err(|_| not_unicode(&name))?;
+                keys.insert(id.to_string(), base64::decode(value.trim())?);
+            }
+        }
+        let active = active
+            .ok_or_else(|| Error::from(format!("Environment variable `{}` not set", id_var)))?;
+        Self::new(kind, active, keys)
+    }
+
+    fn key(&self, id: &str) -> Result<&[u8]> {
+        self.keys
+            .get(id)
+            .map(Vec::as_slice)
+            .ok_or_else(|| format!("Unknown key id `{}`", id).into())
+    }
+
+    /// Writes the active key id header
+    #[allow(clippy::cast_possible_truncation)] // checked in `new`
+    fn write_header(&self, capacity: usize) -> Vec<u8> {
+        let mut res = Vec::with_capacity(1 + self.active.len() + capacity);
+        res.push(self.active.len() as u8);
+        res.extend_from_slice(self.active.as_bytes());
+        res
+    }
+
+    /// Splits a frame into its key and the remaining data
+    fn read_header<'data>(&self, data: &'data [u8]) -> Result<(&[u8], &'data [u8])> {
+        let (len, rest) = data.split_first().ok_or("Empty frame")?;
+        let len = usize::from(*len);
+        if rest.len() < len {
+            return Err("Frame too short for key id".into());
+        }
+        let (id, rest) = rest.split_at(len);
+        let id = std::str::from_utf8(id)?;
+        Ok((self.key(id)?, rest))
+    }
+
+    /// Encrypts data with the active key
+    pub(crate) fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let cipher = Aes256Gcm::new(Key::from_slice(self.key(&self.active)?));
+        let mut nonce = [0_u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let encrypted = cipher
+            .encrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|e| Error::from(format!("AES-GCM encryption failed: {}", e)))?;
+        let mut res = self.write_header(NONCE_LEN + encrypted.len());
+        res.extend_from_slice(&nonce);
+        res.extend_from_slice(&encrypted);
+        Ok(res)
+    }
+
+    /// Decrypts a frame created by `encrypt`
+    pub(crate) fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (key, rest) = self.read_header(data)?;
+        if rest.len() < NONCE_LEN {
+            return Err("Frame too short for AES-GCM nonce".into());
+        }
+        let (nonce, encrypted) = rest.split_at(NONCE_LEN);
+        let cipher = Aes256Gcm::new(Key::from_slice(key));
+        cipher
+            .decrypt(Nonce::from_slice(nonce), encrypted)
+            .map_err(|e| format!("AES-GCM decryption failed: {}", e).into())
+    }
+
+    /// Prefixes data with its HMAC-SHA256 using the active key
+    pub(crate) fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut mac = HmacSha256::new_from_slice(self.key(&self.active)?)
+            .map_err(|e| Error::from(format!("Invalid HMAC key: {}", e)))?;
+        mac.update(data);
+        let mut res = self.write_header(HMAC_LEN + data.len());
+        res.extend_from_slice(&mac.finalize().into_bytes());
+        res.extend_from_slice(data);
+        Ok(res)
+    }
+
+    /// Verifies a frame created by `sign` and returns its payload
+    pub(crate) fn verify(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (key, rest) = self.read_header(data)?;
+        if rest.len() < HMAC_LEN {
+            return Err("Frame too short for HMAC".into());
+        }
+        let (tag, payload) = rest.split_at(HMAC_LEN);
+        let mut mac = HmacSha256::new_from_slice(key)
+            .map_err(|e| Error::from(format!("Invalid HMAC key: {}", e)))?;
+        mac.update(payload);
+        mac.verify_slice(tag)
+            .map_err(|_| Error::from("HMAC verification failed"))?;
+        Ok(payload.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keyring(kind: KeyKind, active: &str) -> Result<Keyring> {
+        let mut keys = HashMap::new();
+        keys.insert("old".to_string(), vec![1_u8; AES_KEY_LEN]);
+        keys.insert("new".to_string(), vec![2_u8; AES_KEY_LEN]);
+        Keyring::new(kind, active.to_string(), keys)
+    }
+
+    #[test]
+    fn encrypt_decrypt_with_rotation() -> Result<()> {
+        let old = keyring(KeyKind::AesGcm, "old")?;
+        let new = keyring(KeyKind::AesGcm, "new")?;
+        let frame = old.encrypt(b"snot badger")?;
+        assert_eq!(b"snot badger".to_vec(), new.decrypt(&frame)?);
+        assert_eq!(b"snot badger".to_vec(), old.decrypt(&frame)?);
+
+        let mut tampered = frame.clone();
+        if let Some(last) = tampered.last_mut() {
+            *last ^= 0xff;
+        }
+        assert!(new.decrypt(&tampered).is_err());
+        assert!(new.decrypt(&frame[..5]).is_err());
+        assert!(new.decrypt(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sign_verify() -> Result<()> {
+        let ring = keyring(KeyKind::Hmac, "new")?;
+        let frame = ring.sign(b"snot badger")?;
+        assert_eq!(b"snot badger".to_vec(), ring.verify(&frame)?);
+
+        let mut tampered = frame.clone();
+        if let Some(last) = tampered.last_mut() {
+            *last ^= 0xff;
+        }
+        assert!(ring.verify(&tampered).is_err());
+
+        let mut keys = HashMap::new();
+        keys.insert("other".to_string(), vec![3_u8; 16]);
+        let other = Keyring::new(KeyKind::Hmac, "other".to_string(), keys)?;
+        assert!(other.verify(&frame).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_keys() {
+        let mut keys = HashMap::new();
+        keys.insert("short".to_string(), vec![1_u8; 16]);
+        assert!(Keyring::new(KeyKind::AesGcm, "short".to_string(), keys.clone()).is_err());
+        assert!(Keyring::new(KeyKind::Hmac, "short".to_string(), keys.clone()).is_ok());
+        assert!(Keyring::new(KeyKind::Hmac, "missing".to_string(), keys).is_err());
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(OsString, OsString)> {
+        vars.iter()
+            .map(|(k, v)| (OsString::from(*k), OsString::from(*v)))
+            .collect()
+    }
+
+    #[test]
+    fn from_vars() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("hmac.key");
+        std::fs::write(&file, format!("{}\n", base64::encode(b"badger")))?;
+        let file = file.to_string_lossy().to_string();
+        let old = base64::encode(b"snot");
+        let mut env = vars(&[
+            ("TREMOR_HMAC_KEY_ID", "new"),
+            ("TREMOR_HMAC_KEY_old", old.as_str()),
+            ("TREMOR_HMAC_KEY_FILE_new", file.as_str()),
+            ("TREMOR_AES_GCM_KEY_ID", "other"),
+        ]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+            // unrelated variables that aren't unicode are skipped
+            env.push((
+                OsString::from_vec(b"TREMOR_SNOT_\xff".to_vec()),
+                OsString::from_vec(b"\xff".to_vec()),
+            ));
+        }
+        let ring = Keyring::from_vars(KeyKind::Hmac, env.into_iter())?;
+        assert_eq!("new", ring.active);
+        assert_eq!(Some(&b"snot".to_vec()), ring.keys.get("old"));
+        assert_eq!(Some(&b"badger".to_vec()), ring.keys.get("new"));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+            let mut env = vars(&[
+                ("TREMOR_HMAC_KEY_ID", "new"),
+                ("TREMOR_HMAC_KEY_FILE_new", file.as_str()),
+            ]);
+            env.push((
+                OsString::from("TREMOR_HMAC_KEY_old"),
+                OsString::from_vec(b"\xff".to_vec()),
+            ));
+            assert!(Keyring::from_vars(KeyKind::Hmac, env.into_iter()).is_err());
+        }
+        // the active key must be configured
+        let env = vars(&[
+            ("TREMOR_HMAC_KEY_ID", "new"),
+            ("TREMOR_HMAC_KEY_old", old.as_str()),
+        ]);
+        assert!(Keyring::from_vars(KeyKind::Hmac, env.into_iter()).is_err());
+        // as well as its id
+        let env = vars(&[("TREMOR_HMAC_KEY_old", old.as_str())]);
+        assert!(Keyring::from_vars(KeyKind::Hmac, env.into_iter()).is_err());
+        Ok(())
+    }
+}
@@ -169,6 +169,8 @@ error_chain! {
         Hex(hex::FromHexError);
         CsvError(csv::Error);
         ModeParseError(file_mode::ModeParseError);
+        ArrowError(arrow::error::ArrowError);
+        ParquetError(parquet::errors::ParquetError);
     }
 
     errors {
@@ -51,6 +51,7 @@ pub(crate) mod async_sink;
 pub mod codec;
 /// Tremor runtime configuration
 pub mod config;
+pub(crate) mod crypto;
 /// Tremor runtime errors
 pub mod errors;
 /// Tremor function library
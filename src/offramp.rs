@@ -18,9 +18,12 @@ use crate::metrics::RampReporter;
 use crate::permge::PriorityMerge;
 use crate::pipeline;
 use crate::registry::ServantId;
+#[cfg(unix)]
+use crate::sink::unix_socket;
 use crate::sink::{
-    self, amqp, blackhole, cb, debug, dns, elastic, exit, file, gcs, gpub, handle_response, kafka,
-    kv, nats, newrelic, otel, postgres, rest, stderr, stdout, tcp, udp, ws,
+    self, amqp, blackhole, cb, debug, dns, elastic, exec, exit, file, gcs, gpub, handle_response,
+    kafka, kv, loki, mqtt, nats, newrelic, otel, postgres, prometheus, redis, rest, s3, splunk_hec,
+    stderr, stdout, tcp, udp, ws,
 };
 use crate::source::Processors;
 use crate::url::ports::{IN, METRICS};
@@ -114,19 +117,28 @@ pub fn lookup(name: &str, config: &Option<OpConfig>) -> Result<Box<dyn Offramp>>
         "debug" => debug::Debug::from_config(config),
         "dns" => dns::Dns::from_config(config),
         "elastic" => elastic::Elastic::from_config(config),
+        "exec" => exec::Exec::from_config(config),
         "exit" => exit::Exit::from_config(config),
         "file" => file::File::from_config(config),
         "kafka" => kafka::Kafka::from_config(config),
         "kv" => kv::Kv::from_config(config),
+        "loki" => loki::Loki::from_config(config),
+        "mqtt" => mqtt::Mqtt::from_config(config),
         "nats" => nats::Nats::from_config(config),
         "newrelic" => newrelic::NewRelic::from_config(config),
         "otel" => otel::OpenTelemetry::from_config(config),
         "postgres" => postgres::Postgres::from_config(config),
+        "prometheus" => prometheus::Prometheus::from_config(config),
+        "redis" => redis::Redis::from_config(config),
         "rest" => rest::Rest::from_config(config),
+        "s3" => s3::S3::from_config(config),
+        "splunk-hec" => splunk_hec::SplunkHec::from_config(config),
         "stderr" => stderr::StdErr::from_config(config),
         "stdout" => stdout::StdOut::from_config(config),
         "tcp" => tcp::Tcp::from_config(config),
         "udp" => udp::Udp::from_config(config),
+        #[cfg(unix)]
+        "unix-socket" => unix_socket::UnixSocket::from_config(config),
         "ws" => ws::Ws::from_config(config),
         "gcs" => gcs::GoogleCloudStorage::from_config(config),
         "gpub" => gpub::GoogleCloudPubSub::from_config(config),
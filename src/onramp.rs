@@ -19,8 +19,8 @@ use crate::source::prelude::*;
 #[cfg(unix)]
 use crate::source::unix_socket;
 use crate::source::{
-    amqp, blaster, cb, crononome, discord, env, file, gsub, kafka, metronome, nats, otel, postgres,
-    rest, sse, stdin, tcp, udp, ws,
+    amqp, blaster, cb, crononome, discord, env, exec, file, gsub, kafka, metronome, mqtt, nats,
+    otel, postgres, prometheus, redis, rest, s3, sse, stdin, tcp, udp, ws,
 };
 use crate::url::TremorUrl;
 use async_std::task::{self, JoinHandle};
@@ -45,6 +45,11 @@ pub enum Msg {
     Cb(CbAction, EventId),
     // TODO pick good naming here: LinkedEvent / Response / Result?
     Response(tremor_pipeline::Event),
+    /// Runtime command for the source, e.g. seeking or pausing, issued via the API
+    Command {
+        command: tremor_script::Value<'static>,
+        tx: async_channel::Sender<Result<()>>,
+    },
 }
 
 pub type Addr = async_channel::Sender<Msg>;
@@ -76,6 +81,7 @@ pub(crate) fn lookup(
         "blaster" => blaster::Blaster::from_config(id, config),
         "cb" => cb::Cb::from_config(id, config),
         "env" => env::Env::from_config(id, config),
+        "exec" => exec::Exec::from_config(id, config),
         "file" => file::File::from_config(id, config),
         "kafka" => kafka::Kafka::from_config(id, config),
         "postgres" => postgres::Postgres::from_config(id, config),
@@ -89,7 +95,11 @@ pub(crate) fn lookup(
         "ws" => ws::Ws::from_config(id, config),
         "discord" => discord::Discord::from_config(id, config),
         "otel" => otel::OpenTelemetry::from_config(id, config),
+        "prometheus" => prometheus::Prometheus::from_config(id, config),
+        "mqtt" => mqtt::Mqtt::from_config(id, config),
         "nats" => nats::Nats::from_config(id, config),
+        "redis" => redis::Redis::from_config(id, config),
+        "s3" => s3::S3::from_config(id, config),
         "gsub" => gsub::GoogleCloudPubSub::from_config(id, config),
         #[cfg(unix)]
         "unix-socket" => unix_socket::UnixSocket::from_config(id, config),
@@ -15,6 +15,7 @@
 mod gelf;
 pub(crate) use gelf::Gelf;
 
+use crate::crypto::{KeyKind, Keyring};
 use crate::errors::Result;
 use byteorder::{BigEndian, WriteBytesExt};
 use std::default::Default;
@@ -56,6 +57,12 @@ pub fn lookup(name: &str) -> Result<Box<dyn Postprocessor>> {
         "gelf-chunking" => Ok(Box::new(Gelf::default())),
         "textual-length-prefix" => Ok(Box::new(TextualLength::default())),
         "zstd" => Ok(Box::new(Zstd::default())),
+        "aes-gcm-encrypt" => Ok(Box::new(AesGcmEncrypt {
+            keyring: Keyring::from_env(KeyKind::AesGcm)?,
+        })),
+        "hmac-sign" => Ok(Box::new(HmacSign {
+            keyring: Keyring::from_env(KeyKind::Hmac)?,
+        })),
         _ => Err(format!("Postprocessor '{}' not found.", name).into()),
     }
 }
@@ -278,6 +285,36 @@ impl Postprocessor for Zstd {
     }
 }
 
+/// Encrypts data with AES-256-GCM, see `crate::crypto` for key configuration and framing
+pub(crate) struct AesGcmEncrypt {
+    keyring: Keyring,
+}
+impl Postprocessor for AesGcmEncrypt {
+    #[cfg(not(tarpaulin_include))]
+    fn name(&self) -> &str {
+        "aes-gcm-encrypt"
+    }
+
+    fn process(&mut self, _ingres_ns: u64, _egress_ns: u64, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![self.keyring.encrypt(data)?])
+    }
+}
+
+/// Prefixes data with its HMAC-SHA256, see `crate::crypto` for key configuration and framing
+pub(crate) struct HmacSign {
+    keyring: Keyring,
+}
+impl Postprocessor for HmacSign {
+    #[cfg(not(tarpaulin_include))]
+    fn name(&self) -> &str {
+        "hmac-sign"
+    }
+
+    fn process(&mut self, _ingres_ns: u64, _egress_ns: u64, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![self.keyring.sign(data)?])
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -15,7 +15,10 @@
 mod gelf;
 pub(crate) use gelf::Gelf;
 pub(crate) mod lines;
+mod multiline;
+pub(crate) use multiline::Multiline;
 
+use crate::crypto::{KeyKind, Keyring};
 use crate::errors::{Error, Result};
 use crate::url::TremorUrl;
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
@@ -40,6 +43,22 @@ pub trait Preprocessor: Sync + Send {
     ///
     /// * Errors if the data can not processed
     fn process(&mut self, ingest_ns: &mut u64, data: &[u8]) -> Result<Vec<Vec<u8>>>;
+
+    /// Interval in which `flush` needs to be called, for preprocessors that
+    /// hold back data until a timeout
+    fn flush_interval_ns(&self) -> Option<u64> {
+        None
+    }
+
+    /// Flushes data that was held back for too long, or all held back data
+    /// if `finish` is set because the stream ended
+    ///
+    /// # Errors
+    ///
+    /// * Errors if the data can not processed
+    fn flush(&mut self, _now_ns: u64, _finish: bool) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![])
+    }
 }
 
 /// Lookup a preprocessor implementation via its unique id
@@ -49,6 +68,14 @@ pub trait Preprocessor: Sync + Send {
 ///   * Errors if the preprocessor is not known
 #[cfg(not(tarpaulin_include))]
 pub fn lookup(name: &str) -> Result<Box<dyn Preprocessor>> {
+    // only `multiline` preprocessors take parameters, given after the name
+    let (name, params) = name.split_once(':').unwrap_or((name, ""));
+    if name.starts_with("multiline") {
+        return Ok(Box::new(Multiline::from_name(name, params)?));
+    }
+    if !params.is_empty() {
+        return Err(format!("Preprocessor '{}' takes no parameters.", name).into());
+    }
     match name {
         // TODO once preprocessors allow configuration, remove multiple entries for lines here
         "lines" => Ok(Box::new(Lines::new('\n', 1_048_576, true))),
@@ -70,6 +97,12 @@ pub fn lookup(name: &str) -> Result<Box<dyn Preprocessor>> {
         "length-prefixed" => Ok(Box::new(LengthPrefix::default())),
         "textual-length-prefix" => Ok(Box::new(TextualLength::default())),
         "zstd" => Ok(Box::new(Zstd::default())),
+        "aes-gcm-decrypt" => Ok(Box::new(AesGcmDecrypt {
+            keyring: Keyring::from_env(KeyKind::AesGcm)?,
+        })),
+        "hmac-verify" => Ok(Box::new(HmacVerify {
+            keyring: Keyring::from_env(KeyKind::Hmac)?,
+        })),
         _ => Err(format!("Preprocessor '{}' not found.", name).into()),
     }
 }
@@ -115,6 +148,43 @@ pub fn preprocess(
     Ok(data)
 }
 
+/// Flushes data held back by preprocessors, data flushed by one preprocessor
+/// is passed through all the preprocessors following it.
+///
+/// # Errors
+///
+///   * If a preprocessor failed
+pub fn flush(
+    preprocessors: &mut [Box<dyn Preprocessor>],
+    ingest_ns: &mut u64,
+    finish: bool,
+    instance_id: &TremorUrl,
+) -> Result<Vec<Vec<u8>>> {
+    let mut data: Vec<Vec<u8>> = Vec::new();
+    let mut data1 = Vec::new();
+    for (i, pp) in preprocessors.iter_mut().enumerate() {
+        data1.clear();
+        for d in &data {
+            match pp.process(ingest_ns, d) {
+                Ok(mut r) => data1.append(&mut r),
+                Err(e) => {
+                    error!("[{}] Preprocessor [{}] error {}", instance_id, i, e);
+                    return Err(e);
+                }
+            }
+        }
+        match pp.flush(*ingest_ns, finish) {
+            Ok(mut r) => data1.append(&mut r),
+            Err(e) => {
+                error!("[{}] Preprocessor [{}] flush error {}", instance_id, i, e);
+                return Err(e);
+            }
+        }
+        std::mem::swap(&mut data, &mut data1);
+    }
+    Ok(data)
+}
+
 trait SliceTrim {
     fn trim(&self) -> &Self;
 }
@@ -343,6 +413,36 @@ impl Preprocessor for Decompress {
         Ok(vec![r])
     }
 }
+/// Decrypts frames created by the `aes-gcm-encrypt` postprocessor, see `crate::crypto`
+pub(crate) struct AesGcmDecrypt {
+    keyring: Keyring,
+}
+impl Preprocessor for AesGcmDecrypt {
+    #[cfg(not(tarpaulin_include))]
+    fn name(&self) -> &str {
+        "aes-gcm-decrypt"
+    }
+
+    fn process(&mut self, _ingest_ns: &mut u64, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![self.keyring.decrypt(data)?])
+    }
+}
+
+/// Verifies frames created by the `hmac-sign` postprocessor and strips the signature, see `crate::crypto`
+pub(crate) struct HmacVerify {
+    keyring: Keyring,
+}
+impl Preprocessor for HmacVerify {
+    #[cfg(not(tarpaulin_include))]
+    fn name(&self) -> &str {
+        "hmac-verify"
+    }
+
+    fn process(&mut self, _ingest_ns: &mut u64, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![self.keyring.verify(data)?])
+    }
+}
+
 #[derive(Clone, Default, Debug)]
 pub(crate) struct LengthPrefix {
     len: Option<usize>,
@@ -0,0 +1,325 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Joins lines into records
+//!
+//! * `multiline`: lines starting with whitespace, `at `, `Caused by:` or `...` belong to the
+//!   previous line, this covers java (and most other) stack traces
+//! * `multiline-indented`: lines starting with whitespace belong to the previous line
+//! * `multiline-iso8601`: lines starting with an ISO 8601 like timestamp start a new record
+//! * `multiline-continuation:pattern=<regex>`: lines matching the regex belong to the previous line
+//! * `multiline-start:pattern=<regex>`: lines matching the regex start a new record
+//!
+//! All of them take the parameters `max_length` (bytes, default: 1 MiB, 0 for no limit) and
+//! `timeout_ms` (default: 1000) after the name, separated by commas, e.g.
+//! `multiline-start:max_length=65536,timeout_ms=500,pattern=^\[\d+\]`. `pattern` has to
+//! be the last parameter as it can contain commas.
+//!
+//! Records are split at line boundaries once they would exceed `max_length`, single lines
+//! longer than it are truncated.
+//!
+//! Lines held back in a record don't produce an event of their own. Transactional onramps
+//! only consider the data they came with done once the record was emitted and acked, it is
+//! emitted with the next record or after the timeout, then with an event id of its own.
+
+use super::Preprocessor;
+use crate::errors::{Error, Result};
+use regex::bytes::Regex;
+
+/// Lines belong to the previous record if they start with whitespace, `at `,
+/// `Caused by:` or `...`, this covers java (and most other) stack traces
+const CONTINUATION: &str = r"^(\s|at |Caused by:|\.\.\.)";
+/// Lines belong to the previous record if they start with whitespace
+const INDENTED: &str = r"^\s";
+/// A new record starts with an ISO 8601 like timestamp, e.g. `2021-12-01 12:23:42` or `2021-12-01T12:23:42`
+const ISO8601_START: &str = r"^\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}";
+/// Records are split once they would exceed this, longer lines are truncated
+const DEFAULT_MAX_LENGTH: usize = 1_048_576;
+/// Timeout after which the last record is emitted
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// How to decide if a line starts a new record
+enum Rule {
+    /// matching lines are appended to the previous record
+    Continuation(Regex),
+    /// matching lines start a new record, all others are appended to the previous one
+    Start(Regex),
+}
+
+/// Joins lines into records, it expects to receive single lines, e.g. from the `lines` preprocessor.
+/// The last record of a stream is flushed after `timeout_ns` without new data, or when the stream ends.
+pub(crate) struct Multiline {
+    rule: Rule,
+    max_length: usize,
+    timeout_ns: u64,
+    record: Option<Vec<u8>>,
+    last_ns: u64,
+}
+
+impl Multiline {
+    fn new(rule: Rule, max_length: usize, timeout_ns: u64) -> Self {
+        Self {
+            rule,
+            max_length,
+            timeout_ns,
+            record: None,
+            last_ns: 0,
+        }
+    }
+
+    /// Joins stack traces and other lines starting with whitespace into the previous line
+    pub(crate) fn continuation(max_length: usize, timeout_ns: u64) -> Result<Self> {
+        Ok(Self::new(
+            Rule::Continuation(Regex::new(CONTINUATION)?),
+            max_length,
+            timeout_ns,
+        ))
+    }
+
+    /// Joins indented lines into the previous line
+    pub(crate) fn indented(max_length: usize, timeout_ns: u64) -> Result<Self> {
+        Ok(Self::new(
+            Rule::Continuation(Regex::new(INDENTED)?),
+            max_length,
+            timeout_ns,
+        ))
+    }
+
+    /// Starts a new record with every line starting with an ISO 8601 timestamp
+    pub(crate) fn iso8601(max_length: usize, timeout_ns: u64) -> Result<Self> {
+        Ok(Self::new(
+            Rule::Start(Regex::new(ISO8601_START)?),
+            max_length,
+            timeout_ns,
+        ))
+    }
+
+    /// Creates the preprocessor `name` with the `params` given after it
+    pub(crate) fn from_name(name: &str, params: &str) -> Result<Self> {
+        let mut max_length = DEFAULT_MAX_LENGTH;
+        let mut timeout_ms = DEFAULT_TIMEOUT_MS;
+        let mut pattern = None;
+        let mut rest = params;
+        while !rest.is_empty() {
+            if let Some(p) = rest.strip_prefix("pattern=") {
+                pattern = Some(p);
+                break;
+            }
+            let (param, next) = rest.split_once(',').unwrap_or((rest, ""));
+            rest = next;
+            match param.split_once('=') {
+                Some(("max_length", v)) => max_length = v.parse()?,
+                Some(("timeout_ms", v)) => timeout_ms = v.parse()?,
+                _ => {
+                    return Err(format!(
+                        "Invalid parameter '{}' for preprocessor '{}'.",
+                        param, name
+                    )
+                    .into())
+                }
+            }
+        }
+        let timeout_ns = timeout_ms.saturating_mul(1_000_000);
+        match (name, pattern) {
+            ("multiline", None) => Self::continuation(max_length, timeout_ns),
+            ("multiline-indented", None) => Self::indented(max_length, timeout_ns),
+            ("multiline-iso8601", None) => Self::iso8601(max_length, timeout_ns),
+            ("multiline-continuation", Some(p)) => Ok(Self::new(
+                Rule::Continuation(Regex::new(p)?),
+                max_length,
+                timeout_ns,
+            )),
+            ("multiline-start", Some(p)) => Ok(Self::new(
+                Rule::Start(Regex::new(p)?),
+                max_length,
+                timeout_ns,
+            )),
+            ("multiline-continuation" | "multiline-start", None) => Err(Error::from(format!(
+                "Preprocessor '{}' requires a pattern.",
+                name
+            ))),
+            ("multiline" | "multiline-indented" | "multiline-iso8601", Some(_)) => Err(
+                Error::from(format!("Preprocessor '{}' takes no pattern.", name)),
+            ),
+            _ => Err(format!("Preprocessor '{}' not found.", name).into()),
+        }
+    }
+
+    fn starts_record(&self, line: &[u8]) -> bool {
+        match &self.rule {
+            Rule::Continuation(re) => !re.is_match(line),
+            Rule::Start(re) => re.is_match(line),
+        }
+    }
+}
+
+impl Preprocessor for Multiline {
+    #[cfg(not(tarpaulin_include))]
+    fn name(&self) -> &str {
+        "multiline"
+    }
+
+    fn process(&mut self, ingest_ns: &mut u64, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.last_ns = *ingest_ns;
+        let data = if self.max_length > 0 && data.len() > self.max_length {
+            warn!(
+                "Truncating line of length {} to the maximum allowed length of {}",
+                data.len(),
+                self.max_length,
+            );
+            data.get(..self.max_length).unwrap_or(data)
+        } else {
+            data
+        };
+        let starts_record = self.starts_record(data);
+        match self.record.take() {
+            Some(mut record) if !starts_record => {
+                if self.max_length > 0 && record.len() + data.len() + 1 > self.max_length {
+                    warn!(
+                        "Splitting record of length {} since it would exceed the maximum allowed length of {}",
+                        record.len() + data.len() + 1,
+                        self.max_length,
+                    );
+                    self.record = Some(data.to_vec());
+                    Ok(vec![record])
+                } else {
+                    record.push(b'\n');
+                    record.extend_from_slice(data);
+                    self.record = Some(record);
+                    Ok(vec![])
+                }
+            }
+            Some(record) => {
+                self.record = Some(data.to_vec());
+                Ok(vec![record])
+            }
+            None => {
+                self.record = Some(data.to_vec());
+                Ok(vec![])
+            }
+        }
+    }
+
+    fn flush_interval_ns(&self) -> Option<u64> {
+        Some(self.timeout_ns)
+    }
+
+    fn flush(&mut self, now_ns: u64, finish: bool) -> Result<Vec<Vec<u8>>> {
+        if finish || now_ns.saturating_sub(self.last_ns) >= self.timeout_ns {
+            Ok(self.record.take().into_iter().collect())
+        } else {
+            Ok(vec![])
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TIMEOUT: u64 = 1_000_000_000;
+
+    fn process_all(pp: &mut Multiline, lines: &[&str]) -> Result<Vec<String>> {
+        let mut ingest_ns = 0;
+        let mut res = Vec::new();
+        for line in lines {
+            for r in pp.process(&mut ingest_ns, line.as_bytes())? {
+                res.push(String::from_utf8(r)?);
+            }
+        }
+        Ok(res)
+    }
+
+    #[test]
+    fn stack_trace() -> Result<()> {
+        let mut pp = Multiline::continuation(1024, TIMEOUT)?;
+        let res = process_all(
+            &mut pp,
+            &[
+                "Exception in thread \"main\" java.lang.IllegalStateException: snot",
+                "\tat com.example.Badger.run(Badger.java:23)",
+                "Caused by: java.lang.NullPointerException",
+                "\t... 2 more",
+                "next event",
+            ],
+        )?;
+        assert_eq!(
+            vec!["Exception in thread \"main\" java.lang.IllegalStateException: snot\n\tat com.example.Badger.run(Badger.java:23)\nCaused by: java.lang.NullPointerException\n\t... 2 more"],
+            res
+        );
+        // the last record is only flushed after the timeout
+        assert!(pp.flush(TIMEOUT - 1, false)?.is_empty());
+        assert_eq!(vec![b"next event".to_vec()], pp.flush(TIMEOUT, false)?);
+        assert!(pp.flush(TIMEOUT, true)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn start_pattern() -> Result<()> {
+        let mut pp = Multiline::iso8601(1024, TIMEOUT)?;
+        let res = process_all(
+            &mut pp,
+            &[
+                "2021-12-01 12:23:42 first",
+                "continued",
+                "2021-12-01T12:23:43 second",
+            ],
+        )?;
+        assert_eq!(vec!["2021-12-01 12:23:42 first\ncontinued"], res);
+        assert_eq!(
+            vec![b"2021-12-01T12:23:43 second".to_vec()],
+            pp.flush(0, true)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn max_length() -> Result<()> {
+        let mut pp = Multiline::indented(12, TIMEOUT)?;
+        let res = process_all(&mut pp, &["snot", " badger", " snot"])?;
+        assert_eq!(vec!["snot\n badger"], res);
+        assert_eq!(vec![b" snot".to_vec()], pp.flush(0, true)?);
+        // lines longer than `max_length` are truncated
+        let res = process_all(&mut pp, &["snot badger snot", "badger"])?;
+        assert_eq!(vec!["snot badger "], res);
+        Ok(())
+    }
+
+    #[test]
+    fn configured() -> Result<()> {
+        let mut pp = Multiline::from_name(
+            "multiline-start",
+            r"max_length=64,timeout_ms=10,pattern=^\[\d+,\d+\]",
+        )?;
+        assert_eq!(64, pp.max_length);
+        assert_eq!(10_000_000, pp.timeout_ns);
+        let res = process_all(&mut pp, &["[1,2] snot", "badger", "[3,4] snot"])?;
+        assert_eq!(vec!["[1,2] snot\nbadger"], res);
+
+        let mut pp = Multiline::from_name("multiline-continuation", r"pattern=^\+")?;
+        assert_eq!(DEFAULT_MAX_LENGTH, pp.max_length);
+        let res = process_all(&mut pp, &["snot", "+badger", "snot"])?;
+        assert_eq!(vec!["snot\n+badger"], res);
+
+        let pp = Multiline::from_name("multiline", "timeout_ms=5000")?;
+        assert_eq!(5_000_000_000, pp.timeout_ns);
+
+        assert!(Multiline::from_name("multiline-start", "").is_err());
+        assert!(Multiline::from_name("multiline-start", "pattern=(").is_err());
+        assert!(Multiline::from_name("multiline", "pattern=^a").is_err());
+        assert!(Multiline::from_name("multiline", "snot=1").is_err());
+        assert!(Multiline::from_name("multiline", "max_length=badger").is_err());
+        Ok(())
+    }
+}
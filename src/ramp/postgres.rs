@@ -117,15 +117,17 @@ impl postgres::types::ToSql for Record<'_> {
 
                 postgres_protocol::types::float8_to_sql(val, w);
             }
-            postgres::types::Type::JSON => {
-                let val = self.value.as_str().unwrap_or_default();
-                simd_json::to_writer(w.writer(), &val)?;
-            }
+            postgres::types::Type::JSON => match self.value.as_str() {
+                Some(val) => simd_json::to_writer(w.writer(), &val)?,
+                None => self.value.write(&mut w.writer())?,
+            },
             postgres::types::Type::JSONB => {
-                let val = self.value.as_str().unwrap_or_default();
                 w.put_u8(1);
 
-                simd_json::to_writer(w.writer(), &val)?;
+                match self.value.as_str() {
+                    Some(val) => simd_json::to_writer(w.writer(), &val)?,
+                    None => self.value.write(&mut w.writer())?,
+                }
             }
             postgres::types::Type::TIMESTAMPTZ => {
                 let val = self.value.as_str().unwrap_or_default();
@@ -189,7 +191,22 @@ pub fn json_to_record<'a>(json: &'a Value<'a>) -> Result<Record> {
         None => return Err("error getting fieldType".into()),
     };
 
-    let t: postgres::types::Type = match field_type {
+    let t = type_from_str(field_type)?;
+
+    let name = json
+        .get_str("name")
+        .ok_or_else(|| Error::from("Missing field `name`"))?;
+
+    let value = json
+        .get("value")
+        .ok_or_else(|| Error::from("Missing field `value`"))?;
+
+    Ok(Record { t, value, name })
+}
+
+/// Looks up a type of the intermediate representation
+pub fn type_from_str(field_type: &str) -> Result<postgres::types::Type> {
+    Ok(match field_type {
         "VARCHAR" => postgres::types::Type::VARCHAR,
         "UNKNOWN" => postgres::types::Type::UNKNOWN,
         "BOOL" => postgres::types::Type::BOOL,
@@ -206,17 +223,7 @@ pub fn json_to_record<'a>(json: &'a Value<'a>) -> Result<Record> {
         "TIMESTAMPTZ" => postgres::types::Type::TIMESTAMPTZ,
         "TIMESTAMP" => postgres::types::Type::TIMESTAMP,
         _ => return Err("intermediate representation does not support field type".into()),
-    };
-
-    let name = json
-        .get_str("name")
-        .ok_or_else(|| Error::from("Missing field `name`"))?;
-
-    let value = json
-        .get("value")
-        .ok_or_else(|| Error::from("Missing field `value`"))?;
-
-    Ok(Record { t, value, name })
+    })
 }
 pub fn row_to_json(
     row: &postgres::row::Row,
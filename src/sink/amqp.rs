@@ -17,14 +17,21 @@
 //! # AMQP Offramp
 //!
 //! The `amqp` offramp allows producing events to an amqp broker.
+//!
+//! The exchange, routing key and headers of each message can be set via
+//! `$amqp.exchange`, `$amqp.routing_key` and `$amqp.headers`. With `confirm`
+//! enabled, events are only acknowledged once the broker confirmed them.
 
+use crate::connectors::amqp::to_amqp;
 use crate::sink::prelude::*;
 use crate::url::TremorUrl;
 use async_channel::{bounded, Receiver};
 use halfbrown::HashMap;
 use lapin::{
-    options::BasicPublishOptions, publisher_confirm::Confirmation, BasicProperties, Channel,
-    Connection, ConnectionProperties, PromiseChain,
+    options::{BasicPublishOptions, ConfirmSelectOptions},
+    publisher_confirm::{Confirmation, PublisherConfirm},
+    types::{AMQPValue, FieldArray, FieldTable, LongString, ShortString},
+    BasicProperties, Channel, Connection, ConnectionProperties,
 };
 use serde::Deserialize;
 use std::{fmt, time::Instant};
@@ -32,22 +39,64 @@ use std::{fmt, time::Instant};
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub amqp_addr: String,
+    /// default routing key, overridden by `$amqp.routing_key`
     #[serde(default = "Default::default")]
     routing_key: String,
+    /// default exchange, overridden by `$amqp.exchange`
     #[serde(default = "Default::default")]
     exchange: String,
     publish_options: BasicPublishOptions,
-    // headers to use for the messages
+    // headers to use for the messages, merged with the ones from `$amqp.headers`
     #[serde(default = "Default::default")]
     pub headers: HashMap<String, Vec<String>>,
+    /// put the channel in confirm mode and only acknowledge events once the broker
+    /// confirmed all their messages, defaults to `true`
+    #[serde(default = "default_confirm")]
+    pub confirm: bool,
+    /// publish messages with delivery mode 2 so the broker persists them
+    #[serde(default = "bool::default")]
+    pub persistent: bool,
+}
+
+fn default_confirm() -> bool {
+    true
 }
 
 impl Config {
-    async fn channel(&self) -> PromiseChain<Channel> {
-        match Connection::connect(&self.amqp_addr, ConnectionProperties::default()).await {
-            Ok(connection) => connection.create_channel(),
-            Err(error) => PromiseChain::new_with_data(Err(error)),
+    async fn channel(&self) -> Result<Channel> {
+        let connection =
+            Connection::connect(&self.amqp_addr, ConnectionProperties::default()).await?;
+        let channel = connection.create_channel().await?;
+        if self.confirm {
+            channel
+                .confirm_select(ConfirmSelectOptions::default())
+                .await?;
         }
+        Ok(channel)
+    }
+
+    /// headers of a message, the configured ones overridden by `$amqp.headers`
+    fn headers(&self, meta: Option<&Value>) -> FieldTable {
+        let mut headers = FieldTable::default();
+        for (key, values) in &self.headers {
+            let value = if let [value] = values.as_slice() {
+                AMQPValue::LongString(LongString::from(value.clone()))
+            } else {
+                AMQPValue::FieldArray(FieldArray::from(
+                    values
+                        .iter()
+                        .map(|v| AMQPValue::LongString(LongString::from(v.clone())))
+                        .collect::<Vec<_>>(),
+                ))
+            };
+            headers.insert(ShortString::from(key.clone()), value);
+        }
+        if let Some(meta) = meta.and_then(ValueAccess::as_object) {
+            for (key, value) in meta.iter() {
+                headers.insert(ShortString::from(key.to_string()), to_amqp(value));
+            }
+        }
+        headers
     }
 }
 
@@ -101,15 +150,82 @@ impl Amqp {
             self.channel = None;
         }
         if self.channel.is_none() {
-            match self.config.channel().await.await {
-                Ok(channel) => self.channel = Some(channel),
-                Err(error) => return Err(error.into()),
-            }
+            self.channel = Some(self.config.channel().await?);
         }
         return Ok(self.channel.as_ref());
     }
 }
 
+/// Waits for the publisher confirms of all messages of an event and sends
+/// an ack or fail insight accordingly.
+///
+/// Signals errors on the channel for reconnecting.
+#[allow(clippy::cast_possible_truncation)]
+async fn wait_for_confirms(
+    sink_url: String,
+    confirms: Vec<PublisherConfirm>,
+    processing_start: Instant,
+    maybe_event: Option<Event>,
+    reply_tx: Sender<sink::Reply>,
+    error_tx: Sender<()>,
+) -> Result<()> {
+    let cb = match futures::future::try_join_all(confirms).await {
+        Ok(confirmations) => {
+            let mut cb = CbAction::Ack;
+            for confirmation in confirmations {
+                match confirmation {
+                    Confirmation::NotRequested | Confirmation::Ack(None) => {}
+                    Confirmation::Ack(Some(returned)) => {
+                        error!(
+                            "[Sink::{}] message returned as unroutable: {} {}",
+                            sink_url, returned.reply_code, returned.reply_text
+                        );
+                        cb = CbAction::Fail;
+                    }
+                    Confirmation::Nack(Some(returned)) => {
+                        error!(
+                            "[Sink::{}] failed to send message: {} {}",
+                            sink_url, returned.reply_code, returned.reply_text
+                        );
+                        cb = CbAction::Fail;
+                    }
+                    Confirmation::Nack(None) => {
+                        error!("[Sink::{}] failed to send message: nacked", sink_url);
+                        cb = CbAction::Fail;
+                    }
+                }
+            }
+            cb
+        }
+        Err(e) => {
+            error!(
+                "[Sink::{}] Publisher confirm failed. Message delivery status unclear, considering it failed: {}",
+                sink_url, e
+            );
+            if error_tx.send(()).await.is_err() {
+                error!(
+                    "[Sink::{}] Error notifying the system about amqp error",
+                    &sink_url
+                );
+            }
+            CbAction::Fail
+        }
+    };
+    if let Some(mut insight) = maybe_event {
+        insight.cb = cb;
+        if cb == CbAction::Ack {
+            let time = processing_start.elapsed().as_millis() as u64;
+            let mut m = Object::with_capacity(1);
+            m.insert("time".into(), time.into());
+            insight.data = (Value::null(), m).into();
+        }
+        if reply_tx.send(sink::Reply::Insight(insight)).await.is_err() {
+            error!("[Sink::{}] Error sending insight", sink_url);
+        }
+    }
+    Ok(())
+}
+
 #[async_trait::async_trait]
 impl Sink for Amqp {
     async fn on_event(
@@ -119,111 +235,79 @@ impl Sink for Amqp {
         _codec_map: &HashMap<String, Box<dyn Codec>>,
         mut event: Event,
     ) -> ResultVec {
-        self.handle_channel().await?;
-        let ingest_ns = event.ingest_ns;
         let processing_start = Instant::now();
-        /*
-        // evaluate here to avoid borrowing again while borrowed.
-        let config_reply = self.config.reply.as_deref();
-        let op_meta = &event.op_meta;
-        self.merged_meta.merge(op_meta.clone());
-        */
-        let insight_event = event.insight_ack();
-        if let Some(channel) = &mut self.channel {
-            for (value, _) in event.value_meta_iter() {
+        let ingest_ns = event.ingest_ns;
+        let mut confirms = Vec::with_capacity(event.len());
+        let mut error = None;
+        if let Some(channel) = self.handle_channel().await?.cloned() {
+            for (value, meta) in event.value_meta_iter() {
+                let amqp = meta.get("amqp");
+                let exchange = amqp
+                    .and_then(|m| m.get_str("exchange"))
+                    .unwrap_or(self.config.exchange.as_str());
+                let routing_key = amqp
+                    .and_then(|m| m.get_str("routing_key"))
+                    .unwrap_or(self.config.routing_key.as_str());
+                let headers = self.config.headers(amqp.and_then(|m| m.get("headers")));
+                let mut properties = BasicProperties::default().with_headers(headers);
+                if self.config.persistent {
+                    properties = properties.with_delivery_mode(2);
+                }
                 let encoded = codec.encode(value)?;
                 let processed =
                     postprocess(self.postprocessors.as_mut_slice(), ingest_ns, encoded)?;
-                //let headers = meta.get("nats").and_then(|v| v.get_object("headers"));
                 for payload in processed {
-                    /*
-                    // prepare message reply
-                    let message_reply = reply.or(config_reply);
-                    */
-                    // prepare message headers
-                    let properties = BasicProperties::default();
-                    /*
-                    let mut key_val: Vec<(&str, &str)> = Vec::with_capacity(
-                        self.config.headers.len() + headers.map(HashMap::len).unwrap_or_default(),
-                    );
-                    for (key, val) in &self.config.headers {
-                        for ele in val.iter() {
-                            key_val.push((key.as_str(), ele.as_str()));
-                        }
-                    }
-                    if let Some(headers) = headers {
-                        for (key, val) in
-                            headers.iter().filter_map(|(k, v)| Some((k, v.as_array()?)))
-                        {
-                            for ele in val.iter().filter_map(value_trait::ValueAccess::as_str) {
-                                key_val.push((key, ele));
-                            }
-                        }
-                    }
-                    let message_headers = if key_val.is_empty() {
-                        None
-                    } else {
-                        Some(Headers::from_iter(key_val))
-                    };
-                    */
-                    let publish_result = channel
+                    match channel
                         .basic_publish(
-                            self.config.exchange.as_str(),
-                            self.config.routing_key.as_str(),
+                            exchange,
+                            routing_key,
                             self.config.publish_options,
                             payload,
-                            properties,
+                            properties.clone(),
                         )
-                        .await?
-                        .await?;
-
-                    match publish_result {
-                        Confirmation::NotRequested | Confirmation::Ack(_) => {
-                            if event.transactional {
-                                let mut insight = insight_event.clone();
-                                insight.cb = CbAction::Ack;
-                                // we hopefully enver wait more then u64 ... if we do we got
-                                // bigger problems
-                                #[allow(clippy::cast_possible_truncation)]
-                                let time = processing_start.elapsed().as_millis() as u64;
-                                let mut m = Object::with_capacity(1);
-                                m.insert("time".into(), time.into());
-                                insight.data = (Value::null(), m).into();
-                                self.reply_channel
-                                    .send(sink::Reply::Insight(insight.clone()))
-                                    .await?;
-                            }
-                        }
-                        Confirmation::Nack(err) => {
-                            if let Some(e) = err {
-                                error!(
-                                    "[Sink::{}] failed to send message: {} {}",
-                                    &self.sink_url, e.reply_code, e.reply_text
-                                );
-                            } else {
-                                error!(
-                                    "[Sink::{}] failed to send message: unknown error",
-                                    &self.sink_url
-                                );
-                            }
-                            if self.error_tx.send(()).await.is_err() {
-                                error!(
-                                    "[Sink::{}] Error notifying the system about amqp error",
-                                    &self.sink_url
-                                );
-                            }
-                            if event.transactional {
-                                let mut insight = insight_event.clone();
-                                insight.cb = CbAction::Fail;
-                                self.reply_channel
-                                    .send(sink::Reply::Response(ERR, insight))
-                                    .await?;
-                            }
+                        .await
+                    {
+                        Ok(confirm) => confirms.push(confirm),
+                        Err(e) => {
+                            error = Some(e);
+                            break;
                         }
                     }
                 }
+                if error.is_some() {
+                    break;
+                }
             }
         }
+        if let Some(e) = error {
+            error!(
+                "[Sink::{}] failed to publish message: {}",
+                &self.sink_url, e
+            );
+            // reconnect on the next event
+            self.channel = None;
+            if event.transactional {
+                self.reply_channel
+                    .send(sink::Reply::Insight(event.insight_fail()))
+                    .await?;
+            }
+            return Ok(None);
+        }
+        let insight_event = if event.transactional {
+            // we gonna change the success status later, if need be
+            Some(event.insight_ack())
+        } else {
+            None
+        };
+        // all messages are published, wait for the broker confirms in the background
+        task::spawn(wait_for_confirms(
+            self.sink_url.to_string(),
+            confirms,
+            processing_start,
+            insight_event,
+            self.reply_channel.clone(),
+            self.error_tx.clone(),
+        ));
         Ok(None)
     }
     fn default_codec(&self) -> &str {
@@ -260,12 +344,15 @@ impl Sink for Amqp {
     }
     async fn terminate(&mut self) {
         if let Some(channel) = self.channel.as_ref() {
+            // wait for outstanding confirms before closing, so they are not lost
+            if self.config.confirm {
+                if let Err(e) = channel.wait_for_confirms().await {
+                    error!("[Sink] Failed to wait for confirms: {}", e);
+                };
+            }
             if let Err(e) = channel.close(0, "terminating sink").await {
                 error!("[Sink] Failed to close channel: {}", e);
             }
-            if let Err(e) = channel.wait_for_confirms().await {
-                error!("[Sink] Failed to close channel: {}", e);
-            };
         }
         /*if self.channel.in_flight_count() > 0 {
             // wait a second in order to flush messages.
@@ -21,9 +21,20 @@
 //! See [Config](struct.Config.html) for details.
 //!
 //! ## Input Metadata Variables
-//!   * `index` - index to write to (required)
-//!   * `doc-type` - document type for the event (required)
-//!   * `pipeline` - pipeline to use
+//!   * `$elastic._index` - index or data stream to write to (required unless `index` is configured)
+//!   * `$elastic._type` - document type for the event
+//!   * `$elastic._id` - document id
+//!   * `$elastic.pipeline` - ingest pipeline to use
+//!   * `$elastic.routing` - shard routing value
+//!   * `$elastic.action` - one of `index` (default), `create`, `update` or `delete`
+//!
+//! ## Bulk item errors
+//!
+//! Every document of a bulk request is handled on its own: documents the cluster
+//! rejected with `429 Too Many Requests` are retried with backoff, documents that can
+//! never be written (e.g. because of mapping errors) are sent to the `err` port together
+//! with the original payload. An event is only failed if one of its documents could
+//! not be written for any other reason or the retries are exhausted.
 //!
 //! ## Outputs
 //!
@@ -32,22 +43,75 @@
 
 #![cfg(not(tarpaulin_include))]
 
+use crate::connectors::backoff::Backoff;
 use crate::sink::prelude::*;
 use async_channel::{bounded, Receiver, Sender};
 use async_std::task::JoinHandle;
-use elastic::{
-    client::responses::bulk::{ErrorItem, OkItem},
-    prelude::*,
-};
 use halfbrown::HashMap;
+use reqwest::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client, StatusCode};
 use simd_json::json;
+use std::iter;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
-use std::{iter, str};
 use tremor_pipeline::{EventId, EventIdGenerator};
 use tremor_script::prelude::*;
 use tremor_script::Object;
 use tremor_value::literal;
 
+/// Authentication against the cluster
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Auth {
+    /// http basic authentication
+    Basic { username: String, password: String },
+    /// an API key as returned by the create API key API
+    ApiKey { id: String, api_key: String },
+}
+
+impl Auth {
+    fn header_value(&self) -> Result<HeaderValue> {
+        let value = match self {
+            Auth::Basic { username, password } => format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            ),
+            Auth::ApiKey { id, api_key } => {
+                format!("ApiKey {}", base64::encode(format!("{}:{}", id, api_key)))
+            }
+        };
+        let mut value = HeaderValue::from_str(&value)?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+}
+
+/// Retries for documents the cluster rejected with `429 Too Many Requests`
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Retry {
+    /// maximum number of retries per document (default: 3)
+    #[serde(default = "dflt_max_retries")]
+    pub max_retries: u32,
+    /// `initial_backoff_ms` and `max_backoff_ms` between retries
+    #[serde(flatten)]
+    pub backoff: Backoff,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_retries: dflt_max_retries(),
+            backoff: Backoff::default(),
+        }
+    }
+}
+
+fn dflt_max_retries() -> u32 {
+    3
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     /// list of elasticsearch cluster nodes
@@ -55,15 +119,241 @@ pub struct Config {
     /// maximum number of paralel in flight batches (default: 4)
     #[serde(default = "concurrency")]
     pub concurrency: usize,
+    /// `basic` auth with `username` and `password` or an `api_key` with `id` and `api_key`
+    #[serde(default)]
+    pub auth: Option<Auth>,
+    /// PEM file with CA certificates to verify the nodes against, in addition to the system ones
+    #[serde(default)]
+    pub cafile: Option<PathBuf>,
+    /// index or data stream to write to if the event does not set `$elastic._index`
+    #[serde(default)]
+    pub index: Option<String>,
+    /// ingest pipeline to use if the event does not set `$elastic.pipeline`
+    #[serde(default)]
+    pub pipeline: Option<String>,
+    /// write to data streams, which only accept `create` operations, so this is the
+    /// default action and all others are rejected (default: false)
+    #[serde(default)]
+    pub data_stream: bool,
+    /// retries for documents rejected with `429 Too Many Requests`
+    #[serde(default)]
+    pub retry: Retry,
 }
 fn concurrency() -> usize {
     4
 }
 impl ConfigImpl for Config {}
 
+/// The nodes of the cluster, used round robin
+#[derive(Clone)]
+struct Nodes {
+    nodes: Arc<Vec<String>>,
+    counter: Arc<AtomicUsize>,
+}
+
+impl Nodes {
+    fn new(nodes: &[String]) -> Result<Self> {
+        if nodes.is_empty() {
+            return Err("Elastic offramp requires at least one node.".into());
+        }
+        Ok(Self {
+            nodes: Arc::new(
+                nodes
+                    .iter()
+                    .map(|n| n.trim_end_matches('/').to_string())
+                    .collect(),
+            ),
+            counter: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    fn pick(&self) -> &str {
+        let i = self.counter.fetch_add(1, Ordering::AcqRel) % self.nodes.len();
+        self.nodes.get(i).map_or("", String::as_str)
+    }
+}
+
+/// Everything needed to send a bulk request
+#[derive(Clone)]
+struct BulkClient {
+    client: Client,
+    nodes: Nodes,
+    auth: Option<HeaderValue>,
+    retry: Retry,
+}
+
+impl BulkClient {
+    fn new(config: &Config) -> Result<Self> {
+        let mut builder = Client::builder();
+        if let Some(cafile) = &config.cafile {
+            let pem = std::fs::read(cafile)?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        Ok(Self {
+            client: builder.build()?,
+            nodes: Nodes::new(&config.nodes)?,
+            auth: config.auth.as_ref().map(Auth::header_value).transpose()?,
+            retry: config.retry,
+        })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}{}", self.nodes.pick(), path));
+        if let Some(auth) = &self.auth {
+            request.header(AUTHORIZATION, auth.clone())
+        } else {
+            request
+        }
+    }
+
+    /// checks the connection and credentials, returns the cluster name
+    async fn ping(&self) -> Result<String> {
+        let response = self
+            .request(reqwest::Method::GET, "/")
+            .send()
+            .await?
+            .error_for_status()?;
+        let mut body = response.bytes().await?.to_vec();
+        let info = tremor_value::parse_to_value(&mut body)?;
+        Ok(info
+            .get_str("cluster_name")
+            .unwrap_or("unknown")
+            .to_string())
+    }
+
+    async fn post_bulk(&self, payload: Vec<u8>) -> Result<(StatusCode, Vec<u8>)> {
+        let response = self
+            .request(reqwest::Method::POST, "/_bulk")
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .body(payload)
+            .send()
+            .await?;
+        let status = response.status();
+        Ok((status, response.bytes().await?.to_vec()))
+    }
+
+    /// Sends the documents, retrying the ones rejected with `429 Too Many Requests`.
+    /// Returns the outcome for every document in the order they were given.
+    async fn execute(&self, items: &[Vec<u8>]) -> Result<Vec<Outcome>> {
+        let mut outcomes: Vec<Option<Outcome>> = vec![None; items.len()];
+        let mut pending: Vec<usize> = (0..items.len()).collect();
+        let mut attempt = 0;
+        while !pending.is_empty() {
+            let payload: Vec<u8> = pending
+                .iter()
+                .filter_map(|i| items.get(*i))
+                .flatten()
+                .copied()
+                .collect();
+            let (status, mut body) = self.post_bulk(payload).await?;
+            let mut retries = Vec::new();
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                let item = literal!({
+                    "status": status.as_u16(),
+                    "error": String::from_utf8_lossy(&body).to_string()
+                });
+                retries = pending.iter().map(|i| (*i, item.clone())).collect();
+            } else if status.is_success() {
+                let bulk_items = parse_bulk_response(&mut body)?;
+                if bulk_items.len() != pending.len() {
+                    return Err(format!(
+                        "Bulk response has {} items for {} documents",
+                        bulk_items.len(),
+                        pending.len()
+                    )
+                    .into());
+                }
+                for (i, (status, item)) in pending.iter().zip(bulk_items) {
+                    if let Some(outcome) = Outcome::from_status(status, item.clone()) {
+                        if let Some(o) = outcomes.get_mut(*i) {
+                            *o = Some(outcome);
+                        }
+                    } else {
+                        retries.push((*i, item));
+                    }
+                }
+            } else {
+                return Err(format!(
+                    "Bulk request failed with {}: {}",
+                    status,
+                    String::from_utf8_lossy(&body)
+                )
+                .into());
+            }
+            if attempt >= self.retry.max_retries {
+                for (i, item) in retries {
+                    if let Some(o) = outcomes.get_mut(i) {
+                        *o = Some(Outcome::Failed(item));
+                    }
+                }
+                break;
+            }
+            pending = retries.into_iter().map(|(i, _)| i).collect();
+            if !pending.is_empty() {
+                task::sleep(self.retry.backoff.delay(attempt)).await;
+                attempt += 1;
+            }
+        }
+        Ok(outcomes
+            .into_iter()
+            .map(|o| {
+                o.unwrap_or_else(|| Outcome::Failed(literal!({"error": "no bulk response item"})))
+            })
+            .collect())
+    }
+}
+
+/// What happened to a single document of a bulk request, with the bulk response item
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    /// the document was written
+    Written(Value<'static>),
+    /// the document will never be accepted as it is, e.g. because of a mapping error
+    Rejected(Value<'static>),
+    /// the document could not be written
+    Failed(Value<'static>),
+}
+
+impl Outcome {
+    /// `None` for documents that should be retried
+    fn from_status(status: u64, item: Value<'static>) -> Option<Self> {
+        match status {
+            200..=299 => Some(Self::Written(item)),
+            429 => None,
+            400..=499 => Some(Self::Rejected(item)),
+            _ => Some(Self::Failed(item)),
+        }
+    }
+}
+
+/// Extracts the status and the item for every document from a bulk response
+fn parse_bulk_response(body: &mut [u8]) -> Result<Vec<(u64, Value<'static>)>> {
+    let response = tremor_value::parse_to_value(body)?.into_static();
+    let items = response
+        .get_array("items")
+        .ok_or_else(|| Error::from("Invalid bulk response: no `items`"))?;
+    items
+        .iter()
+        .map(|item| {
+            // every item is an object with the action as its only key
+            let item = item
+                .as_object()
+                .and_then(|o| o.values().next())
+                .ok_or_else(|| Error::from("Invalid bulk response item"))?;
+            let status = item
+                .get_u64("status")
+                .ok_or_else(|| Error::from("Invalid bulk response item: no `status`"))?;
+            Ok((status, item.clone()))
+        })
+        .collect()
+}
+
 pub struct Elastic {
     sink_url: TremorUrl,
-    client: SyncClient,
+    client: BulkClient,
+    config: Config,
     queue: AsyncSink<u64>,
     insight_tx: Sender<sink::Reply>,
     is_linked: bool,
@@ -76,9 +366,7 @@ impl offramp::Impl for Elastic {
     fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
         if let Some(config) = config {
             let config: Config = Config::new(config)?;
-            let client = SyncClientBuilder::new()
-                .static_nodes(config.nodes.into_iter())
-                .build()?;
+            let client = BulkClient::new(&config)?;
 
             let queue = AsyncSink::new(config.concurrency);
             let (tx, _rx) = bounded(1); // dummy value
@@ -87,6 +375,7 @@ impl offramp::Impl for Elastic {
             Ok(SinkManager::new_box(Self {
                 sink_url: TremorUrl::from_offramp_id("elastic")?, // just a dummy value, gonna be overwritten on init
                 client,
+                config,
                 queue,
                 insight_tx: tx,
                 is_linked: false,
@@ -110,83 +399,75 @@ fn build_source(id: &EventId, origin_uri: Option<&EventOriginUri>) -> Value<'sta
     Value::from(source)
 }
 
-fn build_bulk_error_data(
-    item: &ErrorItem<String, String, String>,
-    id: &EventId,
-    payload: Value<'static>,
-    origin_uri: Option<&EventOriginUri>,
-    maybe_correlation: Option<Value<'static>>,
-) -> Result<EventPayload> {
+fn item_meta(item: &Value<'static>, maybe_correlation: Option<Value<'static>>) -> Value<'static> {
+    let field = |name: &str| item.get(name).cloned().unwrap_or_else(Value::null);
     let mut meta = literal!({
         "elastic": {
-            "_id": item.id().to_string(),
-            "_index": item.index().to_string(),
-            "_type": item.ty().to_string(),
+            "_id": field("_id"),
+            "_index": field("_index"),
+            "_type": field("_type"),
             // TODO: deprecated remove with removing top level es keys
-            "id": item.id().to_string(),
-            "index": item.index().to_string(),
-            "doc_type": item.ty().to_string(),
+            "id": field("_id"),
+            "index": field("_index"),
+            "doc_type": field("_type"),
+
+            "version": field("_version"),
+            "status": field("status")
         }
     });
     if let Some(correlation) = maybe_correlation {
         meta.try_insert("correlation", correlation);
     }
+    meta
+}
 
+fn build_bulk_error_data(
+    item: &Value<'static>,
+    id: &EventId,
+    payload: Value<'static>,
+    origin_uri: Option<&EventOriginUri>,
+    maybe_correlation: Option<Value<'static>>,
+) -> EventPayload {
     let value = literal!({
         "source": build_source(id, origin_uri),
         "payload": payload,
-        "error": tremor_value::to_value(item.err())?,
+        "error": item.get("error").cloned().unwrap_or_else(Value::null),
         "success": false
     });
-    Ok((value, meta).into())
+    (value, item_meta(item, maybe_correlation)).into()
 }
 
 fn build_bulk_success_data(
-    item: &OkItem<String, String, String>,
+    item: &Value<'static>,
     id: &EventId,
     payload: Value<'static>,
     origin_uri: Option<&EventOriginUri>,
     maybe_correlation: Option<Value<'static>>,
 ) -> EventPayload {
-    let mut meta = literal!({
-        "elastic": {
-            "_id": item.id().to_string(),
-            "_index": item.index().to_string(),
-            "_type": item.ty().to_string(),
-            // TODO: deprecated remove with removing top level es keys
-            "id": item.id().to_string(),
-            "index": item.index().to_string(),
-            "doc_type": item.ty().to_string(),
-
-            "version": item.version().map_or_else(Value::null, Value::from)
-        }
-    });
-    if let Some(correlation) = maybe_correlation {
-        meta.try_insert("correlation", correlation);
-    }
-
     let value = literal!({
         "source": build_source(id, origin_uri),
         "payload": payload,
         "success": true
     });
-    (value, meta).into()
+    (value, item_meta(item, maybe_correlation)).into()
 }
 
-/// Build event payload for elasticsearch _bulk request
-fn build_event_payload(event: &Event) -> Result<Vec<u8>> {
-    // We estimate a single message is 512 byte on everage, might be off but it's
-    // a guess
-    let vec_size = 512 * event.len();
-    let mut payload = Vec::with_capacity(vec_size);
+/// Build the action and document lines of an elasticsearch _bulk request for every value of the event
+fn build_bulk_items(event: &Event, config: &Config) -> Result<Vec<Vec<u8>>> {
+    let mut items = Vec::with_capacity(event.len());
 
     for (value, meta) in event.value_meta_iter() {
+        // We estimate a single message is 512 byte on everage, might be off but it's
+        // a guess
+        let mut item = Vec::with_capacity(512);
         let elastic = meta.get("elastic");
         let index = if let Some(idx) = meta.get_str("index") {
             warn!("[Sink::ES] $index is deprecated please use `$elastic._index` instead");
             idx
         } else if let Some(idx) = elastic.get_str("_index") {
             idx
+        } else if let Some(idx) = config.index.as_deref() {
+            idx
         } else {
             return Err(Error::from("'index' not set for elastic offramp!"));
         };
@@ -211,7 +492,12 @@ fn build_event_payload(event: &Event) -> Result<Vec<u8>> {
             index_meta.insert("pipeline", pipeline)?;
         } else if let Some(pipeline) = elastic.get_str("pipeline") {
             index_meta.insert("pipeline", pipeline)?;
+        } else if let Some(pipeline) = config.pipeline.as_deref() {
+            index_meta.insert("pipeline", pipeline)?;
         };
+        if let Some(routing) = elastic.get_str("routing") {
+            index_meta.insert("routing", routing)?;
+        }
         let action = if meta.get_str("action").is_some() {
             warn!("[Sink::ES] $action is deprecated please use `$elastic.action` instead");
             meta.get_str("action")
@@ -219,8 +505,16 @@ fn build_event_payload(event: &Event) -> Result<Vec<u8>> {
             elastic.get_str("action")
         };
         let key = match action {
-            Some("delete") => "delete",
             Some("create") => "create",
+            None if config.data_stream => "create",
+            Some(other) if config.data_stream => {
+                return Err(format!(
+                    "invalid ES operation for a data stream, only `create` is supported: {}",
+                    other
+                )
+                .into())
+            }
+            Some("delete") => "delete",
             Some("update") => "update",
             Some("index") | None => "index",
             Some(other) => {
@@ -232,23 +526,24 @@ fn build_event_payload(event: &Event) -> Result<Vec<u8>> {
             }
         };
         let value_meta = json!({ key: index_meta });
-        value_meta.write(&mut payload)?;
+        value_meta.write(&mut item)?;
         match key {
             "delete" => (),
             "update" => {
-                payload.push(b'\n');
+                item.push(b'\n');
                 let value = json!({ "doc": value });
-                value.write(&mut payload)?;
+                value.write(&mut item)?;
             }
             "create" | "index" => {
-                payload.push(b'\n');
-                value.write(&mut payload)?;
+                item.push(b'\n');
+                value.write(&mut item)?;
             }
             other => error!("[ES::Sink] Unsupported action: {}", other),
         }
-        payload.push(b'\n');
+        item.push(b'\n');
+        items.push(item);
     }
-    Ok(payload)
+    Ok(items)
 }
 
 impl Elastic {
@@ -258,6 +553,8 @@ impl Elastic {
         let insight_tx = self.insight_tx.clone();
         let response_tx = self.response_sender.clone();
         let is_linked = self.is_linked;
+        let client = self.client.clone();
+        let sink_url = self.sink_url.clone();
 
         let transactional = event.transactional;
         let id = event.id.clone();
@@ -272,9 +569,9 @@ impl Elastic {
 
         let mut responses = Vec::with_capacity(if is_linked { 8 } else { 0 });
 
-        // build payload and request
-        let payload = match build_event_payload(&event) {
-            Ok(payload) => payload,
+        // build the documents of the bulk request
+        let items = match build_bulk_items(&event, &self.config) {
+            Ok(items) => items,
             Err(e) => {
                 // send fail
                 self.send_insight(event.to_fail()).await;
@@ -306,7 +603,6 @@ impl Elastic {
                 return;
             }
         };
-        let req = self.client.request(BulkRequest::new(payload));
 
         let mut correlation_values = if is_linked {
             event.correlation_metas()
@@ -314,64 +610,79 @@ impl Elastic {
             vec![]
         };
         // go async
-        task::spawn_blocking(move || {
-            let ress = &mut responses;
-
+        task::spawn(async move {
             let start = Instant::now();
-            let r: Result<BulkResponse> = (move || {
-                // send event to elastic, blockingly
-                Ok(req.send()?.into_response::<BulkResponse>()?)
-            })();
+            let r = client.execute(&items).await;
 
             // The truncation we do is sensible since we're only looking at a short timeframe
             #[allow(clippy::cast_possible_truncation)]
             let time = start.elapsed().as_millis() as u64;
             let mut insight_meta = Value::object_with_capacity(1);
             let cb = match &r {
-                Ok(bulk_response) => {
-                    insight_meta.try_insert("time", Value::from(time));
+                Ok(outcomes) => {
+                    let mut failed = 0;
+                    for outcome in outcomes {
+                        match outcome {
+                            Outcome::Written(_) => (),
+                            Outcome::Rejected(item) => warn!(
+                                "[Sink::{}] Document rejected: {}",
+                                sink_url,
+                                item.get("error").map(Value::encode).unwrap_or_default()
+                            ),
+                            Outcome::Failed(item) => {
+                                failed += 1;
+                                error!(
+                                    "[Sink::{}] Document failed: {}",
+                                    sink_url,
+                                    item.get("error").map(Value::encode).unwrap_or_default()
+                                );
+                            }
+                        }
+                    }
                     if is_linked {
                         // send out response events for each item
                         // success events via OUT port
                         // error   events via ERR port
-                        for ((item, value), correlation) in bulk_response
+                        for ((outcome, value), correlation) in outcomes
                             .iter()
                             .zip(event.value_iter())
                             .zip(correlation_values.into_iter().chain(iter::repeat(None)))
                         {
-                            let item_res = match item {
-                                Ok(ok_item) => (
-                                    OUT,
-                                    Ok(build_bulk_success_data(
-                                        ok_item,
+                            let origin_uri = event.origin_uri.as_ref();
+                            responses.push(match outcome {
+                                Outcome::Written(item) => (
+                                    build_bulk_success_data(
+                                        item,
                                         &id,
                                         value.clone_static(), // uaarrghhh
-                                        event.origin_uri.as_ref(),
+                                        origin_uri,
                                         correlation,
-                                    )),
+                                    ),
+                                    OUT,
                                 ),
-                                Err(err_item) => (
-                                    ERR,
+                                Outcome::Rejected(item) | Outcome::Failed(item) => (
                                     build_bulk_error_data(
-                                        err_item,
+                                        item,
                                         &id,
                                         value.clone_static(), // uaarrrghhh
-                                        event.origin_uri.as_ref(),
+                                        origin_uri,
                                         correlation,
                                     ),
+                                    ERR,
                                 ),
-                            };
-                            if let (port, Ok(item_body)) = item_res {
-                                ress.push((item_body, port));
-                            } else {
-                                error!(
-                                    "Error extracting bulk item response for document id {}",
-                                    item.map_or_else(ErrorItem::id, OkItem::id)
-                                );
-                            }
+                            });
                         }
                     };
-                    CbAction::Ack
+                    if failed > 0 {
+                        insight_meta.try_insert(
+                            "error",
+                            Value::from(format!("{} documents could not be written", failed)),
+                        );
+                        CbAction::Fail
+                    } else {
+                        insight_meta.try_insert("time", Value::from(time));
+                        CbAction::Ack
+                    }
                 }
                 Err(e) => {
                     // request failed
@@ -407,34 +718,32 @@ impl Elastic {
                     CbAction::Fail
                 }
             };
-            task::block_on(async move {
-                // send response events
-                for response in responses {
-                    if let Err(e) = response_tx.send(response).await {
-                        error!("[Sink::ES] Failed to send bulk item response: {}", e);
-                    }
+            // send response events
+            for response in responses {
+                if let Err(e) = response_tx.send(response).await {
+                    error!("[Sink::ES] Failed to send bulk item response: {}", e);
                 }
+            }
 
-                // send insight - if required
-                if transactional {
-                    let insight = Event {
-                        id,
-                        data: (Value::null(), insight_meta).into(),
-                        ingest_ns,
-                        op_meta,
-                        cb,
-                        ..Event::default()
-                    };
-                    if let Err(e) = insight_tx.send(sink::Reply::Insight(insight)).await {
-                        error!("[Sink::ES] Failed to send insight: {}", e);
-                    }
+            // send insight - if required
+            if transactional {
+                let insight = Event {
+                    id,
+                    data: (Value::null(), insight_meta).into(),
+                    ingest_ns,
+                    op_meta,
+                    cb,
+                    ..Event::default()
+                };
+                if let Err(e) = insight_tx.send(sink::Reply::Insight(insight)).await {
+                    error!("[Sink::ES] Failed to send insight: {}", e);
                 }
+            }
 
-                // mark this task as done in order to free a slot
-                if let Err(e) = tx.send(r.map(|_| time)).await {
-                    error!("[Sink::ES] Failed to send AsyncSink done message: {}", e);
-                }
-            });
+            // mark this task as done in order to free a slot
+            if let Err(e) = tx.send(r.map(|_| time)).await {
+                error!("[Sink::ES] Failed to send AsyncSink done message: {}", e);
+            }
         });
         // this should actually never fail, given how we call this from maybe_enqueue
         if let Err(e) = self.queue.enqueue(rx) {
@@ -563,7 +872,7 @@ impl Sink for Elastic {
         reply_channel: Sender<sink::Reply>,
     ) -> Result<()> {
         // try to connect to check provided config and extract the cluster name
-        let cluster_name = self.client.ping().send()?.cluster_name().to_string();
+        let cluster_name = self.client.ping().await?;
         info!(
             "[Sink::{}] Connected to ES cluster {}.",
             &sink_url, &cluster_name
@@ -622,6 +931,11 @@ mod tests {
 
     use super::*;
 
+    fn config(config_s: &str) -> Result<Config> {
+        let v: serde_yaml::Value = serde_yaml::from_str(config_s)?;
+        Config::new(&v)
+    }
+
     #[test]
     fn build_event_payload_test() -> Result<()> {
         let mut numbers = Value::array_with_capacity(3);
@@ -644,7 +958,7 @@ mod tests {
             data: (data.clone(), meta).into(),
             ..Event::default()
         };
-        let payload = build_event_payload(&event)?;
+        let payload = build_bulk_items(&event, &config("nodes: [\"http://snot:9200\"]")?)?;
 
         let mut expected = Vec::new();
         let es_meta = json!({
@@ -661,8 +975,11 @@ mod tests {
         expected.push(b'\n');
 
         assert_eq!(
-            String::from_utf8_lossy(&expected),
-            String::from_utf8_lossy(&payload)
+            vec![String::from_utf8_lossy(&expected)],
+            payload
+                .iter()
+                .map(|item| String::from_utf8_lossy(item.as_slice()))
+                .collect::<Vec<_>>()
         );
         Ok(())
     }
@@ -679,8 +996,91 @@ mod tests {
             ..Event::default()
         };
 
-        let p = build_event_payload(&event);
+        let p = build_bulk_items(&event, &config("nodes: [\"http://snot:9200\"]")?);
         assert!(p.is_err(), "Didnt fail with missing index.");
         Ok(())
     }
+
+    #[test]
+    fn data_streams() -> Result<()> {
+        let config = config(
+            r#"
+            nodes: ["http://snot:9200"]
+            index: logs-tremor-default
+            pipeline: geoip
+            data_stream: true
+            auth:
+              api_key:
+                id: snot
+                api_key: badger
+            "#,
+        )?;
+        assert_eq!(
+            Some("ApiKey c25vdDpiYWRnZXI="),
+            config
+                .auth
+                .as_ref()
+                .map(Auth::header_value)
+                .transpose()?
+                .as_ref()
+                .and_then(|v| v.to_str().ok())
+        );
+        let event = Event {
+            data: (
+                literal!({"message": "snot"}),
+                literal!({"elastic": {"routing": "badger"}}),
+            )
+                .into(),
+            ..Event::default()
+        };
+        let items = build_bulk_items(&event, &config)?;
+        let mut action = items
+            .concat()
+            .split(|b| *b == b'\n')
+            .next()
+            .map(<[u8]>::to_vec)
+            .unwrap_or_default();
+        let action = tremor_value::parse_to_value(&mut action)?;
+        assert_eq!(
+            literal!({"create": {"_index": "logs-tremor-default", "pipeline": "geoip", "routing": "badger"}}),
+            action
+        );
+
+        let event = Event {
+            data: (Value::object(), literal!({"elastic": {"action": "index"}})).into(),
+            ..Event::default()
+        };
+        assert!(build_bulk_items(&event, &config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_items() -> Result<()> {
+        let mut body = br#"{"took": 3, "errors": true, "items": [
+            {"create": {"_index": "snot", "_id": "1", "_version": 1, "status": 201}},
+            {"create": {"_index": "snot", "_id": "2", "status": 429, "error": {"type": "es_rejected_execution_exception"}}},
+            {"create": {"_index": "snot", "_id": "3", "status": 400, "error": {"type": "mapper_parsing_exception"}}},
+            {"create": {"_index": "snot", "_id": "4", "status": 503, "error": {"type": "unavailable_shards_exception"}}}
+        ]}"#
+        .to_vec();
+        let outcomes: Vec<_> = parse_bulk_response(&mut body)?
+            .into_iter()
+            .map(|(status, item)| Outcome::from_status(status, item))
+            .collect();
+        assert_eq!(4, outcomes.len());
+        assert!(matches!(outcomes.get(0), Some(Some(Outcome::Written(_)))));
+        assert!(matches!(outcomes.get(1), Some(None)));
+        if let Some(Some(Outcome::Rejected(item))) = outcomes.get(2) {
+            let meta = item_meta(item, None);
+            assert_eq!(Some("3"), meta.get("elastic").get_str("_id"));
+            assert_eq!(Some(400), meta.get("elastic").get_u64("status"));
+        } else {
+            panic!("mapping errors need to be rejected");
+        }
+        assert!(matches!(outcomes.get(3), Some(Some(Outcome::Failed(_)))));
+
+        let mut body = br#"{"error": "snot"}"#.to_vec();
+        assert!(parse_bulk_response(&mut body).is_err());
+        Ok(())
+    }
 }
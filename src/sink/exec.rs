@@ -0,0 +1,526 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Exec Offramp
+//!
+//! Writes events to the stdin of a long running command, use the `lines` postprocessor
+//! for commands that read line by line. Lines the command writes to stderr are logged.
+//!
+//! With `responses: true` and the offramp being linked, every line the command writes
+//! to stdout is decoded into a response event. Responses are matched to events in the
+//! order they were written, so the command has to answer every written value (every event,
+//! or every value of a batched event) with exactly one line, in order. If it writes a line
+//! no value is waiting for, or more than `QSIZE` values are waiting, the responses are out of
+//! sync and the command is restarted. A command writing no line for a value can not be told
+//! apart from a slow one until that happens, so its responses are attributed to the wrong
+//! events until then.
+//!
+//! If the command exits the circuit breaker is triggered until it could be restarted.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::connectors::exec::{read_line, CommandConfig};
+use crate::sink::prelude::*;
+use async_channel::{bounded, Receiver};
+use async_std::io::BufReader;
+use halfbrown::HashMap;
+use smol::process::{Child, ChildStdin};
+use std::time::{Duration, Instant};
+use tremor_pipeline::{EventId, EventOriginUri};
+
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    #[serde(flatten)]
+    command: CommandConfig,
+    /// send the lines the command writes to stdout as responses (default: false)
+    #[serde(default)]
+    responses: bool,
+}
+
+impl ConfigImpl for Config {}
+
+/// An event written to the command, waiting for its response
+#[derive(Debug)]
+struct Request {
+    id: EventId,
+    correlation: Option<Value<'static>>,
+}
+
+pub struct Exec {
+    config: Config,
+    child: Option<Child>,
+    stdin: Option<ChildStdin>,
+    sink_url: TremorUrl,
+    origin_uri: EventOriginUri,
+    postprocessors: Postprocessors,
+    preprocessors: Vec<String>,
+    /// only set if responses are read
+    codec: Option<Box<dyn Codec>>,
+    reply_tx: Option<Sender<sink::Reply>>,
+    requests: Option<Sender<Request>>,
+}
+
+impl offramp::Impl for Exec {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(SinkManager::new_box(Self {
+                config,
+                child: None,
+                stdin: None,
+                sink_url: TremorUrl::from_offramp_id("exec")?, // dummy, overwritten in init
+                origin_uri: EventOriginUri::default(),         // dummy, overwritten in init
+                postprocessors: vec![],
+                preprocessors: vec![],
+                codec: None,
+                reply_tx: None,
+                requests: None,
+            }))
+        } else {
+            Err("Exec offramp requires a config".into())
+        }
+    }
+}
+
+fn error_response(request: &Request, e: &str, origin_uri: &EventOriginUri, pid: u32) -> Event {
+    let error = e.to_string();
+    let event_id = request.id.to_string();
+    let mut meta = literal!({
+        "error": error.clone(),
+        "exec": {
+            "pid": pid
+        }
+    });
+    if let Some(correlation) = &request.correlation {
+        meta.try_insert("correlation", correlation.clone());
+    }
+    Event {
+        id: request.id.clone(),
+        origin_uri: Some(origin_uri.clone()),
+        data: (
+            literal!({
+                "error": error,
+                "event_id": event_id
+            }),
+            meta,
+        )
+            .into(),
+        ..Event::default()
+    }
+}
+
+fn response_events(
+    request: &Request,
+    data: Vec<u8>,
+    codec: &mut dyn Codec,
+    preprocessors: &mut Preprocessors,
+    sink_url: &TremorUrl,
+    origin_uri: &EventOriginUri,
+    pid: u32,
+) -> Result<Vec<Event>> {
+    let mut meta = literal!({
+        "exec": {
+            "pid": pid
+        }
+    });
+    if let Some(correlation) = &request.correlation {
+        meta.try_insert("correlation", correlation.clone());
+    }
+    let mut ingest_ns = nanotime();
+    let preprocessed = preprocess(preprocessors, &mut ingest_ns, data, sink_url)?;
+    let mut events = Vec::with_capacity(preprocessed.len());
+    for pp in preprocessed {
+        let data = EventPayload::try_new::<crate::Error, _>(pp, |mutd| {
+            let body = codec.decode(mutd, ingest_ns)?.unwrap_or_else(Value::object);
+            Ok(ValueAndMeta::from_parts(body, meta.clone()))
+        })?;
+        events.push(Event {
+            id: request.id.clone(),
+            origin_uri: Some(origin_uri.clone()),
+            ingest_ns,
+            data,
+            ..Event::default()
+        });
+    }
+    Ok(events)
+}
+
+/// Turns the stdout lines of the command into responses for the requests in the order they
+/// were written, one line per request. Stops with an error on a line without a request, the
+/// closed request channel then makes the offramp restart the command.
+#[allow(clippy::too_many_arguments)]
+async fn read_responses<R>(
+    mut stdout: R,
+    pid: u32,
+    requests: Receiver<Request>,
+    mut codec: Box<dyn Codec>,
+    mut preprocessors: Preprocessors,
+    sink_url: TremorUrl,
+    origin_uri: EventOriginUri,
+    reply_tx: Sender<sink::Reply>,
+) -> Result<()>
+where
+    R: async_std::io::BufRead + Unpin,
+{
+    while let Some(line) = read_line(&mut stdout).await? {
+        let request = if let Ok(request) = requests.try_recv() {
+            request
+        } else {
+            let e = format!(
+                "Command {} wrote a line without an event waiting for it",
+                pid
+            );
+            error!("[Sink::{}] {}.", sink_url, e);
+            return Err(e.into());
+        };
+        match response_events(
+            &request,
+            line,
+            codec.as_mut(),
+            &mut preprocessors,
+            &sink_url,
+            &origin_uri,
+            pid,
+        ) {
+            Ok(events) => {
+                for event in events {
+                    reply_tx.send(sink::Reply::Response(OUT, event)).await?;
+                }
+            }
+            Err(e) => {
+                let e = format!("Error decoding response: {}", e);
+                error!("[Sink::{}] {}", sink_url, e);
+                reply_tx
+                    .send(sink::Reply::Response(
+                        ERR,
+                        error_response(&request, &e, &origin_uri, pid),
+                    ))
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn log_stderr<R>(mut stderr: R, pid: u32, sink_url: TremorUrl) -> Result<()>
+where
+    R: async_std::io::BufRead + Unpin,
+{
+    while let Some(line) = read_line(&mut stderr).await? {
+        warn!(
+            "[Sink::{}] {}: {}",
+            sink_url,
+            pid,
+            String::from_utf8_lossy(&line)
+        );
+    }
+    Ok(())
+}
+
+impl Exec {
+    fn spawn(&mut self) -> Result<()> {
+        let with_stdout = self.codec.is_some() && self.reply_tx.is_some();
+        let mut child = self.config.command.spawn(true, with_stdout)?;
+        let pid = child.id();
+        info!(
+            "[Sink::{}] Started {} ({}).",
+            self.sink_url, self.config.command.command, pid
+        );
+        if let Some(stderr) = child.stderr.take() {
+            let sink_url = self.sink_url.clone();
+            task::spawn(log_stderr(BufReader::new(stderr), pid, sink_url));
+        }
+        if let (Some(stdout), Some(codec), Some(reply_tx)) =
+            (child.stdout.take(), &self.codec, &self.reply_tx)
+        {
+            let (tx, rx) = bounded(crate::QSIZE);
+            let preprocessors = make_preprocessors(&self.preprocessors)?;
+            task::spawn(read_responses(
+                BufReader::new(stdout),
+                pid,
+                rx,
+                codec.boxed_clone(),
+                preprocessors,
+                self.sink_url.clone(),
+                self.origin_uri.clone(),
+                reply_tx.clone(),
+            ));
+            self.requests = Some(tx);
+        }
+        self.stdin = child.stdin.take();
+        self.child = Some(child);
+        Ok(())
+    }
+
+    /// Drops the command, killing it if it is still running
+    fn reset(&mut self) {
+        self.stdin = None;
+        self.requests = None;
+        self.child = None;
+    }
+
+    async fn send_event(&mut self, codec: &mut dyn Codec, event: &Event) -> Result<()> {
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorKind::NoSocket))?;
+        for value in event.value_iter() {
+            if let Some(requests) = &self.requests {
+                let request = Request {
+                    id: event.id.clone(),
+                    correlation: event.correlation_meta(),
+                };
+                // the queue is full or the reader stopped, either way responses can no longer
+                // be matched to events
+                if requests.try_send(request).is_err() {
+                    warn!(
+                        "[Sink::{}] Responses of the command are out of sync, restarting it.",
+                        self.sink_url
+                    );
+                    return Err(ErrorKind::NoSocket.into());
+                }
+            }
+            let raw = codec.encode(value)?;
+            let packets = postprocess(&mut self.postprocessors, event.ingest_ns, raw)?;
+            for packet in packets {
+                stdin.write_all(&packet).await?;
+            }
+        }
+        stdin.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for Exec {
+    /// We acknowledge ourself
+    fn auto_ack(&self) -> bool {
+        false
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        mut event: Event,
+    ) -> ResultVec {
+        let processing_start = Instant::now();
+        let replies = match self.send_event(codec, &event).await {
+            Ok(()) => {
+                if event.transactional {
+                    Some(vec![sink::Reply::Insight(event.insight_ack_with_timing(
+                        processing_start.elapsed().as_millis() as u64,
+                    ))])
+                } else {
+                    None
+                }
+            }
+            // the command is gone, we restart it on the next signal
+            Err(e @ Error(ErrorKind::Io(_) | ErrorKind::NoSocket, _)) => {
+                debug!("[Sink::{}] Error sending event: {}.", self.sink_url, e);
+                self.reset();
+                if event.transactional {
+                    Some(vec![
+                        sink::Reply::Insight(event.to_fail()),
+                        sink::Reply::Insight(event.insight_trigger()),
+                    ])
+                } else {
+                    Some(vec![sink::Reply::Insight(event.insight_trigger())])
+                }
+            }
+            Err(e) => {
+                debug!("[Sink::{}] Error sending event: {}", self.sink_url, e);
+                if event.transactional {
+                    Some(vec![sink::Reply::Insight(event.to_fail())])
+                } else {
+                    None
+                }
+            }
+        };
+        Ok(replies)
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        sink_uid: u64,
+        sink_url: &TremorUrl,
+        codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        processors: Processors<'_>,
+        is_linked: bool,
+        reply_channel: Sender<sink::Reply>,
+    ) -> Result<()> {
+        self.sink_url = sink_url.clone();
+        self.origin_uri = self.config.command.origin_uri(sink_uid);
+        self.postprocessors = make_postprocessors(processors.post)?;
+        if self.config.responses && is_linked {
+            self.preprocessors = processors.pre.to_vec();
+            self.codec = Some(codec.boxed_clone());
+            self.reply_tx = Some(reply_channel);
+        }
+        self.spawn()
+    }
+
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        let exited = match self.child.as_mut().map(Child::try_status) {
+            Some(Ok(None)) => return Ok(None),
+            Some(Ok(Some(status))) => {
+                warn!("[Sink::{}] Command {}.", self.sink_url, status);
+                true
+            }
+            Some(Err(e)) => {
+                warn!("[Sink::{}] Command failed: {}.", self.sink_url, e);
+                true
+            }
+            None => false,
+        };
+        if exited {
+            self.reset();
+        }
+        if let Err(e) = self.spawn() {
+            warn!("[Sink::{}] Unable to restart command: {}", self.sink_url, e);
+            Ok(Some(vec![sink::Reply::Insight(Event::cb_trigger(
+                signal.ingest_ns,
+            ))]))
+        } else {
+            Ok(Some(vec![sink::Reply::Insight(Event::cb_restore(
+                signal.ingest_ns,
+            ))]))
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.stdin.is_some()
+    }
+
+    async fn terminate(&mut self) {
+        // closing stdin gives the command a chance to finish up before it is killed
+        self.stdin = None;
+        if let Some(child) = self.child.as_mut() {
+            if async_std::future::timeout(Duration::from_secs(1), child.status())
+                .await
+                .is_err()
+            {
+                warn!("[Sink::{}] Killing command.", self.sink_url);
+            }
+        }
+        self.reset();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::string::String as StringCodec;
+
+    #[test]
+    fn responses() -> Result<()> {
+        let config_s = r#"
+            command: cat
+            responses: true
+        "#;
+        let v: serde_yaml::Value = serde_yaml::from_str(config_s)?;
+        let config = Config::new(&v)?;
+        assert!(config.responses);
+        assert_eq!("cat", config.command.command);
+
+        let request = Request {
+            id: EventId::new(1, 2, 3),
+            correlation: Some(Value::from("snot")),
+        };
+        let mut codec = StringCodec {};
+        let mut preprocessors = make_preprocessors(&[])?;
+        let sink_url = TremorUrl::from_offramp_id("exec")?;
+        let events = response_events(
+            &request,
+            b"badger".to_vec(),
+            &mut codec,
+            &mut preprocessors,
+            &sink_url,
+            &EventOriginUri::default(),
+            42,
+        )?;
+        assert_eq!(1, events.len());
+        let (value, meta) = events[0].data.parts();
+        assert_eq!(&Value::from("badger"), value);
+        assert_eq!(
+            &literal!({"exec": {"pid": 42}, "correlation": "snot"}),
+            meta
+        );
+        assert_eq!(request.id, events[0].id);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn two_lines_for_one_event() -> Result<()> {
+        let config_s = r#"
+            command: sh
+            args: ["-c", "while read l; do echo \"$l\"; echo \"$l\"; done"]
+        "#;
+        let config: CommandConfig = serde_yaml::from_str(config_s)?;
+        let mut child = config.spawn(true, true)?;
+        let mut stdin = child.stdin.take().ok_or_else(|| Error::from("no stdin"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| Error::from("no stdout"))?;
+
+        let (tx, rx) = bounded(crate::QSIZE);
+        let (reply_tx, reply_rx) = bounded(crate::QSIZE);
+        let id = EventId::new(1, 2, 3);
+        assert!(tx
+            .try_send(Request {
+                id: id.clone(),
+                correlation: None,
+            })
+            .is_ok());
+        stdin.write_all(b"snot\n").await?;
+        stdin.flush().await?;
+
+        // the second line has no event waiting for it
+        let res = read_responses(
+            BufReader::new(stdout),
+            child.id(),
+            rx,
+            Box::new(StringCodec {}),
+            make_preprocessors(&[])?,
+            TremorUrl::from_offramp_id("exec")?,
+            EventOriginUri::default(),
+            reply_tx,
+        )
+        .await;
+        assert!(res.is_err());
+        match reply_rx.try_recv() {
+            Ok(sink::Reply::Response(port, event)) => {
+                assert_eq!(OUT, port);
+                assert_eq!(id, event.id);
+                assert_eq!(&Value::from("snot"), event.data.suffix().value());
+            }
+            _ => panic!("expected a response"),
+        }
+        assert!(reply_rx.try_recv().is_err());
+        Ok(())
+    }
+}
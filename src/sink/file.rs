@@ -16,29 +16,49 @@
 //!
 //! Writes events to a file, one event per line
 //!
+//! The file name can be overwritten per event with the `$file.path` metadata, it is
+//! relative to the directory of `file` and must not leave it. File names may
+//! contain `strftime` specifiers. Files are rotated by size, number of events or
+//! when their name changes, see the `rotating` module for details.
+//!
+//! If `parquet` is configured, events are instead batched into parquet files
+//! named `<file>.<timestamp>.parquet`, with the `strftime` specifiers of `file`
+//! expanded, a new one is started whenever the previous one was flushed. Events
+//! are only acked once the file holding them was written.
+//!
 //! ## Configuration
 //!
 //! See [Config](struct.Config.html) for details.
 
 #![cfg(not(tarpaulin_include))]
 
+use crate::connectors::columnar::{self, Finished, ParquetBatcher};
 use crate::sink::prelude::*;
-use async_std::fs::File as FSFile;
 use async_std::io::prelude::*;
 use halfbrown::HashMap;
 use tremor_common::asy::file as cfile;
 
+mod rotating;
+
 /// An offramp that write a given file
 pub struct File {
-    file: Option<FSFile>,
+    writers: rotating::Writers,
+    parquet: Option<ParquetBatcher>,
     postprocessors: Postprocessors,
+    reply_channel: Option<Sender<sink::Reply>>,
     config: Config,
 }
 
 #[derive(Deserialize)]
 pub struct Config {
-    /// Filename to write to
+    /// Filename to write to, used as prefix for parquet files
     pub file: String,
+    /// Write events as parquet files instead of encoding them one by one
+    #[serde(default)]
+    pub(crate) parquet: Option<columnar::Config>,
+    /// Rotation, compression and retention of written files
+    #[serde(flatten)]
+    pub(crate) rotation: rotating::Config,
 }
 
 impl ConfigImpl for Config {}
@@ -47,11 +67,19 @@ impl offramp::Impl for File {
     fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
         if let Some(config) = config {
             let config: Config = Config::new(config)?;
+            let parquet = config
+                .parquet
+                .clone()
+                .map(ParquetBatcher::new)
+                .transpose()?;
+            rotating::expand(&config.file)?;
 
             Ok(SinkManager::new_box(Self {
-                file: None,
+                writers: rotating::Writers::new(config.rotation.clone()),
+                parquet,
                 config,
                 postprocessors: vec![],
+                reply_channel: None,
             }))
         } else {
             Err("Blackhole offramp requires a config".into())
@@ -59,14 +87,50 @@ impl offramp::Impl for File {
     }
 }
 
+impl File {
+    async fn write_parquet(&self, data: Vec<u8>) -> Result<()> {
+        let path = format!(
+            "{}.{}.parquet",
+            rotating::expand(&self.config.file)?,
+            nanotime()
+        );
+        let mut file = cfile::create(&path).await?;
+        file.write_all(&data).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Writes a finished parquet file and returns the insight for its events
+    async fn flush_parquet(&self, finished: Finished) -> Option<sink::Reply> {
+        let res = match finished.data {
+            Ok(data) => self.write_parquet(data).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &res {
+            error!("Failed to write parquet file: {}", e);
+        }
+        finished
+            .events
+            .insight(res.is_ok())
+            .map(sink::Reply::Insight)
+    }
+}
+
 #[async_trait::async_trait]
 impl Sink for File {
     async fn terminate(&mut self) {
-        if let Some(file) = &mut self.file {
-            if let Err(e) = file.flush().await {
-                error!("Failed to flush file: {}", e);
+        if let Some(finished) = self.parquet.as_mut().and_then(ParquetBatcher::finish) {
+            if let Some(reply) = self.flush_parquet(finished).await {
+                if let Some(reply_channel) = &self.reply_channel {
+                    if reply_channel.send(reply).await.is_err() {
+                        error!("Failed to send the insight for the last parquet file");
+                    }
+                }
             }
         }
+        if let Err(e) = self.writers.flush().await {
+            error!("Failed to flush files: {}", e);
+        }
     }
 
     async fn on_event(
@@ -76,16 +140,31 @@ impl Sink for File {
         _codec_map: &HashMap<String, Box<dyn Codec>>,
         mut event: Event,
     ) -> ResultVec {
-        if let Some(file) = &mut self.file {
-            for value in event.value_iter() {
-                let raw = codec.encode(value)?;
-                let packets = postprocess(&mut self.postprocessors, event.ingest_ns, raw)?;
-                for packet in packets {
-                    file.write_all(&packet).await?;
-                    file.write_all(b"\n").await?;
+        if let Some(parquet) = &mut self.parquet {
+            // acks are sent once the file holding the event was written
+            return match parquet.push(&event) {
+                Ok(Some(finished)) => Ok(self.flush_parquet(finished).await.map(|r| vec![r])),
+                Ok(None) => Ok(None),
+                // nothing to write
+                Err(_) if event.value_iter().next().is_none() => Ok(event
+                    .transactional
+                    .then(|| vec![sink::Reply::Insight(event.insight_ack())])),
+                Err(e) => {
+                    error!("Event doesn't fit the parquet schema: {}", e);
+                    Ok(event
+                        .transactional
+                        .then(|| vec![sink::Reply::Insight(event.insight_fail())]))
                 }
-            }
-            file.flush().await?;
+            };
+        }
+        for (value, meta) in event.value_meta_iter() {
+            let template = match meta.get("file").and_then(|f| f.get_str("path")) {
+                Some(path) => rotating::confine(&self.config.file, path)?,
+                None => self.config.file.clone(),
+            };
+            let raw = codec.encode(value)?;
+            let packets = postprocess(&mut self.postprocessors, event.ingest_ns, raw)?;
+            self.writers.write(&template, &packets).await?;
         }
         Ok(Some(vec![sink::Reply::Insight(event.insight_ack())]))
     }
@@ -101,20 +180,31 @@ impl Sink for File {
         _codec_map: &HashMap<String, Box<dyn Codec>>,
         processors: Processors<'_>,
         _is_linked: bool,
-        _reply_channel: Sender<sink::Reply>,
+        reply_channel: Sender<sink::Reply>,
     ) -> Result<()> {
         self.postprocessors = make_postprocessors(processors.post)?;
-        let file = cfile::create(&self.config.file).await?;
-        self.file = Some(file);
+        self.reply_channel = Some(reply_channel);
         Ok(())
     }
-    async fn on_signal(&mut self, _signal: Event) -> ResultVec {
-        Ok(None)
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        let finished = self
+            .parquet
+            .as_mut()
+            .filter(|parquet| parquet.is_due(signal.ingest_ns))
+            .and_then(ParquetBatcher::finish);
+        let reply = if let Some(finished) = finished {
+            self.flush_parquet(finished).await
+        } else {
+            None
+        };
+        self.writers.tick().await?;
+        Ok(reply.map(|r| vec![r]))
     }
     fn is_active(&self) -> bool {
         true
     }
     fn auto_ack(&self) -> bool {
-        true
+        // parquet files are written in batches, their events are acked once written
+        self.parquet.is_none()
     }
 }
@@ -0,0 +1,530 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rotating writers for the file offramp
+//!
+//! File names are templates, `strftime` specifiers like `%Y-%m-%d` are replaced with the
+//! current (UTC) time, so `events-%Y%m%d%H.log` starts a new file every hour. Files are
+//! rotated by size or number of events by renaming them to `<file>.<timestamp>`.
+//! Files that are done (rotated, or their name changed) are optionally compressed, and only
+//! the newest `retention` of them are kept. Retention only ever removes files this offramp
+//! finished itself since it was started, files of earlier runs or other programs are left
+//! alone.
+//!
+//! The state kept per file name is bounded by `max_open_files`: beyond it closed files are
+//! finished, and the done files of the least recently finished template are forgotten.
+
+use crate::errors::{Error, Result};
+use async_std::fs::{self, File, OpenOptions};
+use async_std::io::prelude::*;
+use async_std::task;
+use chrono::format::{Item, StrftimeItems};
+use chrono::Utc;
+use halfbrown::HashMap;
+use std::collections::{HashMap as StdMap, VecDeque};
+use std::path::{Component, Path};
+use tremor_common::time::nanotime;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Zstd => "zst",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Config {
+    /// Rotate files once they grew beyond this many bytes
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Rotate files after this many events
+    #[serde(default)]
+    pub max_events: Option<u64>,
+    /// Compress files that are done
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Number of done files to keep per file name template, only files finished since
+    /// the offramp was started are removed
+    #[serde(default)]
+    pub retention: Option<usize>,
+    /// Maximum number of files kept open at the same time, defaults to 32
+    #[serde(default = "dflt_max_open_files")]
+    pub max_open_files: usize,
+    /// Append to files that already exist instead of truncating them, this applies to every
+    /// file the offramp opens, not just `file`. Files are continued instead of truncated
+    /// when they are opened again, unless more than `max_open_files` other files were
+    /// opened in between
+    #[serde(default)]
+    pub append: bool,
+}
+
+fn dflt_max_open_files() -> usize {
+    32
+}
+
+/// Expands the `strftime` specifiers in a file name template
+pub(crate) fn expand(template: &str) -> Result<String> {
+    if !template.contains('%') {
+        return Ok(template.to_string());
+    }
+    let items: Vec<Item> = StrftimeItems::new(template).collect();
+    if items.iter().any(|i| matches!(i, Item::Error)) {
+        return Err(format!("Invalid file name template `{}`", template).into());
+    }
+    Ok(Utc::now().format_with_items(items.into_iter()).to_string())
+}
+
+/// Resolves the file name template `path` of an event within the directory of `file`,
+/// absolute paths and `..` are rejected so events can't write anywhere else
+pub(crate) fn confine(file: &str, path: &str) -> Result<String> {
+    let relative = Path::new(path);
+    if relative.file_name().is_none()
+        || !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!(
+            "`$file.path` must be a relative path without `..`, got `{}`",
+            path
+        )
+        .into());
+    }
+    let mut res = Path::new(file)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    for c in relative.components() {
+        if let Component::Normal(c) = c {
+            res.push(c);
+        }
+    }
+    Ok(res.to_string_lossy().to_string())
+}
+
+struct Writer {
+    path: String,
+    file: File,
+    bytes: u64,
+    events: u64,
+    last_used: u64,
+}
+
+/// A file that was closed because too many were open, it is continued on the next write
+struct Evicted {
+    path: String,
+    bytes: u64,
+    events: u64,
+    last_used: u64,
+}
+
+/// Open files by their name template
+pub(crate) struct Writers {
+    config: Config,
+    writers: HashMap<String, Writer>,
+    evicted: HashMap<String, Evicted>,
+    /// done files by their name template, oldest first, and when the last one was done
+    done: StdMap<String, (u64, VecDeque<String>)>,
+    /// the last `max_open_files` paths opened, they are not truncated again
+    opened: VecDeque<String>,
+}
+
+impl Writers {
+    pub(crate) fn new(config: Config) -> Self {
+        Self {
+            config,
+            writers: HashMap::new(),
+            evicted: HashMap::new(),
+            done: StdMap::new(),
+            opened: VecDeque::new(),
+        }
+    }
+
+    fn max_open_files(&self) -> usize {
+        self.config.max_open_files.max(1)
+    }
+
+    fn full(&self, bytes: u64, events: u64) -> bool {
+        self.config.max_bytes.map_or(false, |max| bytes >= max)
+            || self.config.max_events.map_or(false, |max| events >= max)
+    }
+
+    /// Writes one event, encoded in `packets`, to the current file of `template`
+    pub(crate) async fn write(&mut self, template: &str, packets: &[Vec<u8>]) -> Result<()> {
+        let path = expand(template)?;
+        if let Some(w) = self.writers.remove(template) {
+            if w.path != path {
+                self.close(template, w, false).await?;
+            } else if self.full(w.bytes, w.events) {
+                self.close(template, w, true).await?;
+            } else {
+                self.writers.insert(template.to_string(), w);
+            }
+        } else if let Some(e) = self.evicted.remove(template) {
+            if e.path != path {
+                self.finish(template, e.path, false).await?;
+            } else if self.full(e.bytes, e.events) {
+                self.finish(template, e.path, true).await?;
+            } else {
+                self.evicted.insert(template.to_string(), e);
+            }
+        }
+        if !self.writers.contains_key(template) {
+            // continue counting the events of an evicted file
+            let evicted = self.evicted.remove(template);
+            self.evict().await?;
+            let events = evicted.as_ref().map_or(0, |e| e.events);
+            let mut options = OpenOptions::new();
+            options.create(true);
+            if self.config.append || evicted.is_some() || self.opened.contains(&path) {
+                options.append(true);
+            } else {
+                options.write(true).truncate(true);
+                self.opened.push_back(path.clone());
+                if self.opened.len() > self.max_open_files() {
+                    self.opened.pop_front();
+                }
+            }
+            let file = options.open(&path).await?;
+            let bytes = file.metadata().await?.len();
+            self.writers.insert(
+                template.to_string(),
+                Writer {
+                    path,
+                    file,
+                    bytes,
+                    events,
+                    last_used: 0,
+                },
+            );
+        }
+        let w = self
+            .writers
+            .get_mut(template)
+            .ok_or_else(|| Error::from("file writer vanished"))?;
+        for packet in packets {
+            w.file.write_all(packet).await?;
+            w.file.write_all(b"\n").await?;
+            w.bytes += packet.len() as u64 + 1;
+        }
+        w.file.flush().await?;
+        w.events += 1;
+        w.last_used = nanotime();
+        Ok(())
+    }
+
+    /// Closes files whose name changed since they were opened
+    pub(crate) async fn tick(&mut self) -> Result<()> {
+        let mut done = Vec::new();
+        for (template, w) in &self.writers {
+            if expand(template)? != w.path {
+                done.push(template.clone());
+            }
+        }
+        for template in done {
+            if let Some(w) = self.writers.remove(&template) {
+                self.close(&template, w, false).await?;
+            }
+        }
+        let mut done = Vec::new();
+        for (template, e) in &self.evicted {
+            if expand(template)? != e.path {
+                done.push(template.clone());
+            }
+        }
+        for template in done {
+            if let Some(e) = self.evicted.remove(&template) {
+                self.finish(&template, e.path, false).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes all open files, they are picked up again on the next start
+    pub(crate) async fn flush(&mut self) -> Result<()> {
+        for w in self.writers.values_mut() {
+            w.file.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Closes the least recently used file if too many are open, it is reopened on the next
+    /// write. Beyond `max_open_files` closed files the least recently used one is finished
+    async fn evict(&mut self) -> Result<()> {
+        if self.writers.len() < self.max_open_files() {
+            return Ok(());
+        }
+        let lru = self
+            .writers
+            .iter()
+            .min_by_key(|(_, w)| w.last_used)
+            .map(|(t, _)| t.clone());
+        if let Some((template, mut w)) = lru.and_then(|t| self.writers.remove(&t).map(|w| (t, w))) {
+            w.file.flush().await?;
+            self.evicted.insert(
+                template,
+                Evicted {
+                    path: w.path,
+                    bytes: w.bytes,
+                    events: w.events,
+                    last_used: w.last_used,
+                },
+            );
+        }
+        if self.evicted.len() > self.max_open_files() {
+            let lru = self
+                .evicted
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(t, _)| t.clone());
+            if let Some((template, e)) = lru.and_then(|t| self.evicted.remove(&t).map(|e| (t, e))) {
+                self.finish(&template, e.path, false).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self, template: &str, mut w: Writer, rotate: bool) -> Result<()> {
+        w.file.flush().await?;
+        drop(w.file);
+        self.finish(template, w.path, rotate).await
+    }
+
+    /// A file is done, it is renamed if it is rotated, compressed and the done files of
+    /// `template` beyond `retention` are removed
+    async fn finish(&mut self, template: &str, mut path: String, rotate: bool) -> Result<()> {
+        if rotate {
+            let rotated = format!("{}.{}", path, nanotime());
+            fs::rename(&path, &rotated).await?;
+            path = rotated;
+        }
+        if let Some(compression) = self.config.compression {
+            path = compress(path, compression).await?;
+        }
+        if let Some(retention) = self.config.retention {
+            let (last_ns, done) = self.done.entry(template.to_string()).or_default();
+            *last_ns = nanotime();
+            // the file may be written to again if the template expands to its name later on
+            done.retain(|p| p != &path);
+            done.push_back(path);
+            while done.len() > retention {
+                if let Some(old) = done.pop_front() {
+                    debug!("Removing old file {}", old);
+                    if let Err(e) = fs::remove_file(&old).await {
+                        warn!("Failed to remove old file {}: {}", old, e);
+                    }
+                }
+            }
+            if self.done.len() > self.max_open_files() {
+                // the files of the least recently finished template are left alone from now on
+                let lru = self
+                    .done
+                    .iter()
+                    .min_by_key(|(_, (last_ns, _))| *last_ns)
+                    .map(|(t, _)| t.clone());
+                if let Some(template) = lru {
+                    self.done.remove(&template);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compresses the file at `path`, returns the path of the compressed file
+async fn compress(path: String, compression: Compression) -> Result<String> {
+    task::spawn_blocking(move || -> Result<String> {
+        let target = format!("{}.{}", path, compression.extension());
+        let mut input = std::fs::File::open(&path)?;
+        let output = std::fs::File::create(&target)?;
+        match compression {
+            Compression::Gzip => {
+                let mut encoder = libflate::gzip::Encoder::new(output)?;
+                std::io::copy(&mut input, &mut encoder)?;
+                encoder.finish().into_result()?;
+            }
+            Compression::Zstd => zstd::stream::copy_encode(&mut input, output, 0)?,
+        }
+        std::fs::remove_file(&path)?;
+        Ok(target)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn config() -> Config {
+        Config {
+            max_bytes: None,
+            max_events: Some(2),
+            compression: Some(Compression::Gzip),
+            retention: Some(2),
+            max_open_files: 1,
+            append: false,
+        }
+    }
+
+    fn files(dir: &std::path::Path) -> Result<Vec<String>> {
+        let mut res = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            res.push(entry?.file_name().to_string_lossy().to_string());
+        }
+        res.sort();
+        Ok(res)
+    }
+
+    #[test]
+    fn templates() -> Result<()> {
+        assert_eq!("out.log", expand("out.log")?);
+        assert_eq!(4, expand("%Y")?.len());
+        assert!(expand("%Q").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn confined() -> Result<()> {
+        assert_eq!("logs/app.log", confine("logs/out.log", "app.log")?);
+        assert_eq!("logs/a/%Y.log", confine("logs/out.log", "./a/%Y.log")?);
+        assert_eq!("app.log", confine("out.log", "app.log")?);
+        assert!(confine("logs/out.log", "/etc/passwd").is_err());
+        assert!(confine("logs/out.log", "../out.log").is_err());
+        assert!(confine("logs/out.log", "a/../../out.log").is_err());
+        assert!(confine("logs/out.log", "").is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn rotate_compress_retain() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("out.log").to_string_lossy().to_string();
+        let other = dir.path().join("other.log").to_string_lossy().to_string();
+        // files we didn't write ourselves are never removed
+        std::fs::write(dir.path().join("out.log.bak"), "snot")?;
+        std::fs::write(dir.path().join("out.log.1.gz"), "snot")?;
+        let mut writers = Writers::new(config());
+        for i in 0..7_u8 {
+            writers.write(&path, &[vec![b'a' + i]]).await?;
+        }
+        // only one file may be open, so this closes `out.log` without rotating it
+        writers.write(&other, &[b"snot".to_vec()]).await?;
+        writers.flush().await?;
+
+        let names = files(dir.path())?;
+        assert_eq!(6, names.len(), "{:?}", names);
+        assert!(names.contains(&"out.log.bak".to_string()));
+        assert!(names.contains(&"out.log.1.gz".to_string()));
+        assert!(names.contains(&"other.log".to_string()));
+        assert!(names.contains(&"out.log".to_string()));
+        assert_eq!(
+            2,
+            names
+                .iter()
+                .filter(|n| n.starts_with("out.log.") && n.len() > 16 && n.ends_with(".gz"))
+                .count()
+        );
+        assert_eq!("g\n", std::fs::read_to_string(&path)?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn evicted_event_counts() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("out.log").to_string_lossy().to_string();
+        let other = dir.path().join("other.log").to_string_lossy().to_string();
+        let mut writers = Writers::new(Config {
+            compression: None,
+            retention: None,
+            ..config()
+        });
+        writers.write(&path, &[b"a".to_vec()]).await?;
+        // evicts `out.log`, its count of events is kept
+        writers.write(&other, &[b"snot".to_vec()]).await?;
+        writers.write(&path, &[b"b".to_vec()]).await?;
+        writers.write(&path, &[b"c".to_vec()]).await?;
+        writers.flush().await?;
+
+        assert_eq!("c\n", std::fs::read_to_string(&path)?);
+        let rotated: Vec<String> = files(dir.path())?
+            .into_iter()
+            .filter(|n| n.starts_with("out.log."))
+            .collect();
+        assert_eq!(1, rotated.len());
+        assert_eq!(
+            "a\nb\n",
+            std::fs::read_to_string(dir.path().join(&rotated[0]))?
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn bounded() -> Result<()> {
+        let dir = tempdir()?;
+        let mut writers = Writers::new(Config {
+            compression: None,
+            retention: Some(1),
+            ..config()
+        });
+        for name in ["a.log", "b.log", "c.log", "a.log"] {
+            let path = dir.path().join(name).to_string_lossy().to_string();
+            writers.write(&path, &[b"snot".to_vec()]).await?;
+        }
+        // closed files beyond `max_open_files` are finished, `a.log` and then `b.log`
+        assert_eq!(1, writers.writers.len());
+        assert_eq!(1, writers.evicted.len());
+        assert_eq!(1, writers.opened.len());
+        assert_eq!(1, writers.done.len());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn append() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("out-%Y.log").to_string_lossy().to_string();
+        let other = dir.path().join("other.log").to_string_lossy().to_string();
+        let config = Config {
+            max_events: None,
+            compression: None,
+            retention: None,
+            ..config()
+        };
+        std::fs::write(expand(&path)?, "snot\n")?;
+        let mut writers = Writers::new(config.clone());
+        writers.write(&path, &[b"a".to_vec()]).await?;
+        // evicts `out-<year>.log`, reopening it must not truncate it again
+        writers.write(&other, &[b"snot".to_vec()]).await?;
+        writers.write(&path, &[b"b".to_vec()]).await?;
+        writers.flush().await?;
+        assert_eq!("a\nb\n", std::fs::read_to_string(expand(&path)?)?);
+
+        let mut writers = Writers::new(Config {
+            append: true,
+            ..config
+        });
+        writers.write(&path, &[b"c".to_vec()]).await?;
+        writers.flush().await?;
+        assert_eq!("a\nb\nc\n", std::fs::read_to_string(expand(&path)?)?);
+        Ok(())
+    }
+}
@@ -12,9 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! # File Offramp
+//! # Google Cloud Storage Offramp
 //!
-//! Writes events to a file, one event per line
+//! Executes storage commands (`upload_object`, `fetch`, ...) contained in events.
+//!
+//! If `parquet` is configured, events are instead batched into parquet files
+//! that are uploaded to `bucket` as `<prefix><timestamp>.parquet`. Events are
+//! only acked once the file holding them was uploaded.
 //!
 //! ## Configuration
 //!
@@ -22,6 +26,7 @@
 
 #![cfg(not(tarpaulin_include))]
 
+use crate::connectors::columnar::{self, Finished, ParquetBatcher};
 use crate::connectors::gcp::{
     auth::{self, GcsClient},
     storage,
@@ -43,6 +48,27 @@ pub struct GoogleCloudStorage {
     postprocessors: Postprocessors,
     sink_url: TremorUrl,
     event_id_gen: EventIdGenerator,
+    parquet: Option<(ParquetUpload, ParquetBatcher)>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct Config {
+    /// Upload events as parquet files instead of interpreting them as commands
+    #[serde(default)]
+    pub(crate) parquet: Option<ParquetUpload>,
+}
+
+impl ConfigImpl for Config {}
+
+#[derive(Deserialize, Clone)]
+pub(crate) struct ParquetUpload {
+    /// Bucket to upload parquet files to
+    pub bucket: String,
+    /// Prefix of the uploaded object names
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(flatten)]
+    pub writer: columnar::Config,
 }
 
 enum StorageCommand {
@@ -78,7 +104,18 @@ impl std::fmt::Display for StorageCommand {
 }
 
 impl offramp::Impl for GoogleCloudStorage {
-    fn from_config(_config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        let config = config
+            .as_ref()
+            .map(Config::new)
+            .transpose()?
+            .unwrap_or_default();
+        let parquet = if let Some(upload) = config.parquet {
+            let batcher = ParquetBatcher::new(upload.writer.clone())?;
+            Some((upload, batcher))
+        } else {
+            None
+        };
         let headers = HeaderMap::new();
         let remote = Some(auth::json_api_client(&headers)?);
         let hostport = "storage.googleapis.com:443";
@@ -92,6 +129,7 @@ impl offramp::Impl for GoogleCloudStorage {
             postprocessors: vec![],
             sink_url: TremorUrl::from_offramp_id("gcs")?,
             event_id_gen: EventIdGenerator::new(0), // Fake ID overwritten in init
+            parquet,
         }))
     }
 }
@@ -134,9 +172,59 @@ fn parse_command(value: &Value) -> Result<StorageCommand> {
     Ok(command)
 }
 
+impl GoogleCloudStorage {
+    /// Uploads a finished parquet file and returns the insight for its events
+    async fn flush_parquet(&mut self, finished: Finished) -> Option<sink::Reply> {
+        let res = match finished.data {
+            Ok(data) => {
+                let res = self.upload_parquet(data).await;
+                self.is_down = res.is_err();
+                res
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &res {
+            error!(
+                "Google Cloud Storage - failed to upload parquet file: {}",
+                e
+            );
+        }
+        finished
+            .events
+            .insight(res.is_ok())
+            .map(sink::Reply::Insight)
+    }
+
+    async fn upload_parquet(&mut self, data: Vec<u8>) -> Result<()> {
+        if self.remote.is_none() {
+            self.remote = Some(auth::json_api_client(&HeaderMap::new())?);
+        }
+        let remote = self.remote.as_ref().ok_or("Client error!")?;
+        if let Some((upload, _)) = &self.parquet {
+            let object = format!("{}{}.parquet", upload.prefix, nanotime());
+            storage::add_object_with_slice(remote, &upload.bucket, &object, data).await?;
+        }
+        Ok(())
+    }
+}
+
 #[async_trait::async_trait]
 impl Sink for GoogleCloudStorage {
-    async fn terminate(&mut self) {}
+    async fn terminate(&mut self) {
+        let finished = self
+            .parquet
+            .as_mut()
+            .and_then(|(_, batcher)| batcher.finish());
+        if let Some(finished) = finished {
+            if let Some(reply) = self.flush_parquet(finished).await {
+                if let Some(reply_channel) = &self.reply_channel {
+                    if reply_channel.send(reply).await.is_err() {
+                        error!("Google Cloud Storage - failed to send the insight for the last parquet file");
+                    }
+                }
+            }
+        }
+    }
 
     #[allow(clippy::too_many_lines)]
     async fn on_event(
@@ -146,6 +234,31 @@ impl Sink for GoogleCloudStorage {
         _codec_map: &HashMap<String, Box<dyn Codec>>,
         mut event: Event,
     ) -> ResultVec {
+        if let Some((_, batcher)) = &mut self.parquet {
+            // acks are sent once the file holding the event was uploaded
+            return match batcher.push(&event) {
+                Ok(Some(finished)) => {
+                    let mut replies: Vec<_> =
+                        self.flush_parquet(finished).await.into_iter().collect();
+                    if self.is_down {
+                        replies.push(qos::close(&mut event));
+                    }
+                    Ok(Some(replies))
+                }
+                Ok(None) => Ok(None),
+                // nothing to upload
+                Err(_) if event.value_iter().next().is_none() => {
+                    Ok(Some(vec![qos::ack(&mut event)]))
+                }
+                Err(e) => {
+                    error!(
+                        "Google Cloud Storage - event doesn't fit the parquet schema: {}",
+                        e
+                    );
+                    Ok(Some(vec![qos::fail(&mut event)]))
+                }
+            };
+        }
         let remote = if let Some(remote) = &self.remote {
             remote
         } else {
@@ -299,6 +412,18 @@ impl Sink for GoogleCloudStorage {
     }
 
     async fn on_signal(&mut self, mut signal: Event) -> ResultVec {
+        let finished = self
+            .parquet
+            .as_mut()
+            .filter(|(_, batcher)| batcher.is_due(signal.ingest_ns))
+            .and_then(|(_, batcher)| batcher.finish());
+        if let Some(finished) = finished {
+            let mut replies: Vec<_> = self.flush_parquet(finished).await.into_iter().collect();
+            if self.is_down {
+                replies.push(qos::close(&mut signal));
+            }
+            return Ok(Some(replies));
+        }
         if self.is_down && self.qos_facility.probe(signal.ingest_ns) {
             self.is_down = false;
             // This means the port is connectable
@@ -18,6 +18,20 @@
 //!
 //! The `kafka` offramp allows persisting events to a kafka queue.
 //!
+//! The key, timestamp and headers of messages are taken from `$kafka.key`, `$kafka.timestamp`
+//! and `$kafka.headers` if set. Only if no `topic` is configured, the topic and partition are
+//! taken from `$kafka.topic` and `$kafka.partition`. Events from the `kafka` onramp carry
+//! these for the message they were read from, so an offramp without a `topic` produces to the
+//! topic and partition the event was read from unless the pipeline sets them.
+//!
+//! With a `transactional_id` messages are produced in transactions of up to
+//! `transaction_size` events, committed at the latest after `transaction_timeout_ms`. The
+//! events of a transaction are acknowledged once it is committed. If `consumer_group` is set
+//! the offsets of the messages the events were read from by a `kafka` onramp of this group
+//! are committed as part of the transaction, so with `isolation.level` set to
+//! `read_committed` for consumers downstream, pipelines between kafka topics process every
+//! message exactly once.
+//!
 //! ## Configuration
 //!
 //! See [Config](struct.Config.html) for details.
@@ -26,24 +40,32 @@ use crate::sink::prelude::*;
 use async_channel::{bounded, Receiver, Sender};
 use halfbrown::HashMap;
 use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, Consumer, ConsumerGroupMetadata};
 use rdkafka::error::RDKafkaError;
 use rdkafka::producer::Producer;
 use rdkafka::{
-    error::KafkaError,
+    error::{KafkaError, KafkaResult},
     message::OwnedHeaders,
     producer::{FutureProducer, FutureRecord},
+    Offset, TopicPartitionList,
 };
+use std::collections::HashMap as StdMap;
 use std::{
     fmt,
     time::{Duration, Instant},
 };
+use tremor_pipeline::{EventId, OpMeta};
+
+/// Wait this long for transactions to be initialized, committed or aborted
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Deserialize)]
 pub struct Config {
     /// list of brokers
     pub brokers: Vec<String>,
-    /// the topic to send to
-    pub topic: String,
+    /// the topic to send to, if not set it is taken from `$kafka.topic`
+    #[serde(default = "Default::default")]
+    pub topic: Option<String>,
     /// a map (string keys and string values) of [librdkafka options](https://github.com/edenhill/librdkafka/blob/master/CONFIGURATION.md) (default: None) - Note this can overwrite default settings.
     ///
     /// Default settings for librdkafka:
@@ -57,12 +79,27 @@ pub struct Config {
     /// hostname to use, defaults to the hostname of the system
     #[serde(default = "d_host")]
     pub hostname: String,
-    /// key to use for messages, defaults to none
+    /// key to use for messages if `$kafka.key` isn't set, defaults to none
     #[serde(default = "Default::default")]
     pub key: Option<String>,
+    /// transactional id, enables producing messages in transactions
+    #[serde(default = "Default::default")]
+    pub transactional_id: Option<String>,
+    /// maximum number of events per transaction (default: 100)
+    #[serde(default = "d_transaction_size")]
+    pub transaction_size: usize,
+    /// commit transactions at the latest after this long (default: 1000)
+    #[serde(default = "d_transaction_timeout_ms")]
+    pub transaction_timeout_ms: u64,
+    /// consumer group of the `kafka` onramp the events are read by, to commit their offsets
+    /// in transactions
+    #[serde(default = "Default::default")]
+    pub consumer_group: Option<String>,
 }
 
 impl Config {
+    /// Creates the producer, transactions still need to be initialized with
+    /// `init_transactions`
     fn producer(&self) -> Result<FutureProducer> {
         let mut producer_config = ClientConfig::new();
 
@@ -75,12 +112,29 @@ impl Config {
             .set("message.timeout.ms", "5000")
             .set("queue.buffering.max.ms", "0"); // set to 0 for sending each message out immediately without kafka client internal batching --> low latency, busy network
 
+        if let Some(transactional_id) = &self.transactional_id {
+            producer_config.set("transactional.id", transactional_id);
+        }
+
         Ok(self
             .rdkafka_options
             .iter()
             .fold(producer_config, |c: &mut ClientConfig, (k, v)| c.set(k, v))
             .create()?)
     }
+
+    /// The metadata of `consumer_group`, to commit offsets in transactions
+    fn group_metadata(&self) -> Result<Option<ConsumerGroupMetadata>> {
+        if let Some(group) = &self.consumer_group {
+            let consumer: BaseConsumer = ClientConfig::new()
+                .set("group.id", group)
+                .set("bootstrap.servers", &self.brokers.join(","))
+                .create()?;
+            Ok(consumer.group_metadata())
+        } else {
+            Ok(None)
+        }
+    }
 }
 
 impl ConfigImpl for Config {}
@@ -89,11 +143,156 @@ fn d_host() -> String {
     hostname()
 }
 
+fn d_transaction_size() -> usize {
+    100
+}
+
+fn d_transaction_timeout_ms() -> u64 {
+    1000
+}
+
+/// A message to produce
+struct Message {
+    topic: String,
+    key: Option<Vec<u8>>,
+    partition: Option<i32>,
+    timestamp: Option<i64>,
+    headers: Option<OwnedHeaders>,
+    payload: Vec<u8>,
+}
+
+impl Message {
+    fn record(&mut self) -> FutureRecord<'_, [u8], [u8]> {
+        let mut record = FutureRecord::to(self.topic.as_str()).payload(self.payload.as_slice());
+        if let Some(key) = &self.key {
+            record = record.key(key.as_slice());
+        }
+        if let Some(partition) = self.partition {
+            record = record.partition(partition);
+        }
+        if let Some(timestamp) = self.timestamp {
+            record = record.timestamp(timestamp);
+        }
+        if let Some(headers) = self.headers.take() {
+            record = record.headers(headers);
+        }
+        record
+    }
+}
+
+/// The topic, partition and offset of the message an event was read from by the `kafka`
+/// onramp
+fn source_offset(event: &Event) -> Option<(String, i32, i64)> {
+    let origin_uri = event.origin_uri.as_ref()?;
+    if origin_uri.scheme != "tremor-kafka" {
+        return None;
+    }
+    match origin_uri.path.as_slice() {
+        [topic, partition, offset] => {
+            Some((topic.clone(), partition.parse().ok()?, offset.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// The events of an open transaction
+struct Transaction {
+    started_ns: u64,
+    events: usize,
+    ingest_ns: u64,
+    id: Option<EventId>,
+    op_meta: OpMeta,
+    /// offsets of the next messages to read by the consumer group
+    offsets: StdMap<(String, i32), Offset>,
+}
+
+impl Transaction {
+    fn new(ingest_ns: u64) -> Self {
+        Self {
+            started_ns: nanotime(),
+            events: 0,
+            ingest_ns,
+            id: None,
+            op_meta: OpMeta::default(),
+            offsets: StdMap::new(),
+        }
+    }
+
+    fn add(&mut self, event: &Event) {
+        self.events += 1;
+        if event.transactional {
+            match self.id.as_mut() {
+                Some(id) => id.track(&event.id),
+                None => self.id = Some(event.id.clone()),
+            }
+            self.op_meta.merge(event.op_meta.clone());
+        }
+        if let Some((topic, partition, offset)) = source_offset(event) {
+            let next = Offset::Offset(offset + 1);
+            let current = self.offsets.entry((topic, partition)).or_insert(next);
+            if let (Offset::Offset(current), Offset::Offset(next)) = (current, next) {
+                *current = (*current).max(next);
+            }
+        }
+    }
+
+    fn insight(self, cb: CbAction) -> Option<sink::Reply> {
+        let mut insight = match cb {
+            CbAction::Ack => Event::cb_ack(self.ingest_ns, self.id?),
+            _ => Event::cb_fail(self.ingest_ns, self.id?),
+        };
+        insight.op_meta = self.op_meta;
+        Some(sink::Reply::Insight(insight))
+    }
+}
+
+/// Initializes transactions of a transactional producer, this blocks so it is run on
+/// a blocking task
+async fn init_transactions(producer: &FutureProducer) -> KafkaResult<()> {
+    let producer = producer.clone();
+    task::spawn_blocking(move || producer.init_transactions(TRANSACTION_TIMEOUT)).await
+}
+
+/// Aborts the open transaction, this blocks so it is run on a blocking task
+async fn abort_transaction(producer: &FutureProducer) -> KafkaResult<()> {
+    let producer = producer.clone();
+    task::spawn_blocking(move || producer.abort_transaction(TRANSACTION_TIMEOUT)).await
+}
+
+/// Commits the open transaction together with `offsets` for the consumer group, this blocks
+/// so it is run on a blocking task. Hands back the group metadata.
+async fn commit_transaction(
+    producer: &FutureProducer,
+    group_metadata: Option<ConsumerGroupMetadata>,
+    offsets: StdMap<(String, i32), Offset>,
+) -> (KafkaResult<()>, Option<ConsumerGroupMetadata>) {
+    let producer = producer.clone();
+    task::spawn_blocking(move || {
+        let committed = || -> KafkaResult<()> {
+            if let Some(group_metadata) = group_metadata.as_ref() {
+                if !offsets.is_empty() {
+                    let offsets = TopicPartitionList::from_topic_map(&offsets)?;
+                    producer.send_offsets_to_transaction(
+                        &offsets,
+                        group_metadata,
+                        TRANSACTION_TIMEOUT,
+                    )?;
+                }
+            }
+            producer.commit_transaction(TRANSACTION_TIMEOUT)
+        };
+        (committed(), group_metadata)
+    })
+    .await
+}
+
 /// Kafka offramp connectoz
 pub struct Kafka {
     sink_url: TremorUrl,
     config: Config,
     producer: FutureProducer,
+    group_metadata: Option<ConsumerGroupMetadata>,
+    transaction: Option<Transaction>,
     postprocessors: Postprocessors,
     reply_tx: Sender<sink::Reply>,
     error_rx: Receiver<RDKafkaError>,
@@ -102,7 +301,11 @@ pub struct Kafka {
 
 impl fmt::Debug for Kafka {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "[Sink::{}] Kafka: {}", &self.sink_url, self.config.topic)
+        write!(
+            f,
+            "[Sink::{}] Kafka: {:?}",
+            &self.sink_url, self.config.topic
+        )
     }
 }
 
@@ -111,6 +314,7 @@ impl offramp::Impl for Kafka {
         if let Some(config) = config {
             let config: Config = Config::new(config)?;
             let producer = config.producer()?;
+            let group_metadata = config.group_metadata()?;
             // Create the thread pool where the expensive computation will be performed.
             let (dummy_tx, _) = bounded(1);
 
@@ -120,6 +324,8 @@ impl offramp::Impl for Kafka {
                 sink_url: TremorUrl::from_offramp_id("kafka")?, // dummy
                 config,
                 producer,
+                group_metadata,
+                transaction: None,
                 postprocessors: vec![],
                 reply_tx: dummy_tx,
                 error_rx,
@@ -195,19 +401,26 @@ async fn wait_for_delivery(
 }
 
 impl Kafka {
-    fn drain_fatal_errors(&mut self) -> Result<()> {
+    async fn drain_fatal_errors(&mut self) -> Result<()> {
         let mut handled = false;
         while let Ok(e) = self.error_rx.try_recv() {
             if !handled {
                 // only handle on first fatal error
-                self.handle_fatal_error(&e)?;
+                self.handle_fatal_error(&e).await?;
                 handled = true;
             }
         }
         Ok(())
     }
 
-    fn handle_fatal_error(&mut self, fatal_error: &RDKafkaError) -> Result<()> {
+    async fn init_transactions(&self) -> Result<()> {
+        if self.config.transactional_id.is_some() {
+            init_transactions(&self.producer).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_fatal_error(&mut self, fatal_error: &RDKafkaError) -> Result<()> {
         error!(
             "[Sink::{}] Fatal Error({:?}): {}",
             &self.sink_url,
@@ -217,10 +430,136 @@ impl Kafka {
 
         error!("[Sink::{}] Reinitiating client...", &self.sink_url);
         self.producer = self.config.producer()?;
+        self.init_transactions().await?;
         error!("[Sink::{}] Client reinitiated.", &self.sink_url);
 
         Ok(())
     }
+
+    /// Builds the messages for an event
+    fn messages(&mut self, codec: &mut dyn Codec, event: &Event) -> Result<Vec<Message>> {
+        let mut res = Vec::with_capacity(event.len());
+        for (value, meta) in event.value_meta_iter() {
+            let kafka = meta.get("kafka");
+            // the metadata of events from the `kafka` onramp names the topic and partition
+            // they were read from, so it is only used if we have no topic of our own
+            let (topic, partition) = match &self.config.topic {
+                Some(topic) => (topic.clone(), None),
+                None => (
+                    kafka
+                        .and_then(|k| k.get_str("topic"))
+                        .ok_or_else(|| {
+                            Error::from("No `topic` configured and no `$kafka.topic` set")
+                        })?
+                        .to_string(),
+                    kafka.and_then(|k| k.get_i32("partition")),
+                ),
+            };
+            let key = match kafka.and_then(|k| k.get_bytes("key")) {
+                Some(key) => Some(key.to_vec()),
+                None => self.config.key.as_ref().map(|k| k.as_bytes().to_vec()),
+            };
+            let timestamp = kafka.and_then(|k| k.get_i64("timestamp"));
+            let headers = kafka.and_then(|k| k.get_object("headers")).map(|h| {
+                let mut headers = OwnedHeaders::new_with_capacity(h.len());
+                for (key, val) in h.iter() {
+                    if let Some(val) = val.as_bytes() {
+                        headers = headers.add(key, val);
+                    }
+                }
+                headers
+            });
+            let encoded = codec.encode(value)?;
+            let processed =
+                postprocess(self.postprocessors.as_mut_slice(), event.ingest_ns, encoded)?;
+            for payload in processed {
+                res.push(Message {
+                    topic: topic.clone(),
+                    key: key.clone(),
+                    partition,
+                    timestamp,
+                    headers: headers.clone(),
+                    payload,
+                });
+            }
+        }
+        Ok(res)
+    }
+
+    async fn handle_transaction_error(&mut self, e: &KafkaError) -> Result<()> {
+        error!("[Sink::{}] Transaction failed: {}", self.sink_url, e);
+        if let KafkaError::Transaction(rd_error) = e {
+            if rd_error.is_fatal() {
+                return self.handle_fatal_error(rd_error).await;
+            }
+        }
+        if let Err(e) = abort_transaction(&self.producer).await {
+            error!(
+                "[Sink::{}] Failed to abort transaction: {}",
+                self.sink_url, e
+            );
+        }
+        Ok(())
+    }
+
+    /// Commits the open transaction, together with the offsets of the messages its events
+    /// were read from
+    async fn commit(&mut self) -> Result<Option<sink::Reply>> {
+        let mut transaction = match self.transaction.take() {
+            Some(transaction) => transaction,
+            None => return Ok(None),
+        };
+        let offsets = std::mem::take(&mut transaction.offsets);
+        let (committed, group_metadata) =
+            commit_transaction(&self.producer, self.group_metadata.take(), offsets).await;
+        self.group_metadata = group_metadata;
+        match committed {
+            Ok(()) => Ok(transaction.insight(CbAction::Ack)),
+            Err(e) => {
+                self.handle_transaction_error(&e).await?;
+                Ok(transaction.insight(CbAction::Fail))
+            }
+        }
+    }
+
+    /// Produces the messages of an event in the open transaction
+    async fn on_transactional_event(&mut self, messages: Vec<Message>, event: &Event) -> ResultVec {
+        if self.transaction.is_none() {
+            if let Err(e) = self.producer.begin_transaction() {
+                self.handle_transaction_error(&e).await?;
+                let mut transaction = Transaction::new(event.ingest_ns);
+                transaction.add(event);
+                return Ok(transaction.insight(CbAction::Fail).map(|r| vec![r]));
+            }
+            self.transaction = Some(Transaction::new(event.ingest_ns));
+        }
+        if let Some(transaction) = self.transaction.as_mut() {
+            transaction.add(event);
+        }
+        for mut message in messages {
+            // delivery is checked when committing, no need to wait for it here
+            if let Err((e, _)) = self.producer.send_result(message.record()) {
+                error!(
+                    "[Sink::{}] failed to enqueue message: {}",
+                    &self.sink_url, e
+                );
+                self.handle_transaction_error(&e).await?;
+                let failed = self.transaction.take();
+                return Ok(failed
+                    .and_then(|t| t.insight(CbAction::Fail))
+                    .map(|r| vec![r]));
+            }
+        }
+        let full = self
+            .transaction
+            .as_ref()
+            .map_or(false, |t| t.events >= self.config.transaction_size);
+        if full {
+            Ok(self.commit().await?.map(|r| vec![r]))
+        } else {
+            Ok(None)
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -233,65 +572,46 @@ impl Sink for Kafka {
         mut event: Event,
     ) -> ResultVec {
         // ensure we handle any fatal errors occured during last on_event invocation
-        self.drain_fatal_errors()?;
+        self.drain_fatal_errors().await?;
 
-        let ingest_ns = event.ingest_ns;
-        let mut delivery_futures = Vec::with_capacity(event.len()); // might not be enough
-        let processing_start = Instant::now();
-        for (value, meta) in event.value_meta_iter() {
-            let encoded = codec.encode(value)?;
-            let processed = postprocess(self.postprocessors.as_mut_slice(), ingest_ns, encoded)?;
-            let meta_kafka_data = meta.get_object("kafka");
-            let mut meta_kafka_key = None;
-            let mut meta_kafka_headers = None;
-            if let Some(meta_data) = meta_kafka_data {
-                meta_kafka_key = meta_data.get("key");
-                meta_kafka_headers = meta_data.get("headers");
+        let messages = match self.messages(codec, &event) {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("[Sink::{}] Invalid event: {}", self.sink_url, e);
+                if event.transactional {
+                    return Ok(Some(vec![sink::Reply::Insight(event.to_fail())]));
+                }
+                return Ok(None);
             }
-            for payload in processed {
-                // TODO: allow defining partition and timestamp in meta
-                let mut record = FutureRecord::to(self.config.topic.as_str());
-                record = record.payload(&payload);
-                if let Some(kafka_key) = meta_kafka_key {
-                    if let Some(kafka_key_str) = kafka_key.as_str() {
-                        record = record.key(kafka_key_str);
-                    }
-                } else if let Some(kafka_key) = &self.config.key {
-                    record = record.key(kafka_key.as_str());
+        };
+        if self.config.transactional_id.is_some() {
+            return self.on_transactional_event(messages, &event).await;
+        }
+
+        let mut delivery_futures = Vec::with_capacity(messages.len());
+        let processing_start = Instant::now();
+        for mut message in messages {
+            // send out without blocking on delivery
+            match self.producer.send_result(message.record()) {
+                Ok(delivery_future) => {
+                    delivery_futures.push(delivery_future);
                 }
-                if let Some(kafka_headers) = meta_kafka_headers {
-                    if let Some(headers_obj) = kafka_headers.as_object() {
-                        let mut headers = OwnedHeaders::new_with_capacity(headers_obj.len());
-                        for (key, val) in headers_obj.iter() {
-                            if let Some(val_str) = val.as_str() {
-                                headers = headers.add(key, val_str);
-                            }
+                Err((e, _)) => {
+                    error!(
+                        "[Sink::{}] failed to enqueue message: {}",
+                        &self.sink_url, e
+                    );
+                    if let KafkaError::Transaction(e) = e {
+                        if e.is_fatal() {
+                            // handle fatal errors right here, without enqueueing
+                            self.handle_fatal_error(&e).await?;
                         }
-                        record = record.headers(headers);
-                    }
-                }
-                // send out without blocking on delivery
-                match self.producer.send_result(record) {
-                    Ok(delivery_future) => {
-                        delivery_futures.push(delivery_future);
                     }
-                    Err((e, _)) => {
-                        error!(
-                            "[Sink::{}] failed to enqueue message: {}",
-                            &self.sink_url, e
-                        );
-                        if let KafkaError::Transaction(e) = e {
-                            if e.is_fatal() {
-                                // handle fatal errors right here, without enqueueing
-                                self.handle_fatal_error(&e)?;
-                            }
-                        }
-                        // bail out with a CB fail on enqueue error
-                        if event.transactional {
-                            return Ok(Some(vec![sink::Reply::Insight(event.to_fail())]));
-                        }
-                        return Ok(None);
+                    // bail out with a CB fail on enqueue error
+                    if event.transactional {
+                        return Ok(Some(vec![sink::Reply::Insight(event.to_fail())]));
                     }
+                    return Ok(None);
                 }
             }
         }
@@ -330,11 +650,19 @@ impl Sink for Kafka {
         self.postprocessors = make_postprocessors(processors.post)?;
         self.reply_tx = reply_channel;
         self.sink_url = sink_url.clone();
-        Ok(())
+        self.init_transactions().await
     }
-    async fn on_signal(&mut self, _signal: Event) -> ResultVec {
-        self.drain_fatal_errors()?;
-        Ok(None)
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        self.drain_fatal_errors().await?;
+        let timeout_ns = self.config.transaction_timeout_ms * 1_000_000;
+        let expired = self.transaction.as_ref().map_or(false, |t| {
+            signal.ingest_ns.saturating_sub(t.started_ns) >= timeout_ns
+        });
+        if expired {
+            Ok(self.commit().await?.map(|r| vec![r]))
+        } else {
+            Ok(None)
+        }
     }
     fn is_active(&self) -> bool {
         true
@@ -343,6 +671,13 @@ impl Sink for Kafka {
         false
     }
     async fn terminate(&mut self) {
+        match self.commit().await {
+            Ok(Some(sink::Reply::Insight(e))) if e.cb == CbAction::Fail => {
+                error!("[Sink::{}] Lost events on shutdown", self.sink_url);
+            }
+            Err(e) => error!("[Sink::{}] Failed to commit: {}", self.sink_url, e),
+            _ => (),
+        }
         if self.producer.in_flight_count() > 0 {
             // wait a second in order to flush messages.
             let wait_secs = 1;
@@ -355,3 +690,129 @@ impl Sink for Kafka {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use simd_json::OwnedValue;
+    use tremor_value::literal;
+
+    fn config(topic: Option<&str>) -> Config {
+        Config {
+            brokers: vec!["localhost:0".to_string()],
+            topic: topic.map(ToString::to_string),
+            rdkafka_options: HashMap::new(),
+            hostname: "snot".to_string(),
+            key: None,
+            transactional_id: None,
+            transaction_size: d_transaction_size(),
+            transaction_timeout_ms: d_transaction_timeout_ms(),
+            consumer_group: None,
+        }
+    }
+
+    fn kafka(config: Config) -> Result<Kafka> {
+        let (reply_tx, _) = bounded(1);
+        let (error_tx, error_rx) = bounded(1);
+        Ok(Kafka {
+            sink_url: TremorUrl::from_offramp_id("kafka")?,
+            producer: config.producer()?,
+            config,
+            group_metadata: None,
+            transaction: None,
+            postprocessors: vec![],
+            reply_tx,
+            error_rx,
+            error_tx,
+        })
+    }
+
+    fn event(id: u64, path: &[&str]) -> Event {
+        Event {
+            id: EventId::new(0, 0, id),
+            transactional: true,
+            origin_uri: Some(EventOriginUri {
+                uid: 0,
+                scheme: "tremor-kafka".to_string(),
+                host: "localhost".to_string(),
+                port: None,
+                path: path.iter().map(ToString::to_string).collect(),
+            }),
+            ..Event::default()
+        }
+    }
+
+    #[test]
+    fn transaction_offsets() {
+        let mut transaction = Transaction::new(0);
+        transaction.add(&event(1, &["snot", "0", "41"]));
+        transaction.add(&event(2, &["snot", "0", "40"]));
+        transaction.add(&event(3, &["snot", "1", "7"]));
+        transaction.add(&event(4, &["badger"]));
+        assert_eq!(4, transaction.events);
+        assert_eq!(
+            Some(&Offset::Offset(42)),
+            transaction.offsets.get(&("snot".to_string(), 0))
+        );
+        assert_eq!(
+            Some(&Offset::Offset(8)),
+            transaction.offsets.get(&("snot".to_string(), 1))
+        );
+        assert_eq!(2, transaction.offsets.len());
+        assert!(matches!(
+            transaction.insight(CbAction::Ack),
+            Some(sink::Reply::Insight(Event {
+                cb: CbAction::Ack,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn topic_from_meta_only_without_config() -> Result<()> {
+        let mut codec = crate::codec::lookup("json")?;
+        let meta = literal!({ "kafka": { "topic": "badger", "partition": 3, "key": "k" } });
+        let event = Event {
+            data: (Value::from("snot"), meta).into(),
+            ..Event::default()
+        };
+
+        let mut configured = kafka(config(Some("snot")))?;
+        let messages = configured.messages(codec.as_mut(), &event)?;
+        assert_eq!("snot", messages[0].topic);
+        assert_eq!(None, messages[0].partition);
+        assert_eq!(Some(b"k".to_vec()), messages[0].key);
+
+        let mut unconfigured = kafka(config(None))?;
+        let messages = unconfigured.messages(codec.as_mut(), &event)?;
+        assert_eq!("badger", messages[0].topic);
+        assert_eq!(Some(3), messages[0].partition);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn failed_commit() -> Result<()> {
+        // the producer isn't transactional, so committing fails right away
+        let mut kafka = kafka(config(Some("snot")))?;
+        assert!(kafka.commit().await?.is_none());
+
+        let mut transaction = Transaction::new(0);
+        let mut e = event(1, &["snot", "0", "41"]);
+        e.op_meta.insert(42, OwnedValue::null());
+        transaction.add(&e);
+        transaction.add(&event(2, &["snot", "0", "42"]));
+        kafka.transaction = Some(transaction);
+
+        match kafka.commit().await? {
+            Some(sink::Reply::Insight(insight)) => {
+                assert_eq!(CbAction::Fail, insight.cb);
+                assert!(insight.op_meta.contains_key(42));
+                assert!(insight.id.is_tracking(&EventId::new(0, 0, 1)));
+                assert!(insight.id.is_tracking(&EventId::new(0, 0, 2)));
+            }
+            _ => return Err("expected a fail insight".into()),
+        }
+        assert!(kafka.transaction.is_none());
+        Ok(())
+    }
+}
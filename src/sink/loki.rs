@@ -0,0 +1,539 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Grafana Loki Offramp
+//!
+//! Batches events into Loki push requests, either snappy compressed protocol buffers
+//! (the default) or JSON.
+//!
+//! Events are grouped into streams by their labels, the configured `labels` merged with
+//! `$loki.labels`. Strings are sent as they are, all other values as JSON. The timestamp
+//! of an entry is `$loki.timestamp` in nanoseconds, or the ingest time of the event.
+//! Loki rejects out of order entries, so entries are sorted per stream and never go back
+//! in time compared to what was sent to the stream before.
+//!
+//! Events are acknowledged once the batch they are part of has been accepted by Loki.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::connectors::batch::{self, Batch};
+use crate::sink::prelude::*;
+use halfbrown::HashMap;
+use http_types::headers::{CONTENT_ENCODING, CONTENT_TYPE};
+use prost::Message;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// snappy compressed protocol buffers
+    Protobuf,
+    Json,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Self::Protobuf
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// Push endpoint, e.g. `http://localhost:3100/loki/api/v1/push`
+    pub url: String,
+    /// `protobuf` (default) or `json`
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// Labels added to every stream, `$loki.labels` takes precedence
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Tenant to send as `X-Scope-OrgID` in multi tenant setups
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// Additional headers to send, e.g. for authentication
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// `batch_size` (entries per request) and `flush_interval_ms`
+    #[serde(flatten)]
+    pub batch: batch::Config,
+}
+
+impl ConfigImpl for Config {}
+
+#[derive(Clone, PartialEq, Message)]
+struct PushRequest {
+    #[prost(message, repeated, tag = "1")]
+    streams: Vec<StreamAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct StreamAdapter {
+    #[prost(string, tag = "1")]
+    labels: String,
+    #[prost(message, repeated, tag = "2")]
+    entries: Vec<EntryAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct EntryAdapter {
+    #[prost(message, optional, tag = "1")]
+    timestamp: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    line: String,
+}
+
+/// `google.protobuf.Timestamp`
+#[derive(Clone, PartialEq, Message)]
+struct Timestamp {
+    #[prost(int64, tag = "1")]
+    seconds: i64,
+    #[prost(int32, tag = "2")]
+    nanos: i32,
+}
+
+impl From<u64> for Timestamp {
+    // ALLOW: nanos are < 1_000_000_000 and seconds of a u64 nanosecond timestamp fit into an i64
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+    fn from(ns: u64) -> Self {
+        Self {
+            seconds: (ns / 1_000_000_000) as i64,
+            nanos: (ns % 1_000_000_000) as i32,
+        }
+    }
+}
+
+/// The entries of a batch that share the same labels
+#[derive(Debug, Default)]
+struct Stream {
+    labels: BTreeMap<String, String>,
+    entries: Vec<(u64, String)>,
+}
+
+/// Renders labels in the LogQL stream selector format, `{key="value", ...}`
+fn render_labels(labels: &BTreeMap<String, String>) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| {
+            format!(
+                "{}=\"{}\"",
+                k,
+                v.replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+            )
+        })
+        .collect();
+    format!("{{{}}}", labels.join(", "))
+}
+
+/// The timestamps of the last entries of `streams`
+fn last_timestamps(streams: &BTreeMap<String, Stream>) -> Vec<(String, u64)> {
+    streams
+        .iter()
+        .filter_map(|(key, stream)| stream.entries.last().map(|(ts, _)| (key.clone(), *ts)))
+        .collect()
+}
+
+pub struct Loki {
+    config: Config,
+    /// streams of the current batch by their rendered labels
+    streams: BTreeMap<String, Stream>,
+    /// timestamp of the last entry sent to every stream
+    last_sent: HashMap<String, u64>,
+    batch: Batch,
+}
+
+impl offramp::Impl for Loki {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            Ok(SinkManager::new_box(Self::new(Config::new(config)?)?))
+        } else {
+            Err("Missing config for loki offramp".into())
+        }
+    }
+}
+
+impl Loki {
+    fn new(config: Config) -> Result<Self> {
+        Ok(Self {
+            batch: Batch::new(config.batch)?,
+            config,
+            streams: BTreeMap::new(),
+            last_sent: HashMap::new(),
+        })
+    }
+
+    fn add(&mut self, value: &Value, meta: &Value, ingest_ns: u64) {
+        let loki = meta.get("loki");
+        let mut labels = self.config.labels.clone();
+        if let Some(event_labels) = loki.and_then(|m| m.get_object("labels")) {
+            for (k, v) in event_labels.iter() {
+                let v = v.as_str().map_or_else(|| v.encode(), ToString::to_string);
+                labels.insert(k.to_string(), v);
+            }
+        }
+        let timestamp = loki
+            .and_then(|m| m.get_u64("timestamp"))
+            .unwrap_or(ingest_ns);
+        let line = value
+            .as_str()
+            .map_or_else(|| value.encode(), ToString::to_string);
+        let stream = self
+            .streams
+            .entry(render_labels(&labels))
+            .or_insert_with(|| Stream {
+                labels,
+                entries: Vec::new(),
+            });
+        stream.entries.push((timestamp, line));
+    }
+
+    /// Orders the entries of every stream, an entry older than the last one sent to its
+    /// stream is moved up to the timestamp of that one. The timestamps of the last entries
+    /// need to be recorded with `sent` once they are sent.
+    fn take_streams(&mut self) -> BTreeMap<String, Stream> {
+        let mut streams = std::mem::take(&mut self.streams);
+        for (key, stream) in &mut streams {
+            // stable, so entries with the same timestamp stay in the order they arrived
+            stream.entries.sort_by_key(|(ts, _)| *ts);
+            let last = self.last_sent.get(key).copied().unwrap_or_default();
+            for (ts, _) in &mut stream.entries {
+                *ts = (*ts).max(last);
+            }
+        }
+        streams
+    }
+
+    /// Records the timestamps of the last entries of `streams` as sent
+    fn sent(&mut self, last: Vec<(String, u64)>) {
+        for (key, ts) in last {
+            self.last_sent.insert(key, ts);
+        }
+    }
+
+    fn encode_protobuf(streams: BTreeMap<String, Stream>) -> Result<Vec<u8>> {
+        let request = PushRequest {
+            streams: streams
+                .into_iter()
+                .map(|(labels, stream)| StreamAdapter {
+                    labels,
+                    entries: stream
+                        .entries
+                        .into_iter()
+                        .map(|(ts, line)| EntryAdapter {
+                            timestamp: Some(Timestamp::from(ts)),
+                            line,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        };
+        Ok(snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?)
+    }
+
+    fn encode_json(streams: BTreeMap<String, Stream>) -> Vec<u8> {
+        let streams: Vec<Value> = streams
+            .into_values()
+            .map(|stream| {
+                let labels: Value = stream
+                    .labels
+                    .into_iter()
+                    .map(|(k, v)| (k, Value::from(v)))
+                    .collect();
+                let values: Vec<Value> = stream
+                    .entries
+                    .into_iter()
+                    .map(|(ts, line)| literal!([ts.to_string(), line]))
+                    .collect();
+                literal!({
+                    "stream": labels,
+                    "values": values
+                })
+            })
+            .collect();
+        literal!({ "streams": streams }).encode().into_bytes()
+    }
+
+    /// Sends the current batch and returns the insight for all events in it
+    async fn flush(&mut self) -> Option<Vec<Reply>> {
+        let events = self.batch.take()?;
+        let streams = self.take_streams();
+        let last = last_timestamps(&streams);
+        let sent = match self.send(streams).await {
+            Ok(()) => {
+                // only entries loki accepted move the streams forward
+                self.sent(last);
+                true
+            }
+            Err(e) => {
+                error!("[Sink::Loki] Failed to push entries: {}", e);
+                false
+            }
+        };
+        Some(vec![Reply::Insight(events.insight(sent))])
+    }
+
+    async fn send(&self, streams: BTreeMap<String, Stream>) -> Result<()> {
+        let mut request = surf::post(&self.config.url);
+        request = match self.config.encoding {
+            Encoding::Protobuf => request
+                .header(CONTENT_ENCODING, "snappy")
+                .header(CONTENT_TYPE, "application/x-protobuf")
+                .body(Self::encode_protobuf(streams)?),
+            Encoding::Json => request
+                .header(CONTENT_TYPE, "application/json")
+                .body(Self::encode_json(streams)),
+        };
+        if let Some(tenant) = &self.config.tenant {
+            request = request.header("X-Scope-OrgID", tenant.as_str());
+        }
+        for (name, value) in &self.config.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        let mut response = request.await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            let body = response
+                .body_string()
+                .await
+                .unwrap_or_else(|e| format!("failed to load body {}", e));
+            Err(format!(
+                "push request failed with status {}: {}",
+                response.status(),
+                body
+            )
+            .into())
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for Loki {
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        _codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        event: Event,
+    ) -> ResultVec {
+        let mut entries = 0;
+        for (value, meta) in event.value_meta_iter() {
+            self.add(value, meta, event.ingest_ns);
+            entries += 1;
+        }
+        self.batch.push(event, entries);
+        if self.batch.is_full() {
+            Ok(self.flush().await)
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        if self.batch.is_due(signal.ingest_ns) {
+            Ok(self.flush().await)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        _sink_uid: u64,
+        _sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        _processors: Processors<'_>,
+        _is_linked: bool,
+        _reply_channel: Sender<Reply>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn terminate(&mut self) {
+        // insights can't be delivered anymore, but the data should still be sent
+        self.flush().await;
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    fn auto_ack(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::channel::{unbounded, Sender as ChannelSender};
+    use simd_json::OwnedValue;
+    use tremor_pipeline::EventId;
+
+    fn loki(config_s: &str) -> Result<Loki> {
+        let v: serde_yaml::Value = serde_yaml::from_str(config_s)?;
+        Loki::new(Config::new(&v)?)
+    }
+
+    #[test]
+    fn ordered_streams() -> Result<()> {
+        let mut loki = loki(
+            r#"
+            url: http://localhost:3100/loki/api/v1/push
+            labels:
+              app: tremor
+            "#,
+        )?;
+        let meta = literal!({"loki": {"labels": {"level": "info"}, "timestamp": 20}});
+        loki.add(&Value::from("second"), &meta, 0);
+        let meta = literal!({"loki": {"labels": {"level": "info"}, "timestamp": 10}});
+        loki.add(&literal!({"snot": "badger"}), &meta, 0);
+        loki.add(&Value::from("other"), &Value::object(), 5);
+
+        let streams = loki.take_streams();
+        assert!(loki.streams.is_empty());
+        let info = streams
+            .get(r#"{app="tremor", level="info"}"#)
+            .ok_or_else(|| Error::from("missing info stream"))?;
+        assert_eq!(
+            vec![
+                (10, r#"{"snot":"badger"}"#.to_string()),
+                (20, "second".to_string())
+            ],
+            info.entries
+        );
+        assert!(streams.contains_key(r#"{app="tremor"}"#));
+
+        // a failed push doesn't move the stream
+        let meta = literal!({"loki": {"labels": {"level": "info"}, "timestamp": 15}});
+        loki.add(&Value::from("late"), &meta, 0);
+        let late = loki.take_streams();
+        assert_eq!(
+            Some(&vec![(15, "late".to_string())]),
+            late.get(r#"{app="tremor", level="info"}"#)
+                .map(|s| &s.entries)
+        );
+
+        // entries never go back in time within a stream once it was sent
+        loki.sent(last_timestamps(&streams));
+        loki.add(&Value::from("late"), &meta, 0);
+        let streams = loki.take_streams();
+        assert_eq!(
+            Some(&vec![(20, "late".to_string())]),
+            streams
+                .get(r#"{app="tremor", level="info"}"#)
+                .map(|s| &s.entries)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn labels() {
+        let mut labels = BTreeMap::new();
+        labels.insert("job".to_string(), "snot \"badger\"".to_string());
+        assert_eq!(r#"{job="snot \"badger\""}"#, render_labels(&labels));
+    }
+
+    async fn push(mut req: tide::Request<ChannelSender<Vec<u8>>>) -> tide::Result {
+        let body = req.body_bytes().await?;
+        let body = if req.header(CONTENT_ENCODING).is_some() {
+            snap::raw::Decoder::new().decompress_vec(&body)?
+        } else {
+            body
+        };
+        req.state().send(body).await?;
+        Ok(tide::Response::new(204))
+    }
+
+    #[async_std::test]
+    async fn push_requests() -> Result<()> {
+        let (tx, rx) = unbounded();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let mut app = tide::with_state(tx);
+        app.at("/loki/api/v1/push").post(push);
+        task::spawn(app.listen(listener));
+
+        let mut loki = loki(&format!(
+            "url: http://{}/loki/api/v1/push\nencoding: json",
+            addr
+        ))?;
+        loki.add(&Value::from("snot"), &Value::object(), 42);
+        let streams = loki.take_streams();
+        loki.send(streams).await?;
+        let mut body = rx.recv().await?;
+        assert_eq!(
+            literal!({"streams": [{"stream": {}, "values": [["42", "snot"]]}]}),
+            tremor_value::parse_to_value(&mut body)?
+        );
+
+        loki.config.encoding = Encoding::Protobuf;
+        loki.add(&Value::from("badger"), &Value::object(), 1_000_000_042);
+        let streams = loki.take_streams();
+        loki.send(streams).await?;
+        let body = rx.recv().await?;
+        let request = PushRequest::decode(body.as_slice())
+            .map_err(|e| Error::from(format!("invalid push request: {}", e)))?;
+        assert_eq!(
+            PushRequest {
+                streams: vec![StreamAdapter {
+                    labels: "{}".to_string(),
+                    entries: vec![EntryAdapter {
+                        timestamp: Some(Timestamp {
+                            seconds: 1,
+                            nanos: 42
+                        }),
+                        line: "badger".to_string()
+                    }]
+                }]
+            },
+            request
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn insights_carry_op_meta() -> Result<()> {
+        // nothing listens there, so the push fails
+        let mut loki = loki("url: http://127.0.0.1:1/loki/api/v1/push\nbatch_size: 1")?;
+        let mut codec = crate::codec::lookup("json")?;
+        let mut event = Event {
+            id: EventId::new(0, 0, 1),
+            data: (Value::from("snot"), Value::object()).into(),
+            ..Event::default()
+        };
+        event.op_meta.insert(42, OwnedValue::null());
+        let replies = loki
+            .on_event("in", codec.as_mut(), &HashMap::new(), event)
+            .await?;
+        match replies.as_deref() {
+            Some([Reply::Insight(insight)]) => {
+                assert_eq!(CbAction::Fail, insight.cb);
+                assert!(insight.op_meta.contains_key(42));
+            }
+            _ => return Err("expected a fail insight".into()),
+        }
+        assert!(loki.last_sent.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # MQTT Offramp
+//!
+//! Publishes events to an MQTT broker. Topic, QoS and retain flag are taken from
+//! `$mqtt.topic`, `$mqtt.qos` and `$mqtt.retain` and fall back to the configured ones,
+//! MQTT 5 properties can be set in `$mqtt.properties`.
+//!
+//! Events are acknowledged once the broker acknowledged all their messages sent with
+//! QoS 1 or 2, messages sent with QoS 0 are acknowledged right away.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::connectors::mqtt::{
+    is_failure, validate_topic, value_to_properties, Client, ConnectionConfig, Packet, Publish,
+    QoS, Version,
+};
+use crate::sink::prelude::*;
+use halfbrown::HashMap;
+use tremor_pipeline::{EventId, OpMeta};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    #[serde(flatten)]
+    pub(crate) connection: ConnectionConfig,
+    /// Topic to publish to if `$mqtt.topic` isn't set
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// QoS to publish with if `$mqtt.qos` isn't set, defaults to 1
+    #[serde(default = "dflt_qos")]
+    pub qos: u8,
+    /// Retain messages if `$mqtt.retain` isn't set, defaults to false
+    #[serde(default)]
+    pub retain: bool,
+}
+
+fn dflt_qos() -> u8 {
+    1
+}
+
+impl ConfigImpl for Config {}
+
+/// An event waiting for the broker to acknowledge its messages
+struct Pending {
+    ingest_ns: u64,
+    id: EventId,
+    op_meta: OpMeta,
+    remaining: usize,
+    failed: bool,
+}
+
+impl Pending {
+    fn insight(self) -> Event {
+        let mut e = if self.failed {
+            Event::cb_fail(self.ingest_ns, self.id)
+        } else {
+            Event::cb_ack(self.ingest_ns, self.id)
+        };
+        e.op_meta = self.op_meta;
+        e
+    }
+}
+
+pub struct Mqtt {
+    sink_url: TremorUrl,
+    config: Config,
+    default_qos: QoS,
+    postprocessors: Postprocessors,
+    client: Option<Client>,
+    /// events waiting for acknowledgements, by a key of our own
+    events: HashMap<u64, Pending>,
+    /// event keys by the packet ids of their messages
+    inflight: HashMap<u16, u64>,
+    next_key: u64,
+    merged_meta: OpMeta,
+}
+
+impl offramp::Impl for Mqtt {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            if let Some(topic) = &config.topic {
+                validate_topic(topic)?;
+            }
+            let default_qos = QoS::from_u8(config.qos)?;
+            Ok(SinkManager::new_box(Self {
+                sink_url: TremorUrl::from_offramp_id("mqtt")?,
+                config,
+                default_qos,
+                postprocessors: vec![],
+                client: None,
+                events: HashMap::new(),
+                inflight: HashMap::new(),
+                next_key: 0,
+                merged_meta: OpMeta::default(),
+            }))
+        } else {
+            Err("Missing config for mqtt offramp".into())
+        }
+    }
+}
+
+impl Mqtt {
+    /// Builds the messages for an event
+    fn publishes(&mut self, codec: &mut dyn Codec, event: &Event) -> Result<Vec<Publish>> {
+        let mut res = Vec::new();
+        for (value, meta) in event.value_meta_iter() {
+            let mqtt = meta.get("mqtt");
+            let topic = match mqtt.and_then(|m| m.get_str("topic")) {
+                Some(topic) => {
+                    validate_topic(topic)?;
+                    topic.to_string()
+                }
+                None => self.config.topic.clone().ok_or_else(|| {
+                    Error::from("No `$mqtt.topic` set and no default topic configured")
+                })?,
+            };
+            let qos = match mqtt.and_then(|m| m.get_u8("qos")) {
+                Some(qos) => QoS::from_u8(qos)?,
+                None => self.default_qos,
+            };
+            let retain = mqtt
+                .and_then(|m| m.get_bool("retain"))
+                .unwrap_or(self.config.retain);
+            let properties = match (
+                self.config.connection.version,
+                mqtt.and_then(|m| m.get("properties")),
+            ) {
+                (Version::V5, Some(properties)) => value_to_properties(properties)?,
+                _ => vec![],
+            };
+            let encoded = codec.encode(value)?;
+            for payload in postprocess(&mut self.postprocessors, event.ingest_ns, encoded)? {
+                res.push(Publish {
+                    topic: topic.clone(),
+                    qos,
+                    retain,
+                    dup: false,
+                    pkid: 0,
+                    properties: properties.clone(),
+                    payload,
+                });
+            }
+        }
+        Ok(res)
+    }
+
+    /// Sends the messages of an event, returns the packet ids of those the broker
+    /// will acknowledge
+    async fn send(&mut self, publishes: Vec<Publish>) -> Result<Vec<u16>> {
+        let client = self
+            .client
+            .as_mut()
+            .ok_or_else(|| Error::from("Not connected to the MQTT broker"))?;
+        let mut pkids = Vec::new();
+        for mut publish in publishes {
+            if publish.qos != QoS::AtMostOnce {
+                publish.pkid = client.next_pkid();
+                pkids.push(publish.pkid);
+            }
+            client.send(Packet::Publish(publish)).await?;
+        }
+        Ok(pkids)
+    }
+
+    /// Accounts for an acknowledgement from the broker
+    fn done(&mut self, pkid: u16, ok: bool) -> Option<Reply> {
+        let key = self.inflight.remove(&pkid)?;
+        let pending = self.events.get_mut(&key)?;
+        pending.failed |= !ok;
+        pending.remaining -= 1;
+        if pending.remaining > 0 {
+            return None;
+        }
+        self.events
+            .remove(&key)
+            .map(|p| Reply::Insight(p.insight()))
+    }
+
+    /// Handles everything the broker sent since the last time
+    fn receive(&mut self) -> Vec<Reply> {
+        let mut res = Vec::new();
+        loop {
+            let packet = match self.client.as_ref().map(Client::try_recv) {
+                Some(Ok(Some(packet))) => packet,
+                Some(Ok(None)) | None => break,
+                Some(Err(e)) => {
+                    error!("[Sink::{}] {}", self.sink_url, e);
+                    res.append(&mut self.disconnected());
+                    break;
+                }
+            };
+            let reply = match packet {
+                Packet::PubAck { pkid, code } | Packet::PubComp { pkid, code } => {
+                    self.done(pkid, !is_failure(code))
+                }
+                // a successful PUBREC is answered by the connection
+                Packet::PubRec { pkid, .. } => self.done(pkid, false),
+                other => {
+                    debug!("[Sink::{}] Ignoring {:?}", self.sink_url, other);
+                    None
+                }
+            };
+            res.extend(reply);
+        }
+        res
+    }
+
+    /// Drops the connection, failing all events still waiting for the broker
+    fn disconnected(&mut self) -> Vec<Reply> {
+        self.client = None;
+        self.inflight.clear();
+        let mut res: Vec<Reply> = self
+            .events
+            .drain()
+            .map(|(_, mut p)| {
+                p.failed = true;
+                Reply::Insight(p.insight())
+            })
+            .collect();
+        let mut e = Event::cb_trigger(nanotime());
+        e.op_meta = self.merged_meta.clone();
+        res.push(Reply::Insight(e));
+        res
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for Mqtt {
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        mut event: Event,
+    ) -> ResultVec {
+        let mut res = self.receive();
+        self.merged_meta.merge(event.op_meta.clone());
+        let publishes = match self.publishes(codec, &event) {
+            Ok(publishes) => publishes,
+            Err(e) => {
+                error!("[Sink::{}] Invalid event: {}", self.sink_url, e);
+                if event.transactional {
+                    res.push(Reply::Insight(event.insight_fail()));
+                }
+                return Ok(Some(res));
+            }
+        };
+        match self.send(publishes).await {
+            Ok(pkids) if event.transactional && pkids.is_empty() => {
+                res.push(Reply::Insight(event.insight_ack()));
+            }
+            Ok(pkids) if event.transactional => {
+                let key = self.next_key;
+                self.next_key += 1;
+                for pkid in &pkids {
+                    self.inflight.insert(*pkid, key);
+                }
+                self.events.insert(
+                    key,
+                    Pending {
+                        ingest_ns: event.ingest_ns,
+                        id: event.id.clone(),
+                        op_meta: std::mem::take(&mut event.op_meta),
+                        remaining: pkids.len(),
+                        failed: false,
+                    },
+                );
+            }
+            Ok(_) => (),
+            Err(e) => {
+                error!("[Sink::{}] Failed to publish: {}", self.sink_url, e);
+                if event.transactional {
+                    res.push(Reply::Insight(event.insight_fail()));
+                }
+                if self.client.is_some() {
+                    res.append(&mut self.disconnected());
+                }
+            }
+        }
+        Ok(Some(res))
+    }
+
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        let mut res = self.receive();
+        if self.client.is_none() {
+            match Client::connect(&self.config.connection).await {
+                Ok((client, _)) => {
+                    info!("[Sink::{}] Reconnected", self.sink_url);
+                    self.client = Some(client);
+                    let mut e = Event::cb_restore(signal.ingest_ns);
+                    e.op_meta = self.merged_meta.clone();
+                    res.push(Reply::Insight(e));
+                }
+                Err(e) => {
+                    warn!("[Sink::{}] Failed to reconnect: {}", self.sink_url, e);
+                }
+            }
+        }
+        Ok(Some(res))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        _sink_uid: u64,
+        sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        processors: Processors<'_>,
+        _is_linked: bool,
+        _reply_channel: Sender<Reply>,
+    ) -> Result<()> {
+        self.postprocessors = make_postprocessors(processors.post)?;
+        self.sink_url = sink_url.clone();
+        let (client, _) = Client::connect(&self.config.connection).await?;
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn is_active(&self) -> bool {
+        self.client.is_some()
+    }
+
+    fn auto_ack(&self) -> bool {
+        false
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    async fn terminate(&mut self) {
+        // dropping the client disconnects
+        self.client = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sink(qos: u8) -> Result<Mqtt> {
+        let config = Config {
+            connection: serde_yaml::from_str("host: localhost")?,
+            topic: Some("tremor/out".to_string()),
+            qos,
+            retain: false,
+        };
+        Ok(Mqtt {
+            sink_url: TremorUrl::from_offramp_id("mqtt")?,
+            default_qos: QoS::from_u8(qos)?,
+            config,
+            postprocessors: vec![],
+            client: None,
+            events: HashMap::new(),
+            inflight: HashMap::new(),
+            next_key: 0,
+            merged_meta: OpMeta::default(),
+        })
+    }
+
+    #[test]
+    fn publishes_from_meta() -> Result<()> {
+        let mut sink = sink(1)?;
+        let mut codec = crate::codec::lookup("string")?;
+        let event = Event {
+            data: (
+                Value::from("snot"),
+                literal!({"mqtt": {"topic": "tremor/in", "qos": 2, "retain": true}}),
+            )
+                .into(),
+            ..Event::default()
+        };
+        let publishes = sink.publishes(codec.as_mut(), &event)?;
+        assert_eq!(1, publishes.len());
+        assert_eq!("tremor/in", publishes[0].topic);
+        assert_eq!(QoS::ExactlyOnce, publishes[0].qos);
+        assert!(publishes[0].retain);
+        assert_eq!(b"snot".to_vec(), publishes[0].payload);
+
+        let event = Event {
+            data: (Value::from("badger"), Value::object()).into(),
+            ..Event::default()
+        };
+        let publishes = sink.publishes(codec.as_mut(), &event)?;
+        assert_eq!("tremor/out", publishes[0].topic);
+        assert_eq!(QoS::AtLeastOnce, publishes[0].qos);
+        assert!(!publishes[0].retain);
+
+        let event = Event {
+            data: (
+                Value::from("snot"),
+                literal!({"mqtt": {"topic": "tremor/#"}}),
+            )
+                .into(),
+            ..Event::default()
+        };
+        assert!(sink.publishes(codec.as_mut(), &event).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn acks_after_all_messages() -> Result<()> {
+        let mut sink = sink(1)?;
+        sink.events.insert(
+            0,
+            Pending {
+                ingest_ns: 1,
+                id: EventId::from_id(0, 0, 1),
+                op_meta: OpMeta::default(),
+                remaining: 2,
+                failed: false,
+            },
+        );
+        sink.inflight.insert(1, 0);
+        sink.inflight.insert(2, 0);
+        assert!(sink.done(1, true).is_none());
+        // unknown packet ids are ignored
+        assert!(sink.done(3, true).is_none());
+        match sink.done(2, true) {
+            Some(Reply::Insight(e)) => assert_eq!(CbAction::Ack, e.cb),
+            _ => return Err("expected an ack".into()),
+        }
+
+        sink.events.insert(
+            1,
+            Pending {
+                ingest_ns: 1,
+                id: EventId::from_id(0, 0, 2),
+                op_meta: OpMeta::default(),
+                remaining: 2,
+                failed: false,
+            },
+        );
+        sink.inflight.insert(4, 1);
+        sink.inflight.insert(5, 1);
+        assert!(sink.done(4, false).is_none());
+        match sink.done(5, true) {
+            Some(Reply::Insight(e)) => assert_eq!(CbAction::Fail, e.cb),
+            _ => return Err("expected a fail".into()),
+        }
+        Ok(())
+    }
+}
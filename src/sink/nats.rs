@@ -17,12 +17,17 @@
 use std::iter::FromIterator;
 use std::time::Instant;
 
+use crate::connectors::jetstream::{self, StreamConfig};
 use crate::sink::prelude::*;
 use async_channel::{bounded, Receiver};
 use async_nats::Connection as NatsConnection;
 use async_nats::Headers;
 use async_nats::Options as NatsOptions;
+use async_nats::Subscription;
+use async_std::future::timeout;
 use halfbrown::HashMap;
+use std::collections::HashSet;
+use std::time::Duration;
 use tremor_pipeline::OpMeta;
 
 // struct containing connection options
@@ -98,6 +103,23 @@ pub struct Config {
     // headers to use for the messages
     #[serde(default = "Default::default")]
     pub headers: HashMap<String, Vec<String>>,
+    // publish to a JetStream stream, acknowledging events once the stream stored
+    // all their messages
+    #[serde(default = "Default::default")]
+    pub jetstream: Option<JetStreamConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct JetStreamConfig {
+    #[serde(flatten)]
+    stream: StreamConfig,
+    // milliseconds to wait for the stream to acknowledge a message, defaults to 5000
+    #[serde(default = "default_ack_timeout_ms")]
+    ack_timeout_ms: u64,
+}
+
+fn default_ack_timeout_ms() -> u64 {
+    5000
 }
 
 impl Config {
@@ -108,6 +130,34 @@ impl Config {
             Ok(connection)
         })
     }
+
+    /// message headers, the configured ones followed by the ones from `$nats.headers`
+    /// and the `Nats-Msg-Id` for deduplication
+    fn headers(&self, headers: Option<&Object>, msg_id: Option<&str>) -> Option<Headers> {
+        let mut key_val: Vec<(&str, &str)> = Vec::with_capacity(
+            self.headers.len() + headers.map(HashMap::len).unwrap_or_default() + 1,
+        );
+        for (key, val) in &self.headers {
+            for ele in val.iter() {
+                key_val.push((key.as_str(), ele.as_str()));
+            }
+        }
+        if let Some(headers) = headers {
+            for (key, val) in headers.iter().filter_map(|(k, v)| Some((k, v.as_array()?))) {
+                for ele in val.iter().filter_map(value_trait::ValueAccess::as_str) {
+                    key_val.push((key, ele));
+                }
+            }
+        }
+        if let Some(msg_id) = msg_id {
+            key_val.push(("Nats-Msg-Id", msg_id));
+        }
+        if key_val.is_empty() {
+            None
+        } else {
+            Some(Headers::from_iter(key_val))
+        }
+    }
 }
 
 impl ConfigImpl for Config {}
@@ -121,6 +171,10 @@ pub struct Nats {
     error_rx: Receiver<()>,
     error_tx: Sender<()>,
     merged_meta: OpMeta,
+    /// inbox the `PubAck`s of `JetStream` publishes are received on
+    ack_inbox: String,
+    ack_sub: Option<Subscription>,
+    ack_seq: u64,
 }
 
 impl offramp::Impl for Nats {
@@ -138,6 +192,9 @@ impl offramp::Impl for Nats {
                 error_rx,
                 error_tx,
                 merged_meta: OpMeta::default(),
+                ack_inbox: format!("_INBOX.tremor.{}", nanotime()),
+                ack_sub: None,
+                ack_seq: 0,
             }))
         } else {
             Err("Nats offramp requires a configuration.".into())
@@ -151,11 +208,86 @@ impl Nats {
             self.connection = None;
         }
         if self.connection.is_none() {
-            self.connection = Some(self.config.connection()?);
+            let connection = self.config.connection()?;
+            self.ack_sub = self.setup_jetstream(&connection)?;
+            self.connection = Some(connection);
             return Ok(self.connection.as_ref());
         }
         Ok(None)
     }
+
+    /// ensures the stream exists and subscribes to the `PubAck` inbox
+    fn setup_jetstream(&self, connection: &NatsConnection) -> Result<Option<Subscription>> {
+        if let Some(js) = &self.config.jetstream {
+            task::block_on(async {
+                jetstream::ensure_stream(connection, &js.stream, &self.config.subject).await?;
+                Ok(Some(
+                    connection
+                        .subscribe(&format!("{}.*", self.ack_inbox))
+                        .await?,
+                ))
+            })
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// publishes all messages of an event to the stream, waiting for all their `PubAck`s
+    async fn publish_jetstream(
+        &mut self,
+        codec: &mut dyn Codec,
+        event: &Event,
+        ack_timeout: Duration,
+    ) -> Result<()> {
+        let (connection, ack_sub) = match (&self.connection, &self.ack_sub) {
+            (Some(connection), Some(ack_sub)) => (connection, ack_sub),
+            _ => return Err("Not connected".into()),
+        };
+        let mut expected = HashSet::new();
+        for (value, meta) in event.value_meta_iter() {
+            let encoded = codec.encode(value)?;
+            let processed =
+                postprocess(self.postprocessors.as_mut_slice(), event.ingest_ns, encoded)?;
+            let nats_meta = meta.get("nats");
+            let headers = nats_meta.and_then(|v| v.get_object("headers"));
+            let msg_id = nats_meta.and_then(|v| v.get_str("msg_id"));
+            for (i, payload) in processed.into_iter().enumerate() {
+                // keep postprocessor splits of a message apart for deduplication
+                let msg_id = match msg_id {
+                    Some(msg_id) if i > 0 => Some(format!("{}-{}", msg_id, i)),
+                    msg_id => msg_id.map(ToString::to_string),
+                };
+                let message_headers = self.config.headers(headers, msg_id.as_deref());
+                self.ack_seq += 1;
+                let reply = format!("{}.{}", self.ack_inbox, self.ack_seq);
+                connection
+                    .publish_with_reply_or_headers(
+                        self.config.subject.as_str(),
+                        Some(reply.as_str()),
+                        message_headers.as_ref(),
+                        payload,
+                    )
+                    .await?;
+                expected.insert(reply);
+            }
+        }
+        while !expected.is_empty() {
+            match timeout(ack_timeout, ack_sub.next()).await {
+                Ok(Some(msg)) => {
+                    // acks of earlier timed out publishes are ignored
+                    if expected.remove(&msg.subject) {
+                        let (seq, duplicate) = jetstream::pub_ack(msg.data)?;
+                        if duplicate {
+                            debug!("[Sink::{}] Duplicate of message {}", &self.sink_url, seq);
+                        }
+                    }
+                }
+                Ok(None) => return Err("PubAck subscription closed".into()),
+                Err(_) => return Err("Timeout waiting for PubAck".into()),
+            }
+        }
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -171,6 +303,37 @@ impl Sink for Nats {
         self.handle_connection()?;
         let ingest_ns = event.ingest_ns;
         let processing_start = Instant::now();
+        if let Some(ack_timeout_ms) = self.config.jetstream.as_ref().map(|js| js.ack_timeout_ms) {
+            let res = self
+                .publish_jetstream(codec, &event, Duration::from_millis(ack_timeout_ms))
+                .await;
+            self.merged_meta.merge(event.op_meta.clone());
+            let insight = match res {
+                Ok(()) => {
+                    let mut insight = Event::cb_ack(ingest_ns, event.id.clone());
+                    insight.op_meta = event.op_meta.clone();
+                    insight.data = (
+                        Value::null(),
+                        literal!({ "time": processing_start.elapsed().as_millis() as u64 }),
+                    )
+                        .into();
+                    insight
+                }
+                Err(e) => {
+                    error!(
+                        "[Sink::{}] failed to publish to JetStream: {}",
+                        &self.sink_url, e
+                    );
+                    event.to_fail()
+                }
+            };
+            if event.transactional {
+                self.reply_channel
+                    .send(sink::Reply::Insight(insight))
+                    .await?;
+            }
+            return Ok(None);
+        }
         // evaluate here to avoid borrowing again while borrowed.
         let config_reply = self.config.reply.as_deref();
         let op_meta = &event.op_meta;
@@ -187,28 +350,7 @@ impl Sink for Nats {
                     // prepare message reply
                     let message_reply = reply.or(config_reply);
                     // prepare message headers
-                    let mut key_val: Vec<(&str, &str)> = Vec::with_capacity(
-                        self.config.headers.len() + headers.map(HashMap::len).unwrap_or_default(),
-                    );
-                    for (key, val) in &self.config.headers {
-                        for ele in val.iter() {
-                            key_val.push((key.as_str(), ele.as_str()));
-                        }
-                    }
-                    if let Some(headers) = headers {
-                        for (key, val) in
-                            headers.iter().filter_map(|(k, v)| Some((k, v.as_array()?)))
-                        {
-                            for ele in val.iter().filter_map(value_trait::ValueAccess::as_str) {
-                                key_val.push((key, ele));
-                            }
-                        }
-                    }
-                    let message_headers = if key_val.is_empty() {
-                        None
-                    } else {
-                        Some(Headers::from_iter(key_val))
-                    };
+                    let message_headers = self.config.headers(headers, None);
 
                     let publish_result = connection
                         .publish_with_reply_or_headers(
@@ -295,7 +437,9 @@ impl Sink for Nats {
         _is_linked: bool,
         reply_channel: Sender<Reply>,
     ) -> Result<()> {
-        self.connection = Some(self.config.connection()?);
+        let connection = self.config.connection()?;
+        self.ack_sub = self.setup_jetstream(&connection)?;
+        self.connection = Some(connection);
         self.postprocessors = make_postprocessors(processors.post)?;
         self.reply_channel = reply_channel;
         self.sink_url = sink_url.clone();
@@ -22,12 +22,17 @@
 
 #![cfg(not(tarpaulin_include))]
 
+use crate::connectors::otel::http::{self as otlp_http, Encoding, Protocol, Signal};
 use crate::connectors::otel::{logs, metrics, trace};
 use crate::connectors::qos::{self, QoSFacilities, SinkQoS};
 use crate::sink::prelude::*;
 use halfbrown::HashMap;
+use prost::Message;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use std::path::PathBuf;
 use tonic::transport::Channel as TonicChannel;
 use tonic::transport::Endpoint as TonicEndpoint;
+use tonic::transport::{Certificate, ClientTlsConfig};
 use tremor_otelapis::opentelemetry::proto::collector::{
     logs::v1::{logs_service_client::LogsServiceClient, ExportLogsServiceRequest},
     metrics::v1::{metrics_service_client::MetricsServiceClient, ExportMetricsServiceRequest},
@@ -40,10 +45,98 @@ pub struct RemoteOpenTelemetryEndpoint {
     trace_client: TraceServiceClient<TonicChannel>,
 }
 
+/// OTLP/HTTP collector endpoint
+pub struct HttpEndpoint {
+    client: reqwest::Client,
+    /// scheme, host and port of the collector
+    base: String,
+    encoding: Encoding,
+    gzip: bool,
+}
+
+impl HttpEndpoint {
+    async fn export<M: Message>(
+        &self,
+        signal: Signal,
+        request: &M,
+        json: &Value<'_>,
+    ) -> Result<()> {
+        let mut body = otlp_http::encode(self.encoding, request, json)?;
+        let mut request = self
+            .client
+            .post(format!("{}{}", self.base, signal.path()))
+            .header(CONTENT_TYPE, self.encoding.content_type());
+        if self.gzip {
+            body = otlp_http::gzip(&body)?;
+            request = request.header(CONTENT_ENCODING, "gzip");
+        }
+        let response = request.body(body).send().await?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            let body = response
+                .text()
+                .await
+                .unwrap_or_else(|e| format!("failed to load body {}", e));
+            Err(format!("OTLP/HTTP export failed with status {}: {}", status, body).into())
+        }
+    }
+}
+
+pub enum Remote {
+    Grpc(RemoteOpenTelemetryEndpoint),
+    Http(HttpEndpoint),
+}
+
+impl Remote {
+    async fn export_logs(
+        &mut self,
+        request: ExportLogsServiceRequest,
+        json: &Value<'_>,
+    ) -> Result<()> {
+        match self {
+            Self::Grpc(remote) => {
+                remote.logs_client.export(request).await?;
+                Ok(())
+            }
+            Self::Http(remote) => remote.export(Signal::Logs, &request, json).await,
+        }
+    }
+
+    async fn export_metrics(
+        &mut self,
+        request: ExportMetricsServiceRequest,
+        json: &Value<'_>,
+    ) -> Result<()> {
+        match self {
+            Self::Grpc(remote) => {
+                remote.metrics_client.export(request).await?;
+                Ok(())
+            }
+            Self::Http(remote) => remote.export(Signal::Metrics, &request, json).await,
+        }
+    }
+
+    async fn export_trace(
+        &mut self,
+        request: ExportTraceServiceRequest,
+        json: &Value<'_>,
+    ) -> Result<()> {
+        match self {
+            Self::Grpc(remote) => {
+                remote.trace_client.export(request).await?;
+                Ok(())
+            }
+            Self::Http(remote) => remote.export(Signal::Trace, &request, json).await,
+        }
+    }
+}
+
 pub struct OpenTelemetry {
     config: Config,
     endpoint: String,
-    remote: Option<RemoteOpenTelemetryEndpoint>,
+    remote: Option<Remote>,
     is_down: bool,
     qos_facility: Box<dyn SinkQoS>,
 }
@@ -63,6 +156,28 @@ pub struct Config {
     /// Enables the metrics service
     #[serde(default = "d_true")]
     pub metrics: bool,
+    /// `grpc` (default) or `http` to export with OTLP/HTTP to `/v1/traces`, `/v1/metrics`
+    /// and `/v1/logs`
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Encoding of OTLP/HTTP requests, `protobuf` (default) or `json`
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// Compress OTLP/HTTP requests with gzip
+    #[serde(default)]
+    pub gzip: bool,
+    /// Connect with TLS
+    #[serde(default)]
+    pub tls: Option<TLSConfig>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct TLSConfig {
+    /// CA certificates to verify the collector with, the system roots are used if not set
+    cafile: Option<PathBuf>,
+    /// Domain to verify the certificate of the collector against, defaults to `host`
+    /// (gRPC only)
+    domain: Option<String>,
 }
 
 fn d_true() -> bool {
@@ -76,7 +191,17 @@ impl offramp::Impl for OpenTelemetry {
         let config = config.as_ref().ok_or("Offramp otel requires a config")?;
         let config: Config = Config::new(config)?;
         let hostport = format!("{}:{}", config.host.clone(), config.port);
-        let endpoint = format!("https://{}:{}", config.host.clone().as_str(), config.port);
+        let scheme = if config.protocol == Protocol::Http && config.tls.is_none() {
+            "http"
+        } else {
+            "https"
+        };
+        let endpoint = format!(
+            "{}://{}:{}",
+            scheme,
+            config.host.clone().as_str(),
+            config.port
+        );
         Ok(SinkManager::new_box(Self {
             config,
             endpoint,
@@ -128,8 +253,8 @@ impl Sink for OpenTelemetry {
                 if o.contains_key("metrics") {
                     if self.config.metrics {
                         let request = json_otel_metrics_to_pb(value)?;
-                        if let Err(e) = remote.metrics_client.export(request).await {
-                            error!("Failed to dispatch otel/gRPC metrics message: {}", e);
+                        if let Err(e) = remote.export_metrics(request, value).await {
+                            error!("Failed to dispatch otel metrics message: {}", e);
                             self.is_down = true;
                             if event.transactional {
                                 return Ok(Some(vec![
@@ -145,8 +270,8 @@ impl Sink for OpenTelemetry {
                 } else if o.contains_key("logs") {
                     if self.config.logs {
                         let request = json_otel_logs_to_pb(value)?;
-                        if let Err(e) = remote.logs_client.export(request).await {
-                            error!("Failed to dispatch otel/gRPC logs message: {}", e);
+                        if let Err(e) = remote.export_logs(request, value).await {
+                            error!("Failed to dispatch otel logs message: {}", e);
                             self.is_down = true;
                             if event.transactional {
                                 return Ok(Some(vec![
@@ -164,8 +289,8 @@ impl Sink for OpenTelemetry {
                 } else if o.contains_key("trace") {
                     if self.config.trace {
                         let request = json_otel_trace_to_pb(value)?;
-                        if let Err(e) = remote.trace_client.export(request).await {
-                            error!("Failed to dispatch otel/gRPC logs message: {}", e);
+                        if let Err(e) = remote.export_trace(request, value).await {
+                            error!("Failed to dispatch otel trace message: {}", e);
                             self.is_down = true;
                             if event.transactional {
                                 return Ok(Some(vec![
@@ -207,10 +332,40 @@ impl Sink for OpenTelemetry {
         _is_linked: bool,
         _reply_channel: Sender<sink::Reply>,
     ) -> Result<()> {
-        let channel = TonicEndpoint::from_shared(self.endpoint.clone())
-            .map_err(|e| format!("Unable to connect to remote otel endpoint: {}", e))?
-            .connect()
-            .await;
+        if self.config.protocol == Protocol::Http {
+            let mut builder = reqwest::Client::builder();
+            if let Some(TLSConfig {
+                cafile: Some(cafile),
+                ..
+            }) = &self.config.tls
+            {
+                let pem = async_std::fs::read(cafile).await?;
+                builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+            }
+            self.remote = Some(Remote::Http(HttpEndpoint {
+                client: builder.build()?,
+                base: self.endpoint.clone(),
+                encoding: self.config.encoding,
+                gzip: self.config.gzip,
+            }));
+            return Ok(());
+        }
+
+        let mut endpoint = TonicEndpoint::from_shared(self.endpoint.clone())
+            .map_err(|e| format!("Unable to connect to remote otel endpoint: {}", e))?;
+        if let Some(tls) = &self.config.tls {
+            let mut tls_config = ClientTlsConfig::new().domain_name(
+                tls.domain
+                    .clone()
+                    .unwrap_or_else(|| self.config.host.clone()),
+            );
+            if let Some(cafile) = &tls.cafile {
+                let pem = async_std::fs::read(cafile).await?;
+                tls_config = tls_config.ca_certificate(Certificate::from_pem(pem));
+            }
+            endpoint = endpoint.tls_config(tls_config)?;
+        }
+        let channel = endpoint.connect().await;
 
         let channel = match channel {
             Ok(channel) => channel,
@@ -221,11 +376,11 @@ impl Sink for OpenTelemetry {
         let metrics_client = MetricsServiceClient::new(channel.clone());
         let trace_client = TraceServiceClient::new(channel);
 
-        self.remote = Some(RemoteOpenTelemetryEndpoint {
+        self.remote = Some(Remote::Grpc(RemoteOpenTelemetryEndpoint {
             logs_client,
             metrics_client,
             trace_client,
-        });
+        }));
 
         Ok(())
     }
@@ -249,3 +404,56 @@ impl Sink for OpenTelemetry {
         false
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::channel::{unbounded, Sender as ChannelSender};
+    use tremor_otelapis::all::OpenTelemetryEvents;
+
+    async fn collector(mut req: tide::Request<ChannelSender<OpenTelemetryEvents>>) -> tide::Result {
+        let encoding = req
+            .content_type()
+            .and_then(|mime| Encoding::from_content_type(mime.essence()))
+            .ok_or_else(|| tide::Error::from_str(415, "unsupported content type"))?;
+        let mut body = otlp_http::gunzip(&req.body_bytes().await?, otlp_http::MAX_BODY_LEN)
+            .map_err(|e| tide::Error::from_str(400, e.to_string()))?
+            .ok_or_else(|| tide::Error::from_str(413, "body too large"))?;
+        let event = otlp_http::decode(Signal::Trace, encoding, &mut body)
+            .map_err(|e| tide::Error::from_str(400, e.to_string()))?;
+        req.state().send(event).await?;
+        Ok(tide::Response::new(200))
+    }
+
+    #[async_std::test]
+    async fn otlp_http() -> Result<()> {
+        let (tx, rx) = unbounded();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let mut app = tide::with_state(tx);
+        app.at(otlp_http::TRACES_PATH).post(collector);
+        task::spawn(app.listen(listener));
+
+        let json = literal!({
+            "trace": [{
+                "instrumentation_library_spans": [],
+                "schema_url": "snot"
+            }]
+        });
+        let request = json_otel_trace_to_pb(&json)?;
+        for encoding in [Encoding::Protobuf, Encoding::Json] {
+            let endpoint = HttpEndpoint {
+                client: reqwest::Client::new(),
+                base: format!("http://{}", addr),
+                encoding,
+                gzip: true,
+            };
+            endpoint.export(Signal::Trace, &request, &json).await?;
+            match rx.recv().await? {
+                OpenTelemetryEvents::Trace(received) => assert_eq!(request, received),
+                _ => return Err("expected a trace export".into()),
+            }
+        }
+        Ok(())
+    }
+}
@@ -18,19 +18,36 @@
 //!
 //! Writes events to a `PostgreSQL` and `TimescaleDB` database
 //!
+//! Rows are batched per table and written with multi-row `INSERT`s, or upserted with
+//! `ON CONFLICT` if `conflict_keys` are set, once `batch_size` rows are collected or the
+//! oldest is `batch_timeout_ms` old. Batches are written in parallel over up to `pool_size`
+//! connections.
+//!
+//! Without `columns` events need to be in the intermediate representation, an object of
+//! `{"fieldType": "INT8", "name": "...", "value": ...}` by column. With `columns` each column
+//! is taken from a field of the event and cast to its type. `$postgres.table` overrides the
+//! configured `table`.
+//!
+//! Events are acknowledged once all their rows are written. If a batch fails its rows are
+//! written one by one, so only the events of the failing rows fail.
+//!
 //! ## Configuration
 //!
 //! See [Config](struct.Config.html) for details.
 
-use crate::ramp::postgres::{json_to_record, Record};
+use crate::ramp::postgres::{json_to_record, type_from_str, Record};
 use crate::sink::prelude::*;
+use async_compat::Compat;
+use chrono::{TimeZone, Utc};
 use halfbrown::HashMap;
-use postgres::{Client, NoTls};
+use postgres::types::{ToSql, Type};
+use tokio_postgres::{Client, NoTls};
+use tremor_pipeline::{EventId, OpMeta};
 
-pub struct Postgres {
-    pub config: Config,
-    client: Option<postgres::Client>,
-}
+/// Postgres supports at most this many parameters per statement
+const MAX_PARAMS: usize = 65_535;
+
+const TIME_FMT: &str = "%Y-%m-%d %H:%M:%S%.6f %:z";
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
@@ -39,105 +56,469 @@ pub struct Config {
     pub user: String,
     pub password: String,
     pub dbname: String,
+    /// Table to write to, `$postgres.table` overrides it
     pub table: String,
+    /// Columns to write, events need to be in the intermediate representation without them
+    #[serde(default)]
+    pub columns: Vec<Column>,
+    /// Columns identifying a row, rows are upserted if set
+    #[serde(default)]
+    pub conflict_keys: Vec<String>,
+    /// Maximum number of rows to write at once, defaults to 100
+    #[serde(default = "dflt_batch_size")]
+    pub batch_size: usize,
+    /// Write rows at the latest after this long, defaults to 1000
+    #[serde(default = "dflt_batch_timeout_ms")]
+    pub batch_timeout_ms: u64,
+    /// Number of connections to write with, defaults to 4
+    #[serde(default = "dflt_pool_size")]
+    pub pool_size: usize,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Column {
+    /// Name of the column
+    pub name: String,
+    /// Dot separated path of the field to take the value from, defaults to the name
+    #[serde(default)]
+    pub field: Option<String>,
+    /// Type to cast the value to, e.g. `INT8` or `TIMESTAMPTZ`
+    #[serde(rename = "type")]
+    pub column_type: String,
+}
+
+fn dflt_batch_size() -> usize {
+    100
+}
+
+fn dflt_batch_timeout_ms() -> u64 {
+    1000
+}
+
+fn dflt_pool_size() -> usize {
+    4
 }
 
 impl ConfigImpl for Config {}
 
+/// Column names and table names are put into statements as they are, so only allow simple
+/// (optionally schema qualified) identifiers
+fn validate_identifier(name: &str) -> Result<()> {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+    {
+        Ok(())
+    } else {
+        Err(format!("Invalid identifier `{}`", name).into())
+    }
+}
+
+/// Converts a value into what its column type expects
+#[allow(clippy::cast_possible_wrap)]
+fn cast(t: &Type, value: &Value) -> Value<'static> {
+    if value.is_null() {
+        return Value::null();
+    }
+    let s = value.as_str();
+    match *t {
+        Type::BOOL => match s {
+            Some("true" | "t") => Value::from(true),
+            Some("false" | "f") => Value::from(false),
+            _ => value.clone_static(),
+        },
+        Type::INT2 | Type::INT4 | Type::INT8 => match s.map(str::parse::<i64>) {
+            Some(Ok(i)) => Value::from(i),
+            _ => value.clone_static(),
+        },
+        Type::FLOAT4 | Type::FLOAT8 => match s.map(str::parse::<f64>) {
+            Some(Ok(f)) => Value::from(f),
+            _ => value.clone_static(),
+        },
+        Type::CHAR | Type::BPCHAR | Type::NAME | Type::TEXT | Type::VARCHAR => s.map_or_else(
+            || Value::from(value.encode()),
+            |s| Value::from(s.to_string()),
+        ),
+        Type::TIMESTAMP | Type::TIMESTAMPTZ => match value.as_u64() {
+            // nanoseconds since the epoch
+            Some(ns) => Value::from(Utc.timestamp_nanos(ns as i64).format(TIME_FMT).to_string()),
+            None => value.clone_static(),
+        },
+        _ => value.clone_static(),
+    }
+}
+
+/// Builds the statement to write `rows` rows
+fn insert_statement(
+    table: &str,
+    columns: &[(String, Type)],
+    rows: usize,
+    conflict_keys: &[String],
+) -> String {
+    let names: Vec<&str> = columns.iter().map(|(n, _)| n.as_str()).collect();
+    let mut values = Vec::with_capacity(rows);
+    for row in 0..rows {
+        let params: Vec<String> = columns
+            .iter()
+            .enumerate()
+            .map(|(i, (_, t))| format!("${}::{}", row * columns.len() + i + 1, t.name()))
+            .collect();
+        values.push(format!("({})", params.join(",")));
+    }
+    let mut q = format!(
+        "INSERT INTO {} ({}) VALUES {}",
+        table,
+        names.join(","),
+        values.join(",")
+    );
+    if !conflict_keys.is_empty() {
+        let updates: Vec<String> = names
+            .iter()
+            .filter(|n| !conflict_keys.iter().any(|k| k == *n))
+            .map(|n| format!("{} = EXCLUDED.{}", n, n))
+            .collect();
+        let action = if updates.is_empty() {
+            "DO NOTHING".to_string()
+        } else {
+            format!("DO UPDATE SET {}", updates.join(","))
+        };
+        q = format!("{} ON CONFLICT ({}) {}", q, conflict_keys.join(","), action);
+    }
+    q
+}
+
+/// The table, columns and values of a row
+type TableRow = (String, Vec<(String, Type)>, Vec<Value<'static>>);
+
+/// A row to write, with the event it belongs to
+struct Row {
+    values: Vec<Value<'static>>,
+    event: u64,
+}
+
+/// Rows for the same table and columns
+struct Batch {
+    table: String,
+    columns: Vec<(String, Type)>,
+    rows: Vec<Row>,
+    started_ns: u64,
+}
+
+/// An event waiting for its rows to be written
+struct Pending {
+    ingest_ns: u64,
+    id: EventId,
+    op_meta: OpMeta,
+    transactional: bool,
+    remaining: usize,
+    failed: bool,
+}
+
+impl Pending {
+    fn insight(self) -> Option<Reply> {
+        if !self.transactional {
+            return None;
+        }
+        let mut e = if self.failed {
+            Event::cb_fail(self.ingest_ns, self.id)
+        } else {
+            Event::cb_ack(self.ingest_ns, self.id)
+        };
+        e.op_meta = self.op_meta;
+        Some(Reply::Insight(e))
+    }
+}
+
+pub struct Postgres {
+    pub config: Config,
+    sink_url: TremorUrl,
+    columns: Vec<(String, String, Type)>,
+    clients: Vec<Option<Client>>,
+    batches: HashMap<(String, Vec<String>), Batch>,
+    events: HashMap<u64, Pending>,
+    next_key: u64,
+}
+
 impl offramp::Impl for Postgres {
     fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
         if let Some(config) = config {
             let config: Config = Config::new(config)?;
-
-            Ok(SinkManager::new_box(Self {
-                config,
-                client: None,
-            }))
+            Ok(SinkManager::new_box(Self::new(config)?))
         } else {
             Err("Missing config for postgres offramp".into())
         }
     }
 }
 
-fn init_cli(config: &Config) -> std::result::Result<postgres::Client, postgres::Error> {
+async fn connect(config: &Config) -> Result<Client> {
     let conn_str = format!(
         "host={} user={} password={} port={} dbname={}",
         config.host, config.user, config.password, config.port, config.dbname
     );
-    let cli = Client::connect(&conn_str, NoTls)?;
+    let (client, connection) = Compat::new(tokio_postgres::connect(&conn_str, NoTls)).await?;
+    task::spawn(async move {
+        if let Err(e) = Compat::new(connection).await {
+            error!("connection error: {}", e);
+        }
+    });
+    Ok(client)
+}
 
-    Ok(cli)
+async fn execute(
+    client: &Client,
+    batch: &Batch,
+    rows: &[Row],
+    conflict_keys: &[String],
+) -> Result<()> {
+    let q = insert_statement(&batch.table, &batch.columns, rows.len(), conflict_keys);
+    let records: Vec<Record> = rows
+        .iter()
+        .flat_map(|row| {
+            row.values
+                .iter()
+                .zip(&batch.columns)
+                .map(|(value, (name, t))| Record {
+                    t: t.clone(),
+                    value,
+                    name,
+                })
+        })
+        .collect();
+    let params: Vec<&(dyn ToSql + Sync)> =
+        records.iter().map(|r| r as &(dyn ToSql + Sync)).collect();
+    Compat::new(client.execute(q.as_str(), &params)).await?;
+    Ok(())
 }
 
-#[async_trait::async_trait]
-impl Sink for Postgres {
-    async fn on_event(
-        &mut self,
-        _input: &str,
-        _codec: &mut dyn Codec,
-        _codec_map: &HashMap<String, Box<dyn Codec>>,
-        event: Event,
-    ) -> ResultVec {
-        for val in event.value_iter() {
-            let obj = val.as_object();
-            if let Some(kv) = obj {
-                let mut fields: Vec<String> = Vec::with_capacity(kv.len());
-                let mut params: Vec<String> = Vec::with_capacity(kv.len());
-                let mut records: Vec<Record> = Vec::with_capacity(kv.len());
-
-                let mut ct: usize = 1;
-                for (field, value) in kv {
-                    fields.push(field.to_string());
-                    params.push(format!("${}", ct));
-                    ct += 1;
-                    let record = match json_to_record(value) {
-                        Ok(v) => v,
+/// Writes a batch, returns the events of the rows with whether they were written
+async fn write(
+    client: Option<&Client>,
+    batch: &Batch,
+    conflict_keys: &[String],
+) -> Vec<(u64, bool)> {
+    let client = match client {
+        Some(client) => client,
+        None => return batch.rows.iter().map(|r| (r.event, false)).collect(),
+    };
+    let per_statement = (MAX_PARAMS / batch.columns.len().max(1)).max(1);
+    let mut res = Vec::with_capacity(batch.rows.len());
+    for rows in batch.rows.chunks(per_statement) {
+        match execute(client, batch, rows, conflict_keys).await {
+            Ok(()) => res.extend(rows.iter().map(|r| (r.event, true))),
+            Err(e) if client.is_closed() => {
+                error!("Failed to write to {}: {}", batch.table, e);
+                res.extend(rows.iter().map(|r| (r.event, false)));
+            }
+            Err(_) => {
+                // find the rows that fail
+                for row in rows {
+                    let ok = match execute(client, batch, std::slice::from_ref(row), conflict_keys)
+                        .await
+                    {
+                        Ok(()) => true,
                         Err(e) => {
-                            return Err(format!("Could not convert json to record: {}", e).into())
+                            error!("Failed to write row to {}: {}", batch.table, e);
+                            false
                         }
                     };
-                    records.push(record);
+                    res.push((row.event, ok));
                 }
+            }
+        }
+    }
+    res
+}
 
-                let fields = fields.join(",");
-                let params = params.join(",");
-
-                let q = format!(
-                    "INSERT INTO {} ({}) VALUES ({});",
-                    self.config.table, fields, params
-                );
+impl Postgres {
+    fn new(config: Config) -> Result<Self> {
+        validate_identifier(&config.table)?;
+        let mut columns = Vec::with_capacity(config.columns.len());
+        for c in &config.columns {
+            validate_identifier(&c.name)?;
+            let field = c.field.clone().unwrap_or_else(|| c.name.clone());
+            columns.push((c.name.clone(), field, type_from_str(&c.column_type)?));
+        }
+        for k in &config.conflict_keys {
+            validate_identifier(k)?;
+        }
+        if config.batch_size == 0 || config.pool_size == 0 {
+            return Err("`batch_size` and `pool_size` need to be at least 1".into());
+        }
+        let clients = (0..config.pool_size).map(|_| None).collect();
+        Ok(Self {
+            config,
+            sink_url: TremorUrl::from_offramp_id("postgres")?,
+            columns,
+            clients,
+            batches: HashMap::new(),
+            events: HashMap::new(),
+            next_key: 0,
+        })
+    }
 
-                if self.client.is_none() {
-                    self.client = match init_cli(&self.config) {
-                        Ok(v) => Some(v),
-                        Err(e) => {
-                            return Err(
-                                format!("Could not initialize a Postgres client: {}", e).into()
-                            )
-                        }
-                    }
+    /// The table, columns and values of the row for an event
+    fn row(&self, value: &Value, meta: &Value) -> Result<TableRow> {
+        let table = match meta.get("postgres").and_then(|m| m.get_str("table")) {
+            Some(table) => {
+                validate_identifier(table)?;
+                table.to_string()
+            }
+            None => self.config.table.clone(),
+        };
+        let mut columns = Vec::new();
+        let mut values = Vec::new();
+        if self.columns.is_empty() {
+            let fields = value
+                .as_object()
+                .ok_or("Events need to be objects in the intermediate representation")?;
+            for (name, field) in fields {
+                validate_identifier(name)?;
+                let record = json_to_record(field)?;
+                values.push(cast(&record.t, record.value));
+                columns.push((name.to_string(), record.t));
+            }
+        } else {
+            for (name, field, t) in &self.columns {
+                let mut v = Some(value);
+                for segment in field.split('.') {
+                    v = v.and_then(|v| v.get(segment));
                 }
+                let v = v.map_or_else(Value::null, |v| cast(t, v));
+                columns.push((name.clone(), t.clone()));
+                values.push(v);
+            }
+        }
+        Ok((table, columns, values))
+    }
+
+    fn add(&mut self, table: String, columns: Vec<(String, Type)>, row: Row) {
+        let key = (
+            table.clone(),
+            columns.iter().map(|(n, _)| n.clone()).collect(),
+        );
+        let batch = self.batches.entry(key).or_insert_with(|| Batch {
+            table,
+            columns,
+            rows: Vec::new(),
+            started_ns: nanotime(),
+        });
+        batch.rows.push(row);
+    }
 
-                let client = match self.client.as_mut() {
-                    Some(v) => v,
-                    None => return Err("could not move client value".into()),
+    /// Writes all batches `done` says are complete
+    async fn flush<F>(&mut self, done: F) -> Vec<Reply>
+    where
+        F: Fn(&Batch) -> bool,
+    {
+        let complete: Vec<(String, Vec<String>)> = self
+            .batches
+            .iter()
+            .filter(|(_, b)| done(b))
+            .map(|(k, _)| k.clone())
+            .collect();
+        if complete.is_empty() {
+            return vec![];
+        }
+        let mut batches: Vec<Batch> = complete
+            .iter()
+            .filter_map(|k| self.batches.remove(k))
+            .collect();
+
+        // (re)connect what we need
+        let needed = batches.len().min(self.clients.len());
+        for client in self.clients.iter_mut().take(needed) {
+            if client.as_ref().map_or(true, Client::is_closed) {
+                *client = match connect(&self.config).await {
+                    Ok(c) => Some(c),
+                    Err(e) => {
+                        error!("[Sink::{}] Failed to connect: {}", self.sink_url, e);
+                        None
+                    }
                 };
+            }
+        }
+
+        let mut written = Vec::new();
+        while !batches.is_empty() {
+            let round: Vec<Batch> = batches.drain(..needed.min(batches.len())).collect();
+            let conflict_keys = &self.config.conflict_keys;
+            let writes = round
+                .iter()
+                .zip(&self.clients)
+                .map(|(batch, client)| write(client.as_ref(), batch, conflict_keys));
+            for res in futures::future::join_all(writes).await {
+                written.extend(res);
+            }
+        }
 
-                match client.query_raw(
-                    q.as_str(),
-                    records
-                        .iter()
-                        .map(|p| p as &dyn postgres::types::ToSql)
-                        .collect::<Vec<&dyn postgres::types::ToSql>>(),
-                ) {
-                    Ok(_) => return Ok(None),
-                    Err(e) => return Err(format!("Failure while querying: {}", e).into()),
+        let mut res = Vec::new();
+        for (key, ok) in written {
+            if let Some(pending) = self.events.get_mut(&key) {
+                pending.failed |= !ok;
+                pending.remaining -= 1;
+                if pending.remaining == 0 {
+                    if let Some(reply) = self.events.remove(&key).and_then(Pending::insight) {
+                        res.push(reply);
+                    }
                 }
             }
         }
+        res
+    }
+}
 
-        Ok(None)
+#[async_trait::async_trait]
+impl Sink for Postgres {
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        _codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        mut event: Event,
+    ) -> ResultVec {
+        let mut rows = Vec::new();
+        let mut error = None;
+        for (value, meta) in event.value_meta_iter() {
+            match self.row(value, meta) {
+                Ok(row) => rows.push(row),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        if let Some(e) = error {
+            error!("[Sink::{}] Invalid event: {}", self.sink_url, e);
+            return Ok(event
+                .transactional
+                .then(|| vec![Reply::Insight(event.insight_fail())]));
+        }
+        if rows.is_empty() {
+            return Ok(event
+                .transactional
+                .then(|| vec![Reply::Insight(event.insight_ack())]));
+        }
+        let key = self.next_key;
+        self.next_key += 1;
+        self.events.insert(
+            key,
+            Pending {
+                ingest_ns: event.ingest_ns,
+                id: event.id.clone(),
+                op_meta: event.op_meta.clone(),
+                transactional: event.transactional,
+                remaining: rows.len(),
+                failed: false,
+            },
+        );
+        for (table, columns, values) in rows {
+            self.add(table, columns, Row { values, event: key });
+        }
+        let batch_size = self.config.batch_size;
+        Ok(Some(self.flush(|b| b.rows.len() >= batch_size).await))
     }
+
     fn default_codec(&self) -> &str {
         "json"
     }
@@ -146,22 +527,120 @@ impl Sink for Postgres {
     async fn init(
         &mut self,
         _sink_uid: u64,
-        _sink_url: &TremorUrl,
+        sink_url: &TremorUrl,
         _codec: &dyn Codec,
         _codec_map: &HashMap<String, Box<dyn Codec>>,
         _processors: Processors<'_>,
         _is_linked: bool,
         _reply_channel: Sender<sink::Reply>,
     ) -> Result<()> {
+        self.sink_url = sink_url.clone();
         Ok(())
     }
-    async fn on_signal(&mut self, _signal: Event) -> ResultVec {
-        Ok(None)
+
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        let timeout_ns = self.config.batch_timeout_ms * 1_000_000;
+        let now = signal.ingest_ns;
+        Ok(Some(
+            self.flush(|b| now.saturating_sub(b.started_ns) >= timeout_ns)
+                .await,
+        ))
     }
+
     fn is_active(&self) -> bool {
         true
     }
+
     fn auto_ack(&self) -> bool {
-        true
+        false
+    }
+
+    async fn terminate(&mut self) {
+        for reply in self.flush(|_| true).await {
+            if let Reply::Insight(e) = reply {
+                if e.cb == CbAction::Fail {
+                    error!("[Sink::{}] Lost events on shutdown", self.sink_url);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn statements() {
+        let columns = vec![
+            ("id".to_string(), Type::INT8),
+            ("name".to_string(), Type::TEXT),
+        ];
+        assert_eq!(
+            "INSERT INTO badgers (id,name) VALUES ($1::int8,$2::text),($3::int8,$4::text)",
+            insert_statement("badgers", &columns, 2, &[])
+        );
+        assert_eq!(
+            "INSERT INTO badgers (id,name) VALUES ($1::int8,$2::text) ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name",
+            insert_statement("badgers", &columns, 1, &["id".to_string()])
+        );
+        assert_eq!(
+            "INSERT INTO badgers (id,name) VALUES ($1::int8,$2::text) ON CONFLICT (id,name) DO NOTHING",
+            insert_statement(
+                "badgers",
+                &columns,
+                1,
+                &["id".to_string(), "name".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn casts() {
+        assert_eq!(Value::from(42), cast(&Type::INT4, &Value::from("42")));
+        assert_eq!(Value::from(1.5), cast(&Type::FLOAT8, &Value::from("1.5")));
+        assert_eq!(Value::from("42"), cast(&Type::TEXT, &Value::from(42)));
+        assert_eq!(Value::from(true), cast(&Type::BOOL, &Value::from("t")));
+        assert_eq!(
+            Value::from("2021-03-04 05:06:07.000000 +00:00"),
+            cast(
+                &Type::TIMESTAMPTZ,
+                &Value::from(1_614_834_367_000_000_000_u64)
+            )
+        );
+        assert_eq!(Value::null(), cast(&Type::INT8, &Value::null()));
+    }
+
+    #[test]
+    fn mapped_rows() -> Result<()> {
+        let config: Config = serde_yaml::from_str(
+            "{host: localhost, port: 5432, user: postgres, password: secret, dbname: snot, table: badgers, columns: [{name: id, type: INT8}, {name: name, field: badger.name, type: TEXT}]}",
+        )?;
+        let sink = Postgres::new(config)?;
+        let (table, columns, values) = sink.row(
+            &literal!({"id": "1", "badger": {"name": "snot"}}),
+            &literal!({"postgres": {"table": "public.honey_badgers"}}),
+        )?;
+        assert_eq!("public.honey_badgers", table);
+        assert_eq!(
+            vec![
+                ("id".to_string(), Type::INT8),
+                ("name".to_string(), Type::TEXT)
+            ],
+            columns
+        );
+        assert_eq!(vec![Value::from(1), Value::from("snot")], values);
+
+        let (table, _, values) = sink.row(&literal!({"id": 2}), &literal!({}))?;
+        assert_eq!("badgers", table);
+        assert_eq!(vec![Value::from(2), Value::null()], values);
+
+        assert!(sink
+            .row(
+                &literal!({}),
+                &literal!({"postgres": {"table": "x; DROP TABLE y"}})
+            )
+            .is_err());
+        Ok(())
     }
 }
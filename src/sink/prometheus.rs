@@ -0,0 +1,176 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Prometheus Remote-Write Offramp
+//!
+//! Batches influx style events into remote-write requests, see `crate::connectors::prometheus`.
+//!
+//! Events are acknowledged once the batch they are part of has been accepted by the remote end.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::connectors::batch::{self, Batch};
+use crate::connectors::prometheus::{encode_write_request, value_to_timeseries, TimeSeries};
+use crate::sink::prelude::*;
+use halfbrown::HashMap;
+use http_types::headers::{CONTENT_ENCODING, CONTENT_TYPE};
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// Remote-write endpoint, e.g. `http://localhost:9090/api/v1/write`
+    pub url: String,
+    /// Additional headers to send, e.g. for authentication
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// `batch_size` (series per request) and `flush_interval_ms`
+    #[serde(flatten)]
+    pub batch: batch::Config,
+}
+
+impl ConfigImpl for Config {}
+
+pub struct Prometheus {
+    config: Config,
+    series: Vec<TimeSeries>,
+    batch: Batch,
+}
+
+impl offramp::Impl for Prometheus {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(SinkManager::new_box(Self {
+                series: Vec::with_capacity(config.batch.batch_size),
+                batch: Batch::new(config.batch)?,
+                config,
+            }))
+        } else {
+            Err("Missing config for prometheus offramp".into())
+        }
+    }
+}
+
+impl Prometheus {
+    /// Sends the current batch and returns the insight for all events in it
+    async fn flush(&mut self) -> Option<Vec<Reply>> {
+        let events = self.batch.take()?;
+        let series = std::mem::take(&mut self.series);
+        let res = self.send(series).await;
+        if let Err(e) = &res {
+            error!(
+                "[Sink::Prometheus] Failed to send remote-write request: {}",
+                e
+            );
+        }
+        Some(vec![Reply::Insight(events.insight(res.is_ok()))])
+    }
+
+    async fn send(&self, series: Vec<TimeSeries>) -> Result<()> {
+        let body = encode_write_request(series)?;
+        debug!("[Sink::Prometheus] sending {} bytes", body.len());
+        let mut request = surf::post(&self.config.url)
+            .header(CONTENT_ENCODING, "snappy")
+            .header(CONTENT_TYPE, "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body);
+        for (name, value) in &self.config.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        let mut response = request.await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            let body = response
+                .body_string()
+                .await
+                .unwrap_or_else(|e| format!("failed to load body {}", e));
+            Err(format!(
+                "remote-write request failed with status {}: {}",
+                response.status(),
+                body
+            )
+            .into())
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for Prometheus {
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        _codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        mut event: Event,
+    ) -> ResultVec {
+        let mut series = Vec::new();
+        for value in event.value_iter() {
+            match value_to_timeseries(value) {
+                Ok(s) => series.extend(s),
+                Err(e) => {
+                    error!("[Sink::Prometheus] Invalid metric: {}", e);
+                    return Ok(Some(vec![Reply::Insight(event.insight_fail())]));
+                }
+            }
+        }
+        self.batch.push(event, series.len());
+        self.series.append(&mut series);
+        if self.batch.is_full() {
+            Ok(self.flush().await)
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        if self.batch.is_due(signal.ingest_ns) {
+            Ok(self.flush().await)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        _sink_uid: u64,
+        _sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        _processors: Processors<'_>,
+        _is_linked: bool,
+        _reply_channel: Sender<Reply>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn terminate(&mut self) {
+        // insights can't be delivered anymore, but the data should still be sent
+        self.flush().await;
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    fn auto_ack(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,514 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Redis Offramp
+//!
+//! Executes commands against Redis, in the style of the `kv` offramp. All commands of an
+//! event (or a batch) are sent as one pipeline, their results are sent out of the `out`
+//! port, failed commands out of the `err` port. `$correlation` is carried over to the
+//! responses.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::connectors::redis::{cmd, Connection, ConnectionConfig, Resp};
+use crate::sink::prelude::*;
+use crate::source::prelude::*;
+use halfbrown::HashMap;
+use tremor_pipeline::EventIdGenerator;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    #[serde(flatten)]
+    pub(crate) connection: ConnectionConfig,
+    /// Field of stream entries added with `xadd` that holds the value, defaults to `data`
+    #[serde(default = "dflt_payload_field")]
+    pub payload_field: String,
+}
+
+fn dflt_payload_field() -> String {
+    "data".to_string()
+}
+
+impl ConfigImpl for Config {}
+
+pub struct Redis {
+    sink_url: TremorUrl,
+    config: Config,
+    event_origin_uri: EventOriginUri,
+    idgen: EventIdGenerator,
+    reply_tx: Sender<Reply>,
+    conn: Option<Connection>,
+}
+
+impl offramp::Impl for Redis {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            let event_origin_uri = EventOriginUri {
+                uid: 0,
+                scheme: "tremor-redis".to_string(),
+                host: config.connection.host.clone(),
+                port: Some(config.connection.port),
+                path: vec![],
+            };
+            // dummy
+            let (dummy_tx, _) = async_channel::bounded(1);
+
+            Ok(SinkManager::new_box(Self {
+                sink_url: TremorUrl::from_offramp_id("redis")?, // dummy value
+                config,
+                event_origin_uri,
+                idgen: EventIdGenerator::new(0),
+                reply_tx: dummy_tx, // dummy, will be replaced in init
+                conn: None,
+            }))
+        } else {
+            Err("Missing config for redis offramp".into())
+        }
+    }
+}
+
+/// A command, without its values, to interpret the reply
+#[derive(Debug, PartialEq)]
+enum Op {
+    /// ```json
+    /// {"get": {"key": "the-key"}}
+    /// ```
+    Get { key: Vec<u8> },
+    /// ```json
+    /// {"put": {"key": "the-key", "value": "the-value"}}
+    /// ```
+    Put { key: Vec<u8> },
+    /// ```json
+    /// {"delete": {"key": "the-key"}}
+    /// ```
+    Delete { key: Vec<u8> },
+    /// ```json
+    /// {"scan": {
+    ///    "cursor": 0,
+    ///    "match": "prefix:*",
+    ///    "count": 100
+    /// }
+    /// ```
+    /// All but `cursor` are optional, scanning is done once the returned cursor is 0.
+    Scan,
+    /// ```json
+    /// {"xadd": {"stream": "the-stream", "value": "the-value", "maxlen": 1000}}
+    /// ```
+    /// `maxlen` is optional and trims the stream to about that many entries.
+    Xadd { stream: Vec<u8> },
+    /// ```json
+    /// {"publish": {"channel": "the-channel", "value": "the-value"}}
+    /// ```
+    Publish { channel: Vec<u8> },
+}
+
+impl Op {
+    fn name(&self) -> &'static str {
+        match self {
+            Op::Get { .. } => "get",
+            Op::Put { .. } => "put",
+            Op::Delete { .. } => "delete",
+            Op::Scan => "scan",
+            Op::Xadd { .. } => "xadd",
+            Op::Publish { .. } => "publish",
+        }
+    }
+
+    fn key(&self) -> Option<&[u8]> {
+        match self {
+            Op::Get { key } | Op::Put { key } | Op::Delete { key } => Some(key),
+            Op::Scan | Op::Xadd { .. } | Op::Publish { .. } => None,
+        }
+    }
+}
+
+fn decode(v: Option<Vec<u8>>, codec: &mut dyn Codec, ingest_ns: u64) -> Result<Value<'static>> {
+    if let Some(mut v) = v {
+        Ok(codec
+            .decode(&mut v, ingest_ns)?
+            .unwrap_or_default()
+            .into_static())
+    } else {
+        Ok(Value::null())
+    }
+}
+
+fn ok(k: &[u8], v: Value<'static>) -> Value<'static> {
+    literal!({
+        "ok": {
+            "key": Value::Bytes(k.to_vec().into()),
+            "value": v
+        }
+    })
+}
+
+fn bytes<'v>(v: &'v Value, field: &str) -> Result<&'v [u8]> {
+    v.get_bytes(field)
+        .ok_or_else(|| ErrorKind::KvError(format!("Missing or invalid `{}` field", field)).into())
+}
+
+fn value<'v>(v: &'v Value<'v>) -> Result<&'v Value<'v>> {
+    v.get("value")
+        .ok_or_else(|| ErrorKind::KvError("Missing `value` field".to_string()).into())
+}
+
+impl Redis {
+    /// Turns a command event into the redis command to run
+    fn command(&self, v: &Value, codec: &mut dyn Codec) -> Result<(Op, Vec<Vec<u8>>)> {
+        if let Some(g) = v.get("get") {
+            let key = bytes(g, "key")?;
+            Ok((Op::Get { key: key.to_vec() }, cmd([&b"GET"[..], key])))
+        } else if let Some(p) = v.get("put") {
+            let key = bytes(p, "key")?;
+            let value = codec.encode(value(p)?)?;
+            let c = cmd([&b"SET"[..], key, value.as_slice()]);
+            Ok((Op::Put { key: key.to_vec() }, c))
+        } else if let Some(d) = v.get("delete") {
+            let key = bytes(d, "key")?;
+            Ok((Op::Delete { key: key.to_vec() }, cmd([&b"DEL"[..], key])))
+        } else if let Some(s) = v.get("scan") {
+            let cursor = s.get_u64("cursor").unwrap_or_default();
+            let mut c = cmd(["SCAN".to_string(), cursor.to_string()]);
+            if let Some(pattern) = s.get_bytes("match") {
+                c.extend(cmd([&b"MATCH"[..], pattern]));
+            }
+            if let Some(count) = s.get_u64("count") {
+                c.extend(cmd(["COUNT".to_string(), count.to_string()]));
+            }
+            Ok((Op::Scan, c))
+        } else if let Some(x) = v.get("xadd") {
+            let stream = bytes(x, "stream")?;
+            let value = codec.encode(value(x)?)?;
+            let mut c = cmd([&b"XADD"[..], stream]);
+            if let Some(maxlen) = x.get_u64("maxlen") {
+                c.extend(cmd(["MAXLEN", "~", maxlen.to_string().as_str()]));
+            }
+            c.extend(cmd([
+                &b"*"[..],
+                self.config.payload_field.as_bytes(),
+                value.as_slice(),
+            ]));
+            Ok((
+                Op::Xadd {
+                    stream: stream.to_vec(),
+                },
+                c,
+            ))
+        } else if let Some(p) = v.get("publish") {
+            let channel = bytes(p, "channel")?;
+            let value = codec.encode(value(p)?)?;
+            let c = cmd([&b"PUBLISH"[..], channel, value.as_slice()]);
+            Ok((
+                Op::Publish {
+                    channel: channel.to_vec(),
+                },
+                c,
+            ))
+        } else {
+            Err(ErrorKind::KvError(format!("Invalid redis command: {}", v)).into())
+        }
+    }
+}
+
+/// Interprets the reply to a command
+fn response(op: &Op, reply: Resp, codec: &mut dyn Codec, ingest_ns: u64) -> Result<Value<'static>> {
+    let reply = reply.into_result()?;
+    Ok(match op {
+        Op::Get { key } => ok(key, decode(reply.into_bytes(), codec, ingest_ns)?),
+        Op::Put { key } | Op::Delete { key } => ok(key, Value::null()),
+        Op::Scan => {
+            let mut parts = reply
+                .into_array()
+                .ok_or("Unexpected SCAN reply")?
+                .into_iter();
+            let cursor = parts
+                .next()
+                .and_then(Resp::into_bytes)
+                .and_then(|c| String::from_utf8(c).ok())
+                .and_then(|c| c.parse::<u64>().ok())
+                .ok_or("Invalid SCAN cursor")?;
+            let keys: Vec<Value<'static>> = parts
+                .next()
+                .and_then(Resp::into_array)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(Resp::into_bytes)
+                .map(|k| Value::Bytes(k.into()))
+                .collect();
+            literal!({ "ok": { "cursor": cursor, "keys": keys } })
+        }
+        Op::Xadd { stream } => literal!({
+            "ok": {
+                "stream": Value::Bytes(stream.clone().into()),
+                "id": reply.into_value()
+            }
+        }),
+        Op::Publish { channel } => literal!({
+            "ok": {
+                "channel": Value::Bytes(channel.clone().into()),
+                "receivers": reply.into_value()
+            }
+        }),
+    })
+}
+
+#[async_trait::async_trait]
+impl Sink for Redis {
+    #[allow(clippy::too_many_lines)]
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        event: Event,
+    ) -> ResultVec {
+        let mut r = Vec::with_capacity(10);
+        let ingest_ns = tremor_common::time::nanotime();
+
+        let mut ops = Vec::new();
+        let mut cmds = Vec::new();
+        for (v, m) in event.value_meta_iter() {
+            let op = self.command(v, codec).map(|(op, c)| {
+                cmds.push(c);
+                op
+            });
+            ops.push((op, m.get("correlation")));
+        }
+
+        // send all commands at once
+        let replies: Result<Vec<Resp>> = if cmds.is_empty() {
+            Ok(Vec::new())
+        } else {
+            if self.conn.is_none() {
+                match Connection::connect(&self.config.connection).await {
+                    Ok(conn) => self.conn = Some(conn),
+                    Err(e) => error!("[Sink::{}] Failed to connect: {}", self.sink_url, e),
+                }
+            }
+            match self.conn.as_mut() {
+                Some(conn) => conn.pipeline(&cmds).await,
+                None => Err("Not connected to redis".into()),
+            }
+        };
+        let mut replies = replies.map(Vec::into_iter);
+        if replies.is_err() {
+            // we can't tell which commands were executed and need a fresh connection
+            self.conn = None;
+        }
+
+        let mut first_error = None;
+        // note: we always try to handle all commands / errors.
+        for (op, correlation) in ops {
+            let executed = match (op, replies.as_mut()) {
+                (Ok(op), Ok(replies)) => {
+                    let reply = replies.next().ok_or("Missing redis reply");
+                    match reply
+                        .map_err(Error::from)
+                        .and_then(|reply| response(&op, reply, codec, ingest_ns))
+                    {
+                        Ok(data) => Ok((op.name(), data)),
+                        Err(e) => Err((Some(op), e)),
+                    }
+                }
+                (Ok(op), Err(e)) => Err((Some(op), Error::from(e.to_string()))),
+                (Err(e), _) => Err((None, e)),
+            };
+            match executed {
+                Ok((op, data)) => {
+                    let mut id = self.idgen.next_id();
+                    id.track(&event.id);
+
+                    let mut meta = Value::object_with_capacity(2);
+                    meta.try_insert("redis", literal!({ "op": op }));
+                    if let Some(correlation) = correlation {
+                        meta.try_insert("correlation", correlation.clone_static());
+                    }
+                    let e = Event {
+                        id,
+                        ingest_ns,
+                        data: (data, meta).into(),
+                        origin_uri: Some(self.event_origin_uri.clone()),
+                        ..Event::default()
+                    };
+                    r.push(Reply::Response(OUT, e));
+                }
+                Err((op, e)) => {
+                    // send ERR response and log err
+                    let mut id = self.idgen.next_id();
+                    id.track(&event.id);
+                    let key = op.as_ref().and_then(Op::key);
+                    let data = literal!({
+                        "key": key.map_or_else(Value::null, |k| Value::Bytes(k.to_vec().into())),
+                        "error": e.to_string(),
+                    });
+                    let mut meta = Value::object_with_capacity(3);
+                    meta.try_insert("redis", literal!({ "op": op.as_ref().map(Op::name) }));
+                    meta.try_insert("error", e.to_string());
+                    if let Some(correlation) = correlation {
+                        meta.try_insert("correlation", correlation.clone_static());
+                    }
+                    let err_event = Event {
+                        id,
+                        ingest_ns,
+                        data: (data, meta).into(),
+                        origin_uri: Some(self.event_origin_uri.clone()),
+                        ..Event::default()
+                    };
+                    r.push(Reply::Response(ERR, err_event));
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = first_error {
+            // send away all response events asynchronously before
+            for reply in r {
+                if let Err(e) = self.reply_tx.send(reply).await {
+                    error!("[Sink::{}] Error sending error reply: {}", self.sink_url, e);
+                }
+            }
+            // trigger CB fail
+            Err(e)
+        } else {
+            Ok(Some(r))
+        }
+    }
+
+    async fn on_signal(&mut self, _signal: Event) -> ResultVec {
+        Ok(None)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        sink_uid: u64,
+        sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        _processors: Processors<'_>,
+        _is_linked: bool,
+        reply_channel: Sender<sink::Reply>,
+    ) -> Result<()> {
+        self.event_origin_uri.uid = sink_uid;
+        self.sink_url = sink_url.clone();
+        self.idgen.set_source(sink_uid);
+        self.reply_tx = reply_channel;
+        self.conn = Some(Connection::connect(&self.config.connection).await?);
+        Ok(())
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    fn auto_ack(&self) -> bool {
+        true
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    async fn terminate(&mut self) {
+        self.conn = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands() -> Result<()> {
+        let sink = Redis {
+            sink_url: TremorUrl::from_offramp_id("redis")?,
+            config: serde_yaml::from_str("host: localhost")?,
+            event_origin_uri: EventOriginUri {
+                uid: 0,
+                scheme: "tremor-redis".to_string(),
+                host: "localhost".to_string(),
+                port: None,
+                path: vec![],
+            },
+            idgen: EventIdGenerator::new(0),
+            reply_tx: async_channel::bounded(1).0,
+            conn: None,
+        };
+        let mut codec = crate::codec::lookup("json")?;
+        let codec = codec.as_mut();
+
+        let (op, c) = sink.command(&literal!({"get": {"key": "snot"}}), codec)?;
+        assert_eq!(
+            Op::Get {
+                key: b"snot".to_vec()
+            },
+            op
+        );
+        assert_eq!(cmd(["GET", "snot"]), c);
+        let reply = Resp::Bulk(Some(b"{\"badger\":1}".to_vec()));
+        assert_eq!(
+            literal!({"ok": {"key": Value::Bytes(b"snot".to_vec().into()), "value": {"badger": 1}}}),
+            response(&op, reply, codec, 0)?
+        );
+        assert_eq!(
+            literal!({"ok": {"key": Value::Bytes(b"snot".to_vec().into()), "value": null}}),
+            response(&op, Resp::Bulk(None), codec, 0)?
+        );
+
+        let (_, c) = sink.command(&literal!({"put": {"key": "snot", "value": [1]}}), codec)?;
+        assert_eq!(cmd(["SET", "snot", "[1]"]), c);
+
+        let (op, c) = sink.command(
+            &literal!({"xadd": {"stream": "events", "value": "snot", "maxlen": 10}}),
+            codec,
+        )?;
+        assert_eq!(
+            cmd(["XADD", "events", "MAXLEN", "~", "10", "*", "data", "\"snot\""]),
+            c
+        );
+        assert_eq!(
+            literal!({"ok": {"stream": Value::Bytes(b"events".to_vec().into()), "id": "1-0"}}),
+            response(&op, Resp::Bulk(Some(b"1-0".to_vec())), codec, 0)?
+        );
+
+        let (op, c) = sink.command(&literal!({"scan": {"cursor": 3, "match": "s*"}}), codec)?;
+        assert_eq!(cmd(["SCAN", "3", "MATCH", "s*"]), c);
+        let reply = Resp::Array(Some(vec![
+            Resp::Bulk(Some(b"0".to_vec())),
+            Resp::Array(Some(vec![Resp::Bulk(Some(b"snot".to_vec()))])),
+        ]));
+        assert_eq!(
+            literal!({"ok": {"cursor": 0, "keys": [Value::Bytes(b"snot".to_vec().into())]}}),
+            response(&op, reply, codec, 0)?
+        );
+
+        let (op, c) = sink.command(
+            &literal!({"publish": {"channel": "news", "value": {}}}),
+            codec,
+        )?;
+        assert_eq!(cmd(["PUBLISH", "news", "{}"]), c);
+        assert!(response(&op, Resp::Error("ERR nope".to_string()), codec, 0).is_err());
+
+        assert!(sink.command(&literal!({"get": {}}), codec).is_err());
+        assert!(sink
+            .command(&literal!({"put": {"key": "snot"}}), codec)
+            .is_err());
+        assert!(sink
+            .command(&literal!({"incr": {"key": "snot"}}), codec)
+            .is_err());
+        Ok(())
+    }
+}
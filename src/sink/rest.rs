@@ -15,13 +15,14 @@
 #![cfg(not(tarpaulin_include))]
 
 use crate::codec::Codec;
+use crate::connectors::backoff::Backoff;
 use crate::errors::ErrorKind;
 use crate::sink::prelude::*;
 use async_channel::{bounded, Receiver, Sender};
 use gouth::Token;
 use halfbrown::HashMap;
 use http_types::mime::Mime;
-use http_types::{headers::HeaderValue, Method};
+use http_types::{headers::HeaderValue, Method, StatusCode};
 use serde::de::{self, MapAccess, Visitor};
 use serde::{Deserialize, Deserializer};
 use std::borrow::Borrow;
@@ -30,7 +31,7 @@ use std::marker::PhantomData;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use surf::{Body, Client, Request, Response};
 use tremor_pipeline::{EventId, EventIdGenerator, OpMeta};
 use tremor_script::Object;
@@ -52,7 +53,65 @@ pub struct Endpoint {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum Auth {
+    /// google cloud platform service account credentials
     Gcp,
+    /// http basic authentication
+    Basic { username: String, password: String },
+    /// static bearer token
+    Bearer(String),
+    /// OAuth2 client credentials flow
+    OAuth2(OAuth2),
+}
+
+/// OAuth2 client credentials, tokens are fetched from `token_url` and refreshed before they expire
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OAuth2 {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+/// Retry configuration for requests failing with connection errors, `5xx` or `429` responses
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Retry {
+    /// maximum number of retries per request (default: 0)
+    #[serde(default)]
+    pub max_retries: u32,
+    /// `initial_backoff_ms` and `max_backoff_ms` between retries
+    #[serde(flatten)]
+    pub backoff: Backoff,
+    /// randomize backoffs to avoid retrying in lockstep (default: true)
+    #[serde(default = "dflt_jitter")]
+    pub jitter: bool,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            backoff: Backoff::default(),
+            jitter: dflt_jitter(),
+        }
+    }
+}
+
+fn dflt_jitter() -> bool {
+    true
+}
+
+impl Retry {
+    /// delay before retry number `attempt` (starting at 0), a delay `requested` by the server
+    /// is used instead of the backoff but capped by `max_backoff_ms`, so a server can't stall
+    /// us for a day
+    fn delay(&self, attempt: u32, requested: Option<Duration>) -> Duration {
+        match requested {
+            Some(requested) => requested.min(self.backoff.max()),
+            None if self.jitter => self.backoff.jittered(attempt),
+            None => self.backoff.delay(attempt),
+        }
+    }
 }
 
 fn none_if_empty(s: &str) -> Option<String> {
@@ -253,6 +312,10 @@ pub struct Config {
 
     #[serde(default)]
     pub headers: HashMap<String, String>,
+
+    /// retries for failed requests, disabled by default
+    #[serde(default)]
+    pub retry: Retry,
 }
 
 fn dflt_concurrency() -> usize {
@@ -358,6 +421,8 @@ impl Sink for Rest {
             let (tx, rx) = bounded::<SendTaskInMsg>(1);
             let max_counter = self.num_inflight_requests.clone();
             let http_client = self.client.clone(); // should be quite cheap, just some Arcs
+            let retry = self.config.retry;
+            let sink_url = self.sink_url.clone();
 
             // spawn send task
             task::spawn(async move {
@@ -382,7 +447,7 @@ impl Sink for Rest {
                         };
                         let request_meta = build_request_metadata(&request)?;
                         // send request
-                        match http_client.send(request).await {
+                        match send_with_retry(&http_client, request, retry, &sink_url).await {
                             Ok(response) => {
                                 #[allow(clippy::cast_possible_truncation)]
                                 // we don't care about the upper 64 bit
@@ -532,31 +597,30 @@ async fn codec_task(
         ..EventOriginUri::default()
     };
     // create token in this lifetime (ambiguous to compiler), instead of bound to the sink.
-    let token = match auth {
-        Some(Auth::Gcp) => {
-            let t = Token::new()?;
-            Some(t)
-        }
-        None => None,
-    };
+    let mut authorization = auth.map(Authorization::new).transpose()?;
     let codec: &mut dyn Codec = codec.as_mut();
     while let Ok(msg) = in_rx.recv().await {
         match msg {
             CodecTaskInMsg::ToRequest(event, tx) => {
                 let mut request_headers = default_headers.clone();
-                if let Some(ref t) = token {
-                    request_headers
-                        .insert("authorization".to_string(), t.header_value()?.to_string());
-                }
-                match build_request(
-                    &event,
-                    codec,
-                    &codec_map,
-                    postprocessors.as_mut_slice(),
-                    default_method,
-                    &request_headers,
-                    &endpoint,
-                ) {
+                let auth_header = match authorization.as_mut() {
+                    Some(a) => a.header_value().await.map(Some),
+                    None => Ok(None),
+                };
+                match auth_header.and_then(|auth_header| {
+                    if let Some(auth_header) = auth_header {
+                        request_headers.insert("authorization".to_string(), auth_header);
+                    }
+                    build_request(
+                        &event,
+                        codec,
+                        &codec_map,
+                        postprocessors.as_mut_slice(),
+                        default_method,
+                        &request_headers,
+                        &endpoint,
+                    )
+                }) {
                     Ok(request) => {
                         if let Err(e) = tx.send(SendTaskInMsg::Request(request)).await {
                             error!(
@@ -614,6 +678,12 @@ async fn codec_task(
             } => {
                 // send CB insight -> handle status >= 400
                 let status = response.status();
+                if status == StatusCode::Unauthorized {
+                    // the token might have been revoked, fetch a new one for the next request
+                    if let Some(a) = authorization.as_mut() {
+                        a.invalidate();
+                    }
+                }
 
                 let meta = literal!({ "time": duration });
                 let mut cb = if status.is_client_error() || status.is_server_error() {
@@ -752,6 +822,178 @@ async fn codec_task(
     Ok(())
 }
 
+/// Authorization state of the codec task
+enum Authorization {
+    Gcp(Token),
+    Static(String),
+    OAuth2(OAuth2Token),
+}
+
+impl Authorization {
+    fn new(auth: Auth) -> Result<Self> {
+        Ok(match auth {
+            Auth::Gcp => Self::Gcp(Token::new()?),
+            Auth::Basic { username, password } => Self::Static(format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            )),
+            Auth::Bearer(token) => Self::Static(format!("Bearer {}", token)),
+            Auth::OAuth2(config) => Self::OAuth2(OAuth2Token::new(config)),
+        })
+    }
+
+    /// value for the `authorization` header
+    async fn header_value(&mut self) -> Result<String> {
+        match self {
+            Self::Gcp(token) => Ok(token.header_value()?.to_string()),
+            Self::Static(value) => Ok(value.clone()),
+            Self::OAuth2(token) => token.header_value().await,
+        }
+    }
+
+    /// forget the current token, if any, so a fresh one is used for the next request
+    fn invalidate(&mut self) {
+        if let Self::OAuth2(token) = self {
+            token.invalidate();
+        }
+    }
+}
+
+/// tokens are refreshed this long before they expire
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+/// Token cache for the OAuth2 client credentials flow
+struct OAuth2Token {
+    config: OAuth2,
+    /// header value and the time it needs to be refreshed at
+    token: Option<(String, Option<Instant>)>,
+}
+
+impl OAuth2Token {
+    fn new(config: OAuth2) -> Self {
+        Self {
+            config,
+            token: None,
+        }
+    }
+
+    fn invalidate(&mut self) {
+        self.token = None;
+    }
+
+    async fn header_value(&mut self) -> Result<String> {
+        match &self.token {
+            Some((value, refresh_at)) if refresh_at.map_or(true, |at| Instant::now() < at) => {
+                Ok(value.clone())
+            }
+            _ => {
+                let token = self.fetch().await?;
+                let refresh_at = token.expires_in.map(|secs| {
+                    Instant::now() + Duration::from_secs(secs).saturating_sub(TOKEN_EXPIRY_MARGIN)
+                });
+                let value = format!("Bearer {}", token.access_token);
+                self.token = Some((value.clone(), refresh_at));
+                Ok(value)
+            }
+        }
+    }
+
+    async fn fetch(&self) -> Result<TokenResponse> {
+        let scope = self.config.scopes.join(" ");
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", self.config.client_id.as_str()),
+            ("client_secret", self.config.client_secret.as_str()),
+        ];
+        if !scope.is_empty() {
+            form.push(("scope", scope.as_str()));
+        }
+        let mut response = surf::post(&self.config.token_url)
+            .body(Body::from_form(&form)?)
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "OAuth2 token request failed with status {}",
+                response.status()
+            )
+            .into());
+        }
+        let mut body = response.body_bytes().await?;
+        Ok(simd_json::from_slice(&mut body)?)
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TooManyRequests
+}
+
+/// delay requested by the server via the `Retry-After` header, only the seconds form is supported
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .header("Retry-After")
+        .and_then(|v| v.last().as_str().trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Sends `request`, retrying connection errors and `5xx` or `429` responses with exponential backoff.
+/// Once retries are exhausted the last response or error is returned.
+async fn send_with_retry(
+    client: &Client,
+    mut request: Request,
+    retry: Retry,
+    sink_url: &TremorUrl,
+) -> surf::Result<Response> {
+    if retry.max_retries == 0 {
+        return client.send(request).await;
+    }
+    // a body can only be sent once, keep its bytes around for retries
+    let body = request.take_body();
+    let mime = body.mime().clone();
+    let bytes = body.into_bytes().await?;
+    let mut attempt = 0;
+    loop {
+        // cloning drops the body
+        let mut attempt_request = request.clone();
+        let mut body = Body::from_bytes(bytes.clone());
+        body.set_mime(mime.clone());
+        attempt_request.set_body(body);
+        let res = client.send(attempt_request).await;
+        let delay = match &res {
+            Ok(response) if is_retryable(response.status()) => {
+                Some(retry.delay(attempt, retry_after(response)))
+            }
+            Ok(_) => None,
+            Err(_) => Some(retry.delay(attempt, None)),
+        };
+        match delay {
+            Some(delay) if attempt < retry.max_retries => {
+                match &res {
+                    Ok(response) => warn!(
+                        "[Sink::{}] HTTP request failed: {}, retrying in {:?}",
+                        sink_url,
+                        response.status(),
+                        delay
+                    ),
+                    Err(e) => warn!(
+                        "[Sink::{}] Error sending HTTP request: {}, retrying in {:?}",
+                        sink_url, e, delay
+                    ),
+                }
+                task::sleep(delay).await;
+                attempt += 1;
+            }
+            _ => return res,
+        }
+    }
+}
+
 #[allow(clippy::too_many_lines)]
 fn build_request(
     event: &Event,
@@ -1283,6 +1525,176 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn deserialize_auth_and_retry() -> Result<()> {
+        let config_s = r#"
+            endpoint: "http://localhost:8080/"
+            auth:
+              oauth2:
+                token_url: "http://localhost:8081/token"
+                client_id: "snot"
+                client_secret: "badger"
+            retry:
+              max_retries: 3
+        "#;
+        let v: serde_yaml::Value = serde_yaml::from_str(config_s)?;
+        let config = Config::new(&v)?;
+        assert!(
+            matches!(config.auth, Some(Auth::OAuth2(OAuth2 { ref client_id, .. })) if client_id == "snot")
+        );
+        assert_eq!(3, config.retry.max_retries);
+        assert_eq!(100, config.retry.backoff.initial_backoff_ms);
+
+        let v: serde_yaml::Value = serde_yaml::from_str("auth: gcp")?;
+        assert!(matches!(Config::new(&v)?.auth, Some(Auth::Gcp)));
+        let v: serde_yaml::Value = serde_yaml::from_str("auth: {bearer: token}")?;
+        assert!(matches!(Config::new(&v)?.auth, Some(Auth::Bearer(ref t)) if t == "token"));
+        let v: serde_yaml::Value =
+            serde_yaml::from_str("auth: {basic: {username: snot, password: badger}}")?;
+        let mut auth = Authorization::new(Config::new(&v)?.auth.ok_or("no auth")?)?;
+        assert_eq!(
+            "Basic c25vdDpiYWRnZXI=",
+            async_std::task::block_on(auth.header_value())?
+        );
+        Ok(())
+    }
+
+    async fn token(mut req: tide::Request<Arc<AtomicUsize>>) -> tide::Result {
+        let body = req.body_string().await?;
+        let n = req.state().fetch_add(1, Ordering::SeqCst) + 1;
+        if !body.contains("grant_type=client_credentials") || !body.contains("scope=read") {
+            return Ok(tide::Response::new(400));
+        }
+        Ok(tide::Response::builder(200)
+            .body(format!(
+                r#"{{"access_token":"token-{}","token_type":"bearer","expires_in":3600}}"#,
+                n
+            ))
+            .build())
+    }
+
+    async fn flaky(mut req: tide::Request<Arc<AtomicUsize>>) -> tide::Result {
+        let body = req.body_string().await?;
+        let n = req.state().fetch_add(1, Ordering::SeqCst) + 1;
+        Ok(if body != "snot" {
+            tide::Response::new(400)
+        } else if n <= 2 {
+            tide::Response::builder(503)
+                .header("Retry-After", "0")
+                .build()
+        } else {
+            tide::Response::new(200)
+        })
+    }
+
+    async fn throttled(req: tide::Request<Arc<AtomicUsize>>) -> tide::Result {
+        let n = req.state().fetch_add(1, Ordering::SeqCst) + 1;
+        Ok(if n <= 1 {
+            tide::Response::builder(429)
+                .header("Retry-After", "86400")
+                .build()
+        } else {
+            tide::Response::new(200)
+        })
+    }
+
+    /// starts a local server, `/token` hands out OAuth2 tokens, `/flaky` fails with a `503`
+    /// for the first 2 requests, `/throttled` asks the first request to retry after a day
+    fn start_server(hits: Arc<AtomicUsize>) -> Result<String> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let mut app = tide::with_state(hits);
+        app.at("/token").post(token);
+        app.at("/flaky").post(flaky);
+        app.at("/throttled").post(throttled);
+        task::spawn(app.listen(listener));
+        Ok(format!("http://{}", addr))
+    }
+
+    #[async_std::test]
+    async fn oauth2_token_refresh() -> Result<()> {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let base = start_server(hits.clone())?;
+        let mut token = OAuth2Token::new(OAuth2 {
+            token_url: format!("{}/token", base),
+            client_id: "snot".to_string(),
+            client_secret: "badger".to_string(),
+            scopes: vec!["read".to_string()],
+        });
+        assert_eq!("Bearer token-1", token.header_value().await?);
+        // cached
+        assert_eq!("Bearer token-1", token.header_value().await?);
+        assert_eq!(1, hits.load(Ordering::SeqCst));
+        token.invalidate();
+        assert_eq!("Bearer token-2", token.header_value().await?);
+        assert_eq!(2, hits.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn retries() -> Result<()> {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let base = start_server(hits.clone())?;
+        let sink_url = TremorUrl::from_offramp_id("rest")?;
+        let client = surf::client();
+        let retry = Retry {
+            max_retries: 1,
+            backoff: Backoff {
+                initial_backoff_ms: 1,
+                max_backoff_ms: 1,
+            },
+            jitter: false,
+        };
+        let url = format!("{}/flaky", base);
+
+        // retries exhausted, the last response is returned
+        let request = surf::post(&url).body("snot").build();
+        let response = send_with_retry(&client, request, retry, &sink_url).await?;
+        assert_eq!(StatusCode::ServiceUnavailable, response.status());
+        assert_eq!(2, hits.load(Ordering::SeqCst));
+
+        let request = surf::post(&url).body("snot").build();
+        let response = send_with_retry(&client, request, retry, &sink_url).await?;
+        assert_eq!(StatusCode::Ok, response.status());
+        assert_eq!(3, hits.load(Ordering::SeqCst));
+
+        // connection errors are retried as well
+        let request = surf::post("http://127.0.0.1:1/").body("snot").build();
+        assert!(send_with_retry(&client, request, retry, &sink_url)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn retry_after_is_capped() -> Result<()> {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let base = start_server(hits.clone())?;
+        let sink_url = TremorUrl::from_offramp_id("rest")?;
+        let client = surf::client();
+        let retry = Retry {
+            max_retries: 1,
+            backoff: Backoff {
+                initial_backoff_ms: 1,
+                max_backoff_ms: 10,
+            },
+            jitter: false,
+        };
+        let request = surf::post(format!("{}/throttled", base))
+            .body("snot")
+            .build();
+        // the requested day is capped at `max_backoff_ms`
+        let response = async_std::future::timeout(
+            Duration::from_secs(5),
+            send_with_retry(&client, request, retry, &sink_url),
+        )
+        .await
+        .map_err(|_| Error::from("Retry-After was not capped"))??;
+        assert_eq!(StatusCode::Ok, response.status());
+        assert_eq!(2, hits.load(Ordering::SeqCst));
+        Ok(())
+    }
+
     // we can't use async_std::tst here as it causes lifetime issues with codec
     #[async_std::test]
     async fn build_response() -> Result<()> {
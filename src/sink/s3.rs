@@ -0,0 +1,398 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # S3 Offramp
+//!
+//! Batches events into objects, one encoded event per line, and uploads them to an S3
+//! compatible store once they reach `max_object_bytes` or are `max_object_age_ms` old.
+//! Objects larger than `part_size` are uploaded in parts.
+//!
+//! Object keys are built from the `key` template, which can contain:
+//!
+//! * `{$path.to.field}`: a metadata field, events with different values go to different objects
+//! * `{date}` and `{hour}`: UTC date (`YYYY-MM-DD`) and hour (`HH`) the object was started
+//! * `{timestamp}`: nanoseconds since the epoch the object was started
+//! * `{seq}`: a counter, incremented with every object
+//!
+//! Events are acknowledged once the object they are in is uploaded.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::connectors::s3::{Client, ConnectionConfig};
+use crate::sink::prelude::*;
+use chrono::{TimeZone, Utc};
+use halfbrown::HashMap;
+use tremor_pipeline::{EventId, OpMeta};
+
+/// Parts of multipart uploads need to be at least this large, except the last one
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    #[serde(flatten)]
+    pub(crate) connection: ConnectionConfig,
+    /// Template for object keys, defaults to `{date}/{timestamp}-{seq}`
+    #[serde(default = "dflt_key")]
+    pub key: String,
+    /// Upload objects once they are this large, defaults to 64 MiB
+    #[serde(default = "dflt_max_object_bytes")]
+    pub max_object_bytes: usize,
+    /// Upload objects once they are this old, defaults to one minute
+    #[serde(default = "dflt_max_object_age_ms")]
+    pub max_object_age_ms: u64,
+    /// Upload objects larger than this in parts of this size, defaults to 8 MiB, needs to be
+    /// at least 5 MiB
+    #[serde(default = "dflt_part_size")]
+    pub part_size: usize,
+}
+
+fn dflt_key() -> String {
+    "{date}/{timestamp}-{seq}".to_string()
+}
+
+fn dflt_max_object_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn dflt_max_object_age_ms() -> u64 {
+    60_000
+}
+
+fn dflt_part_size() -> usize {
+    8 * 1024 * 1024
+}
+
+impl ConfigImpl for Config {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Meta(Vec<String>),
+    Date,
+    Hour,
+    Timestamp,
+    Seq,
+}
+
+/// A parsed key template
+#[derive(Debug, Clone, PartialEq)]
+struct KeyTemplate(Vec<Part>);
+
+impl KeyTemplate {
+    fn parse(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| Error::from(format!("Unclosed `{{` in key `{}`", template)))?
+                + start;
+            let name = &rest[start + 1..end];
+            parts.push(match name {
+                "date" => Part::Date,
+                "hour" => Part::Hour,
+                "timestamp" => Part::Timestamp,
+                "seq" => Part::Seq,
+                _ if name.starts_with('$') && name.len() > 1 => {
+                    Part::Meta(name[1..].split('.').map(ToString::to_string).collect())
+                }
+                _ => {
+                    return Err(
+                        format!("Unknown placeholder `{{{}}}` in key `{}`", name, template).into(),
+                    )
+                }
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        Ok(Self(parts))
+    }
+
+    /// The values of the metadata placeholders, events with the same ones go into the
+    /// same object
+    fn partition(&self, meta: &Value) -> Result<Vec<String>> {
+        self.0
+            .iter()
+            .filter_map(|p| match p {
+                Part::Meta(path) => Some(path),
+                _ => None,
+            })
+            .map(|path| {
+                let mut v = Some(meta);
+                for segment in path {
+                    v = v.and_then(|v| v.get(segment.as_str()));
+                }
+                match v {
+                    Some(v) => Ok(v.as_str().map_or_else(|| v.encode(), ToString::to_string)),
+                    None => Err(Error::from(format!(
+                        "Missing `${}` for the object key",
+                        path.join(".")
+                    ))),
+                }
+            })
+            .collect()
+    }
+
+    #[allow(clippy::cast_possible_wrap)]
+    fn render(&self, partition: &[String], started_ns: u64, seq: u64) -> String {
+        let started = Utc.timestamp_nanos(started_ns as i64);
+        let mut partition = partition.iter();
+        let mut res = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Literal(s) => res.push_str(s),
+                Part::Meta(_) => res.push_str(partition.next().map_or("", String::as_str)),
+                Part::Date => res.push_str(&started.format("%Y-%m-%d").to_string()),
+                Part::Hour => res.push_str(&started.format("%H").to_string()),
+                Part::Timestamp => res.push_str(&started_ns.to_string()),
+                Part::Seq => res.push_str(&seq.to_string()),
+            }
+        }
+        res
+    }
+}
+
+/// An object being filled
+struct Batch {
+    data: Vec<u8>,
+    started_ns: u64,
+    ingest_ns: u64,
+    /// ids and metadata of the transactional events in this object
+    id: Option<EventId>,
+    op_meta: OpMeta,
+}
+
+pub struct S3 {
+    sink_url: TremorUrl,
+    config: Config,
+    template: KeyTemplate,
+    client: Client,
+    postprocessors: Postprocessors,
+    batches: HashMap<Vec<String>, Batch>,
+    seq: u64,
+}
+
+impl offramp::Impl for S3 {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            if config.part_size < MIN_PART_SIZE {
+                return Err(format!("`part_size` needs to be at least {}", MIN_PART_SIZE).into());
+            }
+            let template = KeyTemplate::parse(&config.key)?;
+            let client = Client::new(&config.connection)?;
+            Ok(SinkManager::new_box(Self {
+                sink_url: TremorUrl::from_offramp_id("s3")?,
+                config,
+                template,
+                client,
+                postprocessors: vec![],
+                batches: HashMap::new(),
+                seq: 0,
+            }))
+        } else {
+            Err("Missing config for s3 offramp".into())
+        }
+    }
+}
+
+impl S3 {
+    /// Uploads an object and returns the insight for its events
+    async fn upload(&mut self, partition: &[String], batch: Batch) -> Option<Reply> {
+        let key = self.template.render(partition, batch.started_ns, self.seq);
+        self.seq += 1;
+        let res = self
+            .client
+            .upload(&key, batch.data, self.config.part_size)
+            .await;
+        let mut insight = match (res, batch.id) {
+            (Ok(()), Some(id)) => Event::cb_ack(batch.ingest_ns, id),
+            (Err(e), Some(id)) => {
+                error!("[Sink::{}] Failed to upload {}: {}", self.sink_url, key, e);
+                Event::cb_fail(batch.ingest_ns, id)
+            }
+            (Ok(()), None) => return None,
+            (Err(e), None) => {
+                error!("[Sink::{}] Failed to upload {}: {}", self.sink_url, key, e);
+                return None;
+            }
+        };
+        insight.op_meta = batch.op_meta;
+        Some(Reply::Insight(insight))
+    }
+
+    /// Uploads all objects `done` says are complete
+    async fn flush<F>(&mut self, done: F) -> Vec<Reply>
+    where
+        F: Fn(&Batch) -> bool,
+    {
+        let complete: Vec<Vec<String>> = self
+            .batches
+            .iter()
+            .filter(|(_, b)| done(b))
+            .map(|(p, _)| p.clone())
+            .collect();
+        let mut res = Vec::new();
+        for partition in complete {
+            if let Some(batch) = self.batches.remove(&partition) {
+                res.extend(self.upload(&partition, batch).await);
+            }
+        }
+        res
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for S3 {
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        mut event: Event,
+    ) -> ResultVec {
+        let mut lines = Vec::new();
+        let mut missing = None;
+        for (value, meta) in event.value_meta_iter() {
+            let partition = match self.template.partition(meta) {
+                Ok(partition) => partition,
+                Err(e) => {
+                    missing = Some(e);
+                    break;
+                }
+            };
+            let encoded = codec.encode(value)?;
+            for data in postprocess(&mut self.postprocessors, event.ingest_ns, encoded)? {
+                lines.push((partition.clone(), data));
+            }
+        }
+        if let Some(e) = missing {
+            error!("[Sink::{}] {}", self.sink_url, e);
+            return Ok(event
+                .transactional
+                .then(|| vec![Reply::Insight(event.insight_fail())]));
+        }
+        let mut touched = Vec::new();
+        for (partition, data) in lines {
+            let batch = self
+                .batches
+                .entry(partition.clone())
+                .or_insert_with(|| Batch {
+                    data: Vec::new(),
+                    started_ns: nanotime(),
+                    ingest_ns: event.ingest_ns,
+                    id: None,
+                    op_meta: OpMeta::default(),
+                });
+            batch.data.extend_from_slice(&data);
+            batch.data.push(b'\n');
+            if event.transactional && !touched.contains(&partition) {
+                match batch.id.as_mut() {
+                    Some(id) => id.track(&event.id),
+                    None => batch.id = Some(event.id.clone()),
+                }
+                batch.op_meta.merge(event.op_meta.clone());
+                touched.push(partition);
+            }
+        }
+        let max = self.config.max_object_bytes;
+        Ok(Some(self.flush(|b| b.data.len() >= max).await))
+    }
+
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        let max_age_ns = self.config.max_object_age_ms * 1_000_000;
+        let now = signal.ingest_ns;
+        Ok(Some(
+            self.flush(|b| now.saturating_sub(b.started_ns) >= max_age_ns)
+                .await,
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        _sink_uid: u64,
+        sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        processors: Processors<'_>,
+        _is_linked: bool,
+        _reply_channel: Sender<Reply>,
+    ) -> Result<()> {
+        self.postprocessors = make_postprocessors(processors.post)?;
+        self.sink_url = sink_url.clone();
+        Ok(())
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    fn auto_ack(&self) -> bool {
+        false
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    async fn terminate(&mut self) {
+        for reply in self.flush(|_| true).await {
+            if let Reply::Insight(e) = reply {
+                if e.cb == CbAction::Fail {
+                    error!("[Sink::{}] Lost events on shutdown", self.sink_url);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key_template() -> Result<()> {
+        let template = KeyTemplate::parse("logs/{$s3.app}/{date}/{hour}/{timestamp}-{seq}.json")?;
+        let meta = literal!({"s3": {"app": "snot"}});
+        let partition = template.partition(&meta)?;
+        assert_eq!(vec!["snot".to_string()], partition);
+        // 2021-03-04T05:06:07Z
+        let ns = 1_614_834_367_000_000_000;
+        assert_eq!(
+            "logs/snot/2021-03-04/05/1614834367000000000-3.json",
+            template.render(&partition, ns, 3)
+        );
+        assert!(template.partition(&literal!({})).is_err());
+
+        let template = KeyTemplate::parse("{$tenant}")?;
+        assert_eq!(
+            vec!["42".to_string()],
+            template.partition(&literal!({"tenant": 42}))?
+        );
+        assert_eq!("plain", KeyTemplate::parse("plain")?.render(&[], 0, 0));
+
+        assert!(KeyTemplate::parse("{nope}").is_err());
+        assert!(KeyTemplate::parse("{$}").is_err());
+        assert!(KeyTemplate::parse("{date").is_err());
+        Ok(())
+    }
+}
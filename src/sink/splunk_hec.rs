@@ -0,0 +1,534 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Splunk HTTP Event Collector Offramp
+//!
+//! Batches events and sends them to the `/services/collector/event` endpoint of a
+//! Splunk HTTP Event Collector, optionally gzip compressed.
+//!
+//! The `index`, `sourcetype`, `source` and `host` of an event are taken from the
+//! `$splunk` metadata or the config, `$splunk.time` (seconds since the epoch) overrides
+//! the ingest time and `$splunk.fields` is sent as indexed fields.
+//!
+//! With `ack` enabled the events of a batch are only acknowledged once Splunk reports
+//! them as indexed, batches that aren't indexed within `ack_timeout_ms` are failed.
+//! Indexer acknowledgement requires a `channel`.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::connectors::batch::{self, Batch, Events};
+use crate::sink::prelude::*;
+use halfbrown::HashMap;
+use http_types::headers::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE};
+use libflate::{finish, gzip};
+use std::io::Write;
+
+const CHANNEL_HEADER: &str = "X-Splunk-Request-Channel";
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// Base url of the collector, e.g. `https://localhost:8088`
+    pub url: String,
+    /// HEC token
+    pub token: String,
+    /// Channel to send as `X-Splunk-Request-Channel`, a GUID
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// Wait for indexer acknowledgement before acknowledging events
+    #[serde(default)]
+    pub ack: bool,
+    /// Fail batches that aren't acknowledged by the indexer within this many milliseconds,
+    /// defaults to 30000
+    #[serde(default = "dflt_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+    /// Index for events without `$splunk.index`
+    #[serde(default)]
+    pub index: Option<String>,
+    /// Sourcetype for events without `$splunk.sourcetype`
+    #[serde(default)]
+    pub sourcetype: Option<String>,
+    /// Source for events without `$splunk.source`
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Host for events without `$splunk.host`, defaults to the hostname of tremor
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Compress requests with gzip
+    #[serde(default)]
+    pub gzip: bool,
+    /// `batch_size` (events per request) and `flush_interval_ms`
+    #[serde(flatten)]
+    pub batch: batch::Config,
+}
+
+impl ConfigImpl for Config {}
+
+fn dflt_ack_timeout_ms() -> u64 {
+    30_000
+}
+
+/// A sent batch, waiting for indexer acknowledgement
+struct PendingAck {
+    ack_id: u64,
+    events: Events,
+    sent_ns: u64,
+}
+
+pub struct SplunkHec {
+    config: Config,
+    host: String,
+    /// serialized events of the current batch
+    buffer: Vec<u8>,
+    batch: Batch,
+    acks: Vec<PendingAck>,
+}
+
+impl offramp::Impl for SplunkHec {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            Ok(SinkManager::new_box(Self::new(Config::new(config)?)?))
+        } else {
+            Err("Missing config for splunk-hec offramp".into())
+        }
+    }
+}
+
+impl SplunkHec {
+    fn new(config: Config) -> Result<Self> {
+        if config.ack && config.channel.is_none() {
+            return Err("splunk-hec offramp requires a `channel` for `ack`".into());
+        }
+        let host = config.host.clone().unwrap_or_else(hostname);
+        Ok(Self {
+            batch: Batch::new(config.batch)?,
+            config,
+            host,
+            buffer: Vec::new(),
+            acks: Vec::new(),
+        })
+    }
+
+    /// Builds the HEC event for a value, metadata takes precedence over the config
+    fn hec_event<'v>(&self, value: &Value<'v>, meta: &Value, ingest_ns: u64) -> Value<'v> {
+        let splunk = meta.get("splunk");
+        // ALLOW: HEC wants seconds as a float, precision beyond that is lost on purpose
+        #[allow(clippy::cast_precision_loss)]
+        let time = splunk
+            .and_then(|m| m.get("time"))
+            .and_then(ValueAccess::cast_f64)
+            .unwrap_or_else(|| ingest_ns as f64 / 1_000_000_000.0);
+        let field = |name: &str, dflt: &Option<String>| {
+            splunk
+                .and_then(|m| m.get_str(name))
+                .map(ToString::to_string)
+                .or_else(|| dflt.clone())
+        };
+        let host = field("host", &None).unwrap_or_else(|| self.host.clone());
+        let mut event = Value::object_with_capacity(7);
+        // inserting into an object can't fail
+        let mut insert = |k: &'static str, v: Value<'v>| {
+            if let Some(o) = event.as_object_mut() {
+                o.insert(k.into(), v);
+            }
+        };
+        insert("time", Value::from(time));
+        insert("host", Value::from(host));
+        for (name, dflt) in [
+            ("index", &self.config.index),
+            ("sourcetype", &self.config.sourcetype),
+            ("source", &self.config.source),
+        ] {
+            if let Some(v) = field(name, dflt) {
+                insert(name, Value::from(v));
+            }
+        }
+        if let Some(fields) = splunk.and_then(|m| m.get("fields")) {
+            insert("fields", fields.clone_static());
+        }
+        insert("event", value.clone());
+        event
+    }
+
+    fn request(&self, path: &str) -> surf::RequestBuilder {
+        let mut request = surf::post(format!(
+            "{}/services/collector/{}",
+            self.config.url.trim_end_matches('/'),
+            path
+        ))
+        .header(AUTHORIZATION, format!("Splunk {}", self.config.token));
+        if let Some(channel) = &self.config.channel {
+            request = request.header(CHANNEL_HEADER, channel.as_str());
+        }
+        request
+    }
+
+    fn body(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if self.config.gzip {
+            let mut buffer = Vec::with_capacity(data.len() / 4);
+            {
+                let mut writer = finish::AutoFinishUnchecked::new(gzip::Encoder::new(&mut buffer)?);
+                writer.write_all(data)?;
+            }
+            Ok(buffer)
+        } else {
+            Ok(data.to_vec())
+        }
+    }
+
+    /// Sends a batch, returns the ack id if indexer acknowledgement is enabled
+    async fn send(&self, data: &[u8]) -> Result<Option<u64>> {
+        let mut request = self
+            .request("event")
+            .header(CONTENT_TYPE, "application/json")
+            .body(self.body(data)?);
+        if self.config.gzip {
+            request = request.header(CONTENT_ENCODING, "gzip");
+        }
+        let mut response = request.await?;
+        let mut body = response.body_bytes().await?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "HEC request failed with status {}: {}",
+                response.status(),
+                String::from_utf8_lossy(&body)
+            )
+            .into());
+        }
+        if self.config.ack {
+            let reply = tremor_value::parse_to_value(&mut body)?;
+            let ack_id = reply
+                .get_u64("ackId")
+                .ok_or_else(|| Error::from("HEC response is missing the `ackId`"))?;
+            Ok(Some(ack_id))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Sends the current batch and returns the insight for all events in it,
+    /// if indexer acknowledgement is enabled the ack is deferred
+    async fn flush(&mut self) -> Option<Vec<Reply>> {
+        let events = self.batch.take()?;
+        let data = std::mem::take(&mut self.buffer);
+        let insight = match self.send(&data).await {
+            Ok(None) => events.insight(true),
+            Ok(Some(ack_id)) => {
+                self.acks.push(PendingAck {
+                    ack_id,
+                    events,
+                    sent_ns: nanotime(),
+                });
+                return None;
+            }
+            Err(e) => {
+                error!("[Sink::SplunkHec] Failed to send events: {}", e);
+                events.insight(false)
+            }
+        };
+        Some(vec![Reply::Insight(insight)])
+    }
+
+    /// Asks the collector which of the pending batches have been indexed
+    async fn query_acks(&self) -> Result<HashMap<u64, bool>> {
+        let ack_ids: Vec<Value> = self.acks.iter().map(|a| Value::from(a.ack_id)).collect();
+        let mut response = self
+            .request("ack")
+            .header(CONTENT_TYPE, "application/json")
+            .body(literal!({ "acks": ack_ids }).encode())
+            .await?;
+        let mut body = response.body_bytes().await?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "HEC ack request failed with status {}: {}",
+                response.status(),
+                String::from_utf8_lossy(&body)
+            )
+            .into());
+        }
+        let reply = tremor_value::parse_to_value(&mut body)?;
+        let mut acks = HashMap::new();
+        if let Some(status) = reply.get_object("acks") {
+            for (id, indexed) in status.iter() {
+                if let (Ok(id), Some(indexed)) = (id.parse(), indexed.as_bool()) {
+                    acks.insert(id, indexed);
+                }
+            }
+        }
+        Ok(acks)
+    }
+
+    /// Acknowledges indexed batches and fails the ones that timed out
+    async fn check_acks(&mut self, now_ns: u64) -> Vec<Reply> {
+        if self.acks.is_empty() {
+            return Vec::new();
+        }
+        let status = match self.query_acks().await {
+            Ok(status) => status,
+            Err(e) => {
+                warn!("[Sink::SplunkHec] Failed to query acks: {}", e);
+                HashMap::new()
+            }
+        };
+        let timeout_ns = self.config.ack_timeout_ms * 1_000_000;
+        let mut replies = Vec::new();
+        let mut waiting = Vec::with_capacity(self.acks.len());
+        for ack in self.acks.drain(..) {
+            if status.get(&ack.ack_id).copied().unwrap_or_default() {
+                replies.push(Reply::Insight(ack.events.insight(true)));
+            } else if now_ns.saturating_sub(ack.sent_ns) >= timeout_ns {
+                warn!(
+                    "[Sink::SplunkHec] Batch {} not indexed within {}ms",
+                    ack.ack_id, self.config.ack_timeout_ms
+                );
+                replies.push(Reply::Insight(ack.events.insight(false)));
+            } else {
+                waiting.push(ack);
+            }
+        }
+        self.acks = waiting;
+        replies
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for SplunkHec {
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        _codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        event: Event,
+    ) -> ResultVec {
+        let mut events = 0;
+        for (value, meta) in event.value_meta_iter() {
+            // HEC takes concatenated JSON objects
+            self.hec_event(value, meta, event.ingest_ns)
+                .write(&mut self.buffer)?;
+            events += 1;
+        }
+        self.batch.push(event, events);
+        if self.batch.is_full() {
+            Ok(self.flush().await)
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        let mut replies = if self.batch.is_due(signal.ingest_ns) {
+            self.flush().await.unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        replies.append(&mut self.check_acks(signal.ingest_ns).await);
+        if replies.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(replies))
+        }
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        _sink_uid: u64,
+        _sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        _processors: Processors<'_>,
+        _is_linked: bool,
+        _reply_channel: Sender<Reply>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn terminate(&mut self) {
+        // insights can't be delivered anymore, but the data should still be sent
+        self.flush().await;
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    fn auto_ack(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use simd_json::OwnedValue;
+    use std::io::Read;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    fn splunk(config_s: &str) -> Result<SplunkHec> {
+        let v: serde_yaml::Value = serde_yaml::from_str(config_s)?;
+        SplunkHec::new(Config::new(&v)?)
+    }
+
+    #[test]
+    fn hec_events() -> Result<()> {
+        let hec = splunk(
+            r#"
+            url: http://localhost:8088
+            token: snot
+            host: tremor
+            index: main
+            sourcetype: _json
+            "#,
+        )?;
+        let value = literal!({"snot": "badger"});
+        assert_eq!(
+            literal!({
+                "time": 1.5,
+                "host": "tremor",
+                "index": "main",
+                "sourcetype": "_json",
+                "event": {"snot": "badger"}
+            }),
+            hec.hec_event(&value, &Value::object(), 1_500_000_000)
+        );
+        let meta = literal!({
+            "splunk": {
+                "time": 42.0,
+                "index": "other",
+                "source": "snot.log",
+                "fields": {"level": "info"}
+            }
+        });
+        assert_eq!(
+            literal!({
+                "time": 42.0,
+                "host": "tremor",
+                "index": "other",
+                "sourcetype": "_json",
+                "source": "snot.log",
+                "fields": {"level": "info"},
+                "event": {"snot": "badger"}
+            }),
+            hec.hec_event(&value, &meta, 1_500_000_000)
+        );
+        assert!(splunk("url: http://localhost:8088\ntoken: snot\nack: true").is_err());
+        Ok(())
+    }
+
+    #[derive(Clone, Default)]
+    struct Collector {
+        events: Arc<AtomicU64>,
+        ack_id: Arc<AtomicU64>,
+    }
+
+    async fn event(mut req: tide::Request<Collector>) -> tide::Result {
+        if req.header(AUTHORIZATION).map(|h| h.as_str()) != Some("Splunk snot")
+            || req.header(CHANNEL_HEADER).map(|h| h.as_str()) != Some("badger")
+        {
+            return Ok(tide::Response::new(401));
+        }
+        let body = req.body_bytes().await?;
+        let mut data = String::new();
+        gzip::Decoder::new(body.as_slice())?.read_to_string(&mut data)?;
+        let events = data.matches("\"event\":").count() as u64;
+        req.state().events.fetch_add(events, Ordering::SeqCst);
+        let ack_id = req.state().ack_id.fetch_add(1, Ordering::SeqCst);
+        Ok(tide::Response::builder(200)
+            .body(format!(
+                r#"{{"text":"Success","code":0,"ackId":{}}}"#,
+                ack_id
+            ))
+            .build())
+    }
+
+    async fn ack(mut req: tide::Request<Collector>) -> tide::Result {
+        let mut body = req.body_bytes().await?;
+        let query = tremor_value::parse_to_value(&mut body)?;
+        // only the first batch is indexed
+        let acks: Value = query
+            .get_array("acks")
+            .map(|ids| {
+                ids.iter()
+                    .filter_map(ValueAccess::as_u64)
+                    .map(|id| (id.to_string(), id == 0))
+                    .collect()
+            })
+            .unwrap_or_else(Value::object);
+        Ok(tide::Response::builder(200)
+            .body(literal!({ "acks": acks }).encode())
+            .build())
+    }
+
+    #[async_std::test]
+    async fn indexer_acks() -> Result<()> {
+        let state = Collector::default();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let mut app = tide::with_state(state.clone());
+        app.at("/services/collector/event").post(event);
+        app.at("/services/collector/ack").post(ack);
+        task::spawn(app.listen(listener));
+
+        let mut hec = splunk(&format!(
+            r#"
+            url: http://{}/
+            token: snot
+            channel: badger
+            ack: true
+            ack_timeout_ms: 1000
+            gzip: true
+            batch_size: 2
+            "#,
+            addr
+        ))?;
+        let mut codec = crate::codec::lookup("json")?;
+        let codec_map = HashMap::new();
+        for i in 0..4_u64 {
+            let mut event = Event {
+                id: EventId::new(0, 0, i),
+                ingest_ns: i,
+                data: (Value::from(i), Value::object()).into(),
+                ..Event::default()
+            };
+            event.op_meta.insert(i, OwnedValue::null());
+            // acks are deferred until the collector confirms them
+            assert!(hec
+                .on_event("in", codec.as_mut(), &codec_map, event)
+                .await?
+                .is_none());
+        }
+        assert_eq!(4, state.events.load(Ordering::SeqCst));
+        assert_eq!(2, hec.acks.len());
+
+        let replies = hec.check_acks(1).await;
+        assert_eq!(1, replies.len());
+        assert!(matches!(&replies[0], Reply::Insight(e) if e.cb == CbAction::Ack));
+        // the insight carries the op_meta of all events in the batch
+        assert!(
+            matches!(&replies[0], Reply::Insight(e) if e.op_meta.contains_key(0) && e.op_meta.contains_key(1))
+        );
+        assert_eq!(1, hec.acks.len());
+
+        let replies = hec.check_acks(nanotime() + 1_000_000_000).await;
+        assert!(matches!(&replies[0], Reply::Insight(e) if e.cb == CbAction::Fail));
+        assert!(hec.acks.is_empty());
+        Ok(())
+    }
+}
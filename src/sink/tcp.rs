@@ -59,10 +59,10 @@ pub struct Config {
     pub tls: Option<Either<TLSConfig, bool>>,
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug, Default, Clone)]
 pub struct TLSConfig {
     cafile: Option<PathBuf>,
-    domain: Option<String>,
+    pub(crate) domain: Option<String>,
 }
 
 fn default_no_delay() -> bool {
@@ -237,7 +237,7 @@ impl Sink for Tcp {
 
 /// if we have a cafile configured, we only load it, and no other ca certificates
 /// if there is no cafile configured, we load the default webpki-roots from Mozilla
-async fn connector(config: &TLSConfig) -> Result<TlsConnector> {
+pub(crate) async fn connector(config: &TLSConfig) -> Result<TlsConnector> {
     Ok(match config {
         TLSConfig {
             cafile: Some(cafile),
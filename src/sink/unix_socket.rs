@@ -0,0 +1,296 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Unix Socket Offramp
+//!
+//! Sends each message to a unix domain socket, either over a stream connection or as
+//! datagrams (e.g. to `/dev/log` style syslog targets).
+//!
+//! While disconnected the offramp triggers the circuit breaker and tries to reconnect
+//! with exponential backoff, restoring the circuit breaker once it succeeds.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use std::time::Instant;
+
+use crate::connectors::backoff::Backoff;
+use crate::sink::prelude::*;
+use async_std::os::unix::net::{UnixDatagram, UnixStream};
+use halfbrown::HashMap;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// connect to a `SOCK_STREAM` socket and write the postprocessed data to it
+    Stream,
+    /// send every postprocessed packet as a single datagram to a `SOCK_DGRAM` socket
+    Datagram,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Self::Stream
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    /// path of the socket to connect to
+    pub path: String,
+    /// `stream` (default) or `datagram`
+    #[serde(default)]
+    pub mode: Mode,
+    /// `initial_backoff_ms` and `max_backoff_ms` between reconnect attempts
+    #[serde(flatten)]
+    pub backoff: Backoff,
+}
+
+impl ConfigImpl for Config {}
+
+enum Socket {
+    Stream(UnixStream),
+    Datagram(UnixDatagram),
+}
+
+/// Reconnect schedule, driven by the ingest time of incoming signals
+#[derive(Debug)]
+struct Reconnect {
+    backoff: Backoff,
+    failures: u32,
+    next_attempt_ns: u64,
+}
+
+impl Reconnect {
+    fn new(backoff: Backoff) -> Self {
+        Self {
+            backoff,
+            failures: 0,
+            next_attempt_ns: 0,
+        }
+    }
+
+    fn is_due(&self, now_ns: u64) -> bool {
+        now_ns >= self.next_attempt_ns
+    }
+
+    /// schedules the next attempt, backing off further with every failure
+    fn failed(&mut self, now_ns: u64) {
+        let delay_ns =
+            u64::try_from(self.backoff.delay(self.failures).as_nanos()).unwrap_or(u64::MAX);
+        self.next_attempt_ns = now_ns.saturating_add(delay_ns);
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    fn reset(&mut self) {
+        self.failures = 0;
+        self.next_attempt_ns = 0;
+    }
+}
+
+/// An offramp that writes to a unix domain socket
+pub struct UnixSocket {
+    socket: Option<Socket>,
+    postprocessors: Postprocessors,
+    reconnect: Reconnect,
+    config: Config,
+}
+
+impl offramp::Impl for UnixSocket {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(SinkManager::new_box(Self {
+                socket: None,
+                postprocessors: vec![],
+                reconnect: Reconnect::new(config.backoff),
+                config,
+            }))
+        } else {
+            Err("Unix socket offramp requires a config".into())
+        }
+    }
+}
+
+impl UnixSocket {
+    async fn send_event(&mut self, codec: &mut dyn Codec, event: &Event) -> Result<()> {
+        let socket = self
+            .socket
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorKind::NoSocket))?;
+        for value in event.value_iter() {
+            let raw = codec.encode(value)?;
+            let packets = postprocess(&mut self.postprocessors, event.ingest_ns, raw)?;
+            for packet in packets {
+                match socket {
+                    Socket::Stream(stream) => stream.write_all(&packet).await?,
+                    Socket::Datagram(datagram) => {
+                        datagram.send(&packet).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn connect(config: &Config) -> Result<Socket> {
+        Ok(match config.mode {
+            Mode::Stream => Socket::Stream(UnixStream::connect(&config.path).await?),
+            Mode::Datagram => {
+                let datagram = UnixDatagram::unbound()?;
+                datagram.connect(&config.path)?;
+                Socket::Datagram(datagram)
+            }
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for UnixSocket {
+    /// We acknowledge ourself
+    fn auto_ack(&self) -> bool {
+        false
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        mut event: Event,
+    ) -> ResultVec {
+        let processing_start = Instant::now();
+        let replies = match self.send_event(codec, &event).await {
+            Ok(()) => {
+                if event.transactional {
+                    Some(vec![sink::Reply::Insight(event.insight_ack_with_timing(
+                        processing_start.elapsed().as_millis() as u64,
+                    ))])
+                } else {
+                    None
+                }
+            }
+            // the socket is gone, drop it so we reconnect on the next signal
+            Err(e @ Error(ErrorKind::Io(_) | ErrorKind::NoSocket, _)) => {
+                debug!("[Sink::UnixSocket] Error sending event: {}.", e);
+                if self.socket.take().is_some() {
+                    self.reconnect.reset();
+                }
+                if event.transactional {
+                    Some(vec![
+                        sink::Reply::Insight(event.to_fail()),
+                        sink::Reply::Insight(event.insight_trigger()),
+                    ])
+                } else {
+                    Some(vec![sink::Reply::Insight(event.insight_trigger())])
+                }
+            }
+            Err(e) => {
+                debug!("[Sink::UnixSocket] Error sending event: {}", e);
+                if event.transactional {
+                    Some(vec![sink::Reply::Insight(event.to_fail())])
+                } else {
+                    None
+                }
+            }
+        };
+        Ok(replies)
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        _sink_uid: u64,
+        sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        processors: Processors<'_>,
+        _is_linked: bool,
+        _reply_channel: Sender<sink::Reply>,
+    ) -> Result<()> {
+        self.postprocessors = make_postprocessors(processors.post)?;
+        // a missing socket is not fatal, we keep trying to connect on every signal
+        match Self::connect(&self.config).await {
+            Ok(socket) => self.socket = Some(socket),
+            Err(e) => warn!(
+                "[Sink::{}] Unable to connect to {}: {}",
+                sink_url, self.config.path, e
+            ),
+        }
+        Ok(())
+    }
+
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        if self.socket.is_some() || !self.reconnect.is_due(signal.ingest_ns) {
+            return Ok(None);
+        }
+        if let Ok(socket) = Self::connect(&self.config).await {
+            self.socket = Some(socket);
+            self.reconnect.reset();
+            Ok(Some(vec![sink::Reply::Insight(Event::cb_restore(
+                signal.ingest_ns,
+            ))]))
+        } else {
+            self.reconnect.failed(signal.ingest_ns);
+            Ok(Some(vec![sink::Reply::Insight(Event::cb_trigger(
+                signal.ingest_ns,
+            ))]))
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.socket.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff() -> Result<()> {
+        let config_s = r#"
+            path: /dev/log
+            mode: datagram
+            initial_backoff_ms: 100
+            max_backoff_ms: 300
+        "#;
+        let v: serde_yaml::Value = serde_yaml::from_str(config_s)?;
+        let config = Config::new(&v)?;
+        assert_eq!(Mode::Datagram, config.mode);
+        let mut reconnect = Reconnect::new(config.backoff);
+        assert!(reconnect.is_due(0));
+
+        reconnect.failed(1_000_000_000);
+        assert!(!reconnect.is_due(1_050_000_000));
+        assert!(reconnect.is_due(1_100_000_000));
+
+        reconnect.failed(1_100_000_000);
+        assert_eq!(1_300_000_000, reconnect.next_attempt_ns);
+        reconnect.failed(1_300_000_000);
+        // capped at `max_backoff_ms`
+        assert_eq!(1_600_000_000, reconnect.next_attempt_ns);
+
+        reconnect.reset();
+        assert!(reconnect.is_due(0));
+        assert_eq!(0, reconnect.failures);
+        Ok(())
+    }
+}
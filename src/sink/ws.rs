@@ -14,23 +14,34 @@
 
 #![cfg(not(tarpaulin_include))]
 
+use crate::connectors::backoff::Backoff;
+use crate::connectors::tls::{wrap, Io};
+use crate::connectors::ws::{ping, Keepalive, PROTOCOL_HEADER};
 use crate::sink::prelude::*;
+use crate::sink::tcp::TLSConfig;
 use crate::source::prelude::*;
 use async_channel::{bounded, unbounded, Receiver, Sender};
+use async_std::future::timeout;
 use async_std::net::TcpStream;
+use async_tungstenite::tungstenite::client::IntoClientRequest;
 use async_tungstenite::tungstenite::error::Error as WsError;
 use async_tungstenite::tungstenite::error::ProtocolError as WsProtocolError;
+use async_tungstenite::tungstenite::http::HeaderValue;
 use async_tungstenite::tungstenite::Message;
-use async_tungstenite::{async_std::connect_async, WebSocketStream};
+use async_tungstenite::{client_async, WebSocketStream};
+use either::Either;
 use futures::SinkExt;
 use halfbrown::HashMap;
 use std::boxed::Box;
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Instant;
 use tremor_pipeline::{EventId, OpMeta};
 use tremor_script::EventPayload;
 use url::Url;
 
 type WsUrl = String;
+type WsStream = WebSocketStream<Box<dyn Io>>;
 type WsConnectionHandle = (
     Option<Sender<SendEventConnectionMsg>>,
     task::JoinHandle<Result<()>>,
@@ -49,6 +60,58 @@ pub struct Config {
     pub url: String,
     #[serde(default)]
     pub binary: bool,
+    /// TLS settings, either `true` to verify the server against the default CA certificates,
+    /// or a `cafile` and `domain` to verify against. `wss://` urls always use TLS.
+    #[serde(with = "either::serde_untagged_optional", default = "Default::default")]
+    pub tls: Option<Either<TLSConfig, bool>>,
+    /// Subprotocols to request, the server has to agree on one of them
+    #[serde(default)]
+    pub protocols: Vec<String>,
+    /// Ping the server periodically and reconnect if it doesn't answer
+    #[serde(flatten)]
+    pub(crate) keepalive: Keepalive,
+    /// `initial_backoff_ms` and `max_backoff_ms` between reconnect attempts
+    #[serde(flatten)]
+    pub backoff: Backoff,
+}
+
+/// connects to `url`, returns the stream as well as the peer and local address
+async fn connect(url: &str, config: &Config) -> Result<(WsStream, SocketAddr, SocketAddr)> {
+    let parsed = Url::parse(url)?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| Error::from(format!("Missing host in url {}", url)))?;
+    let port = parsed
+        .port_or_known_default()
+        .ok_or_else(|| Error::from(format!("Missing port in url {}", url)))?;
+    let tcp = TcpStream::connect((host, port)).await?;
+    let peer = tcp.peer_addr()?;
+    let local = tcp.local_addr()?;
+    let io = wrap(tcp, host, config.tls.as_ref(), parsed.scheme() == "wss").await?;
+    let mut request = url.into_client_request()?;
+    if !config.protocols.is_empty() {
+        let protocols = config.protocols.join(", ");
+        request.headers_mut().insert(
+            PROTOCOL_HEADER,
+            HeaderValue::from_str(&protocols).map_err(|e| Error::from(e.to_string()))?,
+        );
+    }
+    let (ws_stream, response) = client_async(request, io).await?;
+    if !config.protocols.is_empty() {
+        let accepted = response
+            .headers()
+            .get(PROTOCOL_HEADER)
+            .and_then(|v| v.to_str().ok());
+        if !accepted.map_or(false, |p| config.protocols.iter().any(|s| s == p)) {
+            return Err(format!(
+                "{} did not agree on any of the websocket subprotocols {}",
+                url,
+                config.protocols.join(", ")
+            )
+            .into());
+        }
+    }
+    Ok((ws_stream, peer, local))
 }
 
 enum WsConnectionMsg {
@@ -117,12 +180,7 @@ async fn handle_error(
 }
 
 /// close the given stream if it is not already closed.
-async fn close_stream_on_error(
-    e: WsError,
-    stream: &mut WebSocketStream<TcpStream>,
-    sink_url: &TremorUrl,
-    url: &str,
-) {
+async fn close_stream_on_error(e: WsError, stream: &mut WsStream, sink_url: &TremorUrl, url: &str) {
     if !matches!(
         e,
         WsError::Io(_)
@@ -152,43 +210,85 @@ async fn ws_connection_loop(
     mut preprocessors: Preprocessors,
     mut postprocessors: Postprocessors,
     mut codec: Box<dyn Codec>,
+    config: Config,
 ) -> Result<()> {
+    let mut attempt = 0;
     loop {
         let codec: &mut dyn Codec = codec.as_mut();
         info!("[Sink::{}] Connecting to {} ...", &sink_url, url);
-        let mut ws_stream = if let Ok((ws_stream, _)) = connect_async(&url).await {
-            if let Ok(peer) = ws_stream.get_ref().peer_addr() {
+        let mut ws_stream = match connect(&url, &config).await {
+            Ok((ws_stream, peer, local)) => {
+                attempt = 0;
                 event_origin_url.port = Some(peer.port());
                 event_origin_url.host = peer.ip().to_string();
-            }
-            if let Ok(local) = ws_stream.get_ref().local_addr() {
                 event_origin_url.path = vec![local.port().to_string()];
+                ws_stream
+            }
+            Err(e) => {
+                let delay = config.backoff.delay(attempt);
+                attempt = attempt.saturating_add(1);
+                error!(
+                    "[Sink::{}] Failed to connect to {}: {}, retrying in {:?}",
+                    &sink_url, url, e, delay
+                );
+                connection_lifecycle_tx
+                    .send(WsConnectionMsg::Disconnected(url.clone()))
+                    .await?;
+                task::sleep(delay).await;
+                continue;
             }
-            ws_stream
-        } else {
-            error!(
-                "[Sink::{}] Failed to connect to {}, retrying in 1s",
-                &sink_url, url
-            );
-            connection_lifecycle_tx
-                .send(WsConnectionMsg::Disconnected(url.clone()))
-                .await?;
-            task::sleep(Duration::from_secs(1)).await;
-            continue;
         };
         connection_lifecycle_tx
             .send(WsConnectionMsg::Connected(url.clone(), tx.clone()))
             .await?;
 
-        'recv_loop: while let Ok(SendEventConnectionMsg {
-            event_id,
-            msg_meta,
-            maybe_op_meta,
-            ingest_ns,
-            data,
-            correlation,
-        }) = rx.recv().await
-        {
+        let mut next_ping = config.keepalive.interval().map(|i| Instant::now() + i);
+        // messages received while waiting for a pong, handed to the link first
+        let mut received = VecDeque::new();
+        'recv_loop: loop {
+            let msg = if let Some(ping_at) = next_ping {
+                match timeout(ping_at.saturating_duration_since(Instant::now()), rx.recv()).await {
+                    Ok(msg) => msg,
+                    Err(_) => {
+                        if let Err(e) = ping(&mut ws_stream, &config.keepalive, &mut received).await
+                        {
+                            error!(
+                                "[Sink::{}] Connection to {} is dead: {}",
+                                &sink_url, &url, e
+                            );
+                            if let Err(e) = ws_stream.close(None).await {
+                                debug!(
+                                    "[Sink::{}] Error closing ws stream to {}: {}",
+                                    &sink_url, &url, e
+                                );
+                            }
+                            connection_lifecycle_tx
+                                .send(WsConnectionMsg::Disconnected(url.clone()))
+                                .await?;
+                            break 'recv_loop; // exit recv loop in order to reconnect
+                        }
+                        if !has_link {
+                            received.clear();
+                        }
+                        next_ping = config.keepalive.interval().map(|i| Instant::now() + i);
+                        continue;
+                    }
+                }
+            } else {
+                rx.recv().await
+            };
+            let SendEventConnectionMsg {
+                event_id,
+                msg_meta,
+                maybe_op_meta,
+                ingest_ns,
+                data,
+                correlation,
+            } = if let Ok(msg) = msg {
+                msg
+            } else {
+                break 'recv_loop;
+            };
             match event_to_message(
                 codec,
                 &mut postprocessors,
@@ -272,7 +372,12 @@ async fn ws_connection_loop(
             }
 
             if has_link {
-                if let Some(msg) = ws_stream.next().await {
+                let msg = if let Some(msg) = received.pop_front() {
+                    Some(Ok(msg))
+                } else {
+                    ws_stream.next().await
+                };
+                if let Some(msg) = msg {
                     match msg {
                         Ok(message @ (Message::Text(_) | Message::Binary(_))) => {
                             let mut ingest_ns = nanotime();
@@ -594,6 +699,7 @@ impl Sink for Ws {
                 make_preprocessors(self.preprocessors.as_slice())?,
                 make_postprocessors(self.postprocessors.as_slice())?,
                 self.shared_codec.boxed_clone(),
+                self.config.clone(),
             ));
             // TODO default to None for initial connection? (like what happens for
             // default offramp config url). if we do circuit-breakers-per-url
@@ -690,6 +796,7 @@ impl Sink for Ws {
                 make_preprocessors(self.preprocessors.as_slice())?,
                 make_postprocessors(self.postprocessors.as_slice())?,
                 self.shared_codec.boxed_clone(),
+                self.config.clone(),
             ))?;
         self.connections
             .insert(self.config.url.clone(), (None, handle));
@@ -764,6 +871,61 @@ mod test {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn connect_with_protocol_and_ping() -> Result<()> {
+        use async_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                task::spawn(async move {
+                    let callback =
+                        |req: &Request,
+                         mut res: Response|
+                         -> std::result::Result<Response, ErrorResponse> {
+                            let offered = req.headers().get(PROTOCOL_HEADER);
+                            if offered.map_or(false, |p| p == "v1.tremor") {
+                                res.headers_mut()
+                                    .insert(PROTOCOL_HEADER, HeaderValue::from_static("v1.tremor"));
+                            }
+                            Ok(res)
+                        };
+                    let mut ws = async_tungstenite::accept_hdr_async(stream, callback).await?;
+                    ws.send(Message::Text("snot".to_string())).await?;
+                    // reading answers pings
+                    while let Some(msg) = ws.next().await {
+                        msg?;
+                    }
+                    Ok::<(), Error>(())
+                });
+            }
+        });
+        let mut config = Config {
+            url: format!("ws://{}/", addr),
+            binary: false,
+            tls: None,
+            protocols: vec!["v1.tremor".to_string()],
+            keepalive: Keepalive {
+                ping_interval_ms: Some(10),
+                pong_timeout_ms: 1000,
+            },
+            backoff: Backoff::default(),
+        };
+        let (mut ws, peer, _) = connect(&config.url, &config).await?;
+        assert_eq!(addr, peer);
+        // messages received while waiting for the pong are kept
+        let mut received = VecDeque::new();
+        ping(&mut ws, &config.keepalive, &mut received).await?;
+        assert_eq!(
+            Some(Message::Text("snot".to_string())),
+            received.pop_front()
+        );
+
+        config.protocols = vec!["v2.tremor".to_string()];
+        assert!(connect(&config.url, &config).await.is_err());
+        Ok(())
+    }
+
     #[async_std::test]
     async fn test_failed_connection_lifecycle() -> Result<()> {
         let (conn_tx, conn_rx) = bounded(10);
@@ -775,6 +937,10 @@ mod test {
         let config = Config {
             url: "http://idonotexist:65535/path".to_string(),
             binary: true,
+            tls: None,
+            protocols: vec![],
+            keepalive: Keepalive::default(),
+            backoff: Backoff::default(),
         };
         let mut sink = Ws {
             sink_url: url.clone(),
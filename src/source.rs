@@ -16,7 +16,7 @@ use crate::errors::Error;
 use crate::metrics::RampReporter;
 use crate::onramp;
 use crate::pipeline;
-use crate::preprocessor::{make_preprocessors, preprocess, Preprocessors};
+use crate::preprocessor::{self, make_preprocessors, preprocess, Preprocessors};
 use crate::url::ports::{ERR, METRICS, OUT};
 use crate::url::TremorUrl;
 use crate::{
@@ -43,15 +43,20 @@ pub(crate) mod cb;
 pub(crate) mod crononome;
 pub(crate) mod discord;
 pub(crate) mod env;
+pub(crate) mod exec;
 pub(crate) mod file;
 pub(crate) mod gsub;
 pub(crate) mod kafka;
 pub(crate) mod metronome;
+pub(crate) mod mqtt;
 pub(crate) mod nats;
 pub(crate) mod otel;
 pub(crate) mod postgres;
 pub(crate) mod prelude;
+pub(crate) mod prometheus;
+pub(crate) mod redis;
 pub(crate) mod rest;
+pub(crate) mod s3;
 pub(crate) mod sse;
 pub(crate) mod stdin;
 pub(crate) mod tcp;
@@ -62,6 +67,18 @@ pub(crate) mod ws;
 
 struct StaticValue(Value<'static>);
 
+/// The origin of the last data received on a stream, used for
+/// events created from data flushed out of preprocessors
+#[derive(Clone)]
+struct StreamContext {
+    /// first and last id reserved for data held back by the preprocessors of a
+    /// transactional onramp, the held back data is sent under the last one
+    held: Option<(u64, u64)>,
+    origin_uri: EventOriginUri,
+    meta: Option<Value<'static>>,
+    codec_override: Option<String>,
+}
+
 #[derive(Default)]
 /// Set of pre and postprocessors
 pub struct Processors<'processor> {
@@ -103,6 +120,11 @@ pub(crate) enum SourceReply {
         origin_uri: EventOriginUri,
         data: EventPayload,
     },
+    /// Already structured events that are sent to the `err` port
+    StructuredErr {
+        origin_uri: EventOriginUri,
+        data: EventPayload,
+    },
     /// A stream is opened
     StartStream(usize),
     /// A stream is closed
@@ -137,6 +159,11 @@ pub(crate) trait Source {
         Ok(())
     }
 
+    /// Handles a runtime command sent to this source via the API
+    async fn on_command(&mut self, _command: &Value<'static>) -> Result<()> {
+        Err(format!("[Source::{}] Commands are not supported", self.id()).into())
+    }
+
     /// Pulls metrics from the source
     fn metrics(&mut self, _t: u64) -> Vec<Event> {
         vec![]
@@ -187,6 +214,10 @@ where
     tx: Sender<onramp::Msg>,
     pp_template: Vec<String>,
     preprocessors: BTreeMap<usize, Preprocessors>,
+    /// interval in which preprocessors need to be flushed, if any
+    pp_flush_interval_ns: Option<u64>,
+    next_pp_flush_ns: u64,
+    stream_contexts: BTreeMap<usize, StreamContext>,
     codec: Box<dyn Codec>,
     codec_map: HashMap<String, Box<dyn Codec>>,
     metrics_reporter: RampReporter,
@@ -234,45 +265,182 @@ where
         data: Vec<u8>,
         meta: Option<StaticValue>, // See: https://github.com/rust-lang/rust/issues/63033
     ) -> Vec<Result<EventPayload>> {
-        let mut results = vec![];
         match self.handle_pp(stream, ingest_ns, data) {
-            Ok(data) => {
-                let meta_value = meta.map_or_else(Value::object, |m| m.0);
-                for d in data {
-                    let line_value = EventPayload::try_new::<Option<Error>, _>(d, |mut_data| {
-                        let codec_map = &mut self.codec_map;
-                        let codec = codec_override
-                            .as_ref()
-                            .and_then(|codec_name| codec_map.get_mut(codec_name))
-                            .unwrap_or(&mut self.codec);
-                        let decoded = codec.decode(mut_data, *ingest_ns);
-                        match decoded {
-                            Ok(None) => Err(None),
-                            Err(e) => Err(Some(e)),
-                            Ok(Some(decoded)) => {
-                                Ok(ValueAndMeta::from_parts(decoded, meta_value.clone()))
-                            }
-                        }
-                    });
-                    match line_value {
-                        Ok(decoded) => results.push(Ok(decoded)),
-                        Err(None) => (),
-                        Err(Some(e)) => {
-                            // TODO: add error context (with error handling update)
-                            results.push(Err(e));
-                        }
-                    }
-                }
-            }
+            Ok(data) => self.decode_data(ingest_ns, codec_override, data, meta),
             Err(e) => {
                 // record preprocessor failures too
                 // TODO: add error context (with error handling update)
-                results.push(Err(e));
+                vec![Err(e)]
+            }
+        }
+    }
+
+    fn decode_data(
+        &mut self,
+        ingest_ns: &mut u64,
+        codec_override: Option<String>,
+        data: Vec<Vec<u8>>,
+        meta: Option<StaticValue>,
+    ) -> Vec<Result<EventPayload>> {
+        let mut results = vec![];
+        let meta_value = meta.map_or_else(Value::object, |m| m.0);
+        for d in data {
+            let line_value = EventPayload::try_new::<Option<Error>, _>(d, |mut_data| {
+                let codec_map = &mut self.codec_map;
+                let codec = codec_override
+                    .as_ref()
+                    .and_then(|codec_name| codec_map.get_mut(codec_name))
+                    .unwrap_or(&mut self.codec);
+                let decoded = codec.decode(mut_data, *ingest_ns);
+                match decoded {
+                    Ok(None) => Err(None),
+                    Err(e) => Err(Some(e)),
+                    Ok(Some(decoded)) => Ok(ValueAndMeta::from_parts(decoded, meta_value.clone())),
+                }
+            });
+            match line_value {
+                Ok(decoded) => results.push(Ok(decoded)),
+                Err(None) => (),
+                Err(Some(e)) => {
+                    // TODO: add error context (with error handling update)
+                    results.push(Err(e));
+                }
             }
         }
         results
     }
 
+    /// Remembers where the data for a stream came from, if its preprocessors might flush data later
+    fn track_stream(
+        &mut self,
+        stream: usize,
+        origin_uri: &EventOriginUri,
+        meta: &Option<Value<'static>>,
+        codec_override: &Option<String>,
+    ) {
+        if self.pp_flush_interval_ns.is_some() {
+            let held = self.stream_contexts.get(&stream).and_then(|ctx| ctx.held);
+            self.stream_contexts.insert(
+                stream,
+                StreamContext {
+                    held,
+                    origin_uri: origin_uri.clone(),
+                    meta: meta.clone(),
+                    codec_override: codec_override.clone(),
+                },
+            );
+        }
+    }
+
+    /// Flushes data held back by the preprocessors of a stream and sends the resulting events
+    async fn flush_pp(&mut self, stream: usize, finish: bool) {
+        // preprocessors only hold back data for streams we have a context for
+        let ctx = if finish {
+            self.stream_contexts.remove(&stream)
+        } else {
+            self.stream_contexts.get(&stream).cloned()
+        };
+        let (ctx, preprocessors) = match (ctx, self.preprocessors.get_mut(&stream)) {
+            (Some(ctx), Some(preprocessors)) => (ctx, preprocessors),
+            _ => return,
+        };
+        let mut ingest_ns = nanotime();
+        let results = match preprocessor::flush(
+            preprocessors.as_mut_slice(),
+            &mut ingest_ns,
+            finish,
+            &self.source_id,
+        ) {
+            Ok(data) if data.is_empty() => return,
+            Ok(data) => self.decode_data(
+                &mut ingest_ns,
+                ctx.codec_override,
+                data,
+                ctx.meta.map(StaticValue),
+            ),
+            Err(e) => vec![Err(e)],
+        };
+        // the id reserved for the held back data is only acked along with it
+        let original_id = if let Some((_, last)) = ctx.held {
+            last
+        } else {
+            let id = self.id;
+            self.id += 1;
+            id
+        };
+        let transactional = self.is_transactional;
+        if let Some(ctx) = self.stream_contexts.get_mut(&stream) {
+            ctx.held = None;
+        }
+        let mut error = false;
+        for result in results {
+            let (port, data) = result.map_or_else(
+                |e| (ERR, make_error(self.source_id.to_string(), &e, original_id)),
+                |data| (OUT, data),
+            );
+            error |= self
+                .send_event(
+                    original_id,
+                    transactional,
+                    data,
+                    ingest_ns,
+                    ctx.origin_uri.clone(),
+                    port,
+                )
+                .await;
+        }
+        if error && transactional {
+            self.source.fail(original_id);
+        }
+    }
+
+    /// Reserves an id for the data the preprocessors of a stream hold back after a pull,
+    /// a pull without events of its own keeps its id. Pulls are settled by acks of later
+    /// ids, so acks are held back until the data was sent, see `ack_limit`. Returns `true`
+    /// if the onramp is transactional and the stream may hold back data
+    fn hold(&mut self, stream: usize, emitted: bool) -> bool {
+        if !self.is_transactional {
+            return false;
+        }
+        if let Some(ctx) = self.stream_contexts.get_mut(&stream) {
+            let id = self.id;
+            self.id += 1;
+            // the events of the pull contain the data held back so far
+            ctx.held = match ctx.held {
+                Some((first, _)) if !emitted => Some((first, id)),
+                _ => Some((id, id)),
+            };
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The highest id that may be acked, acks are cumulative and must not cover data
+    /// that is still held back by preprocessors
+    fn ack_limit(&self) -> Option<u64> {
+        self.stream_contexts
+            .values()
+            .filter_map(|ctx| ctx.held)
+            .map(|(first, _)| first)
+            .min()
+    }
+
+    /// Flushes the preprocessors of all streams if the flush interval passed
+    async fn maybe_flush_pp(&mut self) {
+        if let Some(interval) = self.pp_flush_interval_ns {
+            let now = nanotime();
+            if now >= self.next_pp_flush_ns {
+                // check twice per interval so data is held back no longer than 1.5 intervals
+                self.next_pp_flush_ns = now + interval / 2;
+                let streams: Vec<usize> = self.preprocessors.keys().copied().collect();
+                for stream in streams {
+                    self.flush_pp(stream, false).await;
+                }
+            }
+        }
+    }
+
     fn needs_pipeline_msg(&self) -> bool {
         self.pipelines_out.is_empty()
             || self.triggered
@@ -358,7 +526,14 @@ where
                     // TODO: stream handling
                     // when acknowledging, we use the latest/max event within the tracked set
                     if let Some((_stream_id, id)) = ids.get_max_by_source(self.uid) {
-                        self.source.ack(id);
+                        match self.ack_limit() {
+                            Some(first) if first <= id => {
+                                if let Some(id) = first.checked_sub(1) {
+                                    self.source.ack(id);
+                                }
+                            }
+                            _ => self.source.ack(id),
+                        }
                     }
                 }
                 // Circuit breaker source failure - triggers close
@@ -385,6 +560,15 @@ where
                         );
                     }
                 }
+                onramp::Msg::Command { command, tx } => {
+                    let res = self.source.on_command(&command).await;
+                    if let Err(e) = &res {
+                        warn!("[Source::{}] Command failed: {}", self.source_id, e);
+                    }
+                    if tx.send(res).await.is_err() {
+                        debug!("[Source::{}] Command issuer went away", self.source_id);
+                    }
+                }
             }
         }
     }
@@ -395,19 +579,34 @@ where
         ingest_ns: u64,
         origin_uri: EventOriginUri,
         port: Cow<'static, str>,
+    ) -> bool {
+        let id = self.id;
+        self.id += 1;
+        self.send_event(id, self.is_transactional, data, ingest_ns, origin_uri, port)
+            .await
+    }
+
+    /// Sends an event with the given id to the pipelines connected to `port`
+    async fn send_event(
+        &mut self,
+        id: u64,
+        transactional: bool,
+        data: EventPayload,
+        ingest_ns: u64,
+        origin_uri: EventOriginUri,
+        port: Cow<'static, str>,
     ) -> bool {
         let event = Event {
             // TODO: use EventIdGen and stream handling
-            id: EventId::new(self.uid, DEFAULT_STREAM_ID, self.id),
+            id: EventId::new(self.uid, DEFAULT_STREAM_ID, id),
             data,
             ingest_ns,
             // TODO make origin_uri non-optional here too?
             origin_uri: Some(origin_uri),
-            transactional: self.is_transactional,
+            transactional,
             ..Event::default()
         };
         let mut error = false;
-        self.id += 1;
         let pipelines = if OUT == port {
             &mut self.pipelines_out
         } else if ERR == port {
@@ -490,7 +689,12 @@ where
         }
         let pp_template = config.processors.pre.to_vec();
         let mut preprocessors = BTreeMap::new();
-        preprocessors.insert(0, make_preprocessors(&pp_template)?);
+        let default_pp = make_preprocessors(&pp_template)?;
+        let pp_flush_interval_ns = default_pp
+            .iter()
+            .filter_map(|pp| pp.flush_interval_ns())
+            .min();
+        preprocessors.insert(0, default_pp);
 
         source.init().await?;
         let is_transactional = source.is_transactional();
@@ -502,6 +706,9 @@ where
                 rx,
                 tx: tx.clone(),
                 preprocessors,
+                pp_flush_interval_ns,
+                next_pp_flush_ns: 0,
+                stream_contexts: BTreeMap::new(),
                 //postprocessors,
                 codec,
                 codec_map: resolved_codec_map,
@@ -554,12 +761,14 @@ where
             let pipelines_out_empty = self.pipelines_out.is_empty();
 
             if !self.triggered && !pipelines_out_empty {
+                self.maybe_flush_pp().await;
                 match self.source.pull_event(self.id).await {
                     Ok(SourceReply::StartStream(id)) => {
                         self.preprocessors
                             .insert(id, make_preprocessors(&self.pp_template)?);
                     }
                     Ok(SourceReply::EndStream(id)) => {
+                        self.flush_pp(id, true).await;
                         self.preprocessors.remove(&id);
                     }
                     Ok(SourceReply::Structured { origin_uri, data }) => {
@@ -567,6 +776,11 @@ where
 
                         self.transmit_event(data, ingest_ns, origin_uri, OUT).await;
                     }
+                    Ok(SourceReply::StructuredErr { origin_uri, data }) => {
+                        let ingest_ns = nanotime();
+
+                        self.transmit_event(data, ingest_ns, origin_uri, ERR).await;
+                    }
                     Ok(SourceReply::BatchData {
                         mut origin_uri,
                         batch_data,
@@ -575,9 +789,10 @@ where
                     }) => {
                         for (data, meta_data) in batch_data {
                             origin_uri.maybe_set_uid(self.uid);
+                            let original_id = self.id;
+                            self.track_stream(stream, &origin_uri, &meta_data, &codec_override);
                             let mut ingest_ns = nanotime();
 
-                            let original_id = self.id;
                             let results = self
                                 .make_event_data(
                                     stream,
@@ -587,13 +802,14 @@ where
                                     meta_data.map(StaticValue),
                                 )
                                 .await;
-                            if results.is_empty() {
-                                self.source.on_empty_event(original_id, stream).await?;
-                            }
-
+                            let emitted = !results.is_empty();
                             let error = self
                                 .route_result(results, original_id, ingest_ns, origin_uri.clone())
                                 .await;
+                            // data held back by preprocessors is settled once it is sent
+                            if !self.hold(stream, emitted) && !emitted {
+                                self.source.on_empty_event(original_id, stream).await?;
+                            }
                             // We ONLY fail on transmit errors as preprocessor errors might be
                             // problematic
                             if error {
@@ -609,9 +825,10 @@ where
                         stream,
                     }) => {
                         origin_uri.maybe_set_uid(self.uid);
+                        let original_id = self.id;
+                        self.track_stream(stream, &origin_uri, &meta, &codec_override);
                         let mut ingest_ns = nanotime();
 
-                        let original_id = self.id;
                         let results = self
                             .make_event_data(
                                 stream,
@@ -621,13 +838,14 @@ where
                                 meta.map(StaticValue),
                             )
                             .await;
-                        if results.is_empty() {
-                            self.source.on_empty_event(original_id, stream).await?;
-                        }
-
+                        let emitted = !results.is_empty();
                         let error = self
                             .route_result(results, original_id, ingest_ns, origin_uri)
                             .await;
+                        // data held back by preprocessors is settled once it is sent
+                        if !self.hold(stream, emitted) && !emitted {
+                            self.source.on_empty_event(original_id, stream).await?;
+                        }
 
                         // We ONLY fail on transmit errors as preprocessor errors might be
                         // problematic
@@ -765,6 +983,118 @@ mod tests {
         Ok(())
     }
 
+    #[derive(Debug)]
+    struct TransactionalSource {
+        url: TremorUrl,
+        acks: Vec<u64>,
+    }
+
+    #[async_trait::async_trait]
+    impl Source for TransactionalSource {
+        async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
+            Ok(SourceReply::Empty(0))
+        }
+
+        async fn init(&mut self) -> Result<SourceState> {
+            Ok(SourceState::Connected)
+        }
+
+        fn id(&self) -> &TremorUrl {
+            &self.url
+        }
+
+        fn ack(&mut self, id: u64) {
+            self.acks.push(id);
+        }
+
+        fn is_transactional(&self) -> bool {
+            true
+        }
+    }
+
+    /// Handles `data` like a pull of stream 0, returns if it created events and if the
+    /// stream holds back data
+    async fn pull(sm: &mut SourceManager<TransactionalSource>, data: &str) -> (bool, bool) {
+        let original_id = sm.id;
+        let origin_uri = EventOriginUri::default();
+        sm.track_stream(0, &origin_uri, &None, &None);
+        let mut ingest_ns = nanotime();
+        let results = sm
+            .make_event_data(0, &mut ingest_ns, None, data.as_bytes().to_vec(), None)
+            .await;
+        let emitted = !results.is_empty();
+        sm.route_result(results, original_id, ingest_ns, origin_uri)
+            .await;
+        (emitted, sm.hold(0, emitted))
+    }
+
+    #[async_std::test]
+    async fn held_back_data() -> Result<()> {
+        let onramp_url = TremorUrl::from_onramp_id("transactional")?;
+        let source = TransactionalSource {
+            url: onramp_url.clone(),
+            acks: vec![],
+        };
+        let pre = vec!["multiline".to_string()];
+        let o_config = OnrampConfig {
+            onramp_uid: 1,
+            codec: "string",
+            codec_map: HashMap::new(),
+            processors: Processors {
+                pre: &pre,
+                post: &[],
+            },
+            metrics_reporter: RampReporter::new(onramp_url, None),
+            is_linked: false,
+            err_required: false,
+        };
+        let (mut sm, sender) = SourceManager::new(source, o_config).await?;
+        let pipeline_url = TremorUrl::parse("/pipeline/bla/01/in")?;
+        let (tx1, rx1) = async_channel::unbounded();
+        let (tx2, _rx2) = async_channel::unbounded();
+        let (tx3, _rx3) = async_channel::unbounded();
+        sm.pipelines_out.push((
+            pipeline_url.clone(),
+            pipeline::Addr::new(tx1, tx2, tx3, pipeline_url),
+        ));
+        let event_id = |msg: std::result::Result<pipeline::Msg, _>| match msg {
+            Ok(pipeline::Msg::Event { event, .. }) => {
+                assert!(event.transactional);
+                event.id.get_max_by_source(1).map(|(_, id)| id)
+            }
+            _ => None,
+        };
+
+        // the line of pull 0 is held back, the pull keeps its id
+        assert_eq!((false, true), pull(&mut sm, "snot").await);
+        assert_eq!(Some(0), sm.ack_limit());
+        // pull 1 sends the record of pull 0, id 2 is reserved for its own line
+        assert_eq!((true, true), pull(&mut sm, "badger").await);
+        assert_eq!(Some(1), event_id(rx1.try_recv()));
+        assert_eq!(Some(2), sm.ack_limit());
+
+        // acks can't cover the held back line
+        sender
+            .send(onramp::Msg::Cb(CbAction::Ack, EventId::new(1, 0, 1)))
+            .await?;
+        sender
+            .send(onramp::Msg::Cb(CbAction::Ack, EventId::new(1, 0, 5)))
+            .await?;
+        sm.handle_pipelines().await?;
+        assert_eq!(vec![1, 1], sm.source.acks);
+
+        // the flushed record is sent under the reserved id
+        sm.flush_pp(0, true).await;
+        assert_eq!(Some(2), event_id(rx1.try_recv()));
+        assert_eq!(None, sm.ack_limit());
+        sender
+            .send(onramp::Msg::Cb(CbAction::Ack, EventId::new(1, 0, 2)))
+            .await?;
+        sm.handle_pipelines().await?;
+        assert_eq!(vec![1, 1, 2], sm.source.acks);
+        Ok(())
+    }
+
     #[test]
     fn make_error() {
         let source_id = "snot".to_string();
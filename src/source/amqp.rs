@@ -13,15 +13,23 @@
 // limitations under the License.
 #![cfg(not(tarpaulin_include))]
 
+use crate::connectors::amqp::from_field_table;
+use crate::connectors::inflight::Inflight;
 use crate::errors::Error;
 use crate::source::prelude::*;
 use crate::url::TremorUrl;
+use async_std::future::timeout;
 use lapin::{
-    options::{BasicAckOptions, BasicConsumeOptions, QueueBindOptions, QueueDeclareOptions},
+    options::{
+        BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions, QueueBindOptions,
+        QueueDeclareOptions,
+    },
     types::FieldTable,
-    Connection, ConnectionProperties, Consumer,
+    Channel, Connection, ConnectionProperties, Consumer,
 };
 use serde::Deserialize;
+use std::time::Duration;
+use tremor_value::literal;
 use url::Url;
 
 /*enum QueueProperties {
@@ -39,6 +47,47 @@ pub struct Config {
     routing_key: String,
     #[serde(default = "Default::default")]
     exchange: String,
+    /// When to acknowledge deliveries, defaults to `auto`
+    #[serde(default)]
+    ack_mode: AckMode,
+    /// What to do with deliveries whose events failed in `manual` ack mode,
+    /// defaults to `requeue`
+    #[serde(default)]
+    on_fail: FailMode,
+    /// Maximum number of unacknowledged deliveries, unlimited if not set
+    #[serde(default)]
+    prefetch_count: Option<u16>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AckMode {
+    /// acknowledge deliveries as soon as they are received
+    Auto,
+    /// acknowledge deliveries only once their events are acknowledged downstream
+    Manual,
+}
+
+impl Default for AckMode {
+    fn default() -> Self {
+        Self::Auto
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailMode {
+    /// nack deliveries of failed events with requeue, so they are redelivered
+    Requeue,
+    /// nack deliveries of failed events without requeue, so they are routed to the
+    /// dead letter exchange of the queue, if any
+    DeadLetter,
+}
+
+impl Default for FailMode {
+    fn default() -> Self {
+        Self::Requeue
+    }
 }
 
 impl ConfigImpl for Config {}
@@ -54,13 +103,16 @@ pub struct Int {
     amqp_url: Url,
     onramp_id: TremorUrl,
     origin_uri: EventOriginUri,
+    channel: Option<Channel>,
     consumer: Option<Consumer>,
     with_ack: bool,
+    /// delivery tags of unacknowledged deliveries
+    deliveries: Inflight<u64>,
 }
 
 impl std::fmt::Debug for Int {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Kafka")
+        write!(f, "Amqp")
     }
 }
 
@@ -102,13 +154,35 @@ impl Int {
             config: config.clone(),
             amqp_url,
             onramp_id,
+            channel: None,
             consumer: None,
             origin_uri,
-            with_ack: false,
+            with_ack: config.ack_mode == AckMode::Manual,
+            deliveries: Inflight::default(),
         })
     }
 }
 
+impl Int {
+    /// acks the given deliveries one by one, acking the last one with `multiple` could
+    /// settle a delivery whose nack wasn't sent yet
+    fn send_acks(&self, tags: Vec<u64>) {
+        if let (false, Some(channel)) = (tags.is_empty(), self.channel.clone()) {
+            let onramp_id = self.onramp_id.clone();
+            task::spawn(async move {
+                for tag in tags {
+                    if let Err(e) = channel
+                        .basic_ack(tag, BasicAckOptions { multiple: false })
+                        .await
+                    {
+                        error!("[Source::{}] Failed to ack delivery: {}", onramp_id, e);
+                    }
+                }
+            });
+        }
+    }
+}
+
 impl std::fmt::Debug for Amqp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Amqp")
@@ -129,22 +203,41 @@ impl Source for Int {
     fn id(&self) -> &TremorUrl {
         &self.onramp_id
     }
-    async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
         match self.consumer.as_mut() {
             None => Ok(SourceReply::StateChange(SourceState::Disconnected)),
             Some(consumer) => {
-                match consumer.next().await {
+                // all events of earlier deliveries were created by now
+                if self.with_ack {
+                    let settled = self.deliveries.close(id);
+                    self.send_acks(settled);
+                }
+                // don't wait forever, acks and fails need to be handled in between
+                let delivery = match timeout(Duration::from_millis(100), consumer.next()).await {
+                    Ok(delivery) => delivery,
+                    Err(_) => return Ok(SourceReply::Empty(0)),
+                };
+                match delivery {
                     Some(delivery) => {
                         let (_, delivery) = delivery?;
-                        // TODO: not sure what to do with _ack_result ... we got the ack acked
-                        delivery.ack(BasicAckOptions::default()).await?;
-                        let data = delivery.data;
+                        if self.with_ack {
+                            self.deliveries.insert(id, delivery.delivery_tag);
+                        } else {
+                            delivery.ack(BasicAckOptions::default()).await?;
+                        }
+                        let mut meta = Value::object_with_capacity(4);
+                        meta.try_insert("exchange", delivery.exchange.as_str().to_string());
+                        meta.try_insert("routing_key", delivery.routing_key.as_str().to_string());
+                        meta.try_insert("redelivered", delivery.redelivered);
+                        if let Some(headers) = delivery.properties.headers() {
+                            meta.try_insert("headers", from_field_table(headers));
+                        }
                         let mut origin_uri = self.origin_uri.clone();
                         origin_uri.path = vec![delivery.routing_key.to_string()];
                         Ok(SourceReply::Data {
                             origin_uri,
-                            data,
-                            meta: None, // TODO: what can we put in meta here?
+                            data: delivery.data,
+                            meta: Some(literal!({ "amqp": meta })),
                             codec_override: None,
                             stream: 0,
                         })
@@ -175,6 +268,11 @@ impl Source for Int {
         };
 
         let channel = conn.create_channel().await?;
+        if let Some(prefetch_count) = self.config.prefetch_count {
+            channel
+                .basic_qos(prefetch_count, BasicQosOptions::default())
+                .await?;
+        }
 
         channel
             .queue_declare(
@@ -205,28 +303,97 @@ impl Source for Int {
             Ok(consumer) => Some(consumer),
             Err(_) => return Ok(SourceState::Disconnected),
         };
+        // unacknowledged deliveries of an old channel are redelivered by the broker
+        self.deliveries.clear();
+        self.channel = Some(channel);
         Ok(SourceState::Connected)
     }
     fn trigger_breaker(&mut self) {}
     fn restore_breaker(&mut self) {}
 
-    // If we fail a message we seek back to this failed
-    // message to replay data from here.
-    //
-    // This might seek over multiple topics but since we internally only keep
-    // track of a singular stream this is OK.
-    //
-    // If this is undesirable multiple onramps with an onramp per topic
-    // should be used.
+    // Nacks the delivery the failed event was created from, either
+    // requeuing it or dead-lettering it.
     fn fail(&mut self, id: u64) {
-        if self.with_ack {
-            error!("[amqp] fail not implemented, msgid: {}", id);
+        if !self.with_ack {
+            return;
+        }
+        if let (Some(tag), Some(channel)) = (self.deliveries.take(id), self.channel.clone()) {
+            let options = BasicNackOptions {
+                multiple: false,
+                requeue: self.config.on_fail == FailMode::Requeue,
+            };
+            let onramp_id = self.onramp_id.clone();
+            task::spawn(async move {
+                if let Err(e) = channel.basic_nack(tag, options).await {
+                    error!("[Source::{}] Failed to nack delivery: {}", onramp_id, e);
+                }
+            });
         }
     }
+
+    // Acks all deliveries whose events are acknowledged, the source manager only
+    // passes the max id of a batch of acknowledged events.
     fn ack(&mut self, id: u64) {
-        if self.with_ack {
-            error!("[amqp] ack not implemented, msgid: {}", id);
+        if !self.with_ack {
+            return;
         }
+        let settled = self.deliveries.ack(id);
+        self.send_acks(settled);
+    }
+
+    async fn on_empty_event(&mut self, id: u64, _stream: usize) -> Result<()> {
+        // no event will be acknowledged for the delivery, and `id` is reused for the next one
+        if let Some(tag) = self.deliveries.remove(id) {
+            self.send_acks(vec![tag]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tremor_pipeline::EventId;
+
+    #[async_std::test]
+    async fn manual_acks() -> Result<()> {
+        let config = Config {
+            amqp_addr: "amqp://localhost:5672".to_string(),
+            queue_name: "snot".to_string(),
+            queue_options: QueueDeclareOptions::default(),
+            routing_key: String::new(),
+            exchange: String::new(),
+            ack_mode: AckMode::Manual,
+            on_fail: FailMode::Requeue,
+            prefetch_count: None,
+        };
+        let mut int = Int::from_config(0, TremorUrl::parse("/onramp/amqp/00")?, &config).await?;
+        assert!(int.is_transactional());
+        int.deliveries.insert(1, 10);
+        // events 2, 3 and 4 are created from delivery 11
+        int.deliveries.insert(2, 11);
+        int.deliveries.insert(5, 12);
+        int.deliveries.insert(6, 13);
+        assert!(int.deliveries.close(7).is_empty());
+        // a batched insight of events 1 to 3, the source manager acks its max id
+        let mut batch = EventId::from((0, 0, 1));
+        batch.track_id(0, 0, 2);
+        batch.track_id(0, 0, 3);
+        let (_, max) = batch.get_max_by_source(0).ok_or("no id")?;
+        int.ack(max);
+        // delivery 10 is settled, delivery 11 waits for event 4
+        assert_eq!(None, int.deliveries.take(1));
+        // event 4 fails, delivery 11 is nacked and not acked later on
+        int.fail(4);
+        int.ack(5);
+        assert_eq!(None, int.deliveries.take(2));
+        assert_eq!(None, int.deliveries.take(5));
+        assert_eq!(Some(13), int.deliveries.take(6));
+        // the delivery of event 7 created no event
+        int.deliveries.insert(7, 14);
+        int.on_empty_event(7, 0).await?;
+        assert!(int.deliveries.is_empty());
+        Ok(())
     }
 }
 
@@ -0,0 +1,281 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Exec Onramp
+//!
+//! Runs a command and turns every line it writes to stdout into an event, every line
+//! it writes to stderr is sent to the `err` port. The command is restarted with
+//! exponential backoff when it exits.
+//!
+//! Every run of the command is a separate stream, so preprocessors start fresh.
+
+use crate::connectors::backoff::Backoff;
+use crate::connectors::exec::{read_line, CommandConfig};
+use crate::source::prelude::*;
+use async_channel::{Sender, TryRecvError};
+use async_std::io::BufReader;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    command: CommandConfig,
+    /// restart the command once it exits, if `false` the onramp stops with it (default: true)
+    #[serde(default = "dflt_restart")]
+    restart: bool,
+    /// `initial_backoff_ms` and `max_backoff_ms` between restarts, a command that ran for
+    /// longer than `max_backoff_ms` is restarted after the initial backoff again
+    #[serde(flatten)]
+    backoff: Backoff,
+}
+
+fn dflt_restart() -> bool {
+    true
+}
+
+impl ConfigImpl for Config {}
+
+pub struct Exec {
+    config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for Exec {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for exec onramp".into())
+        }
+    }
+}
+
+pub struct Int {
+    uid: u64,
+    config: Config,
+    onramp_id: TremorUrl,
+    rx: Option<Receiver<SourceReply>>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Exec:{}", self.config.command.command)
+    }
+}
+
+fn exec_meta(pid: u32, stream: &'static str) -> Value<'static> {
+    literal!({
+        "exec": {
+            "pid": pid,
+            "stream": stream
+        }
+    })
+}
+
+/// Forwards the stderr lines of a child to the `err` port
+async fn forward_stderr<R>(
+    mut stderr: R,
+    pid: u32,
+    origin_uri: EventOriginUri,
+    tx: Sender<SourceReply>,
+) -> Result<()>
+where
+    R: async_std::io::BufRead + Unpin,
+{
+    while let Some(line) = read_line(&mut stderr).await? {
+        let line = String::from_utf8_lossy(&line).to_string();
+        tx.send(SourceReply::StructuredErr {
+            origin_uri: origin_uri.clone(),
+            data: (Value::from(line), exec_meta(pid, "stderr")).into(),
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+/// Runs the command, restarting it with backoff until it should not be restarted anymore
+/// or the onramp is gone
+async fn supervise(
+    onramp_id: TremorUrl,
+    config: Config,
+    origin_uri: EventOriginUri,
+    tx: Sender<SourceReply>,
+) -> Result<()> {
+    let mut stream = 0;
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        match config.command.spawn(false, true) {
+            Ok(mut child) => {
+                let pid = child.id();
+                info!(
+                    "[Source::{}] Started {} ({}).",
+                    onramp_id, config.command.command, pid
+                );
+                tx.send(SourceReply::StartStream(stream)).await?;
+                let stderr = child.stderr.take().map(|stderr| {
+                    task::spawn(forward_stderr(
+                        BufReader::new(stderr),
+                        pid,
+                        origin_uri.clone(),
+                        tx.clone(),
+                    ))
+                });
+                if let Some(stdout) = child.stdout.take() {
+                    let mut stdout = BufReader::new(stdout);
+                    let meta = exec_meta(pid, "stdout");
+                    loop {
+                        match read_line(&mut stdout).await {
+                            Ok(Some(data)) => {
+                                tx.send(SourceReply::Data {
+                                    origin_uri: origin_uri.clone(),
+                                    data,
+                                    meta: Some(meta.clone()),
+                                    codec_override: None,
+                                    stream,
+                                })
+                                .await?;
+                            }
+                            Ok(None) => break,
+                            Err(e) => {
+                                warn!("[Source::{}] Error reading stdout: {}", onramp_id, e);
+                                break;
+                            }
+                        }
+                    }
+                }
+                if let Some(stderr) = stderr {
+                    if let Err(e) = stderr.await {
+                        warn!("[Source::{}] Error reading stderr: {}", onramp_id, e);
+                    }
+                }
+                match child.status().await {
+                    Ok(status) => info!("[Source::{}] {} {}.", onramp_id, pid, status),
+                    Err(e) => warn!("[Source::{}] Error waiting for {}: {}", onramp_id, pid, e),
+                }
+                tx.send(SourceReply::EndStream(stream)).await?;
+                stream += 1;
+            }
+            Err(e) => error!(
+                "[Source::{}] Unable to start {}: {}",
+                onramp_id, config.command.command, e
+            ),
+        }
+        if !config.restart {
+            return Ok(());
+        }
+        if started.elapsed() > config.backoff.max() {
+            restarts = 0;
+        }
+        task::sleep(config.backoff.delay(restarts)).await;
+        restarts = restarts.saturating_add(1);
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
+        self.rx.as_ref().map_or_else(
+            || Ok(SourceReply::StateChange(SourceState::Disconnected)),
+            |rx| match rx.try_recv() {
+                Ok(r) => Ok(r),
+                Err(TryRecvError::Empty) => Ok(SourceReply::Empty(10)),
+                Err(TryRecvError::Closed) => {
+                    Ok(SourceReply::StateChange(SourceState::Disconnected))
+                }
+            },
+        )
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let (tx, rx) = bounded(crate::QSIZE);
+        let onramp_id = self.onramp_id.clone();
+        let config = self.config.clone();
+        let origin_uri = self.config.command.origin_uri(self.uid);
+        task::spawn(async move {
+            // the channel only closes once the onramp is gone
+            if let Err(e) = supervise(onramp_id.clone(), config, origin_uri, tx).await {
+                debug!("[Source::{}] Stopped: {}", onramp_id, e);
+            }
+        });
+        self.rx = Some(rx);
+        Ok(SourceState::Connected)
+    }
+}
+
+#[async_trait::async_trait()]
+impl Onramp for Exec {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int {
+            uid: config.onramp_uid,
+            config: self.config.clone(),
+            onramp_id: self.onramp_id.clone(),
+            rx: None,
+        };
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "string"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn restarts() -> Result<()> {
+        let config_s = r#"
+            command: sh
+            args: ["-c", "echo snot; echo badger >&2"]
+            restart: false
+        "#;
+        let v: serde_yaml::Value = serde_yaml::from_str(config_s)?;
+        let config = Config::new(&v)?;
+        assert!(config.command.env.is_empty());
+        let (tx, rx) = bounded(crate::QSIZE);
+        let onramp_id = TremorUrl::from_onramp_id("exec")?;
+        let origin_uri = config.command.origin_uri(1);
+        supervise(onramp_id, config, origin_uri, tx).await?;
+
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        while let Ok(reply) = rx.try_recv() {
+            match reply {
+                SourceReply::Data { data, stream, .. } => {
+                    assert_eq!(0, stream);
+                    stdout.push(data);
+                }
+                SourceReply::StructuredErr { data, .. } => {
+                    stderr.push(data.suffix().value().clone_static());
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(vec![b"snot".to_vec()], stdout);
+        assert_eq!(vec![Value::from("badger")], stderr);
+        Ok(())
+    }
+}
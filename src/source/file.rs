@@ -22,15 +22,33 @@ use async_std::prelude::*;
 use std::process;
 use tremor_common::asy::file;
 
+#[cfg(unix)]
+mod tail;
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     /// source file to read data from, it will be iterated over repeatedly,
-    /// can be xz compressed
+    /// can be xz compressed. In tail mode this is a glob pattern.
     pub source: String,
     #[serde(default = "Default::default")]
     pub close_on_done: bool,
     #[serde(default = "Default::default")]
     pub sleep_on_done: u64,
+    /// follow all files matching `source` as they grow and get rotated,
+    /// instead of reading a single file from start to end (unix only)
+    #[serde(default = "Default::default")]
+    pub tail: bool,
+    /// file to persist the offsets of acknowledged lines in when tailing,
+    /// so a restart resumes where it left off
+    #[serde(default = "Default::default")]
+    pub checkpoint: Option<String>,
+    /// interval in milliseconds to check tailed files for new data
+    #[serde(default = "dflt_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+}
+
+fn dflt_poll_interval_ms() -> u64 {
+    250
 }
 
 impl ConfigImpl for Config {}
@@ -99,6 +117,9 @@ impl onramp::Impl for File {
     fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
         if let Some(config) = config {
             let config: Config = Config::new(config)?;
+            if config.tail && cfg!(not(unix)) {
+                return Err("The file onramp only supports `tail` on unix".into());
+            }
             Ok(Box::new(Self {
                 config,
                 onramp_id: id.clone(),
@@ -147,6 +168,13 @@ impl Source for Int {
 #[async_trait::async_trait]
 impl Onramp for File {
     async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        #[cfg(unix)]
+        if self.config.tail {
+            let source =
+                tail::Tail::from_config(config.onramp_uid, self.onramp_id.clone(), &self.config)
+                    .await?;
+            return SourceManager::start(source, config).await;
+        }
         let source = Int::from_config(
             config.onramp_uid,
             self.onramp_id.clone(),
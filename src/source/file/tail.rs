@@ -0,0 +1,682 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tail mode of the file onramp
+//!
+//! Follows all files matching a glob pattern as they grow. Files are identified by
+//! their inode, so rotation is detected both for rename + create (the inode behind the
+//! path changes, the old file is read to its end first) and copytruncate (the file
+//! shrinks, reading starts over at the beginning).
+//!
+//! Every file gets its own stream, so preprocessors keep their state per file.
+//!
+//! The end offsets of acknowledged lines are persisted in the checkpoint file, if one is
+//! configured, and reading resumes there after a restart. Acks are tracked per file, an
+//! ack only covers earlier lines of the same file, never lines of other files that may
+//! still be in flight. A checkpoint is only used if both the inode and the path of the
+//! file match, so a new file that reuses the inode of an old one is read from the start.
+//! Failed lines are read again.
+
+use super::Config;
+use crate::source::prelude::*;
+use async_std::fs::{self, File};
+use async_std::io::SeekFrom;
+use hashbrown::HashMap;
+use std::collections::{BTreeMap, VecDeque};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use tremor_common::time::nanotime;
+
+/// Upper bound of data read from a single file per poll
+const MAX_READ_PER_POLL: usize = 1024 * 1024;
+const READ_CHUNK: usize = 64 * 1024;
+/// Checkpoints are written at most this often
+const CHECKPOINT_INTERVAL_NS: u64 = 1_000_000_000;
+
+/// The acknowledged position in a file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    path: String,
+    inode: u64,
+    offset: u64,
+}
+
+/// A line that was sent and is not yet acknowledged
+#[derive(Debug)]
+struct Sent {
+    line: Line,
+    /// end of the range of event ids created from the line, `u64::MAX` until the next
+    /// event id is known
+    end: u64,
+}
+
+/// A complete line read from a followed file
+#[derive(Debug)]
+pub(crate) struct Line {
+    stream: usize,
+    path: String,
+    inode: u64,
+    data: Vec<u8>,
+    start: u64,
+    end: u64,
+}
+
+/// Data to send, a stream starts when a file is picked up and ends when it was rotated away
+#[derive(Debug)]
+pub(crate) enum Entry {
+    Start(usize),
+    Line(Line),
+    End(usize),
+}
+
+struct Followed {
+    path: PathBuf,
+    file: File,
+    stream: usize,
+    inode: u64,
+    /// offset of the first byte in `buf`
+    offset: u64,
+    /// incomplete line read so far
+    buf: Vec<u8>,
+    /// offset to continue reading at after a failed line
+    rewind: Option<u64>,
+}
+
+impl Followed {
+    fn path_str(&self) -> String {
+        self.path.to_string_lossy().to_string()
+    }
+
+    /// Reads new data and queues all complete lines, if `finish` is set the
+    /// remaining incomplete line is queued as well
+    async fn read(&mut self, queue: &mut VecDeque<Entry>, finish: bool) -> Result<()> {
+        if let Some(offset) = self.rewind.take() {
+            self.file.seek(SeekFrom::Start(offset)).await?;
+            self.offset = offset;
+            self.buf.clear();
+        }
+        let mut chunk = vec![0_u8; READ_CHUNK];
+        let mut read = 0;
+        while read < MAX_READ_PER_POLL || finish {
+            let n = self.file.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            read += n;
+            self.buf
+                .extend_from_slice(chunk.get(..n).unwrap_or_default());
+        }
+        let path = self.path_str();
+        while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+            let rest = self.buf.split_off(pos + 1);
+            let data = std::mem::replace(&mut self.buf, rest);
+            self.push_line(queue, &path, data);
+        }
+        if finish && !self.buf.is_empty() {
+            let data = std::mem::take(&mut self.buf);
+            self.push_line(queue, &path, data);
+        }
+        Ok(())
+    }
+
+    fn push_line(&mut self, queue: &mut VecDeque<Entry>, path: &str, mut data: Vec<u8>) {
+        let start = self.offset;
+        self.offset += data.len() as u64;
+        if data.last() == Some(&b'\n') {
+            data.pop();
+        }
+        if data.last() == Some(&b'\r') {
+            data.pop();
+        }
+        queue.push_back(Entry::Line(Line {
+            stream: self.stream,
+            path: path.to_string(),
+            inode: self.inode,
+            data,
+            start,
+            end: self.offset,
+        }));
+    }
+}
+
+/// All files followed by the tail onramp
+pub(crate) struct Files {
+    pattern: String,
+    checkpoint_path: Option<PathBuf>,
+    /// acknowledged positions by inode
+    checkpoints: HashMap<u64, Checkpoint>,
+    checkpoints_dirty: bool,
+    last_checkpoint_ns: u64,
+    /// read positions of files that were rotated away, by inode
+    exhausted: HashMap<u64, u64>,
+    followed: Vec<Followed>,
+    queue: VecDeque<Entry>,
+    /// lines that were sent but not yet acknowledged, by their first event id
+    in_flight: BTreeMap<u64, Sent>,
+    /// all events up to this id are acknowledged, by inode
+    acked: HashMap<u64, u64>,
+    next_stream: usize,
+}
+
+impl Files {
+    pub(crate) async fn new(pattern: String, checkpoint_path: Option<PathBuf>) -> Result<Self> {
+        let mut checkpoints = HashMap::new();
+        if let Some(path) = &checkpoint_path {
+            if fs::metadata(path).await.is_ok() {
+                let mut data = fs::read(path).await?;
+                let stored: Vec<Checkpoint> = simd_json::from_slice(&mut data)?;
+                checkpoints.extend(stored.into_iter().map(|c| (c.inode, c)));
+            }
+        }
+        Ok(Self {
+            pattern,
+            checkpoint_path,
+            checkpoints,
+            checkpoints_dirty: false,
+            last_checkpoint_ns: 0,
+            exhausted: HashMap::new(),
+            followed: Vec::new(),
+            queue: VecDeque::new(),
+            in_flight: BTreeMap::new(),
+            acked: HashMap::new(),
+            // stream 0 is the default stream of the onramp
+            next_stream: 1,
+        })
+    }
+
+    pub(crate) fn next(&mut self) -> Option<Entry> {
+        self.queue.pop_front()
+    }
+
+    /// Picks up new files and reads new data from all followed files, returns true if there is something to send
+    pub(crate) async fn poll(&mut self) -> Result<bool> {
+        for mut f in std::mem::take(&mut self.followed) {
+            match fs::metadata(&f.path).await {
+                Ok(meta) if meta.ino() == f.inode => {
+                    if meta.len() < f.offset + f.buf.len() as u64 {
+                        info!(
+                            "File {} was truncated, reading from the start",
+                            f.path_str()
+                        );
+                        f.rewind = Some(0);
+                        self.discard(f.inode, 0);
+                    }
+                    self.followed.push(f);
+                }
+                _ => {
+                    // the file was rotated away or removed, read what is left and let it go
+                    info!("File {} was rotated", f.path_str());
+                    f.read(&mut self.queue, true).await?;
+                    self.exhausted.insert(f.inode, f.offset);
+                    self.queue.push_back(Entry::End(f.stream));
+                }
+            }
+        }
+        self.scan().await?;
+        for f in &mut self.followed {
+            f.read(&mut self.queue, false).await?;
+        }
+        Ok(!self.queue.is_empty())
+    }
+
+    /// Starts following files matching the pattern
+    async fn scan(&mut self) -> Result<()> {
+        let mut seen = Vec::new();
+        let paths = glob::glob(&self.pattern)
+            .map_err(|e| Error::from(format!("Invalid glob pattern: {}", e)))?;
+        for path in paths.flatten() {
+            let meta = match fs::metadata(&path).await {
+                Ok(meta) if meta.is_file() => meta,
+                _ => continue,
+            };
+            let inode = meta.ino();
+            let path_str = path.to_string_lossy().to_string();
+            seen.push((inode, path_str.clone()));
+            if self
+                .followed
+                .iter()
+                .any(|f| f.inode == inode || f.path == path)
+            {
+                continue;
+            }
+            let offset = self
+                .exhausted
+                .get(&inode)
+                .copied()
+                .or_else(|| {
+                    // the inode may have been reused by a new file
+                    self.checkpoints
+                        .get(&inode)
+                        .filter(|c| c.path == path_str)
+                        .map(|c| c.offset)
+                })
+                .filter(|offset| *offset <= meta.len())
+                .unwrap_or_default();
+            if self.exhausted.get(&inode) == Some(&meta.len()) {
+                // a rotated file we already read completely
+                continue;
+            }
+            let mut file = File::open(&path).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            info!(
+                "Following {} from offset {}",
+                path.to_string_lossy(),
+                offset
+            );
+            let stream = self.next_stream;
+            self.next_stream += 1;
+            self.queue.push_back(Entry::Start(stream));
+            self.followed.push(Followed {
+                path,
+                file,
+                stream,
+                inode,
+                offset,
+                buf: Vec::new(),
+                rewind: None,
+            });
+        }
+        self.exhausted
+            .retain(|inode, _| seen.iter().any(|(i, _)| i == inode));
+        let before = self.checkpoints.len();
+        self.checkpoints
+            .retain(|inode, c| seen.iter().any(|(i, p)| i == inode && p == &c.path));
+        self.checkpoints_dirty |= before != self.checkpoints.len();
+        Ok(())
+    }
+
+    /// Remembers a line that was sent as the event with the given id, its data isn't needed anymore
+    pub(crate) fn sent(&mut self, id: u64, line: Line) {
+        self.close(id);
+        // lines that don't create events, e.g. empty ones, leave their id to the next
+        // line and the first line is the one that needs acknowledging
+        self.in_flight.entry(id).or_insert(Sent {
+            line,
+            end: u64::MAX,
+        });
+    }
+
+    /// All events before `id` were created, ends the id range of the last line sent
+    pub(crate) fn close(&mut self, id: u64) {
+        let inode = match self.in_flight.range_mut(..id).next_back() {
+            Some((_, sent)) if sent.end > id => {
+                sent.end = id;
+                sent.line.inode
+            }
+            _ => return,
+        };
+        self.settle(inode);
+    }
+
+    /// The first event id of the line event `id` was created from, if it is in flight
+    fn find(&self, id: u64) -> Option<u64> {
+        self.in_flight
+            .range(..=id)
+            .next_back()
+            .filter(|(_, sent)| id < sent.end)
+            .map(|(first_id, _)| *first_id)
+    }
+
+    /// The event `id` is acknowledged, and with it all earlier events of the same file
+    pub(crate) fn ack(&mut self, id: u64) {
+        let inode = match self.find(id).and_then(|first| self.in_flight.get(&first)) {
+            Some(sent) => sent.line.inode,
+            None => return,
+        };
+        let acked = self.acked.entry(inode).or_default();
+        *acked = (*acked).max(id);
+        self.settle(inode);
+    }
+
+    /// Checkpoints the lines of a file whose events are all acknowledged
+    fn settle(&mut self, inode: u64) {
+        let acked = match self.acked.get(&inode) {
+            Some(acked) => *acked,
+            None => return,
+        };
+        let done: Vec<u64> = self
+            .in_flight
+            .range(..=acked)
+            .filter(|(_, sent)| sent.line.inode == inode && sent.end <= acked.saturating_add(1))
+            .map(|(first_id, _)| *first_id)
+            .collect();
+        let last = done
+            .into_iter()
+            .filter_map(|first_id| self.in_flight.remove(&first_id))
+            .last();
+        if let Some(Sent { line, .. }) = last {
+            self.checkpoints.insert(
+                line.inode,
+                Checkpoint {
+                    path: line.path,
+                    inode: line.inode,
+                    offset: line.end,
+                },
+            );
+            self.checkpoints_dirty = true;
+        }
+    }
+
+    /// The event with `id` failed, its file is read again starting with its line
+    pub(crate) fn fail(&mut self, id: u64) {
+        if let Some(Sent { line, .. }) = self.find(id).and_then(|id| self.in_flight.remove(&id)) {
+            self.discard(line.inode, id);
+            if let Some(f) = self.followed.iter_mut().find(|f| f.inode == line.inode) {
+                f.rewind = Some(line.start);
+            }
+        }
+    }
+
+    /// Drops queued and in flight lines of a file, starting with event `id`
+    fn discard(&mut self, inode: u64, id: u64) {
+        self.queue
+            .retain(|e| !matches!(e, Entry::Line(l) if l.inode == inode));
+        let later = self.in_flight.split_off(&id);
+        self.in_flight
+            .extend(later.into_iter().filter(|(_, s)| s.line.inode != inode));
+    }
+
+    /// Persists the checkpoints if they changed, at most once per `CHECKPOINT_INTERVAL_NS` unless `force` is set
+    pub(crate) async fn checkpoint(&mut self, force: bool) -> Result<()> {
+        let now = nanotime();
+        if !self.checkpoints_dirty
+            || (!force && now.saturating_sub(self.last_checkpoint_ns) < CHECKPOINT_INTERVAL_NS)
+        {
+            return Ok(());
+        }
+        if let Some(path) = &self.checkpoint_path {
+            let checkpoints: Vec<&Checkpoint> = self.checkpoints.values().collect();
+            let mut tmp = path.clone().into_os_string();
+            tmp.push(".tmp");
+            fs::write(&tmp, simd_json::to_vec(&checkpoints)?).await?;
+            fs::rename(&tmp, path).await?;
+        }
+        self.checkpoints_dirty = false;
+        self.last_checkpoint_ns = now;
+        Ok(())
+    }
+}
+
+/// The tail onramp source
+pub(crate) struct Tail {
+    onramp_id: TremorUrl,
+    uid: u64,
+    poll_interval_ms: u64,
+    files: Files,
+}
+
+impl std::fmt::Debug for Tail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FileTail")
+    }
+}
+
+impl Tail {
+    pub(crate) async fn from_config(
+        uid: u64,
+        onramp_id: TremorUrl,
+        config: &Config,
+    ) -> Result<Self> {
+        let files = Files::new(
+            config.source.clone(),
+            config.checkpoint.as_ref().map(PathBuf::from),
+        )
+        .await?;
+        Ok(Self {
+            onramp_id,
+            uid,
+            poll_interval_ms: config.poll_interval_ms,
+            files,
+        })
+    }
+
+    fn reply(&mut self, id: u64) -> Option<SourceReply> {
+        let mut line = match self.files.next()? {
+            Entry::Start(stream) => return Some(SourceReply::StartStream(stream)),
+            Entry::End(stream) => return Some(SourceReply::EndStream(stream)),
+            Entry::Line(line) => line,
+        };
+        let origin_uri = EventOriginUri {
+            uid: self.uid,
+            scheme: "tremor-file".to_string(),
+            host: hostname(),
+            port: None,
+            path: vec![line.path.clone()],
+        };
+        let meta = literal!({
+            "path": line.path.clone(),
+            "offset": line.start
+        });
+        let reply = SourceReply::Data {
+            origin_uri,
+            data: std::mem::take(&mut line.data),
+            meta: Some(meta),
+            codec_override: None,
+            stream: line.stream,
+        };
+        self.files.sent(id, line);
+        Some(reply)
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Tail {
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        self.files.close(id);
+        self.files.checkpoint(false).await?;
+        if let Some(reply) = self.reply(id) {
+            return Ok(reply);
+        }
+        self.files.poll().await?;
+        Ok(self
+            .reply(id)
+            .unwrap_or(SourceReply::Empty(self.poll_interval_ms)))
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        Ok(SourceState::Connected)
+    }
+
+    async fn terminate(&mut self) {
+        if let Err(e) = self.files.checkpoint(true).await {
+            error!(
+                "[Source::{}] Failed to write checkpoint: {}",
+                self.onramp_id, e
+            );
+        }
+    }
+
+    fn ack(&mut self, id: u64) {
+        self.files.ack(id);
+    }
+
+    fn fail(&mut self, id: u64) {
+        self.files.fail(id);
+    }
+
+    fn is_transactional(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::io::WriteExt;
+    use tempfile::tempdir;
+
+    async fn append(path: &std::path::Path, data: &[u8]) -> Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(data).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    fn drain(files: &mut Files) -> Vec<String> {
+        let mut res = Vec::new();
+        while let Some(entry) = files.next() {
+            res.push(match entry {
+                Entry::Start(stream) => format!("start {}", stream),
+                Entry::End(stream) => format!("end {}", stream),
+                Entry::Line(l) => format!("{} {}", l.start, String::from_utf8_lossy(&l.data)),
+            });
+        }
+        res
+    }
+
+    fn next_line(files: &mut Files) -> Result<Line> {
+        match files.next() {
+            Some(Entry::Line(line)) => Ok(line),
+            other => Err(format!("expected a line, got {:?}", other).into()),
+        }
+    }
+
+    #[async_std::test]
+    async fn follow_rotate_and_resume() -> Result<()> {
+        let dir = tempdir()?;
+        let log = dir.path().join("app.log");
+        let checkpoint = dir.path().join("checkpoint.json");
+        let pattern = dir.path().join("*.log").to_string_lossy().to_string();
+
+        append(&log, b"snot\nbad").await?;
+        let mut files = Files::new(pattern.clone(), Some(checkpoint.clone())).await?;
+        assert!(files.poll().await?);
+        assert_eq!(vec!["start 1", "0 snot"], drain(&mut files));
+
+        // appends are followed, incomplete lines are held back
+        append(&log, b"ger\r\n").await?;
+        files.poll().await?;
+        let line = next_line(&mut files)?;
+        assert_eq!(b"badger".to_vec(), line.data);
+        files.sent(1, line);
+        // the next pull ends the id range of the line
+        files.close(2);
+        files.ack(1);
+        files.checkpoint(true).await?;
+
+        // copytruncate
+        fs::write(&log, b"x\n").await?;
+        files.poll().await?;
+        assert_eq!(vec!["0 x"], drain(&mut files));
+
+        // rename + create, the rest of the old file is sent before its stream ends
+        append(&log, b"y").await?;
+        fs::rename(&log, dir.path().join("app.log.1")).await?;
+        append(&log, b"new\n").await?;
+        files.poll().await?;
+        assert_eq!(vec!["2 y", "end 1", "start 2", "0 new"], drain(&mut files));
+
+        // a restart resumes at the checkpoint of the new file
+        append(&log, b"more\n").await?;
+        files.poll().await?;
+        let line = next_line(&mut files)?;
+        files.sent(2, line);
+        files.close(3);
+        files.ack(2);
+        files.checkpoint(true).await?;
+        append(&log, b"badger\n").await?;
+        let mut files = Files::new(pattern, Some(checkpoint)).await?;
+        files.poll().await?;
+        assert_eq!(vec!["start 1", "9 badger"], drain(&mut files));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn fail_rereads() -> Result<()> {
+        let dir = tempdir()?;
+        let log = dir.path().join("app.log");
+        let pattern = dir.path().join("*.log").to_string_lossy().to_string();
+        append(&log, b"a\nb\nc\n").await?;
+        let mut files = Files::new(pattern, None).await?;
+        files.poll().await?;
+        assert!(matches!(files.next(), Some(Entry::Start(1))));
+        for id in 0..3 {
+            let line = next_line(&mut files)?;
+            files.sent(id, line);
+        }
+        files.ack(0);
+        files.fail(1);
+        files.poll().await?;
+        assert_eq!(vec!["2 b", "4 c"], drain(&mut files));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn acks_per_file() -> Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a.log");
+        let b = dir.path().join("b.log");
+        let pattern = dir.path().join("*.log").to_string_lossy().to_string();
+        append(&a, b"a1\na2\n").await?;
+        append(&b, b"b1\n").await?;
+        let a_inode = fs::metadata(&a).await?.ino();
+        let b_inode = fs::metadata(&b).await?.ino();
+        let mut files = Files::new(pattern, None).await?;
+        files.poll().await?;
+        let mut lines = Vec::new();
+        while let Some(entry) = files.next() {
+            if let Entry::Line(line) = entry {
+                lines.push(line);
+            }
+        }
+        let b1 = lines.pop().ok_or("no line")?;
+        let a2 = lines.pop().ok_or("no line")?;
+        let a1 = lines.pop().ok_or("no line")?;
+        files.sent(1, a1);
+        files.sent(2, b1);
+        files.sent(3, a2);
+        files.close(4);
+
+        // the ack of `a2` doesn't cover `b1` of the other file
+        files.ack(3);
+        assert_eq!(Some(6), files.checkpoints.get(&a_inode).map(|c| c.offset));
+        assert!(files.checkpoints.get(&b_inode).is_none());
+        files.fail(2);
+        files.poll().await?;
+        assert_eq!(vec!["0 b1"], drain(&mut files));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn checkpoint_path() -> Result<()> {
+        let dir = tempdir()?;
+        let log = dir.path().join("app.log");
+        let checkpoint = dir.path().join("checkpoint.json");
+        let pattern = dir.path().join("*.log").to_string_lossy().to_string();
+        append(&log, b"snot\n").await?;
+        let mut files = Files::new(pattern.clone(), Some(checkpoint.clone())).await?;
+        files.poll().await?;
+        files.next();
+        let line = next_line(&mut files)?;
+        files.sent(1, line);
+        files.close(2);
+        files.ack(1);
+        files.checkpoint(true).await?;
+
+        // a file with the same inode but another path, as if the inode was reused
+        fs::rename(&log, dir.path().join("other.log")).await?;
+        let mut files = Files::new(pattern, Some(checkpoint)).await?;
+        files.poll().await?;
+        assert_eq!(vec!["start 1", "0 snot"], drain(&mut files));
+        Ok(())
+    }
+}
@@ -27,7 +27,7 @@ use rdkafka::{
     config::ClientConfig,
     consumer::{
         stream_consumer::{self, StreamConsumer},
-        CommitMode, Consumer, ConsumerContext, Rebalance,
+        BaseConsumer, CommitMode, Consumer, ConsumerContext, Rebalance,
     },
     error::{KafkaError, KafkaResult},
     message::{BorrowedMessage, Headers},
@@ -35,10 +35,15 @@ use rdkafka::{
     Message, Offset, TopicPartitionList,
 };
 use rdkafka_sys::RDKafkaErrorCode;
-use std::collections::{BTreeMap, HashMap as StdMap};
+use std::collections::{BTreeMap, HashMap as StdMap, HashSet};
 use std::future::Future;
 use std::mem;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use tremor_value::literal;
+
+/// timeout for broker requests issued by commands
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct SmolRuntime;
 
@@ -114,6 +119,217 @@ pub struct Kafka {
     onramp_id: TremorUrl,
 }
 
+/// Where to reposition the consumer
+#[derive(Debug, PartialEq)]
+enum Seek {
+    /// the earliest available offset of every assigned partition
+    Earliest,
+    /// the end of every assigned partition
+    Latest,
+    /// the first offset at or after the given timestamp in ms, for every assigned partition
+    Timestamp(i64),
+    /// explicit offsets per topic and partition
+    Offsets(Vec<(String, i32, i64)>),
+}
+
+/// Commands accepted via the onramp command API:
+///
+/// * `{"seek": "earliest"}` or `{"seek": "latest"}`
+/// * `{"seek": {"timestamp": 1634567890000}}`
+/// * `{"seek": {"offsets": [{"topic": "t", "partition": 0, "offset": 42}]}}`
+/// * `{"pause": [{"topic": "t", "partition": 0}]}`
+/// * `{"resume": [{"topic": "t", "partition": 0}]}`
+#[derive(Debug, PartialEq)]
+enum Command {
+    Seek(Seek),
+    Pause(Vec<(String, i32)>),
+    Resume(Vec<(String, i32)>),
+}
+
+fn partition(v: &Value) -> Result<(String, i32)> {
+    match (v.get_str("topic"), v.get_i32("partition")) {
+        (Some(topic), Some(partition)) => Ok((topic.to_string(), partition)),
+        _ => Err("Partitions need to be given as `{\"topic\": .., \"partition\": ..}`".into()),
+    }
+}
+
+fn partitions(v: &Value) -> Result<Vec<(String, i32)>> {
+    v.as_array()
+        .ok_or_else(|| Error::from("Expected a list of partitions"))?
+        .iter()
+        .map(partition)
+        .collect()
+}
+
+impl Command {
+    fn parse(command: &Value) -> Result<Self> {
+        if let Some(seek) = command.get("seek") {
+            let seek = match seek.as_str() {
+                Some("earliest") => Seek::Earliest,
+                Some("latest") => Seek::Latest,
+                Some(other) => return Err(format!("Unknown seek position `{}`", other).into()),
+                None => {
+                    if let Some(ts) = seek.get_i64("timestamp") {
+                        Seek::Timestamp(ts)
+                    } else if let Some(offsets) = seek.get_array("offsets") {
+                        offsets
+                            .iter()
+                            .map(|o| -> Result<(String, i32, i64)> {
+                                let (topic, partition) = partition(o)?;
+                                let offset = o
+                                    .get_i64("offset")
+                                    .ok_or_else(|| Error::from("Missing `offset`"))?;
+                                Ok((topic, partition, offset))
+                            })
+                            .collect::<Result<_>>()
+                            .map(Seek::Offsets)?
+                    } else {
+                        return Err("Invalid seek command".into());
+                    }
+                }
+            };
+            Ok(Self::Seek(seek))
+        } else if let Some(p) = command.get("pause") {
+            Ok(Self::Pause(partitions(p)?))
+        } else if let Some(p) = command.get("resume") {
+            Ok(Self::Resume(partitions(p)?))
+        } else {
+            Err("Unknown command, expected one of `seek`, `pause` or `resume`".into())
+        }
+    }
+}
+
+fn partition_list(partitions: &[(String, i32)]) -> TopicPartitionList {
+    let mut tpl = TopicPartitionList::with_capacity(partitions.len());
+    for (topic, partition) in partitions {
+        tpl.add_partition(topic, *partition);
+    }
+    tpl
+}
+
+/// resolve the concrete offsets for a seek on the `assigned` partitions, this queries the
+/// brokers and blocks, so it runs on a blocking task with a client of its own
+fn resolve(
+    consumer: &BaseConsumer,
+    assigned: Vec<(String, i32)>,
+    seek: Seek,
+) -> Result<Vec<(String, i32, i64)>> {
+    match seek {
+        Seek::Earliest | Seek::Latest => {
+            let earliest = seek == Seek::Earliest;
+            assigned
+                .into_iter()
+                .map(|(topic, partition)| -> Result<(String, i32, i64)> {
+                    let (low, high) =
+                        consumer.fetch_watermarks(&topic, partition, COMMAND_TIMEOUT)?;
+                    Ok((topic, partition, if earliest { low } else { high }))
+                })
+                .collect()
+        }
+        Seek::Timestamp(ts) => {
+            let mut tpl = TopicPartitionList::with_capacity(assigned.len());
+            for (topic, partition) in &assigned {
+                tpl.add_partition_offset(topic, *partition, Offset::Offset(ts))?;
+            }
+            consumer
+                .offsets_for_times(tpl, COMMAND_TIMEOUT)?
+                .elements()
+                .iter()
+                .map(|e| -> Result<(String, i32, i64)> {
+                    let offset = if let Offset::Offset(offset) = e.offset() {
+                        offset
+                    } else {
+                        // no message at or after the timestamp, continue at the end
+                        consumer
+                            .fetch_watermarks(e.topic(), e.partition(), COMMAND_TIMEOUT)?
+                            .1
+                    };
+                    Ok((e.topic().to_string(), e.partition(), offset))
+                })
+                .collect()
+        }
+        Seek::Offsets(offsets) => {
+            if let Some((topic, partition, _)) = offsets
+                .iter()
+                .find(|(t, p, _)| !assigned.iter().any(|(at, ap)| at == t && ap == p))
+            {
+                Err(format!(
+                    "Partition {} of topic {} is not assigned to this consumer",
+                    partition, topic
+                )
+                .into())
+            } else {
+                Ok(offsets)
+            }
+        }
+    }
+}
+
+/// Lag and position of a single assigned partition
+#[derive(Debug)]
+struct PartitionState {
+    topic: String,
+    partition: i32,
+    position: Option<i64>,
+    high_watermark: Option<i64>,
+    paused: bool,
+}
+
+impl PartitionState {
+    fn lag(&self) -> Option<i64> {
+        self.position
+            .zip(self.high_watermark)
+            .map(|(position, high)| (high - position).max(0))
+    }
+}
+
+fn metrics_events(ramp: &str, t: u64, partitions: &[PartitionState]) -> Vec<Event> {
+    let paused = partitions.iter().filter(|p| p.paused).count() as u64;
+    let mut events = Vec::with_capacity(partitions.len() + 1);
+    events.push(literal!({
+        "measurement": "kafka_consumer_assignment",
+        "tags": {
+            "ramp": ramp.to_string()
+        },
+        "fields": {
+            "partitions": partitions.len() as u64,
+            "paused": paused
+        },
+        "timestamp": t
+    }));
+    for p in partitions {
+        let mut fields = Value::object_with_capacity(4);
+        if let Some(position) = p.position {
+            fields.try_insert("offset", position);
+        }
+        if let Some(high) = p.high_watermark {
+            fields.try_insert("high_watermark", high);
+        }
+        if let Some(lag) = p.lag() {
+            fields.try_insert("lag", lag);
+        }
+        fields.try_insert("paused", p.paused);
+        events.push(literal!({
+            "measurement": "kafka_consumer_lag",
+            "tags": {
+                "ramp": ramp.to_string(),
+                "topic": p.topic.clone(),
+                "partition": p.partition.to_string()
+            },
+            "fields": fields,
+            "timestamp": t
+        }));
+    }
+    events
+        .into_iter()
+        .map(|data| Event {
+            data: data.into(),
+            ingest_ns: t,
+            ..Event::default()
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 struct MsgOffset {
     topic: String,
@@ -186,6 +402,50 @@ impl rentals::MessageStream {
         Ok(())
     }
 
+    /// the currently assigned partitions, this doesn't query the brokers
+    fn assignment(&mut self) -> Result<Vec<(String, i32)>> {
+        let consumer = unsafe { self.consumer() };
+        Ok(consumer
+            .assignment()?
+            .elements()
+            .iter()
+            .map(|e| (e.topic().to_string(), e.partition()))
+            .collect())
+    }
+
+    /// the position, watermark and pause state of all assigned partitions
+    fn partition_states(&mut self, paused: &HashSet<(String, i32)>) -> Result<Vec<PartitionState>> {
+        let consumer = unsafe { self.consumer() };
+        let positions = consumer.position()?;
+        Ok(positions
+            .elements()
+            .iter()
+            .map(|e| {
+                let topic = e.topic().to_string();
+                let partition = e.partition();
+                let position = if let Offset::Offset(o) = e.offset() {
+                    Some(o)
+                } else {
+                    None
+                };
+                // cached watermarks, these don't query the brokers
+                let high_watermark = consumer
+                    .get_watermark_offsets(&topic, partition)
+                    .ok()
+                    .map(|(_, high)| high)
+                    .filter(|high| *high >= 0);
+                let paused = paused.contains(&(topic.clone(), partition));
+                PartitionState {
+                    topic,
+                    partition,
+                    position,
+                    high_watermark,
+                    paused,
+                }
+            })
+            .collect())
+    }
+
     fn seek(&mut self, map: &StdMap<(String, i32), Offset>) -> Result<()> {
         let consumer = unsafe { self.consumer() };
         for ((t, p), o) in map.iter() {
@@ -204,6 +464,10 @@ pub struct Int {
     origin_uri: EventOriginUri,
     auto_commit: bool,
     messages: BTreeMap<u64, MsgOffset>,
+    /// paused partitions, cleared by rebalances as new assignments are not paused
+    paused: Arc<Mutex<HashSet<(String, i32)>>>,
+    /// client for the broker queries of seek commands, they block and run on blocking tasks
+    queries: Option<Arc<BaseConsumer>>,
     // if it receives anything, we error out, and log the message
     err_rx: Option<Receiver<KafkaError>>,
 }
@@ -271,6 +535,8 @@ impl Int {
             origin_uri,
             auto_commit,
             messages: BTreeMap::new(),
+            paused: Arc::new(Mutex::new(HashSet::new())),
+            queries: None,
             err_rx: None,
         }
     }
@@ -295,6 +561,7 @@ impl onramp::Impl for Kafka {
 pub struct LoggingConsumerContext {
     onramp_id: TremorUrl,
     err_tx: Sender<KafkaError>,
+    paused: Arc<Mutex<HashSet<(String, i32)>>>,
 }
 
 impl ClientContext for LoggingConsumerContext {
@@ -317,6 +584,9 @@ impl ClientContext for LoggingConsumerContext {
 
 impl ConsumerContext for LoggingConsumerContext {
     fn post_rebalance(&self, rebalance: &Rebalance) {
+        if let Ok(mut paused) = self.paused.lock() {
+            paused.clear();
+        }
         match rebalance {
             Rebalance::Assign(tpl) => {
                 let offset_strings: Vec<String> = tpl
@@ -532,6 +802,7 @@ impl Source for Int {
         let context = LoggingConsumerContext {
             onramp_id: self.onramp_id.clone(),
             err_tx,
+            paused: self.paused.clone(),
         };
         let mut client_config = ClientConfig::new();
         let tid = task::current().id();
@@ -600,6 +871,8 @@ impl Source for Int {
 
         // Set up the the consumer
         let consumer: LoggingConsumer = client_config.create_with_context(context)?;
+        // it never subscribes, so it doesn't take part in the group
+        self.queries = Some(Arc::new(client_config.create()?));
 
         // Handle topics
         let topics: Vec<&str> = self
@@ -626,6 +899,68 @@ impl Source for Int {
     fn trigger_breaker(&mut self) {}
     fn restore_breaker(&mut self) {}
 
+    async fn on_command(&mut self, command: &Value<'static>) -> Result<()> {
+        let command = Command::parse(command)?;
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| Error::from(format!("[Source::{}] Not connected", self.onramp_id)))?;
+        info!("[Source::{}] Executing {:?}", self.onramp_id, command);
+        match command {
+            Command::Seek(seek) => {
+                let assigned = stream.assignment()?;
+                let queries = self.queries.clone().ok_or_else(|| {
+                    Error::from(format!("[Source::{}] Not connected", self.onramp_id))
+                })?;
+                let offsets =
+                    task::spawn_blocking(move || resolve(&queries, assigned, seek)).await?;
+                let map: StdMap<(String, i32), Offset> = offsets
+                    .into_iter()
+                    .map(|(topic, partition, offset)| ((topic, partition), Offset::Offset(offset)))
+                    .collect();
+                stream.seek(&map)?;
+                // persist the new position for the whole group, events in flight
+                // from before the seek must not move it again. Asynchronously as this
+                // runs on the source task, failures are logged by the commit callback
+                stream.commit(&map, CommitMode::Async)?;
+                self.messages.clear();
+            }
+            Command::Pause(partitions) => {
+                unsafe { stream.consumer() }.pause(&partition_list(&partitions))?;
+                self.paused
+                    .lock()
+                    .map_err(|_| Error::from("Paused partitions poisoned"))?
+                    .extend(partitions);
+            }
+            Command::Resume(partitions) => {
+                unsafe { stream.consumer() }.resume(&partition_list(&partitions))?;
+                let mut paused = self
+                    .paused
+                    .lock()
+                    .map_err(|_| Error::from("Paused partitions poisoned"))?;
+                for p in &partitions {
+                    paused.remove(p);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn metrics(&mut self, t: u64) -> Vec<Event> {
+        if let (Some(stream), Ok(paused)) = (self.stream.as_mut(), self.paused.lock()) {
+            match stream.partition_states(&paused) {
+                Ok(partitions) => {
+                    return metrics_events(&self.onramp_id.to_string(), t, &partitions);
+                }
+                Err(e) => warn!(
+                    "[Source::{}] Failed to collect consumer metrics: {}",
+                    self.onramp_id, e
+                ),
+            }
+        }
+        vec![]
+    }
+
     // If we fail a message we seek back to this failed
     // message to replay data from here.
     //
@@ -673,3 +1008,91 @@ impl Onramp for Kafka {
         "json"
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands() -> Result<()> {
+        assert_eq!(
+            Command::Seek(Seek::Earliest),
+            Command::parse(&literal!({"seek": "earliest"}))?
+        );
+        assert_eq!(
+            Command::Seek(Seek::Timestamp(1_634_567_890_000)),
+            Command::parse(&literal!({"seek": {"timestamp": 1_634_567_890_000_i64}}))?
+        );
+        assert_eq!(
+            Command::Seek(Seek::Offsets(vec![("snot".to_string(), 1, 42)])),
+            Command::parse(&literal!({
+                "seek": {"offsets": [{"topic": "snot", "partition": 1, "offset": 42}]}
+            }))?
+        );
+        assert_eq!(
+            Command::Resume(vec![("snot".to_string(), 0), ("badger".to_string(), 2)]),
+            Command::parse(&literal!({
+                "resume": [{"topic": "snot", "partition": 0}, {"topic": "badger", "partition": 2}]
+            }))?
+        );
+        assert!(Command::parse(&literal!({"seek": "middle"})).is_err());
+        assert!(Command::parse(&literal!({"pause": [{"topic": "snot"}]})).is_err());
+        assert!(Command::parse(&literal!({"rewind": true})).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rebalance_clears_paused() -> Result<()> {
+        let (err_tx, _err_rx) = bounded(1);
+        let paused = Arc::new(Mutex::new(HashSet::new()));
+        let context = LoggingConsumerContext {
+            onramp_id: TremorUrl::parse("/onramp/kafka/01/out")?,
+            err_tx,
+            paused: paused.clone(),
+        };
+        if let Ok(mut paused) = paused.lock() {
+            paused.insert(("snot".to_string(), 0));
+        }
+        context.post_rebalance(&Rebalance::Revoke);
+        assert!(paused.lock().map_or(false, |p| p.is_empty()));
+        Ok(())
+    }
+
+    #[test]
+    fn lag_metrics() {
+        let partitions = vec![
+            PartitionState {
+                topic: "snot".to_string(),
+                partition: 0,
+                position: Some(10),
+                high_watermark: Some(15),
+                paused: false,
+            },
+            PartitionState {
+                topic: "snot".to_string(),
+                partition: 1,
+                position: None,
+                high_watermark: Some(3),
+                paused: true,
+            },
+        ];
+        let events = metrics_events("/onramp/kafka/01", 42, &partitions);
+        assert_eq!(3, events.len());
+
+        let (assignment, _) = events[0].data.parts();
+        assert_eq!(assignment["measurement"], "kafka_consumer_assignment");
+        assert_eq!(assignment["fields"]["partitions"], 2);
+        assert_eq!(assignment["fields"]["paused"], 1);
+
+        let (lag, _) = events[1].data.parts();
+        assert_eq!(lag["measurement"], "kafka_consumer_lag");
+        assert_eq!(lag["tags"]["partition"], "0");
+        assert_eq!(lag["fields"]["lag"], 5);
+        assert_eq!(lag["fields"]["offset"], 10);
+        assert_eq!(lag["timestamp"], 42);
+
+        let (unknown, _) = events[2].data.parts();
+        assert_eq!(unknown["fields"].get("lag"), None);
+        assert_eq!(unknown["fields"]["paused"], true);
+    }
+}
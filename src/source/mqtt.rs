@@ -0,0 +1,429 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # MQTT Onramp
+//!
+//! Subscribes to topic filters on an MQTT broker, the topic, QoS, retain flag and (MQTT 5)
+//! properties of each message are available as `$mqtt`.
+//!
+//! Messages received with QoS 1 or 2 are acknowledged to the broker once their events are
+//! acknowledged, failed events are emitted again.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::connectors::mqtt::{
+    is_failure, publish_meta, validate_filter, Client, ConnectionConfig, Packet, Publish, QoS,
+};
+use crate::source::prelude::*;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use tremor_common::time::nanotime;
+
+/// Wait this long before reconnecting
+const RECONNECT_INTERVAL_NS: u64 = 1_000_000_000;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    #[serde(flatten)]
+    pub(crate) connection: ConnectionConfig,
+    /// Topic filters to subscribe to, `+` matches a single level, `#` all remaining levels
+    pub topics: Vec<String>,
+    /// Maximum QoS to receive messages with, defaults to 1
+    #[serde(default = "dflt_qos")]
+    pub qos: u8,
+}
+
+fn dflt_qos() -> u8 {
+    1
+}
+
+impl ConfigImpl for Config {}
+
+pub struct Mqtt {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for Mqtt {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            if config.topics.is_empty() {
+                return Err("mqtt onramp needs at least one topic to subscribe to".into());
+            }
+            for topic in &config.topics {
+                validate_filter(topic)?;
+            }
+            QoS::from_u8(config.qos)?;
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for mqtt onramp".into())
+        }
+    }
+}
+
+pub struct Int {
+    onramp_id: TremorUrl,
+    config: Config,
+    client: Option<Client>,
+    origin_uri: EventOriginUri,
+    /// messages to emit (again)
+    pending: VecDeque<Publish>,
+    /// QoS 1 and 2 messages by the id of their event
+    inflight: BTreeMap<u64, Publish>,
+    /// packet ids of QoS 1 and 2 messages in the order they arrived, the broker
+    /// expects acknowledgements in this order
+    order: VecDeque<(u16, QoS)>,
+    /// acknowledged packet ids waiting for the ones before them
+    acked: HashSet<u16>,
+    /// acknowledgements to send to the broker
+    acks: Vec<Packet>,
+    reconnect_at: u64,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MQTT")
+    }
+}
+
+impl Int {
+    fn from_config(uid: u64, onramp_id: TremorUrl, config: &Config) -> Self {
+        let origin_uri = EventOriginUri {
+            uid,
+            scheme: "tremor-mqtt".to_string(),
+            host: config.connection.host.clone(),
+            port: Some(config.connection.port),
+            path: vec![],
+        };
+        Self {
+            onramp_id,
+            config: config.clone(),
+            client: None,
+            origin_uri,
+            pending: VecDeque::new(),
+            inflight: BTreeMap::new(),
+            order: VecDeque::new(),
+            acked: HashSet::new(),
+            acks: Vec::new(),
+            reconnect_at: 0,
+        }
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        let (mut client, session_present) = Client::connect(&self.config.connection).await?;
+        info!(
+            "[Source::{}] Connected to {}:{}",
+            self.onramp_id, self.config.connection.host, self.config.connection.port
+        );
+        // acknowledgements for messages of an earlier connection are meaningless now,
+        // the broker sends them again if it kept our session
+        self.inflight.clear();
+        self.order.clear();
+        self.acked.clear();
+        self.acks.clear();
+        self.pending.retain(|p| p.qos == QoS::AtMostOnce);
+        if !session_present {
+            client
+                .subscribe(&self.config.topics, QoS::from_u8(self.config.qos)?)
+                .await?;
+        }
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn reply(&mut self, id: u64, publish: Publish) -> Result<SourceReply> {
+        let meta = publish_meta(&publish)?;
+        let mut origin_uri = self.origin_uri.clone();
+        origin_uri.path = publish.topic.split('/').map(ToString::to_string).collect();
+        let data = if publish.qos == QoS::AtMostOnce {
+            publish.payload
+        } else {
+            let data = publish.payload.clone();
+            self.inflight.insert(id, publish);
+            data
+        };
+        Ok(SourceReply::Data {
+            origin_uri,
+            data,
+            meta: Some(meta),
+            codec_override: None,
+            stream: 0,
+        })
+    }
+
+    /// Marks all messages up to event `id` as acknowledged
+    fn acknowledge(&mut self, id: u64) {
+        let later = self.inflight.split_off(&(id + 1));
+        let done = std::mem::replace(&mut self.inflight, later);
+        for publish in done.into_values() {
+            self.acked.insert(publish.pkid);
+        }
+        self.release();
+    }
+
+    /// Queues the acknowledgements of acknowledged messages that arrived before the oldest
+    /// message still in flight
+    fn release(&mut self) {
+        while let Some((pkid, qos)) = self.order.front().copied() {
+            if !self.acked.remove(&pkid) {
+                break;
+            }
+            self.order.pop_front();
+            self.acks.push(if qos == QoS::ExactlyOnce {
+                Packet::PubRec { pkid, code: 0 }
+            } else {
+                Packet::PubAck { pkid, code: 0 }
+            });
+        }
+    }
+
+    /// Sends the queued acknowledgements, they are dropped once we reconnect
+    async fn send_acks(&mut self) {
+        if let Some(client) = self.client.as_ref() {
+            for packet in self.acks.drain(..) {
+                if let Err(e) = client.send(packet).await {
+                    error!("[Source::{}] Failed to acknowledge: {}", self.onramp_id, e);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        self.send_acks().await;
+        if let Some(publish) = self.pending.pop_front() {
+            return self.reply(id, publish);
+        }
+        let packet = match self.client.as_ref().map(Client::try_recv) {
+            Some(Ok(Some(packet))) => packet,
+            Some(Ok(None)) => return Ok(SourceReply::Empty(10)),
+            Some(Err(e)) => {
+                warn!("[Source::{}] {}, reconnecting", self.onramp_id, e);
+                self.client = None;
+                self.reconnect_at = nanotime() + RECONNECT_INTERVAL_NS;
+                return Ok(SourceReply::Empty(10));
+            }
+            None => {
+                if nanotime() < self.reconnect_at {
+                    return Ok(SourceReply::Empty(100));
+                }
+                if let Err(e) = self.connect().await {
+                    error!("[Source::{}] Failed to connect: {}", self.onramp_id, e);
+                    self.reconnect_at = nanotime() + RECONNECT_INTERVAL_NS;
+                }
+                return Ok(SourceReply::Empty(10));
+            }
+        };
+        match packet {
+            Packet::Publish(publish) => {
+                if publish.qos != QoS::AtMostOnce {
+                    self.order.push_back((publish.pkid, publish.qos));
+                }
+                self.reply(id, publish)
+            }
+            Packet::SubAck { codes, .. } => {
+                for (topic, code) in self.config.topics.iter().zip(codes) {
+                    if is_failure(code) {
+                        error!(
+                            "[Source::{}] Subscription to {} was refused with reason code 0x{:02x}",
+                            self.onramp_id, topic, code
+                        );
+                    } else {
+                        info!(
+                            "[Source::{}] Subscribed to {} with QoS {}",
+                            self.onramp_id, topic, code
+                        );
+                    }
+                }
+                Ok(SourceReply::Empty(0))
+            }
+            other => {
+                debug!("[Source::{}] Ignoring {:?}", self.onramp_id, other);
+                Ok(SourceReply::Empty(0))
+            }
+        }
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        self.connect().await?;
+        Ok(SourceState::Connected)
+    }
+
+    async fn on_empty_event(&mut self, id: u64, _stream: usize) -> Result<()> {
+        // the message produced no event, its id is used for the next one
+        if let Some(publish) = self.inflight.remove(&id) {
+            self.acked.insert(publish.pkid);
+            self.release();
+        }
+        Ok(())
+    }
+
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    fn ack(&mut self, id: u64) {
+        // acks cover all events up to `id`, they are sent on the next pull
+        self.acknowledge(id);
+    }
+
+    fn fail(&mut self, id: u64) {
+        // emit the failed event and all after it again, in order
+        let failed = self.inflight.split_off(&id);
+        for publish in failed.into_values().rev() {
+            self.pending.push_front(Publish {
+                dup: true,
+                ..publish
+            });
+        }
+    }
+
+    fn is_transactional(&self) -> bool {
+        true
+    }
+
+    async fn terminate(&mut self) {
+        // dropping the client disconnects
+        self.client = None;
+    }
+}
+
+#[async_trait::async_trait]
+impl Onramp for Mqtt {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int::from_config(config.onramp_uid, self.onramp_id.clone(), &self.config);
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connectors::mqtt::Version;
+
+    fn publish(pkid: u16, qos: QoS) -> Publish {
+        Publish {
+            topic: "sensors/1".to_string(),
+            qos,
+            retain: false,
+            dup: false,
+            pkid,
+            properties: vec![],
+            payload: pkid.to_string().into_bytes(),
+        }
+    }
+
+    #[async_std::test]
+    async fn acks_in_order() -> Result<()> {
+        let config = Config {
+            connection: serde_yaml::from_str("host: localhost")?,
+            topics: vec!["sensors/#".to_string()],
+            qos: 2,
+        };
+        assert_eq!(Version::V3, config.connection.version);
+        let mut source = Int::from_config(0, TremorUrl::from_onramp_id("mqtt")?, &config);
+        for (id, pkid) in [(1, 10), (2, 11), (3, 12)] {
+            let p = publish(
+                pkid,
+                if pkid == 11 {
+                    QoS::ExactlyOnce
+                } else {
+                    QoS::AtLeastOnce
+                },
+            );
+            source.order.push_back((p.pkid, p.qos));
+            let reply = source.reply(id, p)?;
+            if let SourceReply::Data {
+                meta: Some(meta),
+                origin_uri,
+                ..
+            } = reply
+            {
+                assert_eq!(Some("sensors/1"), meta.get_str("topic"));
+                assert_eq!(
+                    vec!["sensors".to_string(), "1".to_string()],
+                    origin_uri.path
+                );
+            } else {
+                return Err("expected data".into());
+            }
+        }
+        // a later event is done first, nothing can be acknowledged yet
+        source.inflight.remove(&2).ok_or("no event 2")?;
+        source.acked.insert(11);
+        source.acknowledge(0);
+        assert!(source.acks.is_empty());
+
+        // event 1 fails and is emitted again, with event 3 after it
+        source.fail(1);
+        assert!(source.inflight.is_empty());
+        assert_eq!(2, source.pending.len());
+        assert!(source.pending.iter().all(|p| p.dup));
+        let reply = source.pull_event(4).await?;
+        assert!(matches!(reply, SourceReply::Data { .. }));
+        let reply = source.pull_event(5).await?;
+        assert!(matches!(reply, SourceReply::Data { .. }));
+
+        // acknowledging the latest covers the earlier one
+        source.acknowledge(5);
+        assert_eq!(
+            vec![
+                Packet::PubAck { pkid: 10, code: 0 },
+                Packet::PubRec { pkid: 11, code: 0 },
+                Packet::PubAck { pkid: 12, code: 0 }
+            ],
+            source.acks
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn empty_events() -> Result<()> {
+        let config = Config {
+            connection: serde_yaml::from_str("host: localhost")?,
+            topics: vec!["sensors/#".to_string()],
+            qos: 1,
+        };
+        let mut source = Int::from_config(0, TremorUrl::from_onramp_id("mqtt")?, &config);
+        // the first message produces no event, so its id is used again
+        for pkid in [10, 11] {
+            let p = publish(pkid, QoS::AtLeastOnce);
+            source.order.push_back((p.pkid, p.qos));
+            source.reply(1, p)?;
+            if pkid == 10 {
+                source.on_empty_event(1, 0).await?;
+            }
+        }
+        source.ack(1);
+        assert_eq!(
+            vec![
+                Packet::PubAck { pkid: 10, code: 0 },
+                Packet::PubAck { pkid: 11, code: 0 }
+            ],
+            source.acks
+        );
+        Ok(())
+    }
+}
@@ -13,9 +13,18 @@
 // limitations under the License.
 #![cfg(not(tarpaulin_include))]
 
+use crate::connectors::inflight::Inflight;
+use crate::connectors::jetstream::{self, AckInfo, ConsumerConfig, Delivery, StreamConfig};
 use crate::sink::nats::ConnectOptions;
 use crate::source::prelude::*;
-use async_nats::{Connection as NatsConnection, Subscription};
+use async_nats::{Connection as NatsConnection, Headers, Subscription};
+use async_std::future::timeout;
+use std::time::Duration;
+use tremor_common::time::nanotime;
+use tremor_value::literal;
+
+/// how long a pull request for a batch of `JetStream` messages stays open
+const PULL_EXPIRES: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct Config {
@@ -29,6 +38,18 @@ pub struct Config {
     // options to use when opening a new connection
     #[serde(default = "Default::default")]
     pub options: ConnectOptions,
+    // consume from a durable JetStream consumer instead of the plain subject,
+    // acknowledging messages once their events are acknowledged
+    #[serde(default = "Default::default")]
+    pub jetstream: Option<JetStreamConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct JetStreamConfig {
+    #[serde(flatten)]
+    stream: StreamConfig,
+    #[serde(flatten)]
+    consumer: ConsumerConfig,
 }
 
 impl ConfigImpl for Config {}
@@ -79,6 +100,14 @@ pub struct Int {
     subscription: Option<Subscription>,
     connection: Option<NatsConnection>,
     origin_uri: EventOriginUri,
+    /// `JetStream` ack subjects of unacknowledged messages
+    acks: Inflight<String>,
+    /// inbox pulled `JetStream` messages are delivered to
+    inbox: String,
+    /// messages still to be delivered for the current pull request
+    outstanding: usize,
+    /// when the current pull request expires
+    pull_expires_ns: u64,
 }
 
 impl std::fmt::Debug for Int {
@@ -104,36 +133,120 @@ impl Int {
             subscription: None,
             connection: None,
             origin_uri,
+            acks: Inflight::default(),
+            inbox: format!("_INBOX.tremor.{}.{}", uid, nanotime()),
+            outstanding: 0,
+            pull_expires_ns: 0,
+        }
+    }
+
+    /// sends an ack (`+ACK`) or nak (`-NAK`) for `JetStream` messages
+    fn respond(&self, subjects: Vec<String>, response: &'static str) {
+        if let (false, Some(connection)) = (subjects.is_empty(), self.connection.clone()) {
+            let onramp_id = self.onramp_id.clone();
+            task::spawn(async move {
+                for subject in subjects {
+                    if let Err(e) = connection.publish(&subject, response).await {
+                        error!(
+                            "[Source::{}] Failed to send {} to {}: {}",
+                            onramp_id, response, subject, e
+                        );
+                    }
+                }
+            });
+        }
+    }
+
+    async fn pull_jetstream(&mut self, id: u64) -> Result<SourceReply> {
+        // all events of earlier messages were created by now
+        let settled = self.acks.close(id);
+        self.respond(settled, "+ACK");
+        let (sub, nc, js) = match (&self.subscription, &self.connection, &self.config.jetstream) {
+            (Some(sub), Some(nc), Some(js)) => (sub, nc, js),
+            _ => return Ok(SourceReply::StateChange(SourceState::Disconnected)),
+        };
+        if let Delivery::Pull { batch } = js.consumer.delivery {
+            let now = nanotime();
+            if self.outstanding == 0 || now > self.pull_expires_ns {
+                jetstream::pull(
+                    nc,
+                    &js.stream.stream,
+                    &js.consumer.durable,
+                    &self.inbox,
+                    batch,
+                    PULL_EXPIRES,
+                )
+                .await?;
+                self.outstanding = batch;
+                self.pull_expires_ns =
+                    now + u64::try_from(PULL_EXPIRES.as_nanos()).unwrap_or(u64::MAX);
+            }
+        }
+        // don't wait forever, acks and fails need to be handled in between
+        let msg = match timeout(Duration::from_millis(100), sub.next()).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return Ok(SourceReply::StateChange(SourceState::Disconnected)),
+            Err(_) => return Ok(SourceReply::Empty(0)),
+        };
+        let info = msg.reply.as_deref().and_then(AckInfo::parse);
+        if let (Some(info), Some(reply)) = (info, msg.reply) {
+            self.outstanding = self.outstanding.saturating_sub(1);
+            self.acks.insert(id, reply);
+            let mut origin_uri = self.origin_uri.clone();
+            origin_uri.path = vec![msg.subject];
+            // the reply subject is the ack subject, it must not end up in `$nats.reply`
+            // where a nats offramp would publish responses to it
+            let mut meta = nats_meta(None, msg.headers)?;
+            meta.try_insert("jetstream", info.to_value());
+            Ok(SourceReply::Data {
+                origin_uri,
+                data: msg.data,
+                meta: Some(literal!({ "nats": meta })),
+                codec_override: None,
+                stream: 0,
+            })
+        } else {
+            // a status message without data, e.g. the pull request expired
+            self.outstanding = 0;
+            Ok(SourceReply::Empty(0))
+        }
+    }
+}
+
+/// `$nats` metadata of a received message
+fn nats_meta(reply: Option<String>, headers: Option<Headers>) -> Result<Value<'static>> {
+    let msg_headers = headers.map(|headers| {
+        let mut key_val: Value = Value::object_with_capacity(headers.len());
+        for (key, val) in headers.iter() {
+            let key = String::from(key);
+            let val: Vec<String> = val.iter().map(String::from).collect();
+            key_val.insert(key, val).ok();
         }
+        key_val
+    });
+    let mut meta_data = Value::object_with_capacity(3);
+    if let Some(msg_reply) = reply {
+        meta_data.insert("reply", msg_reply)?;
     }
+    if let Some(msg_headers) = msg_headers {
+        meta_data.insert("headers", msg_headers)?;
+    }
+    Ok(meta_data)
 }
 
 #[async_trait::async_trait]
 impl Source for Int {
-    async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        if self.config.jetstream.is_some() {
+            return self.pull_jetstream(id).await;
+        }
         if let Some(sub) = &self.subscription {
             if let Some(msg) = sub.next().await {
                 let mut origin_uri = self.origin_uri.clone();
                 origin_uri.path = vec![msg.subject];
                 let data = msg.data;
                 let mut nats_meta_data = Value::object_with_capacity(1);
-                let msg_headers = msg.headers.map(|headers| {
-                    let mut key_val: Value = Value::object_with_capacity(headers.len());
-                    for (key, val) in headers.iter() {
-                        let key = String::from(key);
-                        let val: Vec<String> = val.iter().map(String::from).collect();
-                        key_val.insert(key, val).ok();
-                    }
-                    key_val
-                });
-                let mut meta_data = Value::object_with_capacity(2);
-                if let Some(msg_reply) = msg.reply {
-                    meta_data.insert("reply", msg_reply)?;
-                }
-                if let Some(msg_headers) = msg_headers {
-                    meta_data.insert("headers", msg_headers)?;
-                }
-                nats_meta_data.insert("nats", meta_data)?;
+                nats_meta_data.insert("nats", nats_meta(msg.reply, msg.headers)?)?;
                 Ok(SourceReply::Data {
                     origin_uri,
                     data,
@@ -155,7 +268,24 @@ impl Source for Int {
 
     async fn init(&mut self) -> Result<SourceState> {
         let nc = self.config.connection().await?;
-        let sub = if let Some(queue) = &self.config.queue {
+        let sub = if let Some(js) = &self.config.jetstream {
+            let subject = self.config.subject.as_str();
+            jetstream::ensure_stream(&nc, &js.stream, subject).await?;
+            jetstream::ensure_consumer(&nc, &js.stream.stream, &js.consumer, subject).await?;
+            // unacknowledged messages of an old connection are redelivered after `ack_wait_s`
+            self.acks.clear();
+            self.outstanding = 0;
+            match &js.consumer.delivery {
+                Delivery::Pull { .. } => nc.subscribe(&self.inbox).await?,
+                Delivery::Push {
+                    deliver_subject,
+                    deliver_group: Some(group),
+                } => nc.queue_subscribe(deliver_subject, group).await?,
+                Delivery::Push {
+                    deliver_subject, ..
+                } => nc.subscribe(deliver_subject).await?,
+            }
+        } else if let Some(queue) = &self.config.queue {
             nc.queue_subscribe(self.config.subject.as_str(), queue.as_str())
                 .await?
         } else {
@@ -190,6 +320,32 @@ impl Source for Int {
         Ok(SourceState::Connected)
     }
 
+    fn is_transactional(&self) -> bool {
+        self.config.jetstream.is_some()
+    }
+
+    async fn on_empty_event(&mut self, id: u64, _stream: usize) -> Result<()> {
+        // no event will be acknowledged for the message, and `id` is reused for the next one
+        if let Some(subject) = self.acks.remove(id) {
+            self.respond(vec![subject], "+ACK");
+        }
+        Ok(())
+    }
+
+    // Acks all messages whose events are acknowledged, the source manager only
+    // passes the max id of a batch of acknowledged events.
+    fn ack(&mut self, id: u64) {
+        let settled = self.acks.ack(id);
+        self.respond(settled, "+ACK");
+    }
+
+    // Naks the message the failed event was created from, so it is redelivered.
+    fn fail(&mut self, id: u64) {
+        if let Some(subject) = self.acks.take(id) {
+            self.respond(vec![subject], "-NAK");
+        }
+    }
+
     async fn terminate(&mut self) {
         // we don't need to drain the subs here. closing the connection takes care of that.
         if let Some(connection) = &self.connection {
@@ -197,3 +353,64 @@ impl Source for Int {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tremor_pipeline::EventId;
+
+    #[test]
+    fn jetstream_config() -> Result<()> {
+        let config: Config = serde_yaml::from_str(
+            r#"
+hosts: ["localhost:4222"]
+subject: "orders.>"
+jetstream:
+  stream: ORDERS
+  durable: tremor
+  delivery:
+    pull:
+      batch: 10
+"#,
+        )?;
+        let js = config
+            .jetstream
+            .ok_or_else(|| Error::from("missing jetstream config"))?;
+        assert_eq!("ORDERS", js.stream.stream);
+        assert!(js.stream.create_stream);
+        assert_eq!("tremor", js.consumer.durable);
+        assert_eq!(Delivery::Pull { batch: 10 }, js.consumer.delivery);
+        assert_eq!(30, js.consumer.ack_wait_s);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn acks() -> Result<()> {
+        let mut int = Int::from_config(0, TremorUrl::parse("/onramp/nats/00")?, &Config::default());
+        int.acks.insert(1, "a".to_string());
+        // events 2, 3 and 4 are created from "b"
+        int.acks.insert(2, "b".to_string());
+        int.acks.insert(5, "c".to_string());
+        int.acks.insert(6, "d".to_string());
+        assert!(int.acks.close(7).is_empty());
+        // a batched insight of events 1 to 3, the source manager acks its max id
+        let mut batch = EventId::from((0, 0, 1));
+        batch.track_id(0, 0, 2);
+        batch.track_id(0, 0, 3);
+        let (_, max) = batch.get_max_by_source(0).ok_or("no id")?;
+        int.ack(max);
+        // "a" is settled, "b" waits for event 4
+        assert_eq!(None, int.acks.take(1));
+        // event 4 fails, "b" is redelivered and not acked later on
+        int.fail(4);
+        int.ack(5);
+        assert_eq!(None, int.acks.take(2));
+        assert_eq!(None, int.acks.take(5));
+        assert_eq!(Some("d".to_string()), int.acks.take(6));
+        // the message of event 7 produced no event, it is acked right away
+        int.acks.insert(7, "e".to_string());
+        int.on_empty_event(7, 0).await?;
+        assert!(int.acks.is_empty());
+        Ok(())
+    }
+}
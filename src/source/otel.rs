@@ -13,10 +13,32 @@
 // limitations under the License.
 #![cfg(not(tarpaulin_include))]
 
+use crate::connectors::otel::http::{self as otlp_http, Encoding, Protocol, Signal};
 use crate::connectors::otel::{logs, metrics, trace};
 
 use crate::source::prelude::*;
-use tremor_otelapis::all::{self, OpenTelemetryEvents};
+use crate::source::rest::listen_tls;
+use crate::source::tcp::{load_server_config, TLSConfig};
+use async_std::io::ReadExt;
+use http_types::headers::CONTENT_ENCODING;
+use std::net::SocketAddr;
+use tide::{Request, Response};
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tremor_otelapis::all::{OpenTelemetryEvents, OpenTelemetrySender};
+use tremor_otelapis::opentelemetry::proto::collector::{
+    logs::v1::{
+        logs_service_server::{LogsService, LogsServiceServer},
+        ExportLogsServiceRequest, ExportLogsServiceResponse,
+    },
+    metrics::v1::{
+        metrics_service_server::{MetricsService, MetricsServiceServer},
+        ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+    },
+    trace::v1::{
+        trace_service_server::{TraceService, TraceServiceServer},
+        ExportTraceServiceRequest, ExportTraceServiceResponse,
+    },
+};
 use tremor_script::Value;
 
 #[derive(Debug, Clone, Deserialize, Default)]
@@ -33,6 +55,13 @@ pub struct Config {
     /// Enables the metrics service
     #[serde(default = "d_true")]
     pub metrics: bool,
+    /// `grpc` (default) or `http` to accept OTLP/HTTP on `/v1/traces`, `/v1/metrics`
+    /// and `/v1/logs`, protobuf and JSON encoded
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// TLS configuration, for both `grpc` and `http`
+    #[serde(default)]
+    pub tls: Option<TLSConfig>,
 }
 
 fn d_true() -> bool {
@@ -82,6 +111,180 @@ impl Int {
     }
 }
 
+/// Handles an OTLP/HTTP export request
+async fn export(mut req: Request<OpenTelemetrySender>, signal: Signal) -> tide::Result {
+    let encoding = match req
+        .content_type()
+        .and_then(|mime| Encoding::from_content_type(mime.essence()))
+    {
+        Some(encoding) => encoding,
+        None => return Ok(Response::new(415)),
+    };
+    let too_large = || {
+        Response::builder(413)
+            .body(format!(
+                "Request body exceeds {} bytes",
+                otlp_http::MAX_BODY_LEN
+            ))
+            .build()
+    };
+    if req.len().map_or(false, |len| len > otlp_http::MAX_BODY_LEN) {
+        return Ok(too_large());
+    }
+    // reading one byte more than allowed tells us if there is more
+    let limit = u64::try_from(otlp_http::MAX_BODY_LEN)
+        .unwrap_or(u64::MAX)
+        .saturating_add(1);
+    let mut body = Vec::new();
+    req.take_body().take(limit).read_to_end(&mut body).await?;
+    if body.len() > otlp_http::MAX_BODY_LEN {
+        return Ok(too_large());
+    }
+    let decoded = if req.header(CONTENT_ENCODING).map(|h| h.as_str()) == Some("gzip") {
+        match otlp_http::gunzip(&body, otlp_http::MAX_BODY_LEN) {
+            Ok(Some(mut body)) => otlp_http::decode(signal, encoding, &mut body),
+            Ok(None) => return Ok(too_large()),
+            Err(e) => Err(e),
+        }
+    } else {
+        otlp_http::decode(signal, encoding, &mut body)
+    };
+    match decoded {
+        Ok(event) => {
+            req.state().send(event).await?;
+            Ok(Response::builder(200)
+                .content_type(encoding.content_type())
+                .body(encoding.empty_response())
+                .build())
+        }
+        Err(e) => Ok(Response::builder(400).body(e.to_string()).build()),
+    }
+}
+
+/// Forwards OTLP/gRPC export requests to the source
+#[derive(Clone)]
+struct Forwarder(OpenTelemetrySender);
+
+impl Forwarder {
+    async fn forward<T: Default>(
+        &self,
+        event: OpenTelemetryEvents,
+    ) -> std::result::Result<tonic::Response<T>, tonic::Status> {
+        self.0
+            .send(event)
+            .await
+            .map_err(|e| tonic::Status::unavailable(e.to_string()))?;
+        Ok(tonic::Response::new(T::default()))
+    }
+}
+
+#[async_trait::async_trait]
+impl LogsService for Forwarder {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportLogsServiceRequest>,
+    ) -> std::result::Result<tonic::Response<ExportLogsServiceResponse>, tonic::Status> {
+        self.forward(OpenTelemetryEvents::Logs(request.into_inner()))
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl MetricsService for Forwarder {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportMetricsServiceRequest>,
+    ) -> std::result::Result<tonic::Response<ExportMetricsServiceResponse>, tonic::Status> {
+        self.forward(OpenTelemetryEvents::Metrics(request.into_inner()))
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl TraceService for Forwarder {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportTraceServiceRequest>,
+    ) -> std::result::Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+        self.forward(OpenTelemetryEvents::Trace(request.into_inner()))
+            .await
+    }
+}
+
+/// Loads the server identity and, for mutual TLS, the client CA for the gRPC server
+fn grpc_tls_config(tls: &TLSConfig) -> Result<ServerTlsConfig> {
+    let cert = std::fs::read(&tls.cert)?;
+    let key = std::fs::read(&tls.key)?;
+    let mut config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+    if let Some(client_ca) = &tls.client_ca {
+        config = config.client_ca_root(Certificate::from_pem(std::fs::read(client_ca)?));
+    }
+    Ok(config)
+}
+
+/// Serves the OTLP/gRPC logs, metrics and trace services at `addr`
+async fn serve_grpc(
+    addr: SocketAddr,
+    tx: OpenTelemetrySender,
+    tls_config: Option<ServerTlsConfig>,
+) -> Result<()> {
+    let mut server = Server::builder();
+    if let Some(tls_config) = tls_config {
+        server = server.tls_config(tls_config)?;
+    }
+    let forwarder = Forwarder(tx);
+    server
+        .add_service(LogsServiceServer::new(forwarder.clone()))
+        .add_service(MetricsServiceServer::new(forwarder.clone()))
+        .add_service(TraceServiceServer::new(forwarder))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+impl Int {
+    fn listen_http(&self) -> Result<()> {
+        let mut server = tide::with_state(self.tx.clone());
+        for (enabled, signal) in [
+            (self.config.logs, Signal::Logs),
+            (self.config.metrics, Signal::Metrics),
+            (self.config.trace, Signal::Trace),
+        ] {
+            if enabled {
+                server
+                    .at(signal.path())
+                    .post(move |req| export(req, signal));
+            }
+        }
+        let addr = format!("{}:{}", self.config.host, self.config.port);
+        let onramp_id = self.onramp_id.clone();
+        let tls_config = self
+            .config
+            .tls
+            .as_ref()
+            .map(load_server_config)
+            .transpose()?;
+        task::spawn(async move {
+            info!(
+                "[Source::{}] Listening for OTLP/HTTP at {}",
+                onramp_id, addr
+            );
+            let res = if let Some(tls_config) = tls_config {
+                listen_tls(server, &addr, tls_config).await
+            } else {
+                server.listen(addr).await.map_err(Error::from)
+            };
+            if let Err(e) = res {
+                error!(
+                    "[Source::{}] Could not start OTLP/HTTP service: {}",
+                    onramp_id, e
+                );
+            }
+        });
+        Ok(())
+    }
+}
+
 impl onramp::Impl for OpenTelemetry {
     fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
         if let Some(config) = config {
@@ -143,17 +346,20 @@ impl Source for Int {
     }
 
     async fn init(&mut self) -> Result<SourceState> {
-        let addr = format!("{}:{}", self.config.host.as_str(), self.config.port).parse()?;
-        let tx = self.tx.clone();
-        task::spawn(async move {
-            // Builder for gRPC server over HTTP/2 framing
-            match all::make(addr, tx).await {
-                Ok(_) => (),
-                Err(e) => {
-                    error!("Could not start gRPC service: {}", e);
-                }
+        match self.config.protocol {
+            Protocol::Grpc => {
+                let addr = format!("{}:{}", self.config.host.as_str(), self.config.port).parse()?;
+                let tx = self.tx.clone();
+                let tls_config = self.config.tls.as_ref().map(grpc_tls_config).transpose()?;
+                task::spawn(async move {
+                    // Builder for gRPC server over HTTP/2 framing
+                    if let Err(e) = serve_grpc(addr, tx, tls_config).await {
+                        error!("Could not start gRPC service: {}", e);
+                    }
+                });
             }
-        });
+            Protocol::Http => self.listen_http()?,
+        }
         Ok(SourceState::Connected)
     }
 
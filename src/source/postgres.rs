@@ -15,8 +15,13 @@
 
 //! # Postgres Onramp
 //!
+//! Either runs `query` every `interval_ms` or, with `cdc` set, streams changes through
+//! logical decoding (see [cdc](cdc/index.html)).
+//!
 //! See [Config](struct.Config.html) for details.
 
+mod cdc;
+
 use crate::errors::Result;
 use crate::ramp;
 use crate::ramp::postgres::row_to_json;
@@ -37,14 +42,29 @@ pub struct Config {
     pub user: String,
     pub password: String,
     pub dbname: String,
+    #[serde(flatten)]
+    pub mode: Mode,
+}
+
+impl ConfigImpl for Config {}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Mode {
+    /// Stream changes through logical decoding
+    Cdc { cdc: cdc::Config },
+    /// Poll `query` with the time range to consume
+    Poll(PollConfig),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PollConfig {
     pub query: String,
     pub interval_ms: u32,
     pub consume_from: String,
     pub cache: CacheConfig,
 }
 
-impl ConfigImpl for Config {}
-
 pub struct Postgres {
     onramp_id: TremorUrl,
     pub config: Config,
@@ -52,6 +72,7 @@ pub struct Postgres {
 
 pub struct Int {
     pub config: Config,
+    poll: PollConfig,
     // onramp_uid: u64,
     onramp_id: TremorUrl,
     origin_uri: EventOriginUri,
@@ -81,8 +102,27 @@ impl onramp::Impl for Postgres {
     }
 }
 
+async fn connect(config: &Config) -> Result<Client> {
+    let conn_str = format!(
+        "host={} user={} password={} port={} dbname={}",
+        config.host, config.user, config.password, config.port, config.dbname
+    );
+    let (client, connection) = Compat::new(tokio_postgres::connect(&conn_str, NoTls)).await?;
+    task::spawn(async move {
+        if let Err(e) = Compat::new(connection).await {
+            error!("connection error: {}", e);
+        }
+    });
+    Ok(client)
+}
+
 impl Int {
-    async fn from_config(uid: u64, onramp_id: TremorUrl, config: &Config) -> Result<Self> {
+    async fn from_config(
+        uid: u64,
+        onramp_id: TremorUrl,
+        config: &Config,
+        poll: &PollConfig,
+    ) -> Result<Self> {
         let origin_uri = EventOriginUri {
             uid,
             scheme: "tremor-file".to_string(),
@@ -90,7 +130,7 @@ impl Int {
             port: None,
             path: vec![config.host.clone()],
         };
-        let consume_from = DateTime::parse_from_str(&config.consume_from, TIME_FMT)?;
+        let consume_from = DateTime::parse_from_str(&poll.consume_from, TIME_FMT)?;
         let consume_from = consume_from.format(TIME_FMT).to_string();
 
         let consume_until: DateTime<Utc> = chrono::offset::Utc::now();
@@ -101,7 +141,7 @@ impl Int {
         obj.try_insert("consume_from", consume_from);
         obj.try_insert("consume_until", consume_until);
 
-        let cache = match ramp::lookup("mmap_file", Some(config.cache.clone()), &obj) {
+        let cache = match ramp::lookup("mmap_file", Some(poll.cache.clone()), &obj) {
             Ok(v) => v,
             Err(e) => return Err(e),
         };
@@ -110,6 +150,7 @@ impl Int {
             // onramp_uid,
             onramp_id,
             config: config.clone(),
+            poll: poll.clone(),
             origin_uri,
             cache,
             cli: None,
@@ -119,21 +160,7 @@ impl Int {
     }
 
     async fn init_cli(&mut self) -> Result<()> {
-        let conn_str = format!(
-            "host={} user={} password={} port={} dbname={}",
-            self.config.host,
-            self.config.user,
-            self.config.password,
-            self.config.port,
-            self.config.dbname
-        );
-        let (client, connection) = Compat::new(tokio_postgres::connect(&conn_str, NoTls)).await?;
-        task::spawn(async move {
-            if let Err(e) = Compat::new(connection).await {
-                error!("connection error: {}", e);
-            }
-        });
-        self.cli = Some(client);
+        self.cli = Some(connect(&self.config).await?);
         Ok(())
     }
 }
@@ -158,7 +185,7 @@ impl Source for Int {
             .ok_or_else(|| Error::from("No CLI connection"))?;
 
         if self.stmt.is_none() {
-            let q = &self.config.query;
+            let q = &self.poll.query;
             self.stmt = Some(Compat::new(client.prepare(q)).await?);
         };
 
@@ -195,7 +222,7 @@ impl Source for Int {
 
         // prepare interval for the next query
         let cf = DateTime::parse_from_str(&consume_until.to_string(), TIME_FMT)?;
-        let ct = cf + chrono::Duration::milliseconds(i64::from(self.config.interval_ms));
+        let ct = cf + chrono::Duration::milliseconds(i64::from(self.poll.interval_ms));
         let cf = cf.format(TIME_FMT);
         let ct = ct.format(TIME_FMT);
         obj.insert("consume_from", cf.to_string())?;
@@ -225,9 +252,27 @@ impl Source for Int {
 #[async_trait::async_trait]
 impl Onramp for Postgres {
     async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
-        let source =
-            Int::from_config(config.onramp_uid, self.onramp_id.clone(), &self.config).await?;
-        SourceManager::start(source, config).await
+        match &self.config.mode {
+            Mode::Cdc { cdc } => {
+                let source = cdc::Int::from_config(
+                    config.onramp_uid,
+                    self.onramp_id.clone(),
+                    &self.config,
+                    cdc,
+                );
+                SourceManager::start(source, config).await
+            }
+            Mode::Poll(poll) => {
+                let source = Int::from_config(
+                    config.onramp_uid,
+                    self.onramp_id.clone(),
+                    &self.config,
+                    poll,
+                )
+                .await?;
+                SourceManager::start(source, config).await
+            }
+        }
     }
 
     fn default_codec(&self) -> &str {
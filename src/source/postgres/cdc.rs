@@ -0,0 +1,852 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Change data capture through logical decoding
+//!
+//! Changes are read from a logical replication slot with `pgoutput` (requires a
+//! publication, e.g. `CREATE PUBLICATION tremor FOR ALL TABLES`) or `wal2json`, one event
+//! per inserted, updated, deleted or truncated row:
+//!
+//! ```json
+//! {"op": "update", "before": {"id": 1, "name": "snot"}, "after": {"id": 1, "name": "badger"}}
+//! ```
+//!
+//! `$postgres` holds the `schema`, `table`, `xid` and commit `lsn`. `before` holds the replica
+//! identity of the row, so only its key unless the table has `REPLICA IDENTITY FULL`.
+//!
+//! The slot is advanced past a transaction once the events of all its changes are
+//! acknowledged, failed events are emitted again with all changes after them.
+
+use super::connect;
+use crate::source::prelude::*;
+use async_compat::Compat;
+use halfbrown::HashMap;
+use std::collections::{BTreeMap, VecDeque};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Client;
+use tremor_common::time::nanotime;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Plugin {
+    Pgoutput,
+    Wal2json,
+}
+
+impl Plugin {
+    fn name(self) -> &'static str {
+        match self {
+            Plugin::Pgoutput => "pgoutput",
+            Plugin::Wal2json => "wal2json",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// Logical replication slot to read changes from
+    pub slot: String,
+    /// Output plugin of the slot, `pgoutput` (default) or `wal2json`
+    #[serde(default = "dflt_plugin")]
+    pub plugin: Plugin,
+    /// Publication to stream with `pgoutput`, defaults to `tremor`
+    #[serde(default = "dflt_publication")]
+    pub publication: String,
+    /// Create the slot if it doesn't exist, defaults to `true`
+    #[serde(default = "dflt_create_slot")]
+    pub create_slot: bool,
+    /// Look for new changes this often, defaults to 1000
+    #[serde(default = "dflt_interval_ms")]
+    pub interval_ms: u64,
+    /// Read at most about this many changes at once, defaults to 1000
+    #[serde(default = "dflt_max_changes")]
+    pub max_changes: i32,
+}
+
+fn dflt_plugin() -> Plugin {
+    Plugin::Pgoutput
+}
+
+fn dflt_publication() -> String {
+    "tremor".to_string()
+}
+
+fn dflt_create_slot() -> bool {
+    true
+}
+
+fn dflt_interval_ms() -> u64 {
+    1000
+}
+
+fn dflt_max_changes() -> i32 {
+    1000
+}
+
+fn parse_lsn(lsn: &str) -> Result<u64> {
+    let (hi, lo) = lsn
+        .split_once('/')
+        .ok_or_else(|| Error::from(format!("Invalid LSN {}", lsn)))?;
+    Ok(u64::from_str_radix(hi, 16)? << 32 | u64::from_str_radix(lo, 16)?)
+}
+
+fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
+/// A changed row
+#[derive(Debug, Clone, PartialEq)]
+struct Change {
+    op: &'static str,
+    schema: String,
+    table: String,
+    before: Value<'static>,
+    after: Value<'static>,
+}
+
+/// A committed transaction
+#[derive(Debug, Clone, PartialEq)]
+struct Txn {
+    xid: u64,
+    /// LSN of the commit
+    lsn: u64,
+    changes: Vec<Change>,
+}
+
+/// Groups decoded rows into transactions
+#[derive(Default)]
+struct Txns {
+    current: Vec<Change>,
+    done: Vec<Txn>,
+}
+
+impl Txns {
+    fn push(
+        &mut self,
+        op: &'static str,
+        relation: &Relation,
+        before: Value<'static>,
+        after: Value<'static>,
+    ) {
+        self.current.push(Change {
+            op,
+            schema: relation.schema.clone(),
+            table: relation.table.clone(),
+            before,
+            after,
+        });
+    }
+
+    fn commit(&mut self, xid: u64, lsn: u64) {
+        self.done.push(Txn {
+            xid,
+            lsn,
+            changes: std::mem::take(&mut self.current),
+        });
+    }
+}
+
+// pgoutput
+
+#[derive(Debug, Clone, PartialEq)]
+struct Column {
+    name: String,
+    oid: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Relation {
+    schema: String,
+    table: String,
+    columns: Vec<Column>,
+}
+
+struct Reader<'data> {
+    data: &'data [u8],
+}
+
+impl<'data> Reader<'data> {
+    fn bytes(&mut self, n: usize) -> Result<&'data [u8]> {
+        if self.data.len() < n {
+            return Err("Truncated pgoutput message".into());
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_be_bytes(b))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_be_bytes(b))
+    }
+
+    fn cstr(&mut self) -> Result<String> {
+        let end = self
+            .data
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or("Unterminated string in pgoutput message")?;
+        let s = String::from_utf8(self.bytes(end)?.to_vec())?;
+        self.bytes(1)?;
+        Ok(s)
+    }
+}
+
+/// Converts a column in text format to a value of its type
+fn text_value(oid: u32, text: String) -> Value<'static> {
+    match oid {
+        // bool
+        16 => Value::from(text == "t"),
+        // int8, int2, int4, oid
+        20 | 21 | 23 | 26 => text
+            .parse::<i64>()
+            .map_or_else(|_| Value::from(text), Value::from),
+        // float4, float8
+        700 | 701 => text
+            .parse::<f64>()
+            .map_or_else(|_| Value::from(text), Value::from),
+        // json, jsonb
+        114 | 3802 => {
+            // parsing happens in place
+            let mut bytes = text.clone().into_bytes();
+            tremor_value::parse_to_value(&mut bytes)
+                .map_or_else(|_| Value::from(text), Value::into_static)
+        }
+        _ => Value::from(text),
+    }
+}
+
+/// Reads `TupleData`, unchanged TOASTed columns are left out
+fn tuple(r: &mut Reader<'_>, relation: &Relation) -> Result<Value<'static>> {
+    let n = r.u16()?;
+    let mut row = Value::object_with_capacity(n as usize);
+    for i in 0..n as usize {
+        let column = relation
+            .columns
+            .get(i)
+            .ok_or("More columns than in the relation")?;
+        match r.u8()? {
+            b'n' => {
+                row.try_insert(column.name.clone(), Value::null());
+            }
+            b't' => {
+                let len = r.u32()? as usize;
+                let text = String::from_utf8(r.bytes(len)?.to_vec())?;
+                row.try_insert(column.name.clone(), text_value(column.oid, text));
+            }
+            // unchanged TOASTed value
+            b'u' => (),
+            kind => return Err(format!("Unknown pgoutput column kind {}", kind).into()),
+        }
+    }
+    Ok(row)
+}
+
+/// Decodes a `pgoutput` message (protocol version 1)
+fn pgoutput(
+    relations: &mut HashMap<u32, Relation>,
+    txns: &mut Txns,
+    xid: u64,
+    lsn: u64,
+    data: &[u8],
+) -> Result<()> {
+    let mut r = Reader { data };
+    let kind = r.u8()?;
+    match kind {
+        b'R' => {
+            let id = r.u32()?;
+            let schema = r.cstr()?;
+            let table = r.cstr()?;
+            // replica identity
+            r.u8()?;
+            let n = r.u16()?;
+            let mut columns = Vec::with_capacity(n as usize);
+            for _ in 0..n {
+                // flags
+                r.u8()?;
+                let name = r.cstr()?;
+                let oid = r.u32()?;
+                // type modifier
+                r.u32()?;
+                columns.push(Column { name, oid });
+            }
+            relations.insert(
+                id,
+                Relation {
+                    schema,
+                    table,
+                    columns,
+                },
+            );
+        }
+        b'I' | b'U' | b'D' => {
+            let id = r.u32()?;
+            let relation = relations
+                .get(&id)
+                .ok_or_else(|| Error::from(format!("Unknown relation {}", id)))?;
+            let mut before = Value::null();
+            let mut after = Value::null();
+            loop {
+                match r.u8()? {
+                    b'K' | b'O' => before = tuple(&mut r, relation)?,
+                    b'N' => {
+                        after = tuple(&mut r, relation)?;
+                        break;
+                    }
+                    other => return Err(format!("Unexpected pgoutput tuple {}", other).into()),
+                }
+                if kind == b'D' {
+                    break;
+                }
+            }
+            let op = match kind {
+                b'I' => "insert",
+                b'U' => "update",
+                _ => "delete",
+            };
+            txns.push(op, relation, before, after);
+        }
+        b'T' => {
+            let n = r.u32()?;
+            // options
+            r.u8()?;
+            for _ in 0..n {
+                let id = r.u32()?;
+                if let Some(relation) = relations.get(&id) {
+                    txns.push("truncate", relation, Value::null(), Value::null());
+                }
+            }
+        }
+        b'C' => txns.commit(xid, lsn),
+        // begin, origin, type and message
+        _ => (),
+    }
+    Ok(())
+}
+
+// wal2json
+
+/// Turns `wal2json` columns into a row
+fn columns(columns: Option<&Value>) -> Value<'static> {
+    columns
+        .and_then(Value::as_array)
+        .map_or_else(Value::null, |columns| {
+            let mut row = Value::object_with_capacity(columns.len());
+            for c in columns {
+                if let Some(name) = c.get_str("name") {
+                    let value = c
+                        .get("value")
+                        .map_or_else(Value::null, |v| v.clone_static());
+                    row.try_insert(name.to_string(), value);
+                }
+            }
+            row
+        })
+}
+
+/// Decodes a `wal2json` message (format version 2)
+fn wal2json(txns: &mut Txns, xid: u64, lsn: u64, data: String) -> Result<()> {
+    let mut data = data.into_bytes();
+    let msg = tremor_value::parse_to_value(&mut data)?;
+    let (before, after) = match msg.get_str("action") {
+        Some("I") => (Value::null(), columns(msg.get("columns"))),
+        Some("U") => (columns(msg.get("identity")), columns(msg.get("columns"))),
+        Some("D") => (columns(msg.get("identity")), Value::null()),
+        Some("T") => (Value::null(), Value::null()),
+        Some("C") => {
+            txns.commit(xid, lsn);
+            return Ok(());
+        }
+        // begin and message
+        _ => return Ok(()),
+    };
+    let op = match msg.get_str("action") {
+        Some("I") => "insert",
+        Some("U") => "update",
+        Some("D") => "delete",
+        _ => "truncate",
+    };
+    txns.current.push(Change {
+        op,
+        schema: msg.get_str("schema").unwrap_or_default().to_string(),
+        table: msg.get_str("table").unwrap_or_default().to_string(),
+        before,
+        after,
+    });
+    Ok(())
+}
+
+pub struct Int {
+    onramp_id: TremorUrl,
+    config: super::Config,
+    cdc: Config,
+    origin_uri: EventOriginUri,
+    cli: Option<Client>,
+    relations: HashMap<u32, Relation>,
+    /// changes to emit, with the LSN to advance to once they are acknowledged
+    pending: VecDeque<(Txn, Change, Option<u64>)>,
+    /// LSNs to advance to by the ids of the events of changes
+    inflight: BTreeMap<u64, Option<u64>>,
+    /// LSN of the last transaction read
+    read: u64,
+    /// LSN the slot was advanced to
+    confirmed: u64,
+    /// acknowledged LSN to advance the slot to from `pull_event`, acks must not block
+    advance_to: Option<u64>,
+    next_poll: u64,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PostgresCdc")
+    }
+}
+
+impl Int {
+    pub(super) fn from_config(
+        uid: u64,
+        onramp_id: TremorUrl,
+        config: &super::Config,
+        cdc: &Config,
+    ) -> Self {
+        let origin_uri = EventOriginUri {
+            uid,
+            scheme: "tremor-postgres".to_string(),
+            host: config.host.clone(),
+            port: u16::try_from(config.port).ok(),
+            path: vec![config.dbname.clone(), cdc.slot.clone()],
+        };
+        Self {
+            onramp_id,
+            config: config.clone(),
+            cdc: cdc.clone(),
+            origin_uri,
+            cli: None,
+            relations: HashMap::new(),
+            pending: VecDeque::new(),
+            inflight: BTreeMap::new(),
+            read: 0,
+            confirmed: 0,
+            advance_to: None,
+            next_poll: 0,
+        }
+    }
+
+    /// Reads the changes after the ones we already read
+    async fn read_changes(&mut self) -> Result<Vec<Txn>> {
+        if self.cli.is_none() {
+            self.cli = Some(connect(&self.config).await?);
+        }
+        let client = self.cli.as_ref().ok_or("Not connected")?;
+        let mut txns = Txns::default();
+        match self.cdc.plugin {
+            Plugin::Pgoutput => {
+                let rows = Compat::new(client.query(
+                    "SELECT lsn::text, xid::text, data FROM pg_logical_slot_peek_binary_changes($1, NULL, $2, 'proto_version', '1', 'publication_names', $3)",
+                    &[&self.cdc.slot, &self.cdc.max_changes, &self.cdc.publication],
+                ))
+                .await?;
+                for row in rows {
+                    let lsn = parse_lsn(row.try_get(0)?)?;
+                    let xid = row.try_get::<_, &str>(1)?.parse()?;
+                    let data: Vec<u8> = row.try_get(2)?;
+                    pgoutput(&mut self.relations, &mut txns, xid, lsn, &data)?;
+                }
+            }
+            Plugin::Wal2json => {
+                let rows = Compat::new(client.query(
+                    "SELECT lsn::text, xid::text, data FROM pg_logical_slot_peek_changes($1, NULL, $2, 'format-version', '2')",
+                    &[&self.cdc.slot, &self.cdc.max_changes],
+                ))
+                .await?;
+                for row in rows {
+                    let lsn = parse_lsn(row.try_get(0)?)?;
+                    let xid = row.try_get::<_, &str>(1)?.parse()?;
+                    wal2json(&mut txns, xid, lsn, row.try_get(2)?)?;
+                }
+            }
+        }
+        // the slot only moves once events are acknowledged, skip what we read before
+        let read = self.read;
+        Ok(txns.done.into_iter().filter(|t| t.lsn > read).collect())
+    }
+
+    /// Queues the changes of transactions, the last change of each advances the slot
+    fn queue(&mut self, txns: Vec<Txn>) -> Option<u64> {
+        let mut advance = None;
+        for mut txn in txns {
+            self.read = self.read.max(txn.lsn);
+            let changes = std::mem::take(&mut txn.changes);
+            let n = changes.len();
+            if n == 0 {
+                // nothing to wait for but what is already on its way
+                if let Some((_, _, lsn)) = self.pending.back_mut() {
+                    *lsn = Some(txn.lsn);
+                } else if let Some((_, lsn)) = self.inflight.iter_mut().next_back() {
+                    *lsn = Some(txn.lsn);
+                } else {
+                    advance = Some(txn.lsn);
+                }
+            }
+            for (i, change) in changes.into_iter().enumerate() {
+                let lsn = if i + 1 == n { Some(txn.lsn) } else { None };
+                self.pending.push_back((txn.clone(), change, lsn));
+            }
+        }
+        advance
+    }
+
+    fn event(&mut self, id: u64, txn: &Txn, change: Change, lsn: Option<u64>) -> SourceReply {
+        self.inflight.insert(id, lsn);
+        let data = literal!({
+            "op": change.op,
+            "before": change.before,
+            "after": change.after,
+        });
+        let meta = literal!({
+            "postgres": {
+                "schema": change.schema,
+                "table": change.table,
+                "xid": txn.xid,
+                "lsn": format_lsn(txn.lsn),
+            }
+        });
+        SourceReply::Structured {
+            origin_uri: self.origin_uri.clone(),
+            data: (data, meta).into(),
+        }
+    }
+
+    /// The LSN to advance to once all events up to `id` are acknowledged
+    fn acknowledge(&mut self, id: u64) -> Option<u64> {
+        let later = self.inflight.split_off(&(id + 1));
+        let done = std::mem::replace(&mut self.inflight, later);
+        done.into_values().flatten().max()
+    }
+
+    /// Records an LSN to advance the slot to
+    fn record(&mut self, lsn: u64) {
+        self.advance_to = self.advance_to.max(Some(lsn));
+    }
+
+    /// Advances the slot to the recorded LSN, it is kept to try again if that fails
+    async fn advance(&mut self) {
+        let lsn = match self.advance_to.take() {
+            Some(lsn) if lsn > self.confirmed => lsn,
+            _ => return,
+        };
+        let target = format_lsn(lsn);
+        let res = match self.cli.as_ref() {
+            Some(client) => Compat::new(client.execute(
+                "SELECT 1 FROM pg_replication_slot_advance($1, $2::text::pg_lsn)",
+                &[&self.cdc.slot, &target],
+            ))
+            .await
+            .map_err(Error::from),
+            None => Err("Not connected".into()),
+        };
+        match res {
+            Ok(_) => self.confirmed = lsn,
+            Err(e) => {
+                error!(
+                    "[Source::{}] Failed to advance slot to {}: {}",
+                    self.onramp_id, target, e
+                );
+                self.record(lsn);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        self.advance().await;
+        if let Some((txn, change, lsn)) = self.pending.pop_front() {
+            return Ok(self.event(id, &txn, change, lsn));
+        }
+        let now = nanotime();
+        if now < self.next_poll {
+            return Ok(SourceReply::Empty(
+                ((self.next_poll - now) / 1_000_000).min(100),
+            ));
+        }
+        self.next_poll = now + self.cdc.interval_ms * 1_000_000;
+        match self.read_changes().await {
+            Ok(txns) => {
+                if let Some(lsn) = self.queue(txns) {
+                    self.record(lsn);
+                    self.advance().await;
+                }
+            }
+            Err(e) => {
+                error!("[Source::{}] Failed to read changes: {}", self.onramp_id, e);
+                self.cli = None;
+            }
+        }
+        Ok(SourceReply::Empty(0))
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let client = connect(&self.config).await?;
+        if self.cdc.create_slot {
+            let created = Compat::new(client.execute(
+                "SELECT 1 FROM pg_create_logical_replication_slot($1, $2)",
+                &[&self.cdc.slot, &self.cdc.plugin.name()],
+            ))
+            .await;
+            match created {
+                Err(e) if e.code() == Some(&SqlState::DUPLICATE_OBJECT) => (),
+                other => {
+                    other?;
+                }
+            }
+        }
+        self.cli = Some(client);
+        Ok(SourceState::Connected)
+    }
+
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    fn ack(&mut self, id: u64) {
+        // acks cover all events up to `id`
+        if let Some(lsn) = self.acknowledge(id) {
+            self.record(lsn);
+        }
+    }
+
+    fn fail(&mut self, _id: u64) {
+        // read everything that isn't acknowledged again
+        self.pending.clear();
+        self.inflight.clear();
+        self.read = self.advance_to.unwrap_or_default().max(self.confirmed);
+        self.next_poll = 0;
+    }
+
+    fn is_transactional(&self) -> bool {
+        true
+    }
+
+    async fn terminate(&mut self) {
+        self.advance().await;
+        self.cli = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn int() -> Result<Int> {
+        let config: super::super::Config = serde_yaml::from_str(
+            "{host: localhost, port: 5432, user: postgres, password: secret, dbname: snot, cdc: {slot: tremor}}",
+        )?;
+        let cdc = match &config.mode {
+            super::super::Mode::Cdc { cdc } => cdc.clone(),
+            super::super::Mode::Poll(_) => return Err("Expected cdc mode".into()),
+        };
+        Ok(Int::from_config(
+            0,
+            TremorUrl::parse("/onramp/postgres/01/out")?,
+            &config,
+            &cdc,
+        ))
+    }
+
+    #[test]
+    fn lsn() -> Result<()> {
+        assert_eq!(0x16_B374_D848, parse_lsn("16/B374D848")?);
+        assert_eq!("16/B374D848", format_lsn(0x16_B374_D848));
+        assert!(parse_lsn("16B374D848").is_err());
+        Ok(())
+    }
+
+    fn cstr(buf: &mut Vec<u8>, s: &str) {
+        buf.extend_from_slice(s.as_bytes());
+        buf.push(0);
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn text(buf: &mut Vec<u8>, s: &str) {
+        buf.push(b't');
+        buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+        buf.extend_from_slice(s.as_bytes());
+    }
+
+    #[test]
+    fn decode_pgoutput() -> Result<()> {
+        let mut relations = HashMap::new();
+        let mut txns = Txns::default();
+
+        let mut rel = vec![b'R'];
+        rel.extend_from_slice(&42_u32.to_be_bytes());
+        cstr(&mut rel, "public");
+        cstr(&mut rel, "badgers");
+        rel.push(b'd');
+        rel.extend_from_slice(&3_u16.to_be_bytes());
+        for (name, oid) in &[("id", 23_u32), ("name", 25), ("tags", 3802)] {
+            rel.push(1);
+            cstr(&mut rel, name);
+            rel.extend_from_slice(&oid.to_be_bytes());
+            rel.extend_from_slice(&(-1_i32).to_be_bytes());
+        }
+        pgoutput(&mut relations, &mut txns, 7, 1, &rel)?;
+
+        let mut update = vec![b'U'];
+        update.extend_from_slice(&42_u32.to_be_bytes());
+        update.push(b'K');
+        update.extend_from_slice(&3_u16.to_be_bytes());
+        text(&mut update, "1");
+        update.push(b'n');
+        update.push(b'n');
+        update.push(b'N');
+        update.extend_from_slice(&3_u16.to_be_bytes());
+        text(&mut update, "1");
+        text(&mut update, "snot");
+        update.push(b'u');
+        pgoutput(&mut relations, &mut txns, 7, 2, &update)?;
+
+        let mut delete = vec![b'D'];
+        delete.extend_from_slice(&42_u32.to_be_bytes());
+        delete.push(b'O');
+        delete.extend_from_slice(&3_u16.to_be_bytes());
+        text(&mut delete, "1");
+        text(&mut delete, "snot");
+        text(&mut delete, r#"["a"]"#);
+        pgoutput(&mut relations, &mut txns, 7, 3, &delete)?;
+
+        let mut commit = vec![b'C', 0];
+        commit.extend_from_slice(&[0; 24]);
+        pgoutput(&mut relations, &mut txns, 7, 4, &commit)?;
+
+        assert_eq!(
+            vec![Txn {
+                xid: 7,
+                lsn: 4,
+                changes: vec![
+                    Change {
+                        op: "update",
+                        schema: "public".to_string(),
+                        table: "badgers".to_string(),
+                        before: literal!({"id": 1, "name": null, "tags": null}),
+                        after: literal!({"id": 1, "name": "snot"}),
+                    },
+                    Change {
+                        op: "delete",
+                        schema: "public".to_string(),
+                        table: "badgers".to_string(),
+                        before: literal!({"id": 1, "name": "snot", "tags": ["a"]}),
+                        after: Value::null(),
+                    }
+                ]
+            }],
+            txns.done
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decode_wal2json() -> Result<()> {
+        let mut txns = Txns::default();
+        wal2json(&mut txns, 3, 1, r#"{"action":"B"}"#.to_string())?;
+        wal2json(
+            &mut txns,
+            3,
+            2,
+            r#"{"action":"I","schema":"public","table":"badgers","columns":[{"name":"id","type":"integer","value":1}]}"#.to_string(),
+        )?;
+        wal2json(&mut txns, 3, 3, r#"{"action":"C"}"#.to_string())?;
+        assert_eq!(
+            vec![Txn {
+                xid: 3,
+                lsn: 3,
+                changes: vec![Change {
+                    op: "insert",
+                    schema: "public".to_string(),
+                    table: "badgers".to_string(),
+                    before: Value::null(),
+                    after: literal!({"id": 1}),
+                }]
+            }],
+            txns.done
+        );
+        Ok(())
+    }
+
+    fn txn(lsn: u64, n: usize) -> Txn {
+        let change = Change {
+            op: "insert",
+            schema: "public".to_string(),
+            table: "badgers".to_string(),
+            before: Value::null(),
+            after: Value::null(),
+        };
+        Txn {
+            xid: lsn,
+            lsn,
+            changes: vec![change; n],
+        }
+    }
+
+    #[test]
+    fn advance_after_acks() -> Result<()> {
+        let mut s = int()?;
+        assert_eq!(None, s.queue(vec![txn(10, 2), txn(20, 1), txn(30, 0)]));
+        assert_eq!(30, s.read);
+        let mut id = 0;
+        while let Some((txn, change, lsn)) = s.pending.pop_front() {
+            s.event(id, &txn, change, lsn);
+            id += 1;
+        }
+        assert_eq!(None, s.acknowledge(0));
+        assert_eq!(Some(10), s.acknowledge(1));
+        // the empty transaction is covered by the last change before it
+        assert_eq!(Some(30), s.acknowledge(2));
+        // nothing on its way, an empty transaction can be skipped right away
+        assert_eq!(Some(40), s.queue(vec![txn(40, 0)]));
+        Ok(())
+    }
+
+    #[test]
+    fn acks_record_lsn() -> Result<()> {
+        let mut s = int()?;
+        assert_eq!(None, s.queue(vec![txn(10, 1), txn(20, 1)]));
+        let mut id = 0;
+        while let Some((txn, change, lsn)) = s.pending.pop_front() {
+            s.event(id, &txn, change, lsn);
+            id += 1;
+        }
+        s.ack(0);
+        // the slot is advanced from `pull_event`, not connected here
+        assert_eq!(Some(10), s.advance_to);
+        assert_eq!(0, s.confirmed);
+        // acknowledged changes are not read again
+        s.fail(1);
+        assert_eq!(10, s.read);
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Prometheus Remote-Write Onramp
+//!
+//! Accepts Prometheus remote-write requests over HTTP and emits one
+//! influx style event per sample, see `crate::connectors::prometheus`.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::connectors::prometheus;
+use crate::source::prelude::*;
+use async_channel::{Sender, TryRecvError};
+use async_std::io::ReadExt;
+use tide::{Body, Request, Response};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// host to listen to, defaults to "0.0.0.0"
+    #[serde(default = "dflt_host")]
+    pub host: String,
+    /// port to listen to, defaults to 9201
+    #[serde(default = "dflt_port")]
+    pub port: u16,
+    /// path to accept remote-write requests on, defaults to `/api/v1/write`
+    #[serde(default = "dflt_path")]
+    pub path: String,
+}
+
+impl ConfigImpl for Config {}
+
+fn dflt_host() -> String {
+    String::from("0.0.0.0")
+}
+
+fn dflt_port() -> u16 {
+    9201
+}
+
+fn dflt_path() -> String {
+    String::from("/api/v1/write")
+}
+
+pub struct Prometheus {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for Prometheus {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for prometheus onramp".into())
+        }
+    }
+}
+
+pub struct Int {
+    uid: u64,
+    config: Config,
+    onramp_id: TremorUrl,
+    listener: Option<Receiver<SourceReply>>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Prometheus")
+    }
+}
+
+#[derive(Clone)]
+struct ServerState {
+    tx: Sender<SourceReply>,
+    uid: u64,
+}
+
+async fn handle_write(mut req: Request<ServerState>) -> tide::Result<Response> {
+    let origin_uri = EventOriginUri {
+        uid: req.state().uid,
+        scheme: "tremor-prometheus".to_string(),
+        host: req
+            .remote()
+            .unwrap_or("tremor-prometheus-client-host.remote")
+            .to_string(),
+        port: None,
+        path: vec![req.url().path().to_string()],
+    };
+    let too_large = || {
+        Response::builder(413)
+            .body(format!(
+                "Request body exceeds {} bytes",
+                prometheus::MAX_BODY_LEN
+            ))
+            .build()
+    };
+    // reading one byte more than allowed tells us if there is more
+    let limit = u64::try_from(prometheus::MAX_BODY_LEN)
+        .unwrap_or(u64::MAX)
+        .saturating_add(1);
+    let mut body = Vec::new();
+    req.take_body().take(limit).read_to_end(&mut body).await?;
+    if body.len() > prometheus::MAX_BODY_LEN {
+        return Ok(too_large());
+    }
+    match prometheus::decode_write_request(&body, prometheus::MAX_BODY_LEN) {
+        Ok(None) => Ok(too_large()),
+        Ok(Some(samples)) => {
+            for sample in samples {
+                req.state()
+                    .tx
+                    .send(SourceReply::Structured {
+                        origin_uri: origin_uri.clone(),
+                        data: sample.into(),
+                    })
+                    .await?;
+            }
+            Ok(Response::builder(204).body(Body::empty()).build())
+        }
+        Err(e) => {
+            warn!("Invalid prometheus remote-write request: {}", e);
+            Ok(Response::builder(400)
+                .body(Body::from_string(e.to_string()))
+                .build())
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
+        self.listener.as_ref().map_or_else(
+            || Ok(SourceReply::StateChange(SourceState::Disconnected)),
+            |listener| match listener.try_recv() {
+                Ok(reply) => Ok(reply),
+                Err(TryRecvError::Empty) => Ok(SourceReply::Empty(10)),
+                Err(TryRecvError::Closed) => {
+                    Ok(SourceReply::StateChange(SourceState::Disconnected))
+                }
+            },
+        )
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let (tx, rx) = bounded(crate::QSIZE);
+
+        let mut server = tide::Server::with_state(ServerState {
+            tx: tx.clone(),
+            uid: self.uid,
+        });
+        server.at(&self.config.path).post(handle_write);
+
+        let addr = format!("{}:{}", self.config.host, self.config.port);
+        let source_id = self.onramp_id.to_string();
+
+        task::spawn::<_, Result<()>>(async move {
+            info!("[Source::{}] Listening at {}", source_id, addr);
+            if let Err(e) = server.listen(addr).await {
+                error!(
+                    "[Source::{}] Error while listening for remote-write requests: {}",
+                    source_id, e
+                );
+            }
+            warn!("[Source::{}] Server stopped", source_id);
+            tx.send(SourceReply::StateChange(SourceState::Disconnected))
+                .await?;
+            Ok(())
+        });
+
+        self.listener = Some(rx);
+        Ok(SourceState::Connected)
+    }
+
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+}
+
+#[async_trait::async_trait]
+impl Onramp for Prometheus {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int {
+            uid: config.onramp_uid,
+            config: self.config.clone(),
+            onramp_id: self.onramp_id.clone(),
+            listener: None,
+        };
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
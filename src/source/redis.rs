@@ -0,0 +1,738 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Redis Onramp
+//!
+//! Consumes Redis Streams as a member of a consumer group and messages of pub/sub channels.
+//!
+//! Stream entries are acknowledged (`XACK`) once their events are acknowledged, failed events
+//! are emitted again. Entries this consumer received but never acknowledged, e.g. because
+//! tremor was stopped, are read again on start, after reconnects reading resumes after the
+//! last entry read. Pub/sub messages are fire and forget.
+//!
+//! The payload of a stream entry is taken from its `data` field (see `payload_field`), the
+//! stream, entry id and remaining fields are available as `$redis`. For pub/sub messages
+//! `$redis` holds the channel and, for pattern subscriptions, the pattern.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::connectors::redis::{cmd, Connection, ConnectionConfig, Resp};
+use crate::source::prelude::*;
+use async_channel::Sender;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Wait this long before reconnecting
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    #[serde(flatten)]
+    pub(crate) connection: ConnectionConfig,
+    /// Streams to consume
+    #[serde(default)]
+    pub streams: Vec<String>,
+    /// Consumer group to read streams as, it is created at the end of the stream if it
+    /// doesn't exist yet. Defaults to `tremor`
+    #[serde(default = "dflt_group")]
+    pub group: String,
+    /// Name of this consumer within the group, defaults to the hostname
+    #[serde(default)]
+    pub consumer: Option<String>,
+    /// Field of stream entries holding the payload, defaults to `data`
+    #[serde(default = "dflt_payload_field")]
+    pub payload_field: String,
+    /// Maximum number of entries to read at once, defaults to 100
+    #[serde(default = "dflt_count")]
+    pub count: u64,
+    /// Wait at most this long for new entries, defaults to 1000
+    #[serde(default = "dflt_block_ms")]
+    pub block_ms: u64,
+    /// Pub/sub channels to subscribe to
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Pub/sub channel patterns to subscribe to, e.g. `news.*`
+    #[serde(default)]
+    pub patterns: Vec<String>,
+}
+
+fn dflt_group() -> String {
+    "tremor".to_string()
+}
+
+fn dflt_payload_field() -> String {
+    "data".to_string()
+}
+
+fn dflt_count() -> u64 {
+    100
+}
+
+fn dflt_block_ms() -> u64 {
+    1000
+}
+
+impl ConfigImpl for Config {}
+
+pub struct Redis {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for Redis {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            if config.streams.is_empty() && config.channels.is_empty() && config.patterns.is_empty()
+            {
+                return Err(
+                    "redis onramp needs at least one stream, channel or pattern to consume".into(),
+                );
+            }
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for redis onramp".into())
+        }
+    }
+}
+
+/// An entry of a stream
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    stream: String,
+    id: String,
+    fields: Vec<(String, Vec<u8>)>,
+}
+
+#[derive(Debug)]
+enum Msg {
+    Entry(Entry),
+    Message {
+        channel: String,
+        pattern: Option<String>,
+        payload: Vec<u8>,
+    },
+}
+
+fn string(resp: Resp) -> Result<String> {
+    resp.into_bytes()
+        .and_then(|b| String::from_utf8(b).ok())
+        .ok_or_else(|| Error::from("Unexpected redis reply, expected a string"))
+}
+
+/// Parses the reply of `XREADGROUP`, streams without entries are returned with none
+fn parse_entries(resp: Resp) -> Result<Vec<(String, Vec<Entry>)>> {
+    let mut res = Vec::new();
+    // nothing new before the timeout
+    let streams = match resp {
+        Resp::Array(None) => return Ok(res),
+        other => other.into_array().ok_or("Unexpected XREADGROUP reply")?,
+    };
+    for stream in streams {
+        let mut stream = stream
+            .into_array()
+            .ok_or("Unexpected XREADGROUP reply")?
+            .into_iter();
+        let name = string(stream.next().ok_or("Missing stream name")?)?;
+        let mut entries = Vec::new();
+        for entry in stream.next().and_then(Resp::into_array).unwrap_or_default() {
+            let mut entry = entry
+                .into_array()
+                .ok_or("Unexpected XREADGROUP reply")?
+                .into_iter();
+            let id = string(entry.next().ok_or("Missing entry id")?)?;
+            let mut fields = Vec::new();
+            // entries deleted since they were delivered to us have no fields
+            let mut kv = entry
+                .next()
+                .and_then(Resp::into_array)
+                .unwrap_or_default()
+                .into_iter();
+            while let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+                fields.push((string(k)?, v.into_bytes().unwrap_or_default()));
+            }
+            entries.push(Entry {
+                stream: name.clone(),
+                id,
+                fields,
+            });
+        }
+        res.push((name, entries));
+    }
+    Ok(res)
+}
+
+/// Parses a pub/sub message, subscription confirmations are `None`
+fn parse_message(resp: Resp) -> Result<Option<Msg>> {
+    let mut parts = resp
+        .into_array()
+        .ok_or("Unexpected pub/sub message")?
+        .into_iter();
+    let kind = string(parts.next().ok_or("Empty pub/sub message")?)?;
+    let pattern = match kind.as_str() {
+        "message" => None,
+        "pmessage" => Some(string(parts.next().ok_or("Missing pattern")?)?),
+        _ => return Ok(None),
+    };
+    let channel = string(parts.next().ok_or("Missing channel")?)?;
+    let payload = parts
+        .next()
+        .and_then(Resp::into_bytes)
+        .ok_or("Missing payload")?;
+    Ok(Some(Msg::Message {
+        channel,
+        pattern,
+        payload,
+    }))
+}
+
+/// Makes sure the consumer group exists on all streams
+async fn create_groups(conn: &mut Connection, config: &Config) -> Result<()> {
+    for stream in &config.streams {
+        let created = conn
+            .query(cmd([
+                "XGROUP",
+                "CREATE",
+                stream.as_str(),
+                config.group.as_str(),
+                "$",
+                "MKSTREAM",
+            ]))
+            .await;
+        match created {
+            Err(e) if e.to_string().contains("BUSYGROUP") => (),
+            other => {
+                other?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads the streams starting after `ids`, those are kept up to date so reading resumes
+/// where it stopped after a reconnect
+async fn read_streams(
+    config: &Config,
+    consumer: &str,
+    conn: &mut Connection,
+    tx: &Sender<Msg>,
+    ids: &Mutex<Vec<String>>,
+) -> Result<()> {
+    create_groups(conn, config).await?;
+    let count = config.count.to_string();
+    let block_ms = config.block_ms.to_string();
+    loop {
+        let mut read = cmd([
+            "XREADGROUP",
+            "GROUP",
+            config.group.as_str(),
+            consumer,
+            "COUNT",
+            count.as_str(),
+            "BLOCK",
+            block_ms.as_str(),
+            "STREAMS",
+        ]);
+        read.extend(config.streams.iter().map(|s| s.as_bytes().to_vec()));
+        read.extend(
+            ids.lock()
+                .map_err(|_| Error::from("Stream ids poisoned"))?
+                .iter()
+                .map(|id| id.as_bytes().to_vec()),
+        );
+        for (stream, entries) in parse_entries(conn.query(read).await?)? {
+            if let Some(i) = config.streams.iter().position(|s| s == &stream) {
+                let mut ids = ids.lock().map_err(|_| Error::from("Stream ids poisoned"))?;
+                if ids[i] != ">" {
+                    ids[i] = entries
+                        .last()
+                        .map_or_else(|| ">".to_string(), |e| e.id.clone());
+                }
+            }
+            for entry in entries {
+                if tx.send(Msg::Entry(entry)).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn read_messages(config: &Config, conn: &mut Connection, tx: &Sender<Msg>) -> Result<()> {
+    let mut subscribe = Vec::new();
+    if !config.channels.is_empty() {
+        let mut c = cmd(["SUBSCRIBE"]);
+        c.extend(config.channels.iter().map(|s| s.as_bytes().to_vec()));
+        subscribe.push(c);
+    }
+    if !config.patterns.is_empty() {
+        let mut c = cmd(["PSUBSCRIBE"]);
+        c.extend(config.patterns.iter().map(|s| s.as_bytes().to_vec()));
+        subscribe.push(c);
+    }
+    conn.send(&subscribe).await?;
+    loop {
+        if let Some(msg) = parse_message(conn.read().await?.into_result()?)? {
+            if tx.send(msg).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+pub struct Int {
+    onramp_id: TremorUrl,
+    config: Config,
+    consumer: String,
+    origin_uri: EventOriginUri,
+    rx: Option<Receiver<Msg>>,
+    /// connection to acknowledge entries with
+    conn: Option<Connection>,
+    /// entries to emit again
+    pending: VecDeque<Entry>,
+    /// entries by the id of their event
+    inflight: BTreeMap<u64, Entry>,
+    /// `XACK` commands to send
+    acks: Vec<Vec<Vec<u8>>>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Redis")
+    }
+}
+
+impl Int {
+    fn from_config(uid: u64, onramp_id: TremorUrl, config: &Config) -> Self {
+        let origin_uri = EventOriginUri {
+            uid,
+            scheme: "tremor-redis".to_string(),
+            host: config.connection.host.clone(),
+            port: Some(config.connection.port),
+            path: vec![],
+        };
+        let consumer = config.consumer.clone().unwrap_or_else(hostname);
+        Self {
+            onramp_id,
+            config: config.clone(),
+            consumer,
+            origin_uri,
+            rx: None,
+            conn: None,
+            pending: VecDeque::new(),
+            inflight: BTreeMap::new(),
+            acks: Vec::new(),
+        }
+    }
+
+    fn entry(&mut self, id: u64, entry: Entry) -> SourceReply {
+        let mut fields = Value::object_with_capacity(entry.fields.len());
+        let mut data = Vec::new();
+        for (k, v) in &entry.fields {
+            if k == &self.config.payload_field {
+                data = v.clone();
+            } else {
+                fields.try_insert(
+                    k.clone(),
+                    String::from_utf8(v.clone())
+                        .map_or_else(|e| Value::Bytes(e.into_bytes().into()), Value::from),
+                );
+            }
+        }
+        let meta = literal!({
+            "stream": entry.stream.clone(),
+            "id": entry.id.clone(),
+            "fields": fields,
+        });
+        let mut origin_uri = self.origin_uri.clone();
+        origin_uri.path = vec![entry.stream.clone()];
+        self.inflight.insert(id, entry);
+        SourceReply::Data {
+            origin_uri,
+            data,
+            meta: Some(meta),
+            codec_override: None,
+            stream: 0,
+        }
+    }
+
+    fn message(&self, channel: String, pattern: Option<String>, data: Vec<u8>) -> SourceReply {
+        let mut meta = literal!({ "channel": channel.clone() });
+        if let Some(pattern) = pattern {
+            meta.try_insert("pattern", pattern);
+        }
+        let mut origin_uri = self.origin_uri.clone();
+        origin_uri.path = vec![channel];
+        SourceReply::Data {
+            origin_uri,
+            data,
+            meta: Some(meta),
+            codec_override: None,
+            stream: 0,
+        }
+    }
+
+    /// Queues `XACK` commands for all entries up to event `id`
+    fn acknowledge(&mut self, id: u64) {
+        let later = self.inflight.split_off(&(id + 1));
+        let done = std::mem::replace(&mut self.inflight, later);
+        let mut by_stream: BTreeMap<String, Vec<Vec<u8>>> = BTreeMap::new();
+        for entry in done.into_values() {
+            by_stream
+                .entry(entry.stream)
+                .or_default()
+                .push(entry.id.into_bytes());
+        }
+        for (stream, ids) in by_stream {
+            let mut c = cmd(["XACK", stream.as_str(), self.config.group.as_str()]);
+            c.extend(ids);
+            self.acks.push(c);
+        }
+    }
+
+    fn acknowledge_entry(&mut self, entry: &Entry) {
+        self.acks.push(cmd([
+            "XACK",
+            entry.stream.as_str(),
+            self.config.group.as_str(),
+            entry.id.as_str(),
+        ]));
+    }
+
+    /// Sends the queued `XACK` commands, entries that couldn't be acknowledged stay pending
+    /// and are delivered again once the onramp is restarted
+    async fn send_acks(&mut self) {
+        if self.acks.is_empty() {
+            return;
+        }
+        let acks = std::mem::take(&mut self.acks);
+        let res: Result<Vec<Resp>> = async {
+            if self.conn.is_none() {
+                self.conn = Some(Connection::connect(&self.config.connection).await?);
+            }
+            match self.conn.as_mut() {
+                Some(conn) => conn.pipeline(&acks).await,
+                None => Err("Not connected".into()),
+            }
+        }
+        .await;
+        match res {
+            Ok(replies) => {
+                for reply in replies {
+                    if let Err(e) = reply.into_result() {
+                        error!("[Source::{}] Failed to acknowledge: {}", self.onramp_id, e);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("[Source::{}] Failed to acknowledge: {}", self.onramp_id, e);
+                self.conn = None;
+            }
+        }
+    }
+
+    fn spawn<F, Fut>(&self, name: &'static str, tx: Sender<Msg>, f: F)
+    where
+        F: Fn(Config, String, Connection, Sender<Msg>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let config = self.config.clone();
+        let consumer = self.consumer.clone();
+        let onramp_id = self.onramp_id.clone();
+        task::spawn(async move {
+            while !tx.is_closed() {
+                match Connection::connect(&config.connection).await {
+                    Ok(conn) => match f(config.clone(), consumer.clone(), conn, tx.clone()).await {
+                        Ok(()) => break,
+                        Err(e) => error!("[Source::{}] Reading {}: {}", onramp_id, name, e),
+                    },
+                    Err(e) => error!("[Source::{}] Failed to connect: {}", onramp_id, e),
+                }
+                task::sleep(RECONNECT_INTERVAL).await;
+            }
+        });
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        self.send_acks().await;
+        if let Some(entry) = self.pending.pop_front() {
+            return Ok(self.entry(id, entry));
+        }
+        let msg = match self.rx.as_ref().map(Receiver::try_recv) {
+            Some(Ok(msg)) => msg,
+            Some(Err(async_channel::TryRecvError::Closed)) => {
+                return Ok(SourceReply::StateChange(SourceState::Disconnected))
+            }
+            Some(Err(async_channel::TryRecvError::Empty)) | None => {
+                return Ok(SourceReply::Empty(10))
+            }
+        };
+        Ok(match msg {
+            // deleted since it was delivered to us, nothing left to process
+            Msg::Entry(entry) if entry.fields.is_empty() => {
+                self.acknowledge_entry(&entry);
+                SourceReply::Empty(0)
+            }
+            Msg::Entry(entry) => self.entry(id, entry),
+            Msg::Message {
+                channel,
+                pattern,
+                payload,
+            } => self.message(channel, pattern, payload),
+        })
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let mut conn = Connection::connect(&self.config.connection).await?;
+        create_groups(&mut conn, &self.config).await?;
+        self.conn = Some(conn);
+        let (tx, rx) = bounded(crate::QSIZE);
+        if !self.config.streams.is_empty() {
+            // first everything delivered to us but not acknowledged, then new entries
+            let ids: Vec<String> = self
+                .config
+                .streams
+                .iter()
+                .map(|_| "0".to_string())
+                .collect();
+            let ids = Arc::new(Mutex::new(ids));
+            self.spawn(
+                "streams",
+                tx.clone(),
+                move |config, consumer, mut conn, tx| {
+                    let ids = ids.clone();
+                    async move { read_streams(&config, &consumer, &mut conn, &tx, &ids).await }
+                },
+            );
+        }
+        if !self.config.channels.is_empty() || !self.config.patterns.is_empty() {
+            self.spawn("channels", tx, |config, _, mut conn, tx| async move {
+                read_messages(&config, &mut conn, &tx).await
+            });
+        }
+        self.rx = Some(rx);
+        Ok(SourceState::Connected)
+    }
+
+    async fn on_empty_event(&mut self, id: u64, _stream: usize) -> Result<()> {
+        // the entry produced no event, its id is used for the next one
+        if let Some(entry) = self.inflight.remove(&id) {
+            self.acknowledge_entry(&entry);
+        }
+        Ok(())
+    }
+
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    fn ack(&mut self, id: u64) {
+        // acks cover all events up to `id`, they are sent on the next pull
+        self.acknowledge(id);
+    }
+
+    fn fail(&mut self, id: u64) {
+        // emit the failed event and all after it again, in order
+        let failed = self.inflight.split_off(&id);
+        for entry in failed.into_values().rev() {
+            self.pending.push_front(entry);
+        }
+    }
+
+    fn is_transactional(&self) -> bool {
+        !self.config.streams.is_empty()
+    }
+
+    async fn terminate(&mut self) {
+        // closing the channel stops the reading tasks
+        if let Some(rx) = self.rx.take() {
+            rx.close();
+        }
+        self.conn = None;
+    }
+}
+
+#[async_trait::async_trait]
+impl Onramp for Redis {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int::from_config(config.onramp_uid, self.onramp_id.clone(), &self.config);
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bulk(s: &str) -> Resp {
+        Resp::Bulk(Some(s.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn entries() -> Result<()> {
+        let reply = Resp::Array(Some(vec![
+            Resp::Array(Some(vec![
+                bulk("events"),
+                Resp::Array(Some(vec![
+                    Resp::Array(Some(vec![
+                        bulk("1-0"),
+                        Resp::Array(Some(vec![
+                            bulk("data"),
+                            bulk("{}"),
+                            bulk("source"),
+                            bulk("snot"),
+                        ])),
+                    ])),
+                    // deleted in the meantime
+                    Resp::Array(Some(vec![bulk("2-0"), Resp::Array(None)])),
+                ])),
+            ])),
+            Resp::Array(Some(vec![bulk("other"), Resp::Array(Some(vec![]))])),
+        ]));
+        let parsed = parse_entries(reply)?;
+        assert_eq!(2, parsed.len());
+        assert_eq!("events", parsed[0].0);
+        assert_eq!(
+            vec![
+                Entry {
+                    stream: "events".to_string(),
+                    id: "1-0".to_string(),
+                    fields: vec![
+                        ("data".to_string(), b"{}".to_vec()),
+                        ("source".to_string(), b"snot".to_vec())
+                    ],
+                },
+                Entry {
+                    stream: "events".to_string(),
+                    id: "2-0".to_string(),
+                    fields: vec![],
+                }
+            ],
+            parsed[0].1
+        );
+        assert!(parsed[1].1.is_empty());
+        assert!(parse_entries(Resp::Array(None))?.is_empty());
+
+        let msg = Resp::Array(Some(vec![
+            bulk("pmessage"),
+            bulk("news.*"),
+            bulk("news.tech"),
+            bulk("hello"),
+        ]));
+        assert!(matches!(
+            parse_message(msg)?,
+            Some(Msg::Message { channel, pattern: Some(pattern), payload })
+                if channel == "news.tech" && pattern == "news.*" && payload == b"hello"
+        ));
+        let confirmation = Resp::Array(Some(vec![
+            bulk("subscribe"),
+            bulk("news"),
+            Resp::Integer(1),
+        ]));
+        assert!(parse_message(confirmation)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn acks() -> Result<()> {
+        let config: Config = serde_yaml::from_str("{host: localhost, streams: [a, b]}")?;
+        let mut source = Int::from_config(0, TremorUrl::from_onramp_id("redis")?, &config);
+        for (id, (stream, entry_id)) in [("a", "1-0"), ("b", "1-0"), ("a", "2-0")]
+            .iter()
+            .enumerate()
+        {
+            let reply = source.entry(
+                id as u64,
+                Entry {
+                    stream: (*stream).to_string(),
+                    id: (*entry_id).to_string(),
+                    fields: vec![
+                        ("data".to_string(), b"snot".to_vec()),
+                        ("n".to_string(), id.to_string().into_bytes()),
+                    ],
+                },
+            );
+            if let SourceReply::Data { data, meta, .. } = reply {
+                assert_eq!(b"snot".to_vec(), data);
+                let meta = meta.ok_or("no meta")?;
+                assert_eq!(Some(*stream), meta.get_str("stream"));
+                assert_eq!(Some(*entry_id), meta.get_str("id"));
+                let n = id.to_string();
+                assert_eq!(
+                    Some(n.as_str()),
+                    meta.get("fields").and_then(|f| f.get_str("n"))
+                );
+            } else {
+                return Err("expected data".into());
+            }
+        }
+        // the last one failed and is emitted again
+        source.fail(2);
+        assert_eq!(1, source.pending.len());
+        source.acknowledge(1);
+        assert_eq!(
+            vec![
+                cmd(["XACK", "a", "tremor", "1-0"]),
+                cmd(["XACK", "b", "tremor", "1-0"])
+            ],
+            source.acks
+        );
+        source.acks.clear();
+        source.acknowledge(1);
+        assert!(source.acks.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn empty_events() -> Result<()> {
+        let config: Config = serde_yaml::from_str("{host: localhost, streams: [a]}")?;
+        let mut source = Int::from_config(0, TremorUrl::from_onramp_id("redis")?, &config);
+        // the first entry produces no event, so its id is used again
+        for entry_id in ["1-0", "2-0"] {
+            source.entry(
+                1,
+                Entry {
+                    stream: "a".to_string(),
+                    id: entry_id.to_string(),
+                    fields: vec![("data".to_string(), b"".to_vec())],
+                },
+            );
+            if entry_id == "1-0" {
+                source.on_empty_event(1, 0).await?;
+            }
+        }
+        source.ack(1);
+        assert_eq!(
+            vec![
+                cmd(["XACK", "a", "tremor", "1-0"]),
+                cmd(["XACK", "a", "tremor", "2-0"])
+            ],
+            source.acks
+        );
+        Ok(())
+    }
+}
@@ -18,10 +18,18 @@
 use crate::codec::Codec;
 use crate::postprocessor::{make_postprocessors, postprocess, Postprocessors};
 use crate::source::prelude::*;
+use crate::source::tcp::{load_server_config, TLSConfig};
 use async_channel::{unbounded, Sender, TryRecvError};
+use async_std::future::timeout;
+use async_std::net::TcpListener;
+use async_tls::TlsAcceptor;
 use halfbrown::HashMap;
 use http_types::Mime;
+use rustls::{ServerConfig, Session};
+use sha2::{Digest, Sha256};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use tide::http::headers::HeaderValue;
 use tide::{Body, Request, Response};
 use tremor_script::Value;
@@ -34,8 +42,73 @@ pub struct Config {
     /// port to listen to, defaults to 8000
     #[serde(default = "dflt_port")]
     pub port: u16,
+    /// serve HTTPS with this certificate and key, optionally verifying client certificates
+    #[serde(default)]
+    pub tls: Option<TLSConfig>,
+    /// reject requests without valid credentials
+    #[serde(default)]
+    pub auth: Option<Auth>,
 }
 
+/// Credentials accepted by the onramp, keyed by the identity they belong to
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Auth {
+    /// HTTP basic auth, usernames mapped to passwords
+    Basic(HashMap<String, String>),
+    /// Bearer tokens, identities mapped to tokens
+    Bearer(HashMap<String, String>),
+}
+
+impl Auth {
+    fn method(&self) -> &'static str {
+        match self {
+            Self::Basic(_) => "basic",
+            Self::Bearer(_) => "bearer",
+        }
+    }
+
+    fn challenge(&self) -> &'static str {
+        match self {
+            Self::Basic(_) => "Basic realm=\"tremor\"",
+            Self::Bearer(_) => "Bearer realm=\"tremor\"",
+        }
+    }
+
+    /// Checks the `Authorization` header and returns the identity it authenticates
+    fn authenticate(&self, header: &str) -> Option<String> {
+        match self {
+            Self::Basic(users) => {
+                let encoded = header.strip_prefix("Basic ")?.trim();
+                let decoded = String::from_utf8(base64::decode(encoded).ok()?).ok()?;
+                let (user, password) = decoded.split_once(':')?;
+                let expected = users.get(user)?;
+                constant_time_eq(expected, password).then(|| user.to_string())
+            }
+            Self::Bearer(tokens) => {
+                let token = header.strip_prefix("Bearer ")?.trim();
+                tokens
+                    .iter()
+                    .find(|(_, expected)| constant_time_eq(expected, token))
+                    .map(|(identity, _)| identity.clone())
+            }
+        }
+    }
+}
+
+/// Compares secrets without leaking the length of the common prefix through timing
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0_u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// SHA-256 fingerprint of the client certificate of a mutual TLS connection
+#[derive(Clone)]
+struct ClientCert(String);
+
 // TODO possible to do this in source trait?
 impl ConfigImpl for Config {}
 
@@ -139,9 +212,27 @@ struct ServerState {
     tx: Sender<RestSourceReply>,
     uid: u64,
     link: bool,
+    auth: Option<Arc<Auth>>,
 }
 
 async fn handle_request(mut req: Request<ServerState>) -> tide::Result<Response> {
+    // reject unauthenticated requests before they become events
+    let identity = if let Some(auth) = req.state().auth.clone() {
+        let identity = req
+            .header("Authorization")
+            .and_then(|h| auth.authenticate(h.last().as_str()));
+        if let Some(identity) = identity {
+            Some((auth.method(), identity))
+        } else {
+            return Ok(Response::builder(401)
+                .header("WWW-Authenticate", auth.challenge())
+                .body(Body::empty())
+                .build());
+        }
+    } else {
+        None
+    };
+
     // TODO cache parts of this and update host only on new request
     let origin_uri = EventOriginUri {
         uid: req.state().uid,
@@ -157,6 +248,8 @@ async fn handle_request(mut req: Request<ServerState>) -> tide::Result<Response>
 
     let headers = req
         .header_names()
+        // credentials are checked above and don't end up in the metadata
+        .filter(|name| identity.is_none() || name.as_str() != "authorization")
         .map(|name| {
             (
                 name.to_string(),
@@ -198,6 +291,23 @@ async fn handle_request(mut req: Request<ServerState>) -> tide::Result<Response>
     request_meta.insert("method", req.method().to_string())?;
     request_meta.insert("headers", headers)?;
     request_meta.insert("url", url_meta)?;
+    if let Some((method, identity)) = identity {
+        request_meta.insert(
+            "auth",
+            literal!({
+                "method": method,
+                "identity": identity
+            }),
+        )?;
+    }
+    if let Some(ClientCert(fingerprint)) = req.ext::<ClientCert>() {
+        request_meta.insert(
+            "client_cert",
+            literal!({
+                "sha256": fingerprint.clone()
+            }),
+        )?;
+    }
     meta.insert("request", request_meta)?;
 
     let data = req.body_bytes().await?;
@@ -248,6 +358,74 @@ async fn handle_request(mut req: Request<ServerState>) -> tide::Result<Response>
     }
 }
 
+/// How long clients get to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves HTTPS, the fingerprint of client certificates is attached to the requests
+pub(crate) async fn listen_tls<State>(
+    server: tide::Server<State>,
+    addr: &str,
+    tls_config: ServerConfig,
+) -> Result<()>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept HTTPS connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let server = server.clone();
+        task::spawn(async move {
+            // tide sets these for plain HTTP, they are gone once the stream is wrapped
+            let peer_addr = stream.peer_addr().ok();
+            let local_addr = stream.local_addr().ok();
+            let tls_stream = match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(tls_stream)) => tls_stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake failed: {}", e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake timed out");
+                    return;
+                }
+            };
+            let client_cert = tls_stream
+                .get_ref()
+                .1
+                .get_peer_certificates()
+                .and_then(|certs| certs.first().map(|c| hex::encode(Sha256::digest(&c.0))))
+                .map(ClientCert);
+            let stream = async_dup::Arc::new(async_dup::Mutex::new(tls_stream));
+            let res = async_h1::accept(stream, |mut req| {
+                let server = server.clone();
+                let client_cert = client_cert.clone();
+                async move {
+                    req.set_peer_addr(peer_addr);
+                    req.set_local_addr(local_addr);
+                    if let Some(client_cert) = client_cert {
+                        req.ext_mut().insert(client_cert);
+                    }
+                    server.respond(req).await
+                }
+            })
+            .await;
+            if let Err(e) = res {
+                debug!("Error handling HTTPS connection: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
 fn make_response(
     default_codec: &dyn Codec,
     codec_map: &HashMap<String, Box<dyn Codec>>,
@@ -431,6 +609,7 @@ impl Source for Int {
             tx: tx.clone(),
             uid: self.uid,
             link: self.is_linked,
+            auth: self.config.auth.clone().map(Arc::new),
         });
 
         // TODO add override for path and method from config (defaulting to
@@ -442,10 +621,21 @@ impl Source for Int {
 
         let addr = format!("{}:{}", self.config.host, self.config.port);
         let source_id = self.onramp_id.to_string();
+        let tls_config = self
+            .config
+            .tls
+            .as_ref()
+            .map(load_server_config)
+            .transpose()?;
 
         task::spawn::<_, Result<()>>(async move {
             info!("[Source::{}] Listening at {}", source_id, addr);
-            if let Err(e) = server.listen(addr).await {
+            let res = if let Some(tls_config) = tls_config {
+                listen_tls(server, &addr, tls_config).await
+            } else {
+                server.listen(addr).await.map_err(Error::from)
+            };
+            if let Err(e) = res {
                 error!(
                     "[Source::{}] Error while listening from the rest server: {}",
                     e, source_id
@@ -0,0 +1,618 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # S3 Onramp
+//!
+//! Polls a prefix of an S3 compatible bucket for new objects and emits them line by line,
+//! in the order of their keys. `$s3` holds the bucket, key and line number. Objects are
+//! streamed, only the next line and the offsets of unacknowledged lines are kept.
+//!
+//! An object is processed once the events of all its lines are acknowledged, failed
+//! objects are emitted again from their first failed line. Processed keys are kept in
+//! memory, as long as objects with them are listed, and, if `state_file` is set, appended to
+//! it so they aren't emitted again after a restart. The state file is rewritten with the
+//! keys still listed whenever keys are forgotten.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::connectors::s3::{Client, ConnectionConfig};
+use crate::source::prelude::*;
+use async_std::fs::{File, OpenOptions};
+use async_std::io::{BufRead, BufReadExt};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use tremor_common::time::nanotime;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    #[serde(flatten)]
+    pub(crate) connection: ConnectionConfig,
+    /// Only consume objects with keys starting with this, defaults to the whole bucket
+    #[serde(default)]
+    pub prefix: String,
+    /// Look for new objects this often, defaults to 10s
+    #[serde(default = "dflt_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    /// File to record processed keys in
+    #[serde(default)]
+    pub state_file: Option<String>,
+}
+
+fn dflt_poll_interval_ms() -> u64 {
+    10_000
+}
+
+impl ConfigImpl for Config {}
+
+pub struct S3 {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for S3 {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for s3 onramp".into())
+        }
+    }
+}
+
+type Reader = Box<dyn BufRead + Unpin + Send + Sync>;
+
+/// Where a line starts in an object
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Position {
+    /// byte offset
+    offset: u64,
+    /// number of lines before it
+    line: u64,
+}
+
+/// The object currently being emitted
+struct Current {
+    key: String,
+    body: Reader,
+    /// the line to emit next
+    next: Option<(Vec<u8>, Position)>,
+    /// where the line after `next` starts
+    position: Position,
+}
+
+impl Current {
+    /// Reads the next line without its newline, `None` at the end of the object, so there
+    /// is no trailing empty line
+    async fn read_line(&mut self) -> Result<Option<(Vec<u8>, Position)>> {
+        let mut line = Vec::new();
+        let read = self.body.read_until(b'\n', &mut line).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let position = self.position;
+        self.position.offset += read as u64;
+        self.position.line += 1;
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        Ok(Some((line, position)))
+    }
+}
+
+pub struct Int {
+    onramp_id: TremorUrl,
+    config: Config,
+    client: Client,
+    origin_uri: EventOriginUri,
+    state: Option<File>,
+    /// keys that are processed, queued or being emitted, pruned to the ones still listed
+    seen: HashSet<String>,
+    /// keys to emit and where to start
+    queue: VecDeque<(String, Position)>,
+    current: Option<Current>,
+    /// keys of objects and where the lines start by the ids of the events of their lines
+    inflight: BTreeMap<u64, (String, Position)>,
+    /// keys of completely emitted objects by the id of the event of their last line
+    emitted: BTreeMap<u64, String>,
+    /// keys of objects that are done, to record in the state file
+    processed: Vec<String>,
+    next_poll: u64,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "S3")
+    }
+}
+
+impl Int {
+    fn from_config(uid: u64, onramp_id: TremorUrl, config: &Config) -> Result<Self> {
+        let client = Client::new(&config.connection)?;
+        let origin_uri = EventOriginUri {
+            uid,
+            scheme: "tremor-s3".to_string(),
+            host: config
+                .connection
+                .endpoint
+                .clone()
+                .unwrap_or_else(|| "s3.amazonaws.com".to_string()),
+            port: None,
+            path: vec![config.connection.bucket.clone()],
+        };
+        Ok(Self {
+            onramp_id,
+            config: config.clone(),
+            client,
+            origin_uri,
+            state: None,
+            seen: HashSet::new(),
+            queue: VecDeque::new(),
+            current: None,
+            inflight: BTreeMap::new(),
+            emitted: BTreeMap::new(),
+            processed: Vec::new(),
+            next_poll: 0,
+        })
+    }
+
+    /// Queues all objects under the prefix we haven't seen yet
+    async fn poll(&mut self) -> Result<()> {
+        let mut listed = HashSet::new();
+        let mut continuation = None;
+        loop {
+            let listing = self
+                .client
+                .list_objects(&self.config.prefix, continuation.as_deref())
+                .await?;
+            listed.extend(listing.objects.into_iter().map(|o| o.key));
+            continuation = listing.next;
+            if continuation.is_none() {
+                break;
+            }
+        }
+        if self.listed(listed) {
+            self.compact().await;
+        }
+        Ok(())
+    }
+
+    /// Queues the listed keys we haven't seen yet and forgets the ones that are gone, so
+    /// `seen` doesn't grow beyond the objects in the bucket. Returns if any were forgotten.
+    fn listed(&mut self, listed: HashSet<String>) -> bool {
+        let before = self.seen.len();
+        self.seen.retain(|k| listed.contains(k));
+        let forgotten = self.seen.len() < before;
+        let mut keys: Vec<String> = listed
+            .into_iter()
+            .filter(|k| !self.seen.contains(k))
+            .collect();
+        keys.sort();
+        for key in keys {
+            self.seen.insert(key.clone());
+            self.queue.push_back((key, Position::default()));
+        }
+        forgotten
+    }
+
+    /// Starts emitting the object `key` from `position`
+    async fn open(&mut self, key: String, body: Reader, position: Position) -> Result<()> {
+        let mut current = Current {
+            key,
+            body,
+            next: None,
+            position,
+        };
+        if let Some(next) = current.read_line().await? {
+            current.next = Some(next);
+            self.current = Some(current);
+        } else {
+            // nothing (left) to wait for
+            self.processed.push(current.key);
+        }
+        Ok(())
+    }
+
+    /// Emits the next line of the current object
+    async fn line(&mut self, id: u64) -> Option<SourceReply> {
+        let current = self.current.as_mut()?;
+        let (data, position) = current.next.take()?;
+        let key = current.key.clone();
+        match current.read_line().await {
+            Ok(Some(next)) => current.next = Some(next),
+            Ok(None) => {
+                self.current = None;
+                self.emitted.insert(id, key.clone());
+            }
+            Err(e) => {
+                error!("[Source::{}] Failed to read {}: {}", self.onramp_id, key, e);
+                // continue after this line with a new request
+                let position = current.position;
+                self.current = None;
+                self.queue.push_front((key.clone(), position));
+            }
+        }
+        let meta = literal!({
+            "bucket": self.config.connection.bucket.clone(),
+            "key": key.clone(),
+            "line": position.line + 1,
+        });
+        let mut origin_uri = self.origin_uri.clone();
+        origin_uri.path.push(key.clone());
+        self.inflight.insert(id, (key, position));
+        Some(SourceReply::Data {
+            origin_uri,
+            data,
+            meta: Some(meta),
+            codec_override: None,
+            stream: 0,
+        })
+    }
+
+    /// Marks all objects whose lines are acknowledged up to event `id` as processed
+    fn acknowledge(&mut self, id: u64) {
+        let later = self.inflight.split_off(&(id + 1));
+        self.inflight = later;
+        let later = self.emitted.split_off(&(id + 1));
+        let done = std::mem::replace(&mut self.emitted, later);
+        self.processed.extend(done.into_values());
+    }
+
+    /// Queues the objects of event `id` and all after it again, from their first failed line
+    fn reset(&mut self, id: u64) {
+        let failed = self.inflight.split_off(&id);
+        self.emitted.retain(|k, _| *k < id);
+        // lines of an object are emitted in order, so the first failed one starts earliest
+        let mut resume: Vec<(String, Position)> = Vec::new();
+        for (key, position) in failed.into_values() {
+            if !resume.iter().any(|(k, _)| *k == key) {
+                resume.push((key, position));
+            }
+        }
+        if let Some(current) = self.current.take() {
+            if !resume.iter().any(|(k, _)| *k == current.key) {
+                let position = current.next.map_or(current.position, |(_, p)| p);
+                resume.push((current.key, position));
+            }
+        }
+        // an earlier failure may have queued an object from a later line
+        self.queue
+            .retain(|(key, _)| !resume.iter().any(|(k, _)| k == key));
+        for entry in resume.into_iter().rev() {
+            self.queue.push_front(entry);
+        }
+    }
+
+    /// Records processed keys in the state file, outside of `ack` as it must not block
+    async fn persist(&mut self) {
+        if self.processed.is_empty() {
+            return;
+        }
+        let processed = std::mem::take(&mut self.processed);
+        if let Some(file) = self.state.as_mut() {
+            let mut data = processed.join("\n");
+            data.push('\n');
+            if let Err(e) = file.write_all(data.as_bytes()).await {
+                error!(
+                    "[Source::{}] Failed to record processed objects: {}",
+                    self.onramp_id, e
+                );
+            }
+        }
+    }
+
+    /// Processed keys, the ones seen that are neither queued nor being emitted
+    fn done(&self) -> Vec<&str> {
+        let mut pending: HashSet<&str> = self.queue.iter().map(|(k, _)| k.as_str()).collect();
+        pending.extend(self.inflight.values().map(|(k, _)| k.as_str()));
+        pending.extend(self.current.iter().map(|c| c.key.as_str()));
+        let mut done: Vec<&str> = self
+            .seen
+            .iter()
+            .map(String::as_str)
+            .filter(|k| !pending.contains(k))
+            .collect();
+        done.sort_unstable();
+        done
+    }
+
+    /// Rewrites the state file with the processed keys we still know of, so it doesn't grow
+    /// beyond the objects in the bucket
+    async fn compact(&mut self) {
+        if let Some(path) = self.config.state_file.clone() {
+            let mut data = self.done().join("\n");
+            if !data.is_empty() {
+                data.push('\n');
+            }
+            let tmp = format!("{}.tmp", path);
+            let res = async {
+                async_std::fs::write(&tmp, data).await?;
+                async_std::fs::rename(&tmp, &path).await?;
+                OpenOptions::new().append(true).open(&path).await
+            }
+            .await;
+            match res {
+                Ok(file) => {
+                    self.state = Some(file);
+                    // they are part of the rewritten state
+                    self.processed.clear();
+                }
+                Err(e) => error!(
+                    "[Source::{}] Failed to rewrite the state file: {}",
+                    self.onramp_id, e
+                ),
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        self.persist().await;
+        if let Some(reply) = self.line(id).await {
+            return Ok(reply);
+        }
+        if let Some((key, position)) = self.queue.pop_front() {
+            let opened = match self.client.get_object(&key, position.offset).await {
+                Ok(body) => self.open(key.clone(), Box::new(body), position).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = opened {
+                error!("[Source::{}] Failed to get {}: {}", self.onramp_id, key, e);
+                self.queue.push_front((key, position));
+                return Ok(SourceReply::Empty(self.config.poll_interval_ms));
+            }
+            return Ok(self.line(id).await.unwrap_or(SourceReply::Empty(0)));
+        }
+        let now = nanotime();
+        if now < self.next_poll {
+            return Ok(SourceReply::Empty(
+                ((self.next_poll - now) / 1_000_000).min(100),
+            ));
+        }
+        self.next_poll = now + self.config.poll_interval_ms * 1_000_000;
+        if let Err(e) = self.poll().await {
+            error!("[Source::{}] Failed to list objects: {}", self.onramp_id, e);
+        }
+        Ok(SourceReply::Empty(0))
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        if let Some(path) = &self.config.state_file {
+            if let Ok(state) = async_std::fs::read_to_string(path).await {
+                self.seen
+                    .extend(state.lines().filter(|l| !l.is_empty()).map(String::from));
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            self.state = Some(file);
+        }
+        Ok(SourceState::Connected)
+    }
+
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    fn ack(&mut self, id: u64) {
+        // acks cover all events up to `id`
+        self.acknowledge(id);
+    }
+
+    fn fail(&mut self, id: u64) {
+        self.reset(id);
+    }
+
+    fn is_transactional(&self) -> bool {
+        true
+    }
+
+    async fn terminate(&mut self) {
+        self.persist().await;
+        if let Some(mut file) = self.state.take() {
+            if let Err(e) = file.flush().await {
+                error!("[Source::{}] Failed to flush state: {}", self.onramp_id, e);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Onramp for S3 {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int::from_config(config.onramp_uid, self.onramp_id.clone(), &self.config)?;
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn int() -> Result<Int> {
+        let config = Config {
+            connection: ConnectionConfig {
+                bucket: "snot".to_string(),
+                region: "us-east-1".to_string(),
+                endpoint: Some("http://localhost:9000".to_string()),
+                path_style: true,
+                access_key_id: Some("key".to_string()),
+                secret_access_key: Some("secret".to_string()),
+                session_token: None,
+            },
+            prefix: String::new(),
+            poll_interval_ms: 1000,
+            state_file: None,
+        };
+        Int::from_config(0, TremorUrl::parse("/onramp/s3/01/out")?, &config)
+    }
+
+    fn body(data: &[u8]) -> Reader {
+        Box::new(async_std::io::Cursor::new(data.to_vec()))
+    }
+
+    async fn emit(s: &mut Int, key: &str, data: &[u8], first_id: u64) -> Result<u64> {
+        s.open(key.to_string(), body(data), Position::default())
+            .await?;
+        let mut id = first_id;
+        while s.line(id).await.is_some() {
+            id += 1;
+        }
+        Ok(id)
+    }
+
+    fn position(offset: u64, line: u64) -> Position {
+        Position { offset, line }
+    }
+
+    async fn read_all(data: &[u8]) -> Result<Vec<(Vec<u8>, Position)>> {
+        let mut current = Current {
+            key: "a".to_string(),
+            body: body(data),
+            next: None,
+            position: Position::default(),
+        };
+        let mut lines = Vec::new();
+        while let Some(line) = current.read_line().await? {
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+
+    #[async_std::test]
+    async fn split() -> Result<()> {
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), position(0, 0)),
+                (b"b".to_vec(), position(2, 1))
+            ],
+            read_all(b"a\nb\n").await?
+        );
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), position(0, 0)),
+                (vec![], position(2, 1)),
+                (b"b".to_vec(), position(3, 2))
+            ],
+            read_all(b"a\n\nb").await?
+        );
+        assert!(read_all(b"").await?.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn processed_once_all_lines_are_acked() -> Result<()> {
+        let mut s = int()?;
+        let next = emit(&mut s, "a", b"1\n2\n3\n", 0).await?;
+        let next = emit(&mut s, "b", b"4\n", next).await?;
+        assert_eq!(4, next);
+        s.acknowledge(1);
+        assert!(s.processed.is_empty());
+        s.acknowledge(2);
+        assert_eq!(vec!["a".to_string()], s.processed);
+        s.acknowledge(3);
+        assert_eq!(vec!["a".to_string(), "b".to_string()], s.processed);
+        assert!(s.inflight.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn failed_objects_are_queued_again() -> Result<()> {
+        let mut s = int()?;
+        let next = emit(&mut s, "a", b"1\n2\n", 0).await?;
+        emit(&mut s, "b", b"3\n4\n", next).await?;
+        s.queue.push_back(("c".to_string(), Position::default()));
+        s.acknowledge(0);
+        s.reset(1);
+        let queue: Vec<(&str, Position)> = s.queue.iter().map(|(k, p)| (k.as_str(), *p)).collect();
+        assert_eq!(
+            vec![
+                ("a", position(2, 1)),
+                ("b", Position::default()),
+                ("c", Position::default())
+            ],
+            queue
+        );
+        assert!(s.inflight.is_empty());
+        assert!(s.emitted.is_empty());
+        assert!(s.processed.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn failed_objects_resume_at_the_first_failed_line() -> Result<()> {
+        let mut s = int()?;
+        s.open("a".to_string(), body(b"1\n22\n3\n"), Position::default())
+            .await?;
+        assert!(s.line(0).await.is_some());
+        assert!(s.line(1).await.is_some());
+        // the current object continues after the last emitted line
+        s.reset(2);
+        assert!(s.current.is_none());
+        assert_eq!(Some(&("a".to_string(), position(5, 2))), s.queue.front());
+        // an earlier failure replaces the queued position
+        s.reset(1);
+        assert_eq!(1, s.queue.len());
+        assert_eq!(Some(&("a".to_string(), position(2, 1))), s.queue.front());
+        Ok(())
+    }
+
+    #[test]
+    fn seen_keys_are_pruned() -> Result<()> {
+        let mut s = int()?;
+        s.seen.insert("gone".to_string());
+        s.seen.insert("a".to_string());
+        assert!(s.listed(["b", "a", "c"].iter().map(|k| (*k).to_string()).collect()));
+        let queue: Vec<&str> = s.queue.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(vec!["b", "c"], queue);
+        let mut seen: Vec<&str> = s.seen.iter().map(String::as_str).collect();
+        seen.sort_unstable();
+        assert_eq!(vec!["a", "b", "c"], seen);
+        assert!(!s.listed(["b", "a", "c"].iter().map(|k| (*k).to_string()).collect()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn state_file_is_compacted() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("state");
+        async_std::fs::write(&path, "gone\na\nb\n").await?;
+        let mut s = int()?;
+        s.config.state_file = Some(path.to_string_lossy().to_string());
+        s.init().await?;
+        s.listed(["a", "b", "c"].iter().map(|k| (*k).to_string()).collect());
+        s.inflight.insert(0, ("b".to_string(), Position::default()));
+        s.compact().await;
+        assert_eq!("a\n", async_std::fs::read_to_string(&path).await?);
+        // processed keys are appended to the rewritten file
+        s.processed.push("b".to_string());
+        s.persist().await;
+        s.terminate().await;
+        assert_eq!("a\nb\n", async_std::fs::read_to_string(&path).await?);
+        Ok(())
+    }
+}
@@ -20,7 +20,9 @@ use async_channel::TryRecvError;
 use async_std::net::TcpListener;
 use async_tls::TlsAcceptor;
 use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
-use rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey, RootCertStore, ServerConfig,
+};
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -37,8 +39,12 @@ pub struct Config {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TLSConfig {
-    cert: PathBuf,
-    key: PathBuf,
+    pub(crate) cert: PathBuf,
+    pub(crate) key: PathBuf,
+    /// CA certificates to verify client certificates with, if set clients
+    /// have to present a certificate signed by one of them (mutual TLS)
+    #[serde(default)]
+    pub(crate) client_ca: Option<PathBuf>,
 }
 
 impl ConfigImpl for Config {}
@@ -256,11 +262,26 @@ fn load_keys(path: &Path) -> Result<PrivateKey> {
     }
 }
 
-fn load_server_config(config: &TLSConfig) -> Result<ServerConfig> {
+pub(crate) fn load_server_config(config: &TLSConfig) -> Result<ServerConfig> {
     let certs = load_certs(&config.cert)?;
     let keys = load_keys(&config.key)?;
 
-    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    let client_auth = if let Some(client_ca) = &config.client_ca {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(client_ca)? {
+            roots.add(&cert).map_err(|e| {
+                Error::from(ErrorKind::TLSError(format!(
+                    "Invalid client CA certificate in {}: {}",
+                    client_ca.display(),
+                    e
+                )))
+            })?;
+        }
+        AllowAnyAuthenticatedClient::new(roots)
+    } else {
+        NoClientAuth::new()
+    };
+    let mut server_config = ServerConfig::new(client_auth);
     server_config
         // set this server to use one cert together with the loaded private key
         .set_single_cert(certs, keys)?;
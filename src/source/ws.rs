@@ -13,15 +13,23 @@
 // limitations under the License.
 #![cfg(not(tarpaulin_include))]
 
+use crate::connectors::ws::{select_protocol, Keepalive, PROTOCOL_HEADER};
 use crate::postprocessor::{make_postprocessors, postprocess, Postprocessors};
+use crate::source::tcp::{load_server_config, TLSConfig};
 use crate::{codec::Codec, source::prelude::*};
 use async_channel::{Sender, TryRecvError};
-use async_std::net::{TcpListener, TcpStream};
+use async_std::future::timeout;
+use async_std::net::TcpListener;
 use async_std::task;
+use async_tls::TlsAcceptor;
+use async_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use async_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
 use async_tungstenite::tungstenite::Message;
-use futures::{SinkExt, StreamExt};
+use futures::{AsyncRead, AsyncWrite, SinkExt, StreamExt};
 use halfbrown::HashMap;
 use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
 use tremor_pipeline::EventId;
 use tremor_script::Value;
 
@@ -31,6 +39,14 @@ pub struct Config {
     pub port: u16,
     /// Host to listen on
     pub host: String,
+    /// Serve `wss://` with the given certificate and key
+    pub tls: Option<TLSConfig>,
+    /// Supported subprotocols, clients offering protocols have to offer one of them
+    #[serde(default)]
+    pub protocols: Vec<String>,
+    /// Ping clients periodically and close connections of clients that don't answer
+    #[serde(flatten)]
+    pub(crate) keepalive: Keepalive,
 }
 
 impl ConfigImpl for Config {}
@@ -123,30 +139,84 @@ impl Int {
     }
 }
 
-async fn handle_connection(
+#[allow(clippy::too_many_arguments, clippy::too_many_lines)]
+async fn handle_connection<S>(
     source_url: TremorUrl,
     tx: Sender<WsSourceReply>,
-    raw_stream: TcpStream,
+    raw_stream: S,
     origin_uri: EventOriginUri,
     processors: Vec<String>,
     stream: usize,
     link: bool,
-) -> Result<()> {
-    let ws_stream = async_tungstenite::accept_async(raw_stream).await?;
+    config: Config,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut protocol = None;
+    let negotiate =
+        |req: &Request, mut res: Response| -> std::result::Result<Response, ErrorResponse> {
+            let offered = req
+                .headers()
+                .get(PROTOCOL_HEADER)
+                .and_then(|v| v.to_str().ok());
+            match select_protocol(offered, &config.protocols) {
+                Ok(Some(p)) => {
+                    if let Ok(v) = HeaderValue::from_str(p) {
+                        res.headers_mut().insert(PROTOCOL_HEADER, v);
+                    }
+                    protocol = Some(p.to_string());
+                    Ok(res)
+                }
+                Ok(None) => Ok(res),
+                Err(e) => {
+                    let mut err = ErrorResponse::new(Some(e.to_string()));
+                    *err.status_mut() = StatusCode::BAD_REQUEST;
+                    Err(err)
+                }
+            }
+        };
+    let ws_stream = async_tungstenite::accept_hdr_async(raw_stream, negotiate).await?;
 
     let (mut ws_write, mut ws_read) = ws_stream.split();
 
+    let ping_interval = config.keepalive.interval();
+    // closed once the connection is done, which stops the response handling task
+    let (stream_tx, stream_rx): (Sender<SerializedResponse>, Receiver<SerializedResponse>) =
+        bounded(crate::QSIZE);
     // TODO maybe send ws_write from tx and get rid of this task + extra channel?
-    let stream_sender = if link {
-        let (stream_tx, stream_rx): (Sender<SerializedResponse>, Receiver<SerializedResponse>) =
-            bounded(crate::QSIZE);
-        // response handling task
+    if link || ping_interval.is_some() {
+        let source_url = source_url.clone();
+        // response handling task, also sends pings
         task::spawn::<_, Result<()>>(async move {
             // create post-processors for this stream
             match make_postprocessors(processors.as_slice()) {
                 Ok(mut post_processors) => {
+                    let mut next_ping = ping_interval.map(|i| Instant::now() + i);
                     // wait for response messages to arrive (via reply_event)
-                    while let Ok(response) = stream_rx.recv().await {
+                    loop {
+                        let response = if let Some(ping_at) = next_ping {
+                            match timeout(
+                                ping_at.saturating_duration_since(Instant::now()),
+                                stream_rx.recv(),
+                            )
+                            .await
+                            {
+                                Ok(response) => response,
+                                Err(_) => {
+                                    ws_write.send(Message::Ping(Vec::new())).await?;
+                                    next_ping = ping_interval.map(|i| Instant::now() + i);
+                                    continue;
+                                }
+                            }
+                        } else {
+                            stream_rx.recv().await
+                        };
+                        let response = if let Ok(response) = response {
+                            response
+                        } else {
+                            break;
+                        };
                         let event_id = response.event_id.to_string();
                         let msgs = match make_messages(response, &mut post_processors) {
                             // post-process
@@ -186,16 +256,37 @@ async fn handle_connection(
             }
             Ok(())
         });
-        Some(stream_tx)
-    } else {
-        None
-    };
+    }
+    let stream_sender = if link { Some(stream_tx.clone()) } else { None };
 
     tx.send(WsSourceReply::StartStream(stream, stream_sender))
         .await?;
 
-    while let Some(msg) = ws_read.next().await {
-        let mut meta = Value::object_with_capacity(1);
+    // clients answer pings, so we should hear from them at least once per ping interval
+    let read_timeout = ping_interval.map(|i| i + config.keepalive.timeout());
+    loop {
+        let msg = if let Some(read_timeout) = read_timeout {
+            if let Ok(msg) = timeout(read_timeout, ws_read.next()).await {
+                msg
+            } else {
+                warn!(
+                    "[Source::{}] No answer from {}:{} within {:?}, closing the connection.",
+                    &source_url,
+                    origin_uri.host,
+                    origin_uri.port.unwrap_or_default(),
+                    read_timeout
+                );
+                tx.send(WsSourceReply::EndStream(stream)).await?;
+                break;
+            }
+        } else {
+            ws_read.next().await
+        };
+        let msg = if let Some(msg) = msg { msg } else { break };
+        let mut meta = Value::object_with_capacity(2);
+        if let Some(protocol) = &protocol {
+            meta.insert("protocol", protocol.clone())?;
+        }
         match msg {
             Ok(Message::Text(t)) => {
                 meta.insert("binary", false)?;
@@ -342,6 +433,12 @@ impl Source for Int {
     async fn init(&mut self) -> Result<SourceState> {
         let listen_port = self.config.port;
         let listener = TcpListener::bind((self.config.host.as_str(), listen_port)).await?;
+        let acceptor = if let Some(tls_config) = self.config.tls.as_ref() {
+            Some(TlsAcceptor::from(Arc::new(load_server_config(tls_config)?)))
+        } else {
+            None
+        };
+        let config = self.config.clone();
         let (tx, rx) = bounded(crate::QSIZE);
         let uid = self.uid;
         let source_url = self.onramp_id.clone();
@@ -362,15 +459,46 @@ impl Source for Int {
                 };
 
                 stream_id += 1;
-                task::spawn(handle_connection(
-                    source_url.clone(),
-                    tx.clone(),
-                    stream,
-                    uri,
-                    processors.clone(),
-                    stream_id,
-                    link,
-                ));
+                let source_url = source_url.clone();
+                let tx = tx.clone();
+                let processors = processors.clone();
+                let config = config.clone();
+                let acceptor = acceptor.clone();
+                task::spawn(async move {
+                    let res = if let Some(acceptor) = acceptor {
+                        match acceptor.accept(stream).await {
+                            Ok(tls_stream) => {
+                                handle_connection(
+                                    source_url.clone(),
+                                    tx,
+                                    tls_stream,
+                                    uri,
+                                    processors,
+                                    stream_id,
+                                    link,
+                                    config,
+                                )
+                                .await
+                            }
+                            Err(e) => Err(e.into()),
+                        }
+                    } else {
+                        handle_connection(
+                            source_url.clone(),
+                            tx,
+                            stream,
+                            uri,
+                            processors,
+                            stream_id,
+                            link,
+                            config,
+                        )
+                        .await
+                    };
+                    if let Err(e) = res {
+                        error!("[Source::{}] Websocket connection error: {}", source_url, e);
+                    }
+                });
             }
         });
 
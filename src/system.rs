@@ -28,6 +28,7 @@ use async_std::task::{self, JoinHandle};
 use hashbrown::HashMap;
 use tremor_common::asy::file;
 use tremor_common::time::nanotime;
+use tremor_script::Value;
 
 pub(crate) use crate::offramp;
 pub(crate) use crate::onramp;
@@ -349,6 +350,21 @@ impl World {
         }
     }
 
+    /// Sends a runtime command to a running onramp instance
+    ///
+    /// # Errors
+    ///  * if the onramp instance isn't running or rejected the command
+    pub async fn command_onramp(&self, id: &TremorUrl, command: Value<'static>) -> Result<()> {
+        info!("Sending command to onramp {}", id);
+        if let Some(onramp) = self.reg.find_onramp(id).await? {
+            let (tx, rx) = bounded(1);
+            onramp.send(onramp::Msg::Command { command, tx }).await?;
+            rx.recv().await?
+        } else {
+            Err(ErrorKind::ArtefactNotFound(id.to_string()).into())
+        }
+    }
+
     /// Link an onramp
     ///
     /// # Errors
@@ -63,3 +63,19 @@ pub async fn get_artefact(req: Request) -> Result<Response> {
 
     reply(req, result, false, StatusCode::Ok).await
 }
+
+pub async fn command_servant(req: Request) -> Result<Response> {
+    let (req, command): (_, simd_json::OwnedValue) = decode(req).await?;
+    let a_id = req.param("aid").unwrap_or_default();
+    let s_id = req.param("sid").unwrap_or_default();
+    let url = build_url(&["onramp", a_id, s_id])?;
+    let world = &req.state().world;
+    world
+        .command_onramp(&url, command.into())
+        .await
+        .map_err(|e| match e.0 {
+            tremor_runtime::errors::ErrorKind::ArtefactNotFound(_) => Error::not_found(),
+            _ => Error::new(StatusCode::BadRequest, e.to_string()),
+        })?;
+    reply(req, (), false, StatusCode::Ok).await
+}
@@ -70,6 +70,8 @@ fn api_server(world: &World) -> tide::Server<api::State> {
     app.at("/onramp/:aid")
         .get(|r| handle_api_request(r, api::onramp::get_artefact))
         .delete(|r| handle_api_request(r, api::onramp::unpublish_artefact));
+    app.at("/onramp/:aid/:sid/command")
+        .post(|r| handle_api_request(r, api::onramp::command_servant));
     app.at("/offramp")
         .get(|r| handle_api_request(r, api::offramp::list_artefact))
         .post(|r| handle_api_request(r, api::offramp::publish_artefact));
@@ -697,7 +697,9 @@ fn factory(node: &NodeConfig) -> Result<Box<dyn InitializableOperator>> {
     use op::generic::{BatchFactory, CounterFactory};
     use op::grouper::BucketGrouperFactory;
     use op::identity::PassthroughFactory;
-    use op::qos::{BackpressureFactory, PercentileFactory, RoundRobinFactory, WalFactory};
+    use op::qos::{
+        BackpressureFactory, PercentileFactory, RoundRobinFactory, TraceSamplerFactory, WalFactory,
+    };
     let name_parts: Vec<&str> = node.op_type.split("::").collect();
     let factory = match name_parts.as_slice() {
         ["passthrough"] => PassthroughFactory::new_boxed(),
@@ -713,6 +715,7 @@ fn factory(node: &NodeConfig) -> Result<Box<dyn InitializableOperator>> {
         ["qos", "roundrobin"] => RoundRobinFactory::new_boxed(),
         ["qos", "wal"] => WalFactory::new_boxed(),
         ["qos", "percentile"] => PercentileFactory::new_boxed(),
+        ["qos", "trace_sampler"] => TraceSamplerFactory::new_boxed(),
         #[cfg(feature = "bert")]
         ["bert", "sequence_classification"] => SequenceClassificationFactory::new_boxed(),
         #[cfg(feature = "bert")]
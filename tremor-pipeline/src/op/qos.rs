@@ -15,9 +15,11 @@
 pub mod backpressure;
 pub mod percentile;
 pub mod rr;
+pub mod trace_sampler;
 pub mod wal;
 
 pub use backpressure::BackpressureFactory;
 pub use percentile::PercentileFactory;
 pub use rr::RoundRobinFactory;
+pub use trace_sampler::TraceSamplerFactory;
 pub use wal::WalFactory;
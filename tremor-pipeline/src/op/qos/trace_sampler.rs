@@ -0,0 +1,683 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Tail based trace sampler
+//!
+//! Buffers the spans of OpenTelemetry trace events, as delivered by the `otel` onramp,
+//! by their trace id and decides about each trace as a whole once `decision_wait_ms`
+//! passed since its first span arrived. A trace is kept if any of these policies match:
+//!
+//! * one of its spans has an error status
+//! * it took longer than `latency_ms` from the start of its first to the end of its last span
+//! * one of its spans carries one of the configured `attributes`
+//! * its trace id falls into the probabilistic baseline given by `sample_rate`
+//!
+//! The baseline is derived from the trace id, so every sampler keeps the same traces.
+//!
+//! Each trace is emitted at most once, as a single trace event holding all spans that
+//! arrived before it was decided. Spans arriving after their trace was decided are dropped
+//! instead of being emitted as a partial trace, a transactional event whose spans were all
+//! dropped this way is acked.
+//!
+//! At most `max_spans` spans are buffered. Once that limit is reached the circuit breaker
+//! is triggered and the oldest traces are decided early, before their decision window
+//! elapsed, it is restored once the buffer drained to half its size. Spans of an early
+//! decided trace that arrive later are dropped as well, so under overload emitted traces
+//! can lack spans that were still in flight.
+//!
+//! Events that aren't traces are passed on unchanged.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+//!
+//! ## Outputs
+//!
+//! The 1st additional output is used to route traces that were decided to
+//! be discarded.
+
+use crate::errors::{ErrorKind, Result};
+use crate::op::prelude::*;
+use crate::{EventId, EventIdGenerator, EventOriginUri, OpMeta};
+use beef::Cow;
+use lru::LruCache;
+use std::collections::{BTreeMap, VecDeque};
+use tremor_script::prelude::*;
+
+const OVERFLOW: Cow<'static, str> = Cow::const_str("overflow");
+
+/// `STATUS_CODE_ERROR` of an otel span status
+const STATUS_CODE_ERROR: i64 = 2;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// Time in milliseconds to wait for further spans of a trace after its
+    /// first span arrived.
+    ///
+    /// The default is 10s (`10000`).
+    #[serde(default = "d_decision_wait_ms")]
+    pub decision_wait_ms: u64,
+
+    /// Keep traces with at least one span with an error status.
+    ///
+    /// The default is `true`.
+    #[serde(default = "d_errors")]
+    pub errors: bool,
+
+    /// Keep traces taking longer than this many milliseconds.
+    #[serde(default = "Default::default")]
+    pub latency_ms: Option<u64>,
+
+    /// Keep traces with a span that has one of these attributes set to one
+    /// of the given values, or to any value if no values are given.
+    #[serde(default = "Default::default")]
+    pub attributes: BTreeMap<String, Vec<OwnedValue>>,
+
+    /// Share of the remaining traces to keep as a float between `0.0`
+    /// and `1.0`.
+    ///
+    /// The default is to keep none (`0.0`).
+    #[serde(default = "Default::default")]
+    pub sample_rate: f64,
+
+    /// The maximum number of buffered spans.
+    ///
+    /// The default is `100000`.
+    #[serde(default = "d_max_spans")]
+    pub max_spans: usize,
+}
+
+impl ConfigImpl for Config {}
+
+fn d_decision_wait_ms() -> u64 {
+    10_000
+}
+fn d_errors() -> bool {
+    true
+}
+fn d_max_spans() -> usize {
+    100_000
+}
+
+/// The buffered spans of a single trace
+struct Trace {
+    /// arrival of the first span
+    first_ns: u64,
+    /// tracking the ids of all events that contributed spans
+    id: EventId,
+    origin_uri: Option<EventOriginUri>,
+    /// merged operator metadata of all events that contributed spans
+    op_meta: OpMeta,
+    transactional: bool,
+    resource_spans: Vec<Value<'static>>,
+    spans: usize,
+    error: bool,
+    start_ns: u64,
+    end_ns: u64,
+    matched: bool,
+}
+
+impl Trace {
+    fn new(id: EventId, event: &Event) -> Self {
+        Self {
+            first_ns: event.ingest_ns,
+            id,
+            origin_uri: event.origin_uri.clone(),
+            op_meta: OpMeta::default(),
+            transactional: false,
+            resource_spans: Vec::new(),
+            spans: 0,
+            error: false,
+            start_ns: u64::MAX,
+            end_ns: 0,
+            matched: false,
+        }
+    }
+
+    fn track(&mut self, event: &Event) {
+        self.id.track(&event.id);
+        self.op_meta.merge(event.op_meta.clone());
+        self.transactional |= event.transactional;
+    }
+
+    /// adds resource spans only holding spans of this trace
+    fn add(&mut self, resource_spans: Value<'static>, config: &Config) {
+        let spans = resource_spans
+            .get_array("instrumentation_library_spans")
+            .into_iter()
+            .flatten()
+            .flat_map(|ils| ils.get_array("spans").into_iter().flatten());
+        for span in spans {
+            self.spans += 1;
+            self.error |= span.get("status").and_then(|status| status.get_i64("code"))
+                == Some(STATUS_CODE_ERROR);
+            if let (Some(start_ns), Some(end_ns)) = (
+                span.get_u64("start_time_unix_nano"),
+                span.get_u64("end_time_unix_nano"),
+            ) {
+                self.start_ns = self.start_ns.min(start_ns);
+                self.end_ns = self.end_ns.max(end_ns);
+            }
+            self.matched |= span.get_object("attributes").map_or(false, |attributes| {
+                config.attributes.iter().any(|(key, values)| {
+                    attributes.get(key.as_str()).map_or(false, |value| {
+                        values.is_empty() || values.iter().any(|expected| value == expected)
+                    })
+                })
+            });
+        }
+        self.resource_spans.push(resource_spans);
+    }
+
+    fn duration_ns(&self) -> u64 {
+        self.end_ns.saturating_sub(self.start_ns)
+    }
+
+    fn into_event(self) -> Event {
+        let mut data = Value::object_with_capacity(1);
+        data.try_insert("trace", self.resource_spans);
+        Event {
+            id: self.id,
+            data: (data, Value::object()).into(),
+            ingest_ns: self.first_ns,
+            origin_uri: self.origin_uri,
+            op_meta: self.op_meta,
+            transactional: self.transactional,
+            ..Event::default()
+        }
+    }
+}
+
+pub struct TraceSampler {
+    pub config: Config,
+    wait_ns: u64,
+    latency_ns: Option<u64>,
+    traces: HashMap<String, Trace>,
+    /// trace ids in the order their first span arrived
+    arrivals: VecDeque<String>,
+    /// recently decided traces, their late spans are dropped
+    decided: LruCache<String, ()>,
+    spans: usize,
+    triggered: bool,
+    event_id_gen: EventIdGenerator,
+}
+
+impl std::fmt::Debug for TraceSampler {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "TraceSampler")
+    }
+}
+
+impl TraceSampler {
+    fn new(uid: u64, config: Config) -> Self {
+        Self {
+            wait_ns: config.decision_wait_ms * 1_000_000,
+            latency_ns: config.latency_ms.map(|latency_ms| latency_ms * 1_000_000),
+            traces: HashMap::new(),
+            arrivals: VecDeque::new(),
+            decided: LruCache::new(config.max_spans),
+            spans: 0,
+            triggered: false,
+            event_id_gen: EventIdGenerator::new(uid),
+            config,
+        }
+    }
+
+    fn keep(&self, trace_id: &str, trace: &Trace) -> bool {
+        (self.config.errors && trace.error)
+            || self
+                .latency_ns
+                .map_or(false, |latency_ns| trace.duration_ns() > latency_ns)
+            || trace.matched
+            || baseline(trace_id) < self.config.sample_rate
+    }
+
+    fn output(keep: bool, event: Event) -> (Cow<'static, str>, Event) {
+        if keep {
+            (OUT, event)
+        } else {
+            (OVERFLOW, event)
+        }
+    }
+
+    /// decides about the trace whose first span arrived the longest ago
+    fn decide_oldest(&mut self) -> Option<(Cow<'static, str>, Event)> {
+        let trace_id = self.arrivals.pop_front()?;
+        let trace = self.traces.remove(&trace_id)?;
+        let keep = self.keep(&trace_id, &trace);
+        self.spans -= trace.spans;
+        self.decided.put(trace_id, ());
+        Some(Self::output(keep, trace.into_event()))
+    }
+
+    fn expired(&self, now_ns: u64) -> bool {
+        self.arrivals
+            .front()
+            .and_then(|trace_id| self.traces.get(trace_id))
+            .map_or(false, |trace| trace.first_ns + self.wait_ns <= now_ns)
+    }
+
+    /// decides about all traces whose decision window elapsed
+    fn flush(&mut self, now_ns: u64) -> Vec<(Cow<'static, str>, Event)> {
+        let mut events = Vec::new();
+        while self.expired(now_ns) {
+            events.extend(self.decide_oldest());
+        }
+        events
+    }
+
+    fn backpressure(&mut self, ingest_ns: u64) -> Vec<Event> {
+        if !self.triggered && self.spans >= self.config.max_spans {
+            self.triggered = true;
+            vec![Event::cb_trigger(ingest_ns)]
+        } else if self.triggered && self.spans <= self.config.max_spans / 2 {
+            self.triggered = false;
+            vec![Event::cb_restore(ingest_ns)]
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// Normalizes a trace id given as hex string, bytes or array of bytes to
+/// a lower case hex string
+fn trace_id(id: Option<&Value>) -> Result<String> {
+    let bytes = if let Some(hex) = id.and_then(Value::as_str) {
+        if hex.len() == 32 && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok(hex.to_ascii_lowercase());
+        }
+        None
+    } else if let Some(bytes) = id.and_then(Value::as_bytes) {
+        Some(bytes.to_vec())
+    } else {
+        id.and_then(Value::as_array)
+            .and_then(|array| array.iter().map(Value::as_u8).collect::<Option<Vec<u8>>>())
+    };
+    match bytes {
+        Some(bytes) if bytes.len() == 16 => {
+            Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+        }
+        _ => Err("Invalid or missing trace id of a span".into()),
+    }
+}
+
+/// Position of a trace id in `[0.0, 1.0)`, given by its leading 8 bytes
+#[allow(clippy::cast_precision_loss)]
+fn baseline(trace_id: &str) -> f64 {
+    u64::from_str_radix(&trace_id[..16], 16).map_or(1.0, |n| n as f64 / (u64::MAX as f64 + 1.0))
+}
+
+/// Copies `keys` of `value` into a new object holding `items` as `field`
+fn fragment(
+    value: &Value,
+    keys: [&'static str; 2],
+    field: &'static str,
+    items: Vec<Value<'static>>,
+) -> Value<'static> {
+    let mut fragment = Value::object_with_capacity(3);
+    for key in keys {
+        if let Some(v) = value.get(key) {
+            fragment.try_insert(key, v.clone_static());
+        }
+    }
+    fragment.try_insert(field, items);
+    fragment
+}
+
+/// Splits the resource spans of a trace event into resource spans only
+/// holding the spans of a single trace
+fn split(resource_spans: &[Value]) -> Result<BTreeMap<String, Vec<Value<'static>>>> {
+    let mut traces: BTreeMap<String, Vec<Value<'static>>> = BTreeMap::new();
+    for rs in resource_spans {
+        let mut by_trace: BTreeMap<String, Vec<Value<'static>>> = BTreeMap::new();
+        for ils in rs
+            .get_array("instrumentation_library_spans")
+            .into_iter()
+            .flatten()
+        {
+            let mut spans: BTreeMap<String, Vec<Value<'static>>> = BTreeMap::new();
+            for span in ils.get_array("spans").into_iter().flatten() {
+                spans
+                    .entry(trace_id(span.get("trace_id"))?)
+                    .or_default()
+                    .push(span.clone_static());
+            }
+            for (id, spans) in spans {
+                by_trace.entry(id).or_default().push(fragment(
+                    ils,
+                    ["instrumentation_library", "schema_url"],
+                    "spans",
+                    spans,
+                ));
+            }
+        }
+        for (id, ils) in by_trace {
+            traces.entry(id).or_default().push(fragment(
+                rs,
+                ["resource", "schema_url"],
+                "instrumentation_library_spans",
+                ils,
+            ));
+        }
+    }
+    Ok(traces)
+}
+
+op!(TraceSamplerFactory(uid, node) {
+    if let Some(map) = &node.config {
+        let config: Config = Config::new(map)?;
+        if !(0.0..=1.0).contains(&config.sample_rate) {
+            return Err(ErrorKind::BadOpConfig("sample_rate must be between 0.0 and 1.0".into()).into());
+        }
+        if config.max_spans == 0 {
+            return Err(ErrorKind::BadOpConfig("max_spans must be greater than 0".into()).into());
+        }
+        Ok(Box::new(TraceSampler::new(uid, config)))
+    } else {
+        Err(ErrorKind::MissingOpConfig(node.id.clone()).into())
+    }
+});
+
+impl Operator for TraceSampler {
+    fn on_event(
+        &mut self,
+        _uid: u64,
+        _port: &str,
+        _state: &mut Value<'static>,
+        event: Event,
+    ) -> Result<EventAndInsights> {
+        let mut events = self.flush(event.ingest_ns);
+        let traces = match event.data.parts().0.get_array("trace") {
+            Some(resource_spans) => split(resource_spans)?,
+            None => {
+                events.push((OUT, event));
+                return Ok(EventAndInsights {
+                    events,
+                    ..EventAndInsights::default()
+                });
+            }
+        };
+        let mut buffered = false;
+        for (trace_id, resource_spans) in traces {
+            if self.decided.get(&trace_id).is_some() {
+                // late spans of a trace we already emitted, drop them rather
+                // than emitting a partial trace
+                continue;
+            }
+            buffered = true;
+            if !self.traces.contains_key(&trace_id) {
+                let trace = Trace::new(self.event_id_gen.next_id(), &event);
+                self.arrivals.push_back(trace_id.clone());
+                self.traces.insert(trace_id.clone(), trace);
+            }
+            if let Some(trace) = self.traces.get_mut(&trace_id) {
+                let spans = trace.spans;
+                trace.track(&event);
+                for rs in resource_spans {
+                    trace.add(rs, &self.config);
+                }
+                self.spans += trace.spans - spans;
+            }
+        }
+        let mut insights = self.backpressure(event.ingest_ns);
+        if !buffered && event.transactional {
+            // none of the spans made it into a trace that will be acked or failed
+            let mut event = event;
+            insights.push(event.insight_ack());
+        }
+        while self.spans > self.config.max_spans {
+            events.extend(self.decide_oldest());
+        }
+        Ok(EventAndInsights { events, insights })
+    }
+
+    fn handles_signal(&self) -> bool {
+        true
+    }
+
+    fn on_signal(
+        &mut self,
+        _uid: u64,
+        _state: &mut Value<'static>,
+        signal: &mut Event,
+    ) -> Result<EventAndInsights> {
+        let events = self.flush(signal.ingest_ns);
+        let insights = self.backpressure(signal.ingest_ns);
+        Ok(EventAndInsights { events, insights })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tremor_value::literal;
+
+    const ERROR_TRACE: &str = "0af7651916cd43dd8448eb211c80319c";
+    const OK_TRACE: &str = "f1e2d3c4b5a697880123456789abcdef";
+
+    fn config() -> Config {
+        Config {
+            decision_wait_ms: 1,
+            errors: true,
+            latency_ms: None,
+            attributes: BTreeMap::new(),
+            sample_rate: 0.0,
+            max_spans: 100,
+        }
+    }
+
+    fn span(trace_id: &str, code: u64) -> Value<'static> {
+        literal!({
+            "trace_id": trace_id.to_string(),
+            "span_id": "051581bf3cb55c13",
+            "start_time_unix_nano": 1_000_000,
+            "end_time_unix_nano": 2_000_000,
+            "status": { "code": code, "deprecated_code": 0, "message": "" },
+            "attributes": { "http.status_code": 200 }
+        })
+    }
+
+    fn event(id: u64, ingest_ns: u64, spans: Vec<Value<'static>>) -> Event {
+        let data = literal!({
+            "trace": [{
+                "resource": { "attributes": {}, "dropped_attributes_count": 0 },
+                "instrumentation_library_spans": [{ "spans": spans, "schema_url": "" }],
+                "schema_url": ""
+            }]
+        });
+        Event {
+            id: (1, 1, id).into(),
+            ingest_ns,
+            data: (data, Value::object()).into(),
+            ..Event::default()
+        }
+    }
+
+    fn signal(ingest_ns: u64) -> Event {
+        Event {
+            ingest_ns,
+            ..Event::default()
+        }
+    }
+
+    fn spans(event: &Event) -> Vec<Value<'static>> {
+        event
+            .data
+            .parts()
+            .0
+            .get_array("trace")
+            .into_iter()
+            .flatten()
+            .flat_map(|rs| {
+                rs.get_array("instrumentation_library_spans")
+                    .into_iter()
+                    .flatten()
+            })
+            .flat_map(|ils| ils.get_array("spans").into_iter().flatten())
+            .map(Value::clone_static)
+            .collect()
+    }
+
+    #[test]
+    fn trace_ids() -> Result<()> {
+        let hex = ERROR_TRACE.to_uppercase();
+        assert_eq!(ERROR_TRACE, trace_id(Some(&Value::from(hex)))?);
+        let bytes: Value = (0_u8..16).collect();
+        assert_eq!("000102030405060708090a0b0c0d0e0f", trace_id(Some(&bytes))?);
+        assert!(trace_id(Some(&Value::from("snot"))).is_err());
+        assert!(trace_id(None).is_err());
+        assert!(baseline("00000000000000000000000000000000") < 0.01);
+        assert!(baseline("ffffffffffffffff0000000000000000") > 0.99);
+        Ok(())
+    }
+
+    #[test]
+    fn whole_traces() -> Result<()> {
+        let mut op = TraceSampler::new(0, config());
+        let mut state = Value::null();
+
+        // spans of both traces arrive in two events, nothing is emitted
+        // before the decision window elapsed
+        let e1 = event(1, 0, vec![span(ERROR_TRACE, 0), span(OK_TRACE, 0)]);
+        let r = op.on_event(0, "in", &mut state, e1)?;
+        assert!(r.events.is_empty());
+        let e2 = event(2, 500_000, vec![span(ERROR_TRACE, 2), span(OK_TRACE, 1)]);
+        let r = op.on_event(0, "in", &mut state, e2)?;
+        assert!(r.events.is_empty());
+        assert_eq!(4, op.spans);
+
+        let mut r = op.on_signal(0, &mut state, &mut signal(1_000_000))?.events;
+        assert_eq!(2, r.len());
+        let (port, dropped) = r.pop().expect("no results");
+        assert_eq!("overflow", port);
+        assert_eq!(2, spans(&dropped).len());
+        let (port, kept) = r.pop().expect("no results");
+        assert_eq!("out", port);
+        let kept_spans = spans(&kept);
+        assert_eq!(2, kept_spans.len());
+        assert!(kept_spans
+            .iter()
+            .all(|span| span.get_str("trace_id") == Some(ERROR_TRACE)));
+        assert!(kept.id.is_tracking(&(1, 1, 1).into()));
+        assert!(kept.id.is_tracking(&(1, 1, 2).into()));
+        assert_eq!(0, op.spans);
+
+        // late spans of decided traces are dropped, their event is acked
+        let mut e3 = event(3, 2_000_000, vec![span(OK_TRACE, 0), span(ERROR_TRACE, 0)]);
+        e3.transactional = true;
+        let r = op.on_event(0, "in", &mut state, e3)?;
+        assert!(r.events.is_empty());
+        assert_eq!(1, r.insights.len());
+        assert_eq!(CbAction::Ack, r.insights[0].cb);
+        assert_eq!(0, op.spans);
+        Ok(())
+    }
+
+    #[test]
+    fn policies() -> Result<()> {
+        let mut state = Value::null();
+        let mut config = config();
+        config.errors = false;
+        config.latency_ms = Some(5);
+        config.attributes.insert(
+            "http.status_code".to_string(),
+            vec![OwnedValue::from(500_i64)],
+        );
+        let mut op = TraceSampler::new(0, config.clone());
+
+        let mut slow = span(OK_TRACE, 0);
+        slow.insert("end_time_unix_nano", 10_000_000)?;
+        let mut failed = span(ERROR_TRACE, 2);
+        failed.insert("attributes", literal!({ "http.status_code": 500 }))?;
+        let e = event(1, 0, vec![span(OK_TRACE, 0), slow, failed]);
+        op.on_event(0, "in", &mut state, e)?;
+        let r = op.on_signal(0, &mut state, &mut signal(1_000_000))?.events;
+        assert_eq!(2, r.len());
+        assert!(r.iter().all(|(port, _)| &**port == "out"));
+
+        // only the probabilistic baseline is left
+        config.latency_ms = None;
+        config.attributes.clear();
+        config.sample_rate = 0.5;
+        let mut op = TraceSampler::new(0, config);
+        let e = event(1, 0, vec![span(ERROR_TRACE, 2), span(OK_TRACE, 0)]);
+        op.on_event(0, "in", &mut state, e)?;
+        let r = op.on_signal(0, &mut state, &mut signal(1_000_000))?.events;
+        let ports: Vec<&str> = r.iter().map(|(port, _)| &**port).collect();
+        assert_eq!(vec!["out", "overflow"], ports);
+        Ok(())
+    }
+
+    #[test]
+    fn backpressure() -> Result<()> {
+        let mut config = config();
+        config.max_spans = 2;
+        let mut op = TraceSampler::new(0, config);
+        let mut state = Value::null();
+
+        let e = event(1, 0, vec![span(ERROR_TRACE, 2), span(ERROR_TRACE, 0)]);
+        let r = op.on_event(0, "in", &mut state, e)?;
+        assert!(r.events.is_empty());
+        assert_eq!(1, r.insights.len());
+        assert_eq!(CbAction::Close, r.insights[0].cb);
+
+        // the buffer overflows, the oldest trace is decided early, as a whole
+        let e = event(2, 10, vec![span(OK_TRACE, 0)]);
+        let r = op.on_event(0, "in", &mut state, e)?;
+        assert!(r.insights.is_empty());
+        assert_eq!(1, r.events.len());
+        assert_eq!(2, spans(&r.events[0].1).len());
+        assert_eq!(1, op.spans);
+
+        let r = op.on_signal(0, &mut state, &mut signal(20))?;
+        assert!(r.events.is_empty());
+        assert_eq!(1, r.insights.len());
+        assert_eq!(CbAction::Open, r.insights[0].cb);
+        Ok(())
+    }
+
+    #[test]
+    fn op_meta() -> Result<()> {
+        let mut op = TraceSampler::new(0, config());
+        let mut state = Value::null();
+        let mut e1 = event(1, 0, vec![span(ERROR_TRACE, 2)]);
+        e1.op_meta.insert(1, 1);
+        op.on_event(0, "in", &mut state, e1)?;
+        let mut e2 = event(2, 10, vec![span(ERROR_TRACE, 0)]);
+        e2.op_meta.insert(2, 2);
+        op.on_event(0, "in", &mut state, e2)?;
+
+        let r = op.on_signal(0, &mut state, &mut signal(1_000_000))?.events;
+        assert_eq!(1, r.len());
+        let op_meta = &r[0].1.op_meta;
+        assert!(op_meta.contains_key(1));
+        assert!(op_meta.contains_key(2));
+        Ok(())
+    }
+
+    #[test]
+    fn pass_through() -> Result<()> {
+        let mut op = TraceSampler::new(0, config());
+        let mut state = Value::null();
+        let e = Event {
+            id: (1, 1, 1).into(),
+            data: (literal!({ "logs": [] }), Value::object()).into(),
+            ..Event::default()
+        };
+        let r = op.on_event(0, "in", &mut state, e)?;
+        assert_eq!(1, r.events.len());
+        assert_eq!("out", r.events[0].0);
+        Ok(())
+    }
+}